@@ -0,0 +1,267 @@
+//! Adaptation algorithm implementation (described as in Figure 6).
+
+/// Signal
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    /// QueueCongest signal carries the outgoing rate and the estimated latency.
+    QueueCongest(f64, f64),
+
+    /// Queue is empty, try to be aggressive.
+    QueueEmpty,
+
+    /// Congestion signal from the remote.
+    RemoteCongest(f64, f64),
+
+    /// The remote's analytics can't keep up (its compute, not the network, is
+    /// the bottleneck). Carries the rate of a level it keeps up with and its
+    /// processing latency.
+    ComputeCongest(f64, f64),
+
+    /// Data is lost on the way, which the latency may not show (e.g. on
+    /// cellular links, until throughput drops all at once). Carries the rate
+    /// that gets through and the share of bytes lost.
+    LossCongest(f64, f64),
+
+    /// Probe done
+    ProbeDone,
+}
+
+/// Action
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Nothing to do.
+    NoOp,
+
+    /// Move to the next (less degraded) configuration.
+    AdvanceConfig,
+
+    /// When the action is `AdjustConfig`, we inform the estimated outgoing rate
+    AdjustConfig(f64),
+
+    /// Start the probe with a target bandwidth (in kbps)
+    StartProbe,
+
+    /// Probe more aggressively.
+    IncreaseProbePace,
+
+    /// Stop the probe.
+    StopProbe,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+/// States of the rate adaptation algorithm.
+pub enum State {
+    /// Ramping up from the initial configuration.
+    Startup,
+
+    /// Degrading because of congestion.
+    Degrade,
+
+    /// The configuration matches the available bandwidth.
+    Steady,
+
+    /// Probing for more bandwidth.
+    Probe,
+}
+
+/// A signal that does not apply to the state it arrives in, e.g. `ProbeDone`
+/// racing with a congestion signal that has already stopped the probe. The
+/// state machine ignores it; senders may want to log it.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct StraySignal {
+    /// The state the signal arrived in.
+    pub state: State,
+
+    /// The signal.
+    pub signal: Signal,
+}
+
+/// The rate adaptation state machine.
+pub struct Adaptation {
+    state: State,
+    steady_count: usize,
+    startup_congest: usize,
+    startup_congest_enough: usize,
+    steady_enough: usize,
+}
+
+impl Default for Adaptation {
+    fn default() -> Adaptation {
+        Adaptation::with_params(Adaptation::STARTUP_CONGEST_ENOUGH, Adaptation::STEADY_ENOUGH)
+    }
+}
+
+impl Adaptation {
+    /// Allow (transit) congestion during the startup phase as TCP is adjusting
+    pub const STARTUP_CONGEST_ENOUGH: usize = 3;
+
+    /// Only start probing if we are steady enough (that is, enough Q_E).
+    pub const STEADY_ENOUGH: usize = 3;
+
+    /// Creates a state machine that tolerates `startup_congest_enough`
+    /// congestion signals during startup and waits for `steady_enough`
+    /// `QueueEmpty` before probing, e.g. to tune them.
+    pub fn with_params(startup_congest_enough: usize, steady_enough: usize) -> Adaptation {
+        Adaptation {
+            state: State::Startup,
+            steady_count: 0,
+            startup_congest: 0,
+            startup_congest_enough,
+            steady_enough,
+        }
+    }
+
+    /// Returns the current state.
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Reacts to `signal` given whether the configuration is at its maximum.
+    /// Returns the action the sender should take.
+    pub fn transit(&mut self, signal: Signal, max_config: bool) -> Action {
+        self.react(signal, max_config).0
+    }
+
+    /// Same as `transit`, but also returns the signal if it does not apply to
+    /// the current state (the action is then `NoOp`).
+    pub fn react(&mut self, signal: Signal, max_config: bool) -> (Action, Option<StraySignal>) {
+        let stray = StraySignal {
+            state: self.state,
+            signal,
+        };
+        let action = match (self.state, signal, max_config) {
+            (State::Startup, Signal::QueueEmpty, false) => {
+                // transition 1
+                self.startup_congest = 0;
+                Action::AdvanceConfig
+            }
+            (State::Startup, Signal::QueueEmpty, true) => {
+                // transition 2, queue is empty and config at max
+                self.startup_congest = 0;
+                self.state = State::Steady;
+                Action::NoOp
+            }
+            (State::Startup, Signal::ComputeCongest(rate, _latency), _) => {
+                // Unlike network congestion, a compute backlog is not transient
+                // as TCP adjusts: degrade right away.
+                self.startup_congest = 0;
+                self.state = State::Degrade;
+                Action::AdjustConfig(rate)
+            }
+            (State::Startup, Signal::QueueCongest(rate, _latency), _) |
+            (State::Startup, Signal::RemoteCongest(rate, _latency), _) |
+            (State::Startup, Signal::LossCongest(rate, _latency), _) => {
+                // transition 3
+                // transition 7
+                if self.startup_congest > self.startup_congest_enough {
+                    self.startup_congest = 0;
+                    self.state = State::Degrade;
+                    Action::AdjustConfig(rate)
+                } else {
+                    self.startup_congest += 1;
+                    Action::NoOp
+                }
+            }
+            (State::Degrade, Signal::QueueCongest(rate, _latency), _) |
+            (State::Degrade, Signal::RemoteCongest(rate, _latency), _) |
+            (State::Degrade, Signal::LossCongest(rate, _latency), _) |
+            (State::Degrade, Signal::ComputeCongest(rate, _latency), _) => {
+                // transition 4
+                self.state = State::Degrade;
+                Action::AdjustConfig(rate)
+            }
+            (State::Degrade, Signal::QueueEmpty, _) => {
+                // transition 5
+                self.state = State::Steady;
+                Action::NoOp
+            }
+            (State::Steady, Signal::QueueCongest(rate, _latency), _) |
+            (State::Steady, Signal::RemoteCongest(rate, _latency), _) |
+            (State::Steady, Signal::LossCongest(rate, _latency), _) |
+            (State::Steady, Signal::ComputeCongest(rate, _latency), _) => {
+                // transition 6
+                self.steady_count = 0;
+                self.state = State::Degrade;
+                Action::AdjustConfig(rate)
+            }
+            (State::Steady, Signal::QueueEmpty, false) => {
+                // transition 7
+                if self.steady_count > self.steady_enough {
+                    self.steady_count = 0;
+                    self.state = State::Probe;
+                    Action::StartProbe
+                } else {
+                    self.steady_count += 1;
+                    Action::NoOp
+                }
+            }
+            (State::Probe, Signal::QueueCongest(_rate, _latency), _) |
+            (State::Probe, Signal::RemoteCongest(_rate, _latency), _) |
+            (State::Probe, Signal::LossCongest(_rate, _latency), _) |
+            (State::Probe, Signal::ComputeCongest(_rate, _latency), _) => {
+                // transtion 8
+                self.state = State::Steady;
+                Action::StopProbe
+            }
+            (State::Probe, Signal::ProbeDone, _) => {
+                // transition 9
+                self.state = State::Steady;
+                Action::AdvanceConfig
+            }
+            (State::Probe, Signal::QueueEmpty, _) => {
+                // transition 10
+                Action::IncreaseProbePace
+            }
+            (State::Steady, Signal::QueueEmpty, true) => {
+                // The right state to stay in for as long as possible
+                Action::NoOp
+            }
+            (State::Startup, Signal::ProbeDone, _) |
+            (State::Degrade, Signal::ProbeDone, _) |
+            (State::Steady, Signal::ProbeDone, _) => {
+                // There is no probe to finish (it has been stopped, or never
+                // started): ignore it.
+                return (Action::NoOp, Some(stray));
+            }
+        };
+        (action, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compute_congest_degrades_without_grace() {
+        let mut network = Adaptation::default();
+        match network.transit(Signal::RemoteCongest(100.0, 500.0), false) {
+            Action::NoOp => {}
+            action => panic!("unexpected {:?}", action),
+        }
+
+        let mut compute = Adaptation::default();
+        match compute.transit(Signal::ComputeCongest(100.0, 500.0), false) {
+            Action::AdjustConfig(rate) => assert_eq!(rate, 100.0),
+            action => panic!("unexpected {:?}", action),
+        }
+        match compute.state() {
+            State::Degrade => {}
+            state => panic!("unexpected {:?}", state),
+        }
+    }
+
+    #[test]
+    fn stray_probe_done_is_reported() {
+        let mut adaptation = Adaptation::default();
+        adaptation.transit(Signal::ComputeCongest(100.0, 500.0), false);
+        adaptation.transit(Signal::QueueEmpty, false);
+        let expected = StraySignal {
+            state: State::Steady,
+            signal: Signal::ProbeDone,
+        };
+        assert_eq!(adaptation.react(Signal::ProbeDone, false), (Action::NoOp, Some(expected)));
+        assert_eq!(adaptation.state(), State::Steady);
+        assert_eq!(adaptation.react(Signal::QueueEmpty, false), (Action::NoOp, None));
+    }
+}
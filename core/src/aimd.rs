@@ -0,0 +1,102 @@
+//! AIMD (additive increase, multiplicative decrease) rate control, the classic
+//! baseline AWStream is compared against. Unlike `adaptation::Adaptation`, it
+//! knows nothing about the profile: it only keeps a target rate, cuts it on
+//! congestion and adds a fixed step whenever the queue stays empty. The sender
+//! then uses the highest level that fits within the target.
+
+use adaptation::Signal;
+
+/// Default additive increase (kbps) for every `QueueEmpty`.
+pub const AIMD_INCREASE: f64 = 100.0;
+
+/// Default multiplicative decrease on congestion.
+pub const AIMD_DECREASE: f64 = 0.5;
+
+/// An AIMD controller on the sending rate.
+#[derive(Clone, Debug)]
+pub struct Aimd {
+    /// The target rate (kbps).
+    rate: f64,
+
+    /// The target never grows beyond this rate (kbps).
+    max_rate: f64,
+
+    /// Additive increase (kbps).
+    increase: f64,
+
+    /// Multiplicative decrease, between 0 and 1.
+    decrease: f64,
+}
+
+impl Aimd {
+    /// Creates a controller starting at `rate`, growing up to `max_rate`,
+    /// with the default increase and decrease.
+    pub fn new(rate: f64, max_rate: f64) -> Aimd {
+        Aimd::with_params(rate, max_rate, AIMD_INCREASE, AIMD_DECREASE)
+    }
+
+    /// Creates a controller with a custom increase (kbps) and decrease.
+    pub fn with_params(rate: f64, max_rate: f64, increase: f64, decrease: f64) -> Aimd {
+        Aimd {
+            rate: rate.min(max_rate),
+            max_rate,
+            increase,
+            decrease,
+        }
+    }
+
+    /// The current target rate (kbps).
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Reacts to a signal. Returns the new target rate if it has changed.
+    ///
+    /// Congestion signals carry the rate that actually gets through; the
+    /// target is only cut if it is above that rate, so that one congestion
+    /// episode (reported every monitor tick until the queue drains) causes one
+    /// decrease rather than a collapse to the lowest level.
+    pub fn transit(&mut self, signal: Signal) -> Option<f64> {
+        let rate = match signal {
+            Signal::QueueCongest(rate, _) |
+            Signal::RemoteCongest(rate, _) |
+            Signal::ComputeCongest(rate, _) |
+            Signal::LossCongest(rate, _) => {
+                if self.rate <= rate {
+                    return None;
+                }
+                self.rate * self.decrease
+            }
+            Signal::QueueEmpty => (self.rate + self.increase).min(self.max_rate),
+            Signal::ProbeDone => return None,
+        };
+
+        if rate == self.rate {
+            None
+        } else {
+            self.rate = rate;
+            Some(rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn additive_increase_multiplicative_decrease() {
+        let mut aimd = Aimd::new(1000.0, 1250.0);
+        assert_eq!(aimd.transit(Signal::QueueEmpty), Some(1100.0));
+        assert_eq!(aimd.transit(Signal::QueueEmpty), Some(1200.0));
+        assert_eq!(aimd.transit(Signal::QueueEmpty), Some(1250.0));
+        assert_eq!(aimd.transit(Signal::QueueEmpty), None);
+
+        // Cut once, then wait for the queue to drain.
+        assert_eq!(aimd.transit(Signal::QueueCongest(900.0, 50.0)), Some(625.0));
+        assert_eq!(aimd.transit(Signal::QueueCongest(900.0, 30.0)), None);
+        assert_eq!(aimd.transit(Signal::RemoteCongest(500.0, 300.0)), Some(312.5));
+        assert_eq!(aimd.transit(Signal::ProbeDone), None);
+        assert_eq!(aimd.rate(), 312.5);
+    }
+}
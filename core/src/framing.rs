@@ -0,0 +1,270 @@
+//! Wire framing. Every frame is the payload length (8 bytes, big endian), the
+//! CRC32 of the length and the payload (4 bytes, big endian), and then the
+//! payload itself. A frame that does not match its checksum is skipped as a
+//! whole, so that decoding carries on with the next frame. A length above
+//! `MAX_FRAME_LEN` can't be told from garbage, and nothing after it is framed.
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::mem;
+
+/// Size of the length prefix.
+pub const LEN_SIZE: usize = mem::size_of::<u64>();
+
+/// Size of the checksum.
+pub const CRC_SIZE: usize = mem::size_of::<u32>();
+
+/// Size of everything in a frame before the payload.
+pub const HEADER_SIZE: usize = LEN_SIZE + CRC_SIZE;
+
+/// Largest payload a frame may carry, well above any frame of video, so that a
+/// corrupted length is rejected before its bytes are waited for.
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Lookup table of the CRC32 (IEEE 802.3) polynomial, reflected.
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+/// Computes a CRC32 (IEEE 802.3, as in zlib) over bytes fed in pieces.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32 { state: !0 }
+    }
+}
+
+impl Crc32 {
+    /// Starts a checksum over no bytes.
+    pub fn new() -> Crc32 {
+        Crc32::default()
+    }
+
+    /// Adds `bytes` to the checksum.
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            let index = ((self.state ^ u32::from(b)) & 0xFF) as usize;
+            self.state = CRC_TABLE[index] ^ (self.state >> 8);
+        }
+    }
+
+    /// Returns the checksum of the bytes so far.
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+/// Returns the CRC32 of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+/// Returns the checksum of a frame, which covers its length prefix as well as
+/// its payload, so that a corrupted length is caught too.
+pub fn frame_crc(len: u64, payload: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&len.to_be_bytes());
+    crc.update(payload);
+    crc.finish()
+}
+
+/// A frame that does not match its checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corrupt {
+    /// The checksum the frame carries.
+    pub expected: u32,
+
+    /// The checksum of the frame as received.
+    pub actual: u32,
+}
+
+/// Why a frame can't be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The frame does not match its checksum; it is skipped.
+    Corrupt(Corrupt),
+
+    /// The length prefix is above `MAX_FRAME_LEN`, or doesn't fit in memory.
+    /// The stream can't be framed past it.
+    TooLong(u64),
+}
+
+/// Returns the header of a frame of `len` bytes with checksum `crc`.
+pub fn encode_header(len: u64, crc: u32) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[..LEN_SIZE].copy_from_slice(&len.to_be_bytes());
+    header[LEN_SIZE..].copy_from_slice(&crc.to_be_bytes());
+    header
+}
+
+/// Returns the payload length and the checksum a frame header carries, or
+/// `DecodeError::TooLong` for a length that can't be taken.
+pub fn decode_header(header: &[u8; HEADER_SIZE]) -> Result<(usize, u32), DecodeError> {
+    let mut len = [0; LEN_SIZE];
+    len.copy_from_slice(&header[..LEN_SIZE]);
+    let len = u64::from_be_bytes(len);
+    let mut crc = [0; CRC_SIZE];
+    crc.copy_from_slice(&header[LEN_SIZE..]);
+    match usize::try_from(len) {
+        Ok(n) if n <= MAX_FRAME_LEN => Ok((n, u32::from_be_bytes(crc))),
+        _ => Err(DecodeError::TooLong(len)),
+    }
+}
+
+/// Appends `payload` as a frame to `out`.
+pub fn encode(payload: &[u8], out: &mut Vec<u8>) {
+    let len = payload.len() as u64;
+    out.reserve(HEADER_SIZE + payload.len());
+    out.extend_from_slice(&encode_header(len, frame_crc(len, payload)));
+    out.extend_from_slice(payload);
+}
+
+/// `FrameDecoder` reassembles frames from bytes as they arrive, regardless of
+/// how the transport chunks them.
+#[derive(Default, Debug)]
+pub struct FrameDecoder {
+    /// Bytes received but not yet returned as a frame.
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Creates an empty decoder.
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::default()
+    }
+
+    /// Feeds received bytes into the decoder.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Returns the payload of the next complete frame, or `None` if more bytes
+    /// are needed. A corrupt frame is consumed all the same, so the next call
+    /// moves on to the frame after it. A length that is too long is returned
+    /// on every call, with nothing buffered for it.
+    pub fn next_frame(&mut self) -> Option<Result<Vec<u8>, DecodeError>> {
+        if self.buf.len() < HEADER_SIZE {
+            return None;
+        }
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&self.buf[..HEADER_SIZE]);
+        let (len, expected) = match decode_header(&header) {
+            Ok(header) => header,
+            Err(e) => return Some(Err(e)),
+        };
+        let end = match HEADER_SIZE.checked_add(len) {
+            Some(end) => end,
+            None => return Some(Err(DecodeError::TooLong(len as u64))),
+        };
+        if self.buf.len() < end {
+            return None;
+        }
+        let payload = self.buf[HEADER_SIZE..end].to_vec();
+        self.buf.drain(..end);
+        let actual = frame_crc(len as u64, &payload);
+        if actual != expected {
+            return Some(Err(DecodeError::Corrupt(Corrupt { expected, actual })));
+        }
+        Some(Ok(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_survive_arbitrary_chunks() {
+        let mut wire = Vec::new();
+        encode(b"hello", &mut wire);
+        encode(b"", &mut wire);
+        encode(b"awstream", &mut wire);
+
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for chunk in wire.chunks(3) {
+            decoder.push(chunk);
+            while let Some(frame) = decoder.next_frame() {
+                frames.push(frame.unwrap());
+            }
+        }
+        assert_eq!(frames, [&b"hello"[..], &b""[..], &b"awstream"[..]]);
+    }
+
+    #[test]
+    fn corrupt_frames_are_skipped() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let mut wire = Vec::new();
+        encode(b"hello", &mut wire);
+        encode(b"awstream", &mut wire);
+        wire[HEADER_SIZE + 1] ^= 0x20;
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&wire);
+        match decoder.next_frame() {
+            Some(Err(DecodeError::Corrupt(Corrupt { expected, actual }))) => {
+                assert_eq!(expected, frame_crc(5, b"hello"));
+                assert_eq!(actual, frame_crc(5, b"hEllo"));
+            }
+            other => panic!("expected a corrupt frame, got {:?}", other),
+        }
+        assert_eq!(decoder.next_frame(), Some(Ok(b"awstream".to_vec())));
+        assert_eq!(decoder.next_frame(), None);
+    }
+
+    #[test]
+    fn corrupt_lengths_are_caught() {
+        let mut wire = Vec::new();
+        encode(b"hello", &mut wire);
+        wire[LEN_SIZE - 1] = 4;
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&wire);
+        match decoder.next_frame() {
+            Some(Err(DecodeError::Corrupt(Corrupt { expected, .. }))) => {
+                assert_eq!(expected, frame_crc(5, b"hello"))
+            }
+            other => panic!("expected a corrupt frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lengths_past_the_limit_are_rejected() {
+        let mut wire = Vec::new();
+        encode(b"hello", &mut wire);
+        wire[..LEN_SIZE].copy_from_slice(&u64::max_value().to_be_bytes());
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&wire[..HEADER_SIZE]);
+        let too_long = Some(Err(DecodeError::TooLong(u64::max_value())));
+        assert_eq!(decoder.next_frame(), too_long);
+        assert_eq!(decoder.next_frame(), too_long);
+
+        let len = MAX_FRAME_LEN as u64 + 1;
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&len.to_be_bytes());
+        decoder.push(&[0; CRC_SIZE]);
+        assert_eq!(decoder.next_frame(), Some(Err(DecodeError::TooLong(len))));
+    }
+}
@@ -0,0 +1,31 @@
+//! The transport-independent core of an AWStream client: the wire framing, the
+//! profile levels, the adaptation state machine, and AIMD and PID baselines.
+//!
+//! This crate does not depend on `std` (disable the default `std` feature) so
+//! that it can be compiled to `wasm32-unknown-unknown`. It does no I/O by
+//! itself: a transport feeds received bytes to `framing::FrameDecoder` and
+//! writes whatever `framing::encode` produces. Because every frame carries its
+//! own length, a message-based transport can also carry one frame per message,
+//! e.g. a browser's WebSocket; `websocket` frames those messages for peers that
+//! don't have a browser to do it.
+
+#![no_std]
+#![deny(missing_docs)]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+extern crate alloc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+pub mod adaptation;
+pub mod aimd;
+pub mod framing;
+pub mod pid;
+pub mod profile;
+pub mod websocket;
+
+#[cfg(test)]
+mod scenarios;
@@ -0,0 +1,133 @@
+//! A PID controller on the sending rate, an alternative to
+//! `adaptation::Adaptation` that neither probes nor waits for signals. Every
+//! monitor interval, it compares the latency of the sender's queue against a
+//! setpoint and moves the target rate by the error, its integral and its
+//! derivative. The sender then uses the highest level that fits within the
+//! target.
+//!
+//! The controller is in velocity form: it adjusts the previous target rather
+//! than computing it from the accumulated error, so that it never winds up
+//! while the target is pinned at the lowest or highest rate.
+
+/// Default setpoint of the queue latency (ms).
+pub const PID_SETPOINT: f64 = 20.0;
+
+/// Default proportional gain (kbps per ms of error).
+pub const PID_KP: f64 = 2.0;
+
+/// Default integral gain (kbps per ms of error and second).
+pub const PID_KI: f64 = 10.0;
+
+/// Default derivative gain (kbps per ms of error per second).
+pub const PID_KD: f64 = 0.0;
+
+/// The setpoint and gains of a `Pid`. Those not set take their default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct PidGains {
+    /// Queue latency (ms) the controller aims for.
+    pub setpoint: f64,
+
+    /// Proportional gain (kbps per ms of error).
+    pub kp: f64,
+
+    /// Integral gain (kbps per ms of error and second).
+    pub ki: f64,
+
+    /// Derivative gain (kbps per ms of error per second).
+    pub kd: f64,
+}
+
+impl Default for PidGains {
+    fn default() -> PidGains {
+        PidGains {
+            setpoint: PID_SETPOINT,
+            kp: PID_KP,
+            ki: PID_KI,
+            kd: PID_KD,
+        }
+    }
+}
+
+/// A PID controller on the sending rate.
+#[derive(Clone, Debug)]
+pub struct Pid {
+    gains: PidGains,
+
+    /// The target rate (kbps).
+    rate: f64,
+
+    /// The target stays within these rates (kbps).
+    min_rate: f64,
+    max_rate: f64,
+
+    /// The errors (ms) of the last two updates.
+    errors: Option<(f64, f64)>,
+}
+
+impl Pid {
+    /// Creates a controller starting at `rate`, kept between `min_rate` and
+    /// `max_rate`.
+    pub fn new(rate: f64, min_rate: f64, max_rate: f64, gains: PidGains) -> Pid {
+        Pid {
+            gains,
+            rate: rate.max(min_rate).min(max_rate),
+            min_rate,
+            max_rate,
+            errors: None,
+        }
+    }
+
+    /// The current target rate (kbps).
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Updates the target with the queue `latency` (ms) measured over the
+    /// last `interval` (s). Returns the new target rate.
+    pub fn update(&mut self, latency: f64, interval: f64) -> f64 {
+        let g = self.gains;
+        let error = g.setpoint - latency;
+        let (last, before) = self.errors.unwrap_or((error, error));
+        let delta = g.kp * (error - last) + g.ki * error * interval +
+            g.kd * (error - 2.0 * last + before) / interval;
+        self.errors = Some((error, last));
+        self.rate = (self.rate + delta).max(self.min_rate).min(self.max_rate);
+        self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_follows_the_latency_error() {
+        let gains = PidGains {
+            setpoint: 20.0,
+            kp: 2.0,
+            ki: 10.0,
+            kd: 0.0,
+        };
+        let mut pid = Pid::new(1000.0, 100.0, 1500.0, gains);
+
+        // Below the setpoint, the integral grows the rate up to the max.
+        assert_eq!(pid.update(0.0, 0.1), 1020.0);
+        assert_eq!(pid.update(0.0, 0.1), 1040.0);
+        for _ in 0..100 {
+            pid.update(0.0, 0.1);
+        }
+        assert_eq!(pid.rate(), 1500.0);
+
+        // A queue builds: both terms cut the rate, the proportional one once.
+        assert_eq!(pid.update(120.0, 0.1), 1500.0 - 240.0 - 100.0);
+        assert_eq!(pid.update(120.0, 0.1), 1160.0 - 100.0);
+
+        // Never below the min, and no windup: it recovers at once.
+        for _ in 0..100 {
+            pid.update(500.0, 0.1);
+        }
+        assert_eq!(pid.rate(), 100.0);
+        assert!(pid.update(0.0, 0.1) > 100.0);
+    }
+}
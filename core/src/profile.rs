@@ -0,0 +1,368 @@
+//! Profile levels. A profile is a list of bandwidth requirements, one per
+//! level, sorted in ascending order. The client only needs the levels to adapt;
+//! what each level means (the configuration) is up to the source.
+//!
+//! A profile may also carry the accuracy of every level. Profiles from the
+//! profiler are Pareto-sorted, each level more accurate than the ones below,
+//! so that the highest level that fits a bandwidth is also the most accurate.
+//! When they are not (e.g. a level measured with a different model, or
+//! accuracies recalibrated online), `Selection::Accuracy` picks by accuracy.
+
+use alloc::vec::Vec;
+
+/// How many times we stick to the current level when asked to adjust to it.
+pub const ADJUST_STICKY_MAX: usize = 3;
+
+fn default_adjust_sticky_max() -> usize {
+    ADJUST_STICKY_MAX
+}
+
+/// How a profile picks the level for a bandwidth.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Selection {
+    /// The highest level that fits.
+    #[default]
+    Index,
+
+    /// The most accurate level that fits, the cheapest of them on a tie. The
+    /// same as `Index` if accuracies are unknown.
+    Accuracy,
+}
+
+/// Accuracies given for a different number of levels than a profile has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelMismatch {
+    /// The number of levels of the profile.
+    pub levels: usize,
+
+    /// The number of accuracies given.
+    pub accuracies: usize,
+}
+
+/// A `SimpleProfile` isn't parameterized by the config.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SimpleProfile {
+    /// A list of bandwidths
+    levels: Vec<f64>,
+
+    /// The current config (serving as cache)
+    current: usize,
+
+    /// How many times we can stick to current without degrading.
+    adjust_sticky_count: usize,
+
+    /// What `adjust_sticky_count` starts from.
+    #[serde(default = "default_adjust_sticky_max")]
+    adjust_sticky_max: usize,
+
+    /// Accuracy of every level, if known.
+    #[serde(default)]
+    accuracies: Vec<f64>,
+
+    /// How levels are picked for a bandwidth.
+    #[serde(default)]
+    selection: Selection,
+
+    /// The highest level allowed for now, if limited.
+    #[serde(default)]
+    max_level: Option<usize>,
+}
+
+impl SimpleProfile {
+    /// Creates a profile with bandwidths of all levels, starting at level 0.
+    pub fn new(levels: Vec<f64>) -> SimpleProfile {
+        SimpleProfile {
+            levels,
+            current: 0,
+            adjust_sticky_count: ADJUST_STICKY_MAX,
+            adjust_sticky_max: ADJUST_STICKY_MAX,
+            accuracies: Vec::new(),
+            selection: Selection::Index,
+            max_level: None,
+        }
+    }
+
+    /// Sets how levels are picked for a bandwidth (`Selection::Index` by
+    /// default).
+    pub fn set_selection(&mut self, selection: Selection) {
+        self.selection = selection;
+    }
+
+    /// Returns how levels are picked for a bandwidth.
+    pub fn selection(&self) -> Selection {
+        self.selection
+    }
+
+    /// Sets how many times to stick to the current level when asked to adjust
+    /// to it (`ADJUST_STICKY_MAX` by default), e.g. to tune it.
+    pub fn set_adjust_sticky(&mut self, max: usize) {
+        self.adjust_sticky_max = max;
+        self.adjust_sticky_count = max;
+    }
+
+    /// Sets the accuracy of every level, for `Selection::Accuracy` and so that
+    /// levels can be recalibrated with the accuracy actually achieved. The
+    /// profile is left as is unless there is one accuracy per level.
+    pub fn set_accuracies(&mut self, accuracies: Vec<f64>) -> Result<(), LevelMismatch> {
+        if accuracies.len() != self.levels.len() {
+            return Err(LevelMismatch {
+                levels: self.levels.len(),
+                accuracies: accuracies.len(),
+            });
+        }
+        self.accuracies = accuracies;
+        Ok(())
+    }
+
+    /// Drops the levels whose bandwidth is above `rate` (but never the lowest
+    /// level), moving down to the highest remaining one if above it. Returns
+    /// the number of levels dropped.
+    pub fn cap(&mut self, rate: f64) -> usize {
+        let keep = ::core::cmp::max(self.levels.iter().filter(|&&r| r <= rate).count(), 1);
+        let dropped = self.levels.len().saturating_sub(keep);
+        self.levels.truncate(keep);
+        self.accuracies.truncate(keep);
+        self.current = ::core::cmp::min(self.current, keep - 1);
+        dropped
+    }
+
+    /// Allows no level above `max_level` until lifted with `None`, unlike
+    /// `cap` keeping the levels, e.g. for a budget that changes over the day.
+    /// Moves down to `max_level` if above it. Returns the new level.
+    pub fn set_max_level(&mut self, max_level: Option<usize>) -> usize {
+        self.max_level = max_level;
+        self.current = ::core::cmp::min(self.current, self.top());
+        self.current
+    }
+
+    /// Returns the highest level that is allowed.
+    fn top(&self) -> usize {
+        let highest = self.levels.len().saturating_sub(1);
+        self.max_level.map_or(highest, |max| ::core::cmp::min(max, highest))
+    }
+
+    /// Returns the accuracy of `level`, if known.
+    pub fn accuracy_of(&self, level: usize) -> Option<f64> {
+        self.accuracies.get(level).cloned()
+    }
+
+    /// Moves the accuracy of `level` towards `accuracy` (exponentially
+    /// weighted, `weight` is that of the new value). Does nothing if accuracies
+    /// are unknown or `accuracy` isn't a number.
+    pub fn recalibrate(&mut self, level: usize, accuracy: f64, weight: f64) {
+        if !accuracy.is_finite() {
+            return;
+        }
+        if let Some(a) = self.accuracies.get_mut(level) {
+            *a = (1.0 - weight) * *a + weight * accuracy;
+        }
+    }
+
+    /// Get current profile
+    #[inline]
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Returns the number of levels.
+    pub fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Whether the profile has no levels.
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    /// Jumps to `level`, or the highest level allowed if `level` is beyond
+    /// it. Returns the new level.
+    pub fn set_level(&mut self, level: usize) -> usize {
+        self.current = ::core::cmp::min(level, self.top());
+        self.adjust_sticky_count = self.adjust_sticky_max;
+        self.current
+    }
+
+    /// Finds the index of the configuration that matches (equal or smaller
+    /// than) the provided bandwidth.
+    fn get_level_index(&self, bw: f64) -> usize {
+        let pos = self.levels.binary_search_by(|v| {
+            v.partial_cmp(&bw).expect("failed to compare bandwidth")
+        });
+        let highest = match pos {
+            Ok(i) => i,
+            // If error, it could be the first (only 1 profile) or the last
+            // (fail to find).
+            Err(i) => if i == 0 { 0 } else { i - 1 },
+        };
+        let highest = ::core::cmp::min(highest, self.top());
+        if self.selection == Selection::Index || self.accuracies.is_empty() {
+            return highest;
+        }
+        // The cheapest of the most accurate levels that fit.
+        (0..highest + 1).fold(0, |best, level| {
+            if self.accuracies[level] > self.accuracies[best] {
+                level
+            } else {
+                best
+            }
+        })
+    }
+
+    /// Returns the bandwidth of `level`, if there is such a level.
+    pub fn rate_of(&self, level: usize) -> Option<f64> {
+        self.levels.get(level).cloned()
+    }
+
+    /// Returns the level whose bandwidth is equal or smaller than `bw` (see
+    /// `Selection`), or the lowest level if none is.
+    pub fn level_for_rate(&self, bw: f64) -> usize {
+        self.get_level_index(bw)
+    }
+
+    /// Adjusts the profile with a configuration that satisfies the provided
+    /// bandwidth, i.e., equal or smaller. Returns the new level.
+    pub fn adjust_level(&mut self, bw: f64) -> Option<usize> {
+        let new_level = self.get_level_index(bw);
+        // Only if new level is more conservative
+        if self.current > new_level {
+            self.current = new_level;
+            self.adjust_sticky_count = self.adjust_sticky_max;
+            Some(new_level)
+        } else if self.current == new_level {
+            if self.adjust_sticky_count == 0 {
+                // we've done enough sticky actions, decrease one level
+                self.adjust_sticky_count = self.adjust_sticky_max;
+                self.decrease_level()
+            } else {
+                self.adjust_sticky_count -= 1;
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Advances to next level. Returns the level if successful; otherwise,
+    /// return None (when we cannot advance any more).
+    pub fn advance_level(&mut self) -> Option<usize> {
+        if self.current < self.top() {
+            self.current += 1;
+            Some(self.current)
+        } else {
+            None
+        }
+    }
+
+    /// Decreases to the previous level. Returns the level if successful;
+    /// otherwise, return None (when we are at the lowest level).
+    pub fn decrease_level(&mut self) -> Option<usize> {
+        if self.current > 0 {
+            self.current -= 1;
+            Some(self.current)
+        } else {
+            None
+        }
+    }
+
+    /// Finds out the required rate for next configuration.
+    pub fn next_rate(&self) -> Option<f64> {
+        if self.current < self.top() {
+            Some(self.levels[self.current + 1])
+        } else {
+            None
+        }
+    }
+
+    /// Finds out the required delta rate for next configuration.
+    pub fn next_rate_delta(&self) -> Option<f64> {
+        if self.current < self.top() {
+            Some(self.levels[self.current + 1] - self.levels[self.current])
+        } else {
+            None
+        }
+    }
+
+    /// Am I current at maximum allowed configuration?
+    pub fn is_max(&self) -> bool {
+        self.current == self.top()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn recalibrated_levels() {
+        let mut profile = SimpleProfile::new(vec![100.0, 200.0, 400.0]);
+        profile.set_selection(Selection::Accuracy);
+        assert_eq!(profile.level_for_rate(500.0), 2);
+
+        // Without feedback, the offline accuracies keep the same choice.
+        let mismatch = LevelMismatch {
+            levels: 3,
+            accuracies: 2,
+        };
+        assert_eq!(profile.set_accuracies(vec![0.5, 0.7]), Err(mismatch));
+        assert_eq!(profile.accuracy_of(0), None);
+        profile.set_accuracies(vec![0.5, 0.7, 0.9]).unwrap();
+        assert_eq!(profile.level_for_rate(500.0), 2);
+        assert_eq!(profile.level_for_rate(300.0), 1);
+
+        // The scene gets hard for level 2: it is no better than level 1.
+        profile.recalibrate(2, 0.5, 0.5);
+        assert_eq!(profile.accuracy_of(2), Some(0.7));
+        assert_eq!(profile.level_for_rate(500.0), 1);
+        profile.recalibrate(2, f64::NAN, 0.5);
+        assert_eq!(profile.accuracy_of(2), Some(0.7));
+    }
+
+    #[test]
+    fn capped_levels() {
+        let mut profile = SimpleProfile::new(vec![100.0, 200.0, 400.0]);
+        profile.set_level(2);
+        assert_eq!(profile.cap(250.0), 1);
+        assert_eq!((profile.len(), profile.current()), (2, 1));
+        assert!(profile.is_max());
+        assert_eq!(profile.advance_level(), None);
+        assert_eq!(profile.level_for_rate(1000.0), 1);
+
+        // The lowest level stays, even above the cap.
+        assert_eq!(profile.cap(50.0), 1);
+        assert_eq!(profile.len(), 1);
+    }
+
+    #[test]
+    fn limited_levels() {
+        let mut profile = SimpleProfile::new(vec![100.0, 200.0, 400.0]);
+        profile.set_level(2);
+        assert_eq!(profile.set_max_level(Some(1)), 1);
+        assert!(profile.is_max());
+        assert_eq!((profile.advance_level(), profile.next_rate()), (None, None));
+        assert_eq!(profile.level_for_rate(1000.0), 1);
+        assert_eq!(profile.set_level(2), 1);
+
+        // Lifted, the levels are all there again.
+        assert_eq!(profile.set_max_level(None), 1);
+        assert_eq!(profile.advance_level(), Some(2));
+        assert_eq!(profile.len(), 3);
+    }
+
+    #[test]
+    fn selection_by_accuracy() {
+        // Level 2 is costlier than level 1 but less accurate.
+        let mut profile = SimpleProfile::new(vec![100.0, 200.0, 300.0, 400.0]);
+        profile.set_accuracies(vec![0.5, 0.8, 0.6, 0.9]).unwrap();
+        assert_eq!(profile.level_for_rate(350.0), 2);
+        assert_eq!(profile.level_for_rate(50.0), 0);
+
+        profile.set_selection(Selection::Accuracy);
+        assert_eq!(profile.level_for_rate(350.0), 1);
+        assert_eq!(profile.level_for_rate(400.0), 3);
+        assert_eq!(profile.level_for_rate(50.0), 0);
+        profile.set_level(3);
+        assert_eq!(profile.adjust_level(350.0), Some(1));
+    }
+}
@@ -0,0 +1,156 @@
+//! Scenarios of the adaptation state machine driving a profile: each feeds a
+//! scripted sequence of signals and checks, after every signal, the action,
+//! the state and the level.
+
+use adaptation::{Action, Adaptation, Signal, State};
+use adaptation::Action::*;
+use adaptation::Signal::*;
+use adaptation::State::*;
+use alloc::vec::Vec;
+use alloc::vec;
+use profile::SimpleProfile;
+
+const LEVELS: [f64; 4] = [100.0, 200.0, 400.0, 800.0];
+
+struct Scenario {
+    name: &'static str,
+
+    /// The level to start at.
+    start: usize,
+
+    /// Signal, and the expected action, state and level after it.
+    steps: Vec<(Signal, Action, State, usize)>,
+}
+
+fn run(scenario: &Scenario) {
+    let mut adaptation = Adaptation::default();
+    let mut profile = SimpleProfile::new(LEVELS.to_vec());
+    profile.set_level(scenario.start);
+
+    for (i, &(signal, action, state, level)) in scenario.steps.iter().enumerate() {
+        let actual = adaptation.transit(signal, profile.is_max());
+        match actual {
+            AdvanceConfig => {
+                profile.advance_level();
+            }
+            AdjustConfig(rate) => {
+                profile.adjust_level(rate);
+            }
+            _ => {}
+        }
+        assert_eq!(
+            (actual, adaptation.state(), profile.current()),
+            (action, state, level),
+            "{}, step {} ({:?})",
+            scenario.name,
+            i,
+            signal
+        );
+    }
+}
+
+/// `n` times the same step.
+fn repeat(n: usize, step: (Signal, Action, State, usize)) -> Vec<(Signal, Action, State, usize)> {
+    (0..n).map(|_| step).collect()
+}
+
+fn scenarios() -> Vec<Scenario> {
+    let mut scenarios = Vec::new();
+
+    // Transitions 1 and 2, and staying at the maximum.
+    scenarios.push(Scenario {
+        name: "startup ramps up to the maximum",
+        start: 0,
+        steps: vec![
+            (QueueEmpty, AdvanceConfig, Startup, 1),
+            (QueueEmpty, AdvanceConfig, Startup, 2),
+            (QueueEmpty, AdvanceConfig, Startup, 3),
+            (QueueEmpty, NoOp, Steady, 3),
+            (QueueEmpty, NoOp, Steady, 3),
+        ],
+    });
+
+    // Transition 3: congestion during startup is tolerated a few times.
+    let mut steps = repeat(2, (QueueCongest(150.0, 10.0), NoOp, Startup, 2));
+    steps.extend(repeat(2, (RemoteCongest(150.0, 10.0), NoOp, Startup, 2)));
+    steps.push((QueueCongest(150.0, 10.0), AdjustConfig(150.0), Degrade, 0));
+    scenarios.push(Scenario {
+        name: "startup tolerates transient congestion",
+        start: 2,
+        steps,
+    });
+
+    scenarios.push(Scenario {
+        name: "startup degrades on compute congestion right away",
+        start: 2,
+        steps: vec![(ComputeCongest(250.0, 100.0), AdjustConfig(250.0), Degrade, 1)],
+    });
+
+    // Transitions 4, 5, 7, 10, 9 and 6.
+    let mut steps = vec![
+        (ComputeCongest(250.0, 100.0), AdjustConfig(250.0), Degrade, 1),
+        (QueueCongest(150.0, 10.0), AdjustConfig(150.0), Degrade, 0),
+        (QueueEmpty, NoOp, Steady, 0),
+    ];
+    steps.extend(repeat(4, (QueueEmpty, NoOp, Steady, 0)));
+    steps.extend(vec![
+        (QueueEmpty, StartProbe, Probe, 0),
+        (QueueEmpty, IncreaseProbePace, Probe, 0),
+        (ProbeDone, AdvanceConfig, Steady, 1),
+        (RemoteCongest(150.0, 300.0), AdjustConfig(150.0), Degrade, 0),
+    ]);
+    scenarios.push(Scenario {
+        name: "degrade, steady, probe and advance",
+        start: 2,
+        steps,
+    });
+
+    // Transition 8.
+    let mut steps = vec![
+        (ComputeCongest(150.0, 100.0), AdjustConfig(150.0), Degrade, 0),
+        (QueueEmpty, NoOp, Steady, 0),
+    ];
+    steps.extend(repeat(4, (QueueEmpty, NoOp, Steady, 0)));
+    steps.extend(vec![
+        (QueueEmpty, StartProbe, Probe, 0),
+        (QueueCongest(90.0, 20.0), StopProbe, Steady, 0),
+    ]);
+    scenarios.push(Scenario {
+        name: "probe stops on congestion",
+        start: 1,
+        steps,
+    });
+
+    // Loss is congestion like any other, also at a flat latency.
+    scenarios.push(Scenario {
+        name: "steady degrades on loss",
+        start: 1,
+        steps: vec![
+            (ComputeCongest(250.0, 100.0), AdjustConfig(250.0), Degrade, 1),
+            (QueueEmpty, NoOp, Steady, 1),
+            (LossCongest(150.0, 0.08), AdjustConfig(150.0), Degrade, 0),
+        ],
+    });
+
+    // Signals that do not apply to the state are ignored.
+    scenarios.push(Scenario {
+        name: "stray probe done",
+        start: 1,
+        steps: vec![
+            (ProbeDone, NoOp, Startup, 1),
+            (ComputeCongest(250.0, 100.0), AdjustConfig(250.0), Degrade, 1),
+            (ProbeDone, NoOp, Degrade, 1),
+            (QueueEmpty, NoOp, Steady, 1),
+            (ProbeDone, NoOp, Steady, 1),
+        ],
+    });
+
+    scenarios
+}
+
+#[test]
+fn all_scenarios() {
+    for scenario in &scenarios() {
+        run(scenario);
+    }
+}
@@ -0,0 +1,225 @@
+//! WebSocket messages (RFC 6455), for senders that reach the server over a
+//! WebSocket. Frames go in binary messages, and since every frame carries its
+//! own length, a message may hold any part of the stream: the payloads of the
+//! binary messages, in order, are the bytes `framing::FrameDecoder` takes.
+//!
+//! A browser frames messages itself and only needs `framing`; this is for the
+//! other end (the server) and for senders that open their own sockets. The
+//! opening handshake is HTTP and is left to the transport.
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use framing;
+
+/// Largest message payload taken, enough for the largest frame with its
+/// header.
+pub const MAX_MESSAGE_LEN: usize = framing::MAX_FRAME_LEN + framing::HEADER_SIZE;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// A message, or part of one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Bytes of the stream: a binary message or its continuation.
+    Binary(Vec<u8>),
+
+    /// A ping, to be answered with a pong of the same payload.
+    Ping(Vec<u8>),
+
+    /// A pong.
+    Pong(Vec<u8>),
+
+    /// The peer closes the connection.
+    Close,
+}
+
+/// Why a message can't be decoded. The stream can't be read past it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageError {
+    /// A text message, which carries no frames.
+    Text,
+
+    /// An opcode RFC 6455 doesn't define, or reserved bits set.
+    Unknown(u8),
+
+    /// A payload above `MAX_MESSAGE_LEN`.
+    TooLong(u64),
+}
+
+/// Appends `payload` as one binary message to `out`, masked with `mask` (as a
+/// client must) if set.
+pub fn encode(payload: &[u8], mask: Option<[u8; 4]>, out: &mut Vec<u8>) {
+    encode_message(BINARY, payload, mask, out);
+}
+
+/// Appends a pong answering a ping of `payload` to `out`.
+pub fn encode_pong(payload: &[u8], mask: Option<[u8; 4]>, out: &mut Vec<u8>) {
+    encode_message(PONG, payload, mask, out);
+}
+
+fn encode_message(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>, out: &mut Vec<u8>) {
+    out.reserve(14 + payload.len());
+    out.push(FIN | opcode);
+    let masked = if mask.is_some() { MASKED } else { 0 };
+    if payload.len() < 126 {
+        out.push(masked | payload.len() as u8);
+    } else if payload.len() <= usize::from(u16::max_value()) {
+        out.push(masked | 126);
+        out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        out.push(masked | 127);
+        out.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    match mask {
+        Some(mask) => {
+            out.extend_from_slice(&mask);
+            out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => out.extend_from_slice(payload),
+    }
+}
+
+/// `MessageDecoder` reassembles messages from bytes as they arrive, and
+/// unmasks them.
+#[derive(Default, Debug)]
+pub struct MessageDecoder {
+    /// Bytes received but not yet returned as a message.
+    buf: Vec<u8>,
+}
+
+impl MessageDecoder {
+    /// Creates an empty decoder.
+    pub fn new() -> MessageDecoder {
+        MessageDecoder::default()
+    }
+
+    /// Feeds received bytes into the decoder.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete message, or `None` if more bytes are needed.
+    /// An error is returned on every call, with nothing buffered for it.
+    pub fn next_message(&mut self) -> Option<Result<Message, MessageError>> {
+        if self.buf.len() < 2 {
+            return None;
+        }
+        let (first, second) = (self.buf[0], self.buf[1]);
+        let opcode = first & 0x0F;
+        if first & 0x70 != 0 {
+            return Some(Err(MessageError::Unknown(first)));
+        }
+        let (len, mut at) = match second & 0x7F {
+            126 if self.buf.len() < 4 => return None,
+            126 => (u64::from(u16::from_be_bytes([self.buf[2], self.buf[3]])), 4),
+            127 if self.buf.len() < 10 => return None,
+            127 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&self.buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            len => (u64::from(len), 2),
+        };
+        let len = match usize::try_from(len) {
+            Ok(n) if n <= MAX_MESSAGE_LEN => n,
+            _ => return Some(Err(MessageError::TooLong(len))),
+        };
+        let mut mask = None;
+        if second & MASKED != 0 {
+            if self.buf.len() < at + 4 {
+                return None;
+            }
+            let mut key = [0; 4];
+            key.copy_from_slice(&self.buf[at..at + 4]);
+            mask = Some(key);
+            at += 4;
+        }
+        if self.buf.len() < at + len {
+            return None;
+        }
+        let message = match opcode {
+            CONTINUATION | BINARY | PING | PONG => {
+                let mut payload = self.buf[at..at + len].to_vec();
+                if let Some(mask) = mask {
+                    for (i, b) in payload.iter_mut().enumerate() {
+                        *b ^= mask[i % 4];
+                    }
+                }
+                match opcode {
+                    PING => Message::Ping(payload),
+                    PONG => Message::Pong(payload),
+                    _ => Message::Binary(payload),
+                }
+            }
+            CLOSE => Message::Close,
+            TEXT => return Some(Err(MessageError::Text)),
+            _ => return Some(Err(MessageError::Unknown(first))),
+        };
+        self.buf.drain(..at + len);
+        Some(Ok(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use framing::FrameDecoder;
+
+    #[test]
+    fn frames_survive_messages() {
+        let mut stream = Vec::new();
+        framing::encode(b"hello", &mut stream);
+        framing::encode(&[7; 300], &mut stream);
+
+        // A client masks, and splits the stream wherever it likes.
+        let mut wire = Vec::new();
+        encode(&stream[..3], Some([1, 2, 3, 4]), &mut wire);
+        encode(&stream[3..], Some([5, 6, 7, 8]), &mut wire);
+        encode_pong(b"", None, &mut wire);
+
+        let mut messages = MessageDecoder::new();
+        let mut frames = FrameDecoder::new();
+        let mut pongs = 0;
+        for chunk in wire.chunks(5) {
+            messages.push(chunk);
+            while let Some(message) = messages.next_message() {
+                match message.unwrap() {
+                    Message::Binary(bytes) => frames.push(&bytes),
+                    Message::Pong(_) => pongs += 1,
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+        }
+        assert_eq!(frames.next_frame(), Some(Ok(b"hello".to_vec())));
+        assert_eq!(frames.next_frame(), Some(Ok([7; 300].to_vec())));
+        assert_eq!(frames.next_frame(), None);
+        assert_eq!(pongs, 1);
+    }
+
+    #[test]
+    fn text_and_long_messages_are_rejected() {
+        let mut decoder = MessageDecoder::new();
+        decoder.push(&[FIN | TEXT, 2, b'h', b'i']);
+        assert_eq!(decoder.next_message(), Some(Err(MessageError::Text)));
+        assert_eq!(decoder.next_message(), Some(Err(MessageError::Text)));
+
+        let mut decoder = MessageDecoder::new();
+        decoder.push(&[FIN | BINARY, 127]);
+        decoder.push(&u64::max_value().to_be_bytes());
+        let too_long = MessageError::TooLong(u64::max_value());
+        assert_eq!(decoder.next_message(), Some(Err(too_long)));
+
+        let mut decoder = MessageDecoder::new();
+        decoder.push(&[FIN | CLOSE, 0]);
+        assert_eq!(decoder.next_message(), Some(Ok(Message::Close)));
+        assert_eq!(decoder.next_message(), None);
+    }
+}
@@ -1,9 +1,14 @@
 use super::VideoConfig;
 use csv::{self, ReaderBuilder};
 use itertools::Itertools;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Read;
 use std::path::Path;
+use std::str::FromStr;
+
+/// Frames per second of the groundtruth.
+const GT_FPS: usize = 30;
 
 /// Detection represents detected object. This struct is mostly constructed from
 /// the CSV log.
@@ -246,17 +251,128 @@ pub fn get_frame_stats(dir: &str, vc: VideoConfig, limit: Option<usize>) -> Vec<
         .collect()
 }
 
+/// How finely accuracy is measured.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    /// The detections of every frame against the groundtruth of the frame.
+    Frame,
+
+    /// The dominant objects of every second against those of the groundtruth,
+    /// for applications that only consume one output per second.
+    Second,
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Granularity, String> {
+        match s {
+            "frame" => Ok(Granularity::Frame),
+            "second" => Ok(Granularity::Second),
+            _ => Err(format!("unknown granularity `{}` (frame or second)", s)),
+        }
+    }
+}
+
+/// Returns the dominant objects of `frames`: for every label, the number of
+/// objects that most frames have (the larger number on a tie). Labels that
+/// most frames lack are left out.
+fn dominant_objects(frames: &[&FrameDetections]) -> BTreeMap<String, usize> {
+    let mut per_frame = BTreeMap::new();
+    for (i, frame) in frames.iter().enumerate() {
+        for d in &frame.dets {
+            let counts = per_frame
+                .entry(d.label.clone())
+                .or_insert_with(|| vec![0; frames.len()]);
+            counts[i] += 1;
+        }
+    }
+    per_frame
+        .into_iter()
+        .filter_map(|(label, counts)| {
+            let mut frequency = BTreeMap::new();
+            for count in counts {
+                *frequency.entry(count).or_insert(0) += 1;
+            }
+            let (&count, _) = frequency.iter().max_by_key(|&(&count, &n)| (n, count))?;
+            if count > 0 { Some((label, count)) } else { None }
+        })
+        .collect()
+}
+
+/// Returns Stat of the dominant objects of `test` against those of
+/// `groundtruth`: as many objects of a label as both have are true positives.
+fn dominant_stat(test: &[&FrameDetections], groundtruth: &[&FrameDetections]) -> Stat {
+    let test = dominant_objects(test);
+    let groundtruth = dominant_objects(groundtruth);
+    let tp = groundtruth
+        .iter()
+        .map(|(label, &n)| cmp::min(n, test.get(label).cloned().unwrap_or(0)))
+        .sum::<usize>();
+    let tp_and_fp = test.values().sum::<usize>();
+    let tp_and_fn = groundtruth.values().sum::<usize>();
+    Stat::new(tp, tp_and_fp - tp, tp_and_fn - tp)
+}
+
+/// For a particular configuration, this function will return the stats of
+/// every second against the groundtruth (see `Granularity::Second`). What the
+/// configuration outputs in a second is made of the frames it processes.
+fn get_vec_of_second_stats(dir: &str, vc: VideoConfig) -> Vec<Stat> {
+    let groundtruth = load_groundtruth(dir, LoadAccOption::All);
+    let test = load_test(dir, vc, groundtruth.len());
+    second_stats(&groundtruth, &test, vc.skip)
+}
+
+/// Returns the stats of every second of `test`, frames processed with `skip`,
+/// against `groundtruth`. Seconds without any test frame are skipped.
+fn second_stats(
+    groundtruth: &[FrameDetections],
+    test: &[FrameDetections],
+    skip: usize,
+) -> Vec<Stat> {
+    let last = match test.len().checked_sub(1) {
+        Some(last) => last,
+        None => return Vec::new(),
+    };
+    groundtruth
+        .chunks(GT_FPS)
+        .enumerate()
+        .map(|(second, gt_frames)| {
+            let first = second * GT_FPS;
+            let mut processed = (first..first + gt_frames.len())
+                .map(|frame_num| cmp::min(frame_num / (skip + 1), last))
+                .collect::<Vec<_>>();
+            processed.dedup();
+            let test_frames = processed.into_iter().map(|i| &test[i]).collect::<Vec<_>>();
+            let gt_frames = gt_frames.iter().collect::<Vec<_>>();
+            dominant_stat(&test_frames, &gt_frames)
+        })
+        .collect()
+}
+
 /// This function takes an input file (accuracy measurement by frame) and
 /// processes it generate an output file (accuracy by time). The granuarilty of
 /// the generated file is configurable with duration (second).
 pub fn aggregate_accuracy(dir: &str, outdir: &str, vc: VideoConfig, duration_in_sec: usize) {
-    // Because the groundtruth is 30 frames per second, so we collect stats
-    // every `duration` seconds
-    let duration = duration_in_sec * 30;
+    aggregate_accuracy_with(dir, outdir, vc, duration_in_sec, Granularity::Frame);
+}
 
-    // stats is a vector of stats (tp, fp, fn) and aggregate (chunk) them with
-    // duration.
-    let stats = get_vec_of_stats(dir, vc, None);
+/// Does what `aggregate_accuracy` does, with accuracy measured per frame or
+/// per second (see `Granularity`). The output file is the same either way.
+pub fn aggregate_accuracy_with(
+    dir: &str,
+    outdir: &str,
+    vc: VideoConfig,
+    duration_in_sec: usize,
+    granularity: Granularity,
+) {
+    // stats is a vector of stats (tp, fp, fn), per frame or per second, and
+    // aggregate (chunk) them with `duration` seconds.
+    let (stats, duration) = match granularity {
+        Granularity::Frame => (get_vec_of_stats(dir, vc, None), duration_in_sec * GT_FPS),
+        Granularity::Second => (get_vec_of_second_stats(dir, vc), duration_in_sec),
+    };
 
     // Write out accuracy (aggregated with `duration`)
     let of = vc.derive_acc_file(outdir);
@@ -421,4 +537,42 @@ mod tests {
         assert_eq!(gt.len(), 2);
         assert_eq!(test.len(), 2);
     }
+
+    #[test]
+    fn test_dominant_objects_per_second() {
+        // Two people in most frames, a flickering cup in one.
+        let gt_str = "
+000001,1.0,person,0.5,0.1,0.1,0.2,0.2
+000001,1.0,person,0.5,0.4,0.4,0.2,0.2
+000002,1.0,person,0.5,0.1,0.1,0.2,0.2
+000002,1.0,person,0.5,0.4,0.4,0.2,0.2
+000003,1.0,person,0.5,0.1,0.1,0.2,0.2
+000003,1.0,cup,0.5,0.7,0.7,0.1,0.1";
+        let gt = load_accuracy(gt_str.as_bytes(), LoadAccOption::All);
+        let gt = gt.iter().collect::<Vec<_>>();
+        let objects = dominant_objects(&gt);
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects["person"], 2);
+
+        // One frame of the second, with a person (anywhere) and a dog.
+        let test_str = "
+000001,1.0,person,0.5,0.9,0.9,0.1,0.1
+000001,1.0,dog,0.5,0.1,0.1,0.2,0.2";
+        let test = load_accuracy(test_str.as_bytes(), LoadAccOption::All);
+        let test = test.iter().collect::<Vec<_>>();
+        assert_eq!(dominant_stat(&test, &gt), Stat::new(1, 1, 1));
+
+        assert_eq!("second".parse::<Granularity>(), Ok(Granularity::Second));
+        assert!("minute".parse::<Granularity>().is_err());
+    }
+
+    #[test]
+    fn seconds_without_test_frames_are_skipped() {
+        let gt_str = "
+000001,1.0,person,0.5,0.1,0.1,0.2,0.2
+000002,1.0,person,0.5,0.1,0.1,0.2,0.2";
+        let gt = load_accuracy(gt_str.as_bytes(), LoadAccOption::All);
+        assert_eq!(second_stats(&gt, &gt, 0), vec![Stat::new(1, 0, 0)]);
+        assert_eq!(second_stats(&gt, &[], 0), Vec::new());
+    }
 }
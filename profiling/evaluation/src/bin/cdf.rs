@@ -0,0 +1,52 @@
+//! Writes the CDFs of per-frame latency and accuracy of runs, ready to plot.
+//! Every run is a directory with `frames.csv` (see `RunFrame`); the name of a
+//! run is that of its directory.
+//!
+//! ```ignore
+//! cdf <outdir> <bins> <run-dir> [<run-dir> ...]
+//! ```
+//!
+//! Writes `<run>-latency.csv` and `<run>-accuracy.csv` into `outdir`, each with
+//! `bins` rows of `value,fraction`.
+
+extern crate csv;
+extern crate evaluation;
+
+use evaluation::{CdfPoint, frame_cdfs, read_frames};
+use std::env;
+use std::path::Path;
+use std::process;
+
+fn write_cdf(path: &str, points: &[CdfPoint]) {
+    let mut writer = csv::Writer::from_path(path).expect("failed to create output");
+    for point in points {
+        writer.serialize(point).expect("failed to write row");
+    }
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() < 4 {
+        eprintln!("usage: {} <outdir> <bins> <run-dir> [<run-dir> ...]", args[0]);
+        process::exit(2);
+    }
+    let outdir = &args[1];
+    let bins = args[2].parse::<usize>().unwrap_or_else(|_| {
+        eprintln!("invalid number of bins: {}", args[2]);
+        process::exit(2);
+    });
+    for dir in &args[3..] {
+        let name = Path::new(dir)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| dir.clone());
+        let frames = read_frames(dir).unwrap_or_else(|e| {
+            eprintln!("failed to read run {}: {}", dir, e);
+            process::exit(1);
+        });
+        let (latency, accuracy) = frame_cdfs(&frames, bins);
+        write_cdf(&format!("{}/{}-latency.csv", outdir, name), &latency);
+        write_cdf(&format!("{}/{}-accuracy.csv", outdir, name), &accuracy);
+        println!("{}\t{} frames", name, frames.len());
+    }
+}
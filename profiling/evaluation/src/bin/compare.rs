@@ -0,0 +1,91 @@
+//! Compares runs (e.g. AWStream, the HLS baseline and an oracle) second by
+//! second. Every run is a directory with `seconds.csv` (see `RunSecond`), or
+//! a file with what the `hls` or `runtime` binary prints (the accuracy of
+//! every second); the name of a run is that of its directory or file.
+//!
+//! ```ignore
+//! compare <outdir> <run> <run> [<run> ...]
+//! ```
+//!
+//! Writes `accuracy.csv`, `latency.csv` and `bytes.csv` into `outdir`, each
+//! with one row per second and one column per run (empty if the run has no
+//! such second), and `summary.csv` with one row per run.
+
+extern crate csv;
+extern crate evaluation;
+
+use evaluation::{RunSecond, align_runs, read_run, summarize_run};
+use std::env;
+use std::path::Path;
+use std::process;
+
+fn write_aligned<F>(path: &str,
+                   names: &[String],
+                   aligned: &[(usize, Vec<Option<RunSecond>>)],
+                   field: F)
+where
+    F: Fn(&RunSecond) -> String,
+{
+    let mut writer = csv::Writer::from_path(path).expect("failed to create output");
+    let mut header = vec!["second".to_string()];
+    header.extend(names.iter().cloned());
+    writer.write_record(&header).expect("failed to write header");
+    for &(second, ref row) in aligned {
+        let mut record = vec![second.to_string()];
+        record.extend(row.iter().map(|s| s.as_ref().map(&field).unwrap_or_default()));
+        writer.write_record(&record).expect("failed to write row");
+    }
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() < 4 {
+        eprintln!("usage: {} <outdir> <run> <run> [<run> ...]", args[0]);
+        process::exit(2);
+    }
+    let outdir = &args[1];
+    let paths = &args[2..];
+    let names = paths.iter()
+        .map(|path| {
+            Path::new(path)
+                .file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.clone())
+        })
+        .collect::<Vec<_>>();
+    let runs = paths.iter()
+        .map(|path| {
+            read_run(path).unwrap_or_else(|e| {
+                eprintln!("failed to read run {}: {}", path, e);
+                process::exit(1);
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let aligned = align_runs(&runs);
+    let out = |name: &str| format!("{}/{}", outdir, name);
+    write_aligned(&out("accuracy.csv"), &names, &aligned, |s| s.accuracy.to_string());
+    write_aligned(&out("latency.csv"), &names, &aligned, |s| s.latency.to_string());
+    write_aligned(&out("bytes.csv"), &names, &aligned, |s| s.bytes.to_string());
+
+    let mut writer = csv::Writer::from_path(format!("{}/summary.csv", outdir))
+        .expect("failed to create summary");
+    println!("{:>20} {:>8} {:>10} {:>10} {:>12}",
+             "run",
+             "seconds",
+             "accuracy",
+             "p95 (ms)",
+             "bytes");
+    for (name, run) in names.iter().zip(&runs) {
+        let summary = summarize_run(name, run);
+        println!(
+            "{:>20} {:>8} {:>10.4} {:>10.1} {:>12}",
+            summary.name,
+            summary.seconds,
+            summary.mean_accuracy,
+            summary.p95_latency,
+            summary.bytes
+        );
+        writer.serialize(&summary).expect("failed to write summary");
+    }
+}
@@ -0,0 +1,88 @@
+//! Quick profiling on a downsampled dataset, for a provisional profile before
+//! committing to the full run.
+//!
+//! ```ignore
+//! downsample sample <frames> <ext> <outdir> [<clips> <clip-len> <per-dim>]
+//! downsample profile <measure-dir> <outdir> <configs.csv> [<clip-len>]
+//! ```
+//!
+//! `sample` copies `clips` clips (20 by default) of `clip-len` frames (30) spread
+//! over the dataset into `<outdir>/frames` and writes the configurations to
+//! measure, `per-dim` (3) of each dimension, into `<outdir>/configs.csv`. Run
+//! `video-profiling quick <outdir>` on it, then `profile`, which summarizes the
+//! measurement of those configurations (per clip) and writes the provisional
+//! `profile.csv` and `pareto.csv` into `outdir`.
+
+extern crate evaluation;
+extern crate rayon;
+
+use evaluation::{CONFIG_FILE, VideoConfig};
+use rayon::prelude::*;
+use std::env;
+use std::path::Path;
+use std::process;
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} sample <frames> <ext> <outdir> [<clips> <clip-len> <per-dim>]", program);
+    eprintln!("       {} profile <measure-dir> <outdir> <configs.csv> [<clip-len>]", program);
+    process::exit(2);
+}
+
+fn arg(args: &[String], i: usize, default: usize) -> usize {
+    args.get(i).map_or(default, |a| {
+        a.parse::<usize>().unwrap_or_else(|_| usage(&args[0]))
+    })
+}
+
+fn sample(args: &[String]) {
+    let (dir, ext, outdir) = (&args[2], &args[3], &args[4]);
+    let (clips, clip_len, per_dim) = (arg(args, 5, 20), arg(args, 6, 30), arg(args, 7, 3));
+
+    let frames = evaluation::count_frames(dir, ext);
+    let clips = evaluation::stratified_clips(frames, clips, clip_len);
+    let frame_dir = format!("{}/frames", outdir);
+    let copied = evaluation::downsample(dir, ext, &clips, &frame_dir).unwrap_or_else(|e| {
+        eprintln!("failed to downsample {}: {}", dir, e);
+        process::exit(1);
+    });
+
+    let configurations = evaluation::sample_configurations(per_dim);
+    let path = Path::new(outdir).join(CONFIG_FILE);
+    if let Err(e) = evaluation::write_configurations(&path, &configurations) {
+        eprintln!("failed to write {:?}: {}", path, e);
+        process::exit(1);
+    }
+    println!(
+        "{} of {} frames in {} clips, {} configurations",
+        copied,
+        frames,
+        clips.len(),
+        configurations.len()
+    );
+}
+
+fn profile(args: &[String]) {
+    let (dir, outdir, path) = (&args[2], &args[3], &args[4]);
+    let clip_len = arg(args, 5, 30);
+    let configurations = evaluation::read_configurations(path).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", path, e);
+        process::exit(1);
+    });
+
+    // Chunks of one clip (at least a second), so that means weigh clips evenly.
+    let duration = ::std::cmp::max(clip_len / 30, 1);
+    configurations.par_iter().for_each(|&vc: &VideoConfig| {
+        evaluation::aggregate_bandwidth(dir, outdir, vc, duration);
+        evaluation::aggregate_accuracy(dir, outdir, vc, duration);
+    });
+    evaluation::summarize_profile_for(outdir, outdir, &configurations, None);
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    match args.get(1).map(|a| a.as_str()) {
+        Some("sample") if args.len() >= 5 => sample(&args),
+        Some("profile") if args.len() >= 5 => profile(&args),
+        _ => usage(&args[0]),
+    }
+}
@@ -9,6 +9,10 @@
 //! 1,5
 //! 2,5
 //! ```
+//!
+//! Logs may run longer than the dataset. Its length comes from the manifest,
+//! `--limit`, or else the stats; frames past it wrap around to the first
+//! frame (`--wraparound wrap`, the default) or are left out (`truncate`).
 
 extern crate evaluation;
 extern crate structopt;
@@ -16,7 +20,8 @@ extern crate structopt;
 extern crate structopt_derive;
 extern crate csv;
 
-use evaluation::{FrameStat, Profile, VideoConfig, f1, precision, recall};
+use evaluation::{FrameRange, Manifest, Profile, StatIndex, VideoConfig, Wraparound, f1, precision,
+                 recall};
 use std::path::Path;
 use std::vec::Vec;
 use structopt::StructOpt;
@@ -26,7 +31,7 @@ use structopt::StructOpt;
 #[structopt(about = "Evaluate runtime logs and generate accuracy")]
 struct Opt {
     /// The path to the stat file that has per-frame stat (true positive, false
-    /// positive, false negative).
+    /// positive, false negative), `stat.csv` or its index.
     #[structopt(short = "s", long = "stat")]
     #[structopt(help = "Path to stat file")]
     stat_path: String,
@@ -41,10 +46,20 @@ struct Opt {
     #[structopt(help = "The path to the profile")]
     profile_path: String,
 
-    /// The limit of frames to process
+    /// The dataset manifest, which tells its length and frame rate.
+    #[structopt(short = "m", long = "manifest")]
+    #[structopt(help = "The path to the dataset manifest")]
+    manifest_path: Option<String>,
+
+    /// The number of frames in the dataset, if there's no manifest.
     #[structopt(short = "l", long = "limit")]
-    #[structopt(help = "Number of frames to process")]
-    limit: usize,
+    #[structopt(help = "Number of frames in the dataset")]
+    limit: Option<usize>,
+
+    /// What becomes of frames past the end of the dataset.
+    #[structopt(short = "w", long = "wraparound")]
+    #[structopt(help = "wrap (default) or truncate")]
+    wraparound: Option<Wraparound>,
 }
 
 fn main() {
@@ -52,37 +67,45 @@ fn main() {
     println!("{:?}", opt);
 
     let profile: Profile<VideoConfig> = Profile::new(&opt.profile_path);
-    let frame_stats: Vec<FrameStat> = FrameStat::from_csv(&opt.stat_path);
+    let frame_stats = StatIndex::open(&opt.stat_path).expect("failed to load stats");
     let logs: Vec<(usize, usize)> = read_log(&opt.log_path);
 
+    let policy = opt.wraparound.unwrap_or_default();
+    let manifest = opt.manifest_path
+        .as_ref()
+        .map(|path| Manifest::load(path).expect("failed to load manifest"));
+    let (range, fps) = match (manifest, opt.limit) {
+        (Some(manifest), _) => {
+            let fps = manifest.fps.round() as usize;
+            (FrameRange::from_manifest(&manifest, policy), fps)
+        }
+        (None, Some(limit)) => (FrameRange::new(limit, policy), 30),
+        (None, None) => (FrameRange::new(frame_stats.frames(), policy), 30),
+    };
+    let range = range.unwrap_or_else(|e| panic!("no frames to evaluate: {}", e));
+
     // for each log entry, find stat according to the profile
     let per_frame_stat = logs.into_iter()
-        .flat_map(|entry| {
+        .map(|entry| {
             let (second, level) = entry;
             let config = profile.n_th(level);
 
-            // For this `second`, it includes frames in the following range:
-            // `second * 30 : (second + 1) * 30`
-            ((second * 30)..((second + 1) * 30))
-                .map(|frame_num| {
-                    let frame = frame_num % opt.limit;
-                    let frame_stat = frame_stats.iter().find(|i| {
-                        i.frame_num == frame && i.config == config
-                    });
-                    match frame_stat {
-                        Some(s) => (frame, s.stat),
-                        None => {
-                            println!("{}, {:?}", frame_num, config);
-                            unimplemented!{}
-                        }
-                    }
+            // For this `second`, it includes frames in the following range
+            // (frames are numbered from 1): `second * fps + 1 ..= (second + 1) * fps`
+            ((second * fps + 1)..((second + 1) * fps + 1))
+                .filter_map(|frame_num| range.map(frame_num))
+                .map(|frame| match frame_stats.get(frame, config) {
+                    Some(stat) => (frame, stat),
+                    None => panic!("no stat for frame {} at {}", frame, config),
                 })
                 .collect::<Vec<_>>()
         })
+        // Truncated seconds are left out.
+        .filter(|second| !second.is_empty())
         .collect::<Vec<_>>();
 
-    // Split into per second chunks and evaluate accuracy
-    for chunk in per_frame_stat.chunks(30) {
+    // Evaluate accuracy per second
+    for chunk in &per_frame_stat {
         let true_positive = chunk.iter().map(|i| i.1.true_positive).sum::<usize>();
         let false_postive = chunk.iter().map(|i| i.1.false_positive).sum::<usize>();
         let false_negative = chunk.iter().map(|i| i.1.false_negative).sum::<usize>();
@@ -0,0 +1,26 @@
+//! Converts per-frame stats (`stat.csv`) into their index, which the server and
+//! the evaluation binaries load and look up faster.
+//!
+//! ```ignore
+//! index <stat.csv> <stat.idx>
+//! ```
+
+extern crate evaluation;
+
+use evaluation::{FrameStat, StatIndex};
+use std::env;
+use std::process;
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 3 {
+        eprintln!("usage: {} <stat.csv> <stat.idx>", args[0]);
+        process::exit(2);
+    }
+    let index = StatIndex::build(&FrameStat::from_csv(&args[1]));
+    if let Err(e) = index.write(&args[2]) {
+        eprintln!("failed to write {}: {}", args[2], e);
+        process::exit(1);
+    }
+    println!("{} records of {} configurations", index.len(), index.configs().len());
+}
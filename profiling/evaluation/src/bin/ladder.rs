@@ -0,0 +1,19 @@
+//! Takes summary directory and produces a `pareto.csv` with one level per
+//! bandwidth operating point: the most accurate configuration that fits.
+//!
+//! ```text
+//! DIR=<summary data> OUTPUT_DIR=<dir> POINTS=250,500,1000,2000 cargo run --bin ladder
+//! ```
+//!
+//! `POINTS` are in kbps.
+extern crate evaluation;
+use std::env;
+
+fn main() {
+    let dir = env::var("DIR").expect("Use DIR=<summary data>");
+    let outdir = env::var("OUTPUT_DIR").expect("Use OUTPUT_DIR=<dir>");
+    let points = env::var("POINTS").expect("Use POINTS=<kbps,kbps,...>");
+    let points = evaluation::parse_points(&points).expect("Use POINTS=<kbps,kbps,...>");
+
+    evaluation::summarize_ladder(&dir, &outdir, &points);
+}
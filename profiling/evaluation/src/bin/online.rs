@@ -2,9 +2,17 @@ extern crate evaluation;
 extern crate rayon;
 extern crate itertools;
 
-use evaluation::VideoConfig;
+use evaluation::{Split, VideoConfig};
 use rayon::prelude::*;
 use std::env;
+use std::ops::Range;
+use std::process;
+
+/// Number of chunks (seconds) to evaluate.
+const NUM_CHUNK: usize = 24;
+
+/// Bandwidth available to the application (in Mbps).
+const TARGET_BW: f64 = 11.0;
 
 struct Online {
     enable: bool,
@@ -57,20 +65,19 @@ fn eval_online_option(
     online: Online,
 ) -> Vec<(f64, f64)> {
     println!("running eval");
-    let num_chunk = 24;
-    let target_bw = 11.0;
 
     let mut working_param = evaluation::Configuration {
         param: VideoConfig::new(1280, 0, 20),
         bandwidth: 9.74,
         accuracy: 0.909,
+        proc_time: None,
     };
 
     // empty pareto profile
     let mut sample = evaluation::Pareto::default();
 
     let mut res = Vec::new();
-    for chunk_num in 0..num_chunk {
+    for chunk_num in 0..NUM_CHUNK {
         // find the index of current working param in configurations
         let idx = configurations
             .iter()
@@ -98,7 +105,7 @@ fn eval_online_option(
 
             let profile = evaluation::Profile::from(&configurations, perf_measures);
             let pareto = profile.pareto();
-            let new_param = pareto.find_param(target_bw).expect("no viable param");
+            let new_param = pareto.find_param(TARGET_BW).expect("no viable param");
 
             let new_working_param = {
                 if !online.trigger {
@@ -131,6 +138,77 @@ fn eval_online_option(
     res
 }
 
+/// Builds the profile from the training chunks only, picks the configuration
+/// for the target bandwidth and reports how it performs on every chunk.
+fn eval_held_out(
+    configurations: &Vec<VideoConfig>,
+    all_bandwidth_accuracy_data: &[Vec<(f64, f64)>],
+    split: &Split,
+) -> Vec<(f64, f64)> {
+    println!("running held-out eval");
+    let perf_measures = all_bandwidth_accuracy_data
+        .iter()
+        .map(|p| evaluation::mean_over(p, &split.train).expect("no complete training chunk"))
+        .collect::<Vec<_>>();
+    let profile = evaluation::Profile::from(configurations, perf_measures);
+    let param = profile.pareto().find_param(TARGET_BW).expect("no viable param");
+    let idx = configurations.iter().position(|c| *c == param).unwrap();
+    all_bandwidth_accuracy_data[idx]
+        .iter()
+        .take(NUM_CHUNK)
+        .cloned()
+        .collect()
+}
+
+/// Splits the chunks at `train`: the first `train` chunks build the profile,
+/// the rest evaluate it. Both parts need at least one chunk.
+fn train_split(train: &str) -> Result<Split, String> {
+    let train = train
+        .parse::<usize>()
+        .map_err(|_| format!("invalid TRAIN_CHUNKS `{}`", train))?;
+    if train == 0 || train >= NUM_CHUNK {
+        return Err(format!(
+            "TRAIN_CHUNKS must be within 1..{} to leave chunks to test on, got {}",
+            NUM_CHUNK,
+            train
+        ));
+    }
+    Ok(Split {
+        train: 0..train,
+        test: train..NUM_CHUNK,
+    })
+}
+
+/// Prints the mean bandwidth and accuracy of `res` on the training and the
+/// test chunks.
+fn print_split_summary(name: &str, res: &[(f64, f64)], split: &Split) -> Result<(), String> {
+    let mean = |range: &Range<usize>| {
+        if range.start >= range.end || range.end > res.len() {
+            return Err(format!(
+                "{}: chunks {:?} are not within the {} evaluated",
+                name,
+                range,
+                res.len()
+            ));
+        }
+        let len = range.len() as f64;
+        Ok(res[range.clone()].iter().fold((0.0, 0.0), |sum, i| {
+            (sum.0 + i.0 / len, sum.1 + i.1 / len)
+        }))
+    };
+    let train = mean(&split.train)?;
+    let test = mean(&split.test)?;
+    println!(
+        "{}\ttrain\t{:6.02}\t{:6.02}\ttest\t{:6.02}\t{:6.02}",
+        name,
+        train.0,
+        train.1,
+        test.0,
+        test.1
+    );
+    Ok(())
+}
+
 pub fn main() {
     let dir = env::var("DIR").expect("use DIR=<summary data>");
 
@@ -178,4 +256,27 @@ pub fn main() {
             d.1
         );
     }
+
+    // Optionally, quantify how well a profile generalizes: build it from the
+    // first TRAIN_CHUNKS chunks and evaluate on the rest.
+    if let Ok(train) = env::var("TRAIN_CHUNKS") {
+        let split = train_split(&train).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        });
+        let held_out = eval_held_out(&configurations, &all_bandwidth_accuracy_data, &split);
+        let results = [
+            ("offline", &offline),
+            ("online", &online),
+            ("online_lt", &online_lt),
+            ("trigger", &trigger),
+            ("held_out", &held_out),
+        ];
+        for &(name, res) in &results {
+            if let Err(e) = print_split_summary(name, res, &split) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
 }
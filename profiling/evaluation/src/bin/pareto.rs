@@ -1,5 +1,8 @@
 /// Takes summary directory and produce `profile.csv` and `pareto.csv`.
 /// Primarily use for training summarization (i.e. offline profiling).
+///
+/// Optionally, `TRAIN=<start:end>` and `TEST=<start:end>` (in chunks) build the
+/// profile from the training range only and evaluate it on the test range.
 extern crate evaluation;
 use std::env;
 
@@ -7,5 +10,13 @@ fn main() {
     let dir = env::var("DIR").expect("Use DIR=<summary data>");
     let outdir = env::var("OUTPUT_DIR").expect("Use OUTPUT_DIR=<dir>");
 
-    evaluation::summarize_profile(&dir, &outdir);
+    let split = match (env::var("TRAIN"), env::var("TEST")) {
+        (Ok(train), Ok(test)) => {
+            let split = evaluation::Split::parse(&train, &test);
+            Some(split.expect("Use TRAIN=<start:end> TEST=<start:end>"))
+        }
+        _ => None,
+    };
+
+    evaluation::summarize_profile_with_split(&dir, &outdir, split.as_ref());
 }
@@ -3,9 +3,8 @@ extern crate evaluation;
 extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
-extern crate csv;
 
-use evaluation::{FrameStat, Profile, VideoConfig, f1, precision, recall};
+use evaluation::{Profile, StatIndex, VideoConfig, f1, precision, recall, read_levels};
 use std::path::Path;
 use std::vec::Vec;
 use structopt::StructOpt;
@@ -15,12 +14,13 @@ use structopt::StructOpt;
 #[structopt(about = "Evaluate runtime logs and generate accuracy")]
 struct Opt {
     /// The path to the stat file that has per-frame stat (true positive, false
-    /// positive, false negative).
+    /// positive, false negative), `stat.csv` or its index.
     #[structopt(short = "s", long = "stat")]
     #[structopt(help = "Path to stat file")]
     stat_path: String,
 
-    /// The path to the runtime log file (that contains frame and level).
+    /// The path to the runtime log file (`timestamp, frame_num, level`, as the
+    /// client writes with `level_log`, or `frame_num, level` of older logs).
     #[structopt(short = "l", long = "log")]
     #[structopt(help = "Path to runtime log")]
     log_path: String,
@@ -36,7 +36,7 @@ fn main() {
     println!("{:?}", opt);
 
     let profile: Profile<VideoConfig> = Profile::new(&opt.profile_path);
-    let frame_stats: Vec<FrameStat> = FrameStat::from_csv(&opt.stat_path);
+    let frame_stats = StatIndex::open(&opt.stat_path).expect("failed to load stats");
     let logs: Vec<(usize, usize)> = read_log(&opt.log_path);
 
     // for each log entry, find stat according to the profile
@@ -45,10 +45,7 @@ fn main() {
             let (frame, level) = entry;
             let config = profile.n_th(level);
 
-            let frame_stat = frame_stats.iter().find(|i| {
-                i.frame_num == frame && i.config == config
-            });
-            (frame, frame_stat.expect("failed to find").stat)
+            (frame, frame_stats.get(frame, config).expect("failed to find"))
         })
         .collect::<Vec<_>>();
 
@@ -67,10 +64,5 @@ fn main() {
 // Log is a vector of (frame_num, level) pair.
 fn read_log<P: AsRef<Path>>(path: P) -> Vec<(usize, usize)> {
     let errmsg = "failed to read log file";
-    csv::ReaderBuilder::new()
-        .from_path(path)
-        .expect(&errmsg)
-        .deserialize()
-        .map(|r| r.unwrap())
-        .collect::<Vec<(usize, usize)>>()
+    read_levels(path).expect(&errmsg)
 }
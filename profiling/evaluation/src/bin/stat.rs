@@ -1,6 +1,6 @@
 //! This binary takes all profiling results within the `INPUT_DIR` directory and
 //! generates per-frame stats: (frame_num, width, skip, quant, true_positive,
-//! false_positive, false_negative).
+//! false_positive, false_negative), and optionally their index (`stat.idx`).
 
 extern crate evaluation;
 extern crate rayon;
@@ -8,7 +8,7 @@ extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 
-use evaluation::{Profile, VideoConfig, FrameStat};
+use evaluation::{FrameStat, Manifest, Profile, StatIndex, VideoConfig};
 use rayon::prelude::*;
 use structopt::StructOpt;
 
@@ -23,6 +23,17 @@ fn main() {
         &None => evaluation::all_configurations(),
     };
 
+    if let Some(ref path) = opt.manifest_path {
+        let manifest = Manifest::load(path).expect("failed to load manifest");
+        let problems = manifest.check_measurements(&opt.input_dir, &configurations);
+        if !problems.is_empty() {
+            for problem in &problems {
+                eprintln!("{}", problem);
+            }
+            ::std::process::exit(1);
+        }
+    }
+
     let vec_frame_stat = configurations
         .par_iter()
         .map(|&vc| {
@@ -32,10 +43,12 @@ fn main() {
         .flat_map(|s| s)
         .collect::<Vec<_>>();
 
-    let cwd = ".".to_string();
-    let outfile = format!("{}/stat.csv", opt.output_dir.unwrap_or(cwd));
-
-    FrameStat::to_csv(vec_frame_stat, outfile);
+    let outdir = opt.output_dir.unwrap_or_else(|| ".".to_string());
+    if opt.index {
+        let index = StatIndex::build(&vec_frame_stat);
+        index.write(format!("{}/stat.idx", outdir)).expect("failed to write index");
+    }
+    FrameStat::to_csv(vec_frame_stat, format!("{}/stat.csv", outdir));
 }
 
 #[derive(StructOpt, Debug)]
@@ -51,6 +64,11 @@ struct Opt {
     #[structopt(help = "The path to the profile")]
     profile_path: Option<String>,
 
+    /// The dataset manifest to check the measurement against.
+    #[structopt(short = "m", long = "manifest")]
+    #[structopt(help = "The path to the dataset manifest")]
+    manifest_path: Option<String>,
+
     /// The folder that contains profiling measurement.
     #[structopt(short = "o", long = "out")]
     #[structopt(help = "Output directory, current directory if empty")]
@@ -60,4 +78,9 @@ struct Opt {
     #[structopt(short = "l", long = "limit")]
     #[structopt(help = "Number of frames to process")]
     limit: Option<usize>,
+
+    /// Also write the index of the stats, which loads and looks up faster.
+    #[structopt(short = "i", long = "index")]
+    #[structopt(help = "Also write stat.idx")]
+    index: bool,
 }
@@ -1,20 +1,48 @@
 /// Process measurement data to generate `bw-XXXX.csv`, `acc-XXXX.csv` and
-/// `ts-XXXX.csv`.
+/// `ts-XXXX.csv` (and `bwtype-XXXX.csv`, `quality-chunk-XXXX.csv` and
+/// `res-XXXX.csv` if frame types, image quality and resources are measured). With
+/// `GRANULARITY=second`, accuracy compares the dominant objects of every second
+/// instead of every frame's detections.
 extern crate evaluation;
 extern crate rayon;
 
+use evaluation::{Granularity, Manifest, VideoConfig};
 use rayon::prelude::*;
 use std::env;
 
+/// Checks measurement data against the dataset manifest and exits if they
+/// don't match (e.g. frames missing or renumbered).
+fn check_manifest(path: &str, dir: &str, configurations: &[VideoConfig]) {
+    let manifest = Manifest::load(path).expect("failed to load manifest");
+    let problems = manifest.check_measurements(dir, configurations);
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        ::std::process::exit(1);
+    }
+}
+
 fn main() {
     let dir = env::var("INPUT_DIR").expect("Use INPUT_DIR=<measure data dir>");
     let outdir = env::var("OUTPUT_DIR").expect("Use OUTPUT_DIR=<dir>");
 
+    let granularity = env::var("GRANULARITY")
+        .map(|g| g.parse::<Granularity>().expect("invalid GRANULARITY via environment variable"))
+        .unwrap_or(Granularity::Frame);
+
     let configurations = evaluation::all_configurations();
+    if let Ok(path) = env::var("MANIFEST") {
+        check_manifest(&path, &dir, &configurations);
+    }
+
     configurations.par_iter().for_each(|&vc| {
         println!("running for {}", vc);
         evaluation::aggregate_bandwidth(&dir, &outdir, vc, 10);
-        evaluation::aggregate_accuracy(&dir, &outdir, vc, 10);
+        evaluation::aggregate_frame_types(&dir, &outdir, vc, 10);
+        evaluation::aggregate_accuracy_with(&dir, &outdir, vc, 10, granularity);
         evaluation::extract_proc_time(&dir, &outdir, vc);
+        evaluation::aggregate_quality(&dir, &outdir, vc, 10);
+        evaluation::aggregate_resource(&dir, &outdir, vc);
     });
 }
@@ -0,0 +1,19 @@
+//! Evaluates how well a profile built on one dataset works on another: takes
+//! the summary data of both and produces `transfer.csv`, which compares every
+//! level of A's profile with the best configuration on B at the same
+//! bandwidth (the regret).
+//!
+//! ```text
+//! DIR_A=<summary data> DIR_B=<summary data> OUTPUT_DIR=<dir> cargo run --bin transfer
+//! ```
+extern crate evaluation;
+use std::env;
+
+fn main() {
+    let dir_a = env::var("DIR_A").expect("Use DIR_A=<summary data the profile is built on>");
+    let dir_b = env::var("DIR_B").expect("Use DIR_B=<summary data the profile is used on>");
+    let outdir = env::var("OUTPUT_DIR").expect("Use OUTPUT_DIR=<dir>");
+
+    let regret = evaluation::summarize_transfer(&dir_a, &dir_b, &outdir);
+    println!("mean regret: {:.4}", regret);
+}
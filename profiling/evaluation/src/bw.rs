@@ -16,15 +16,23 @@ pub fn aggregate_bandwidth(dir: &str, outdir: &str, vc: VideoConfig, duration: u
     // reader and writer for the input/output file
     let mut reader = csv::ReaderBuilder::new()
         .has_headers(false)
+        .flexible(true)
         .from_path(&infile)
         .expect("failed to open bandwidth file");
     let mut writer = csv::Writer::from_path(outfile).expect("failed to open outfile");
 
     // read input data as a vector
-    // it must follow `frame_num, size` format
+    // it must follow `frame_num, size` format (optionally followed by the
+    // frame type, which is ignored here)
     let data = reader
-        .deserialize()
+        .records()
         .map(|record| record.expect("unexpected data format"))
+        .map(|record| {
+            let field = |i: usize| {
+                record[i].trim().parse::<usize>().expect("unexpected data format")
+            };
+            (field(0), field(1))
+        })
         .collect::<Vec<(usize, usize)>>();
 
     // iterate over windows and write the bandwidth (in mbps). it must follow
@@ -35,3 +43,69 @@ pub fn aggregate_bandwidth(dir: &str, outdir: &str, vc: VideoConfig, duration: u
         writer.serialize((i, bw)).expect("failed to write bw to csv");
     }
 }
+
+/// Per-frame sizes with frame types, read from a bandwidth file that follows
+/// `frame_num, size, keyframe` format (`keyframe` is 1 or 0). Returns `None`
+/// if the file does not carry frame types.
+fn read_typed_frames(path: &str) -> Option<Vec<(usize, usize, bool)>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)
+        .expect("failed to open bandwidth file");
+    reader
+        .records()
+        .map(|record| {
+            let record = record.expect("unexpected data format");
+            let field = |i: usize| record.get(i).map(|f| f.trim().parse::<usize>());
+            match (field(0), field(1), field(2)) {
+                (Some(Ok(frame)), Some(Ok(size)), Some(Ok(keyframe))) => {
+                    Some((frame, size, keyframe != 0))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Splits the bandwidth of every chunk of `chunk_size` frames into the part
+/// used by keyframes and the part used by delta frames (in mbps, given the
+/// chunk lasts `duration` seconds). Also returns the number of keyframes.
+pub fn split_by_frame_type(
+    data: &[(usize, usize, bool)],
+    chunk_size: usize,
+    duration: usize,
+) -> Vec<(f64, f64, usize)> {
+    let to_mbps = |bytes: usize| (bytes * 8) as f64 / 1_000_000.0 / (duration as f64);
+    data.chunks(chunk_size)
+        .map(|chunk| {
+            let key = chunk.iter().filter(|i| i.2).map(|i| i.1).sum::<usize>();
+            let delta = chunk.iter().filter(|i| !i.2).map(|i| i.1).sum::<usize>();
+            let keyframes = chunk.iter().filter(|i| i.2).count();
+            (to_mbps(key), to_mbps(delta), keyframes)
+        })
+        .collect()
+}
+
+/// Same as `aggregate_bandwidth`, but distinguishes keyframes from delta
+/// frames. Produces `bwtype-X.csv` with `chunk, keyframe_bw, delta_bw,
+/// keyframes` entries. Does nothing if the input has no frame types.
+pub fn aggregate_frame_types(dir: &str, outdir: &str, vc: VideoConfig, duration: usize) {
+    let infile = vc.derive_bw_file(dir);
+    let data = match read_typed_frames(&infile) {
+        Some(data) => data,
+        None => {
+            info!("{} has no frame types, skip", infile);
+            return;
+        }
+    };
+
+    let fps = helper::skip_to_fps(vc.skip);
+    let outfile = vc.derive_bw_type_file(outdir);
+    let mut writer = csv::Writer::from_path(outfile).expect("failed to open outfile");
+    for (i, c) in split_by_frame_type(&data, fps * duration, duration).iter().enumerate() {
+        writer
+            .serialize((i, c.0, c.1, c.2))
+            .expect("failed to write bw to csv");
+    }
+}
@@ -0,0 +1,75 @@
+//! CDFs of per-frame latency and accuracy, the tables behind the accuracy and
+//! latency figures. A run directory has `FRAME_FILE`, one `RunFrame` per frame
+//! the server analyzed (it writes it per connection with `result_dir`).
+
+use csv;
+use std::path::Path;
+
+/// The file of per-frame results in a run directory.
+pub const FRAME_FILE: &str = "frames.csv";
+
+/// What a run achieves for one frame.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RunFrame {
+    /// The frame number.
+    pub frame_num: usize,
+
+    /// The level the frame was sent at.
+    pub level: usize,
+
+    /// Latency (ms) of the frame when it was received.
+    pub latency: f64,
+
+    /// Accuracy (F1) of the frame.
+    pub accuracy: f64,
+}
+
+/// One point of a CDF: `fraction` of the samples are at most `value`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CdfPoint {
+    /// The value.
+    pub value: f64,
+
+    /// Fraction of the samples at most `value`, in [0, 1].
+    pub fraction: f64,
+}
+
+/// Reads the per-frame results of the run in `dir`.
+pub fn read_frames<P: AsRef<Path>>(dir: P) -> csv::Result<Vec<RunFrame>> {
+    csv::Reader::from_path(dir.as_ref().join(FRAME_FILE))?.deserialize().collect()
+}
+
+/// Returns the CDF of `samples` at `bins` evenly spaced values from the
+/// smallest sample to the largest. Samples that are not finite (e.g. frames
+/// without ground truth) are left out.
+pub fn cdf(samples: &[f64], bins: usize) -> Vec<CdfPoint> {
+    let mut sorted = samples.iter().cloned().filter(|s| s.is_finite()).collect::<Vec<_>>();
+    if sorted.is_empty() || bins == 0 {
+        return Vec::new();
+    }
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let (min, max) = (sorted[0], sorted[sorted.len() - 1]);
+    let step = if bins > 1 { (max - min) / (bins - 1) as f64 } else { 0.0 };
+
+    let mut below = 0;
+    (0..bins)
+        .map(|i| {
+            // The last bin is the maximum, not what the steps add up to.
+            let value = if i + 1 == bins { max } else { min + step * i as f64 };
+            while below < sorted.len() && sorted[below] <= value {
+                below += 1;
+            }
+            CdfPoint {
+                value,
+                fraction: below as f64 / sorted.len() as f64,
+            }
+        })
+        .collect()
+}
+
+/// Returns the CDFs of the latency and of the accuracy of `frames`.
+pub fn frame_cdfs(frames: &[RunFrame], bins: usize) -> (Vec<CdfPoint>, Vec<CdfPoint>) {
+    let latencies = frames.iter().map(|f| f.latency).collect::<Vec<_>>();
+    let accuracies = frames.iter().map(|f| f.accuracy).collect::<Vec<_>>();
+    (cdf(&latencies, bins), cdf(&accuracies, bins))
+}
@@ -0,0 +1,132 @@
+//! Comparison of runs, e.g. AWStream against the HLS baseline and an oracle.
+//! A run is either a directory with `RUN_FILE`, one `RunSecond` per second
+//! (the server writes it per connection with `result_dir`), or the accuracy
+//! of every second as the `hls` and `runtime` binaries print it, one per line.
+//! Runs are aligned by second, so that they can be plotted side by side.
+
+use csv;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// The file of per-second results in a run directory.
+pub const RUN_FILE: &str = "seconds.csv";
+
+/// What a run achieves in one second.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RunSecond {
+    /// Seconds since the start of the run.
+    pub second: usize,
+
+    /// Accuracy (F1) of the frames received in this second.
+    pub accuracy: f64,
+
+    /// Mean latency (ms) of the frames received in this second.
+    pub latency: f64,
+
+    /// Bytes received in this second.
+    pub bytes: usize,
+
+    /// Share of the frames received in this second that the server shed
+    /// instead of analyzing; `accuracy` only covers the others.
+    #[serde(default)]
+    pub shed: f64,
+
+    /// Mean time (ms) the server spent decoding and analyzing a frame in
+    /// this second, waiting for the analytics aside.
+    #[serde(default)]
+    pub processing: f64,
+}
+
+/// Summary statistics of a run.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunSummary {
+    /// Name of the run.
+    pub name: String,
+
+    /// Number of seconds.
+    pub seconds: usize,
+
+    /// Mean accuracy over all seconds.
+    pub mean_accuracy: f64,
+
+    /// 95th percentile of the per-second latency (ms).
+    pub p95_latency: f64,
+
+    /// Bytes received over the run.
+    pub bytes: usize,
+}
+
+/// Reads the per-second results of the run at `path`: a directory with
+/// `RUN_FILE`, or a file of accuracies (see `read_accuracies`).
+pub fn read_run<P: AsRef<Path>>(path: P) -> csv::Result<Vec<RunSecond>> {
+    let path = path.as_ref();
+    if path.is_dir() {
+        csv::Reader::from_path(path.join(RUN_FILE))?.deserialize().collect()
+    } else {
+        read_accuracies(path)
+    }
+}
+
+/// Reads the accuracy of every second, one per line, as the `hls` and
+/// `runtime` binaries print it. Lines that are not numbers (e.g. the options
+/// they print first) are skipped. Latency is unknown and bytes are 0.
+pub fn read_accuracies<P: AsRef<Path>>(path: P) -> csv::Result<Vec<RunSecond>> {
+    let mut run = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        if let Ok(accuracy) = line?.trim().parse::<f64>() {
+            run.push(RunSecond {
+                second: run.len(),
+                accuracy,
+                latency: ::std::f64::NAN,
+                bytes: 0,
+                shed: 0.0,
+                processing: 0.0,
+            });
+        }
+    }
+    Ok(run)
+}
+
+/// Summarizes a run. Seconds whose accuracy or latency is not a number (e.g.
+/// nothing was received) are left out of the mean and the percentile.
+pub fn summarize_run(name: &str, run: &[RunSecond]) -> RunSummary {
+    let accuracies = run.iter().map(|s| s.accuracy).filter(|a| a.is_finite()).collect::<Vec<_>>();
+    let mut latencies = run.iter().map(|s| s.latency).filter(|l| l.is_finite()).collect::<Vec<_>>();
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mean_accuracy = if accuracies.is_empty() {
+        ::std::f64::NAN
+    } else {
+        accuracies.iter().sum::<f64>() / accuracies.len() as f64
+    };
+    let p95_latency = if latencies.is_empty() {
+        ::std::f64::NAN
+    } else {
+        latencies[((latencies.len() - 1) as f64 * 0.95) as usize]
+    };
+    RunSummary {
+        name: name.to_string(),
+        seconds: run.len(),
+        mean_accuracy,
+        p95_latency,
+        bytes: run.iter().map(|s| s.bytes).sum(),
+    }
+}
+
+/// Aligns runs by second: one row per second that any run has, with what
+/// each run achieves in it (`None` if the run has no such second).
+pub fn align_runs(runs: &[Vec<RunSecond>]) -> Vec<(usize, Vec<Option<RunSecond>>)> {
+    let last = runs.iter().flat_map(|run| run.iter().map(|s| s.second)).max();
+    let last = match last {
+        Some(last) => last,
+        None => return Vec::new(),
+    };
+    (0..last + 1)
+        .map(|second| {
+            let row = runs.iter()
+                .map(|run| run.iter().find(|s| s.second == second).cloned())
+                .collect();
+            (second, row)
+        })
+        .collect()
+}
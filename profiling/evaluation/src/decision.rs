@@ -0,0 +1,40 @@
+use csv;
+use std::path::Path;
+
+/// A level decision of the runtime client: the level a frame is sent at.
+/// Runtime logs are CSV files of these entries (with a header), which the
+/// `runtime` binary evaluates.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LevelDecision {
+    /// When the frame is sent (seconds since the Unix epoch).
+    pub timestamp: f64,
+
+    /// The frame number.
+    pub frame_num: usize,
+
+    /// The level the frame is sent at.
+    pub level: usize,
+}
+
+/// Reads a runtime log.
+pub fn read_decisions<P: AsRef<Path>>(path: P) -> csv::Result<Vec<LevelDecision>> {
+    csv::Reader::from_path(path)?.deserialize().collect()
+}
+
+/// Reads the `(frame_num, level)` pairs of a runtime log, either in the format
+/// above or in the older one without timestamps (`frame_num, level`).
+pub fn read_levels<P: AsRef<Path>>(path: P) -> csv::Result<Vec<(usize, usize)>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let mut levels = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let level = if record.len() == 2 {
+            record.deserialize(None)?
+        } else {
+            let decision: LevelDecision = record.deserialize(None)?;
+            (decision.frame_num, decision.level)
+        };
+        levels.push(level);
+    }
+    Ok(levels)
+}
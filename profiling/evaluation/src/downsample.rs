@@ -0,0 +1,113 @@
+//! Downsampling for quick profiling. Profiling every configuration on the whole
+//! dataset takes hours; a provisional profile comes much faster from a few
+//! short clips spread over the dataset and a subset of the configurations.
+//!
+//! The dataset is cut into equal strata in time and one clip (consecutive
+//! frames, so that skipping and encoding behave as on the whole dataset) is
+//! picked at random within each. The clips are copied into a new dataset,
+//! renumbered from 1, with `SAMPLE_FILE` mapping its frames back.
+
+use super::VideoConfig;
+use csv;
+use helper;
+use rand::{Rng, thread_rng};
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+/// The file that maps the frames of a downsampled dataset back to the original
+/// ones (`frame_num, original`).
+pub const SAMPLE_FILE: &str = "sample.csv";
+
+/// The file of the configurations a quick profiling pass measures (`width,
+/// skip, quant`).
+pub const CONFIG_FILE: &str = "configs.csv";
+
+/// Returns `clips` clips of `clip_len` frames among frames `1..=frames`, one at
+/// a random offset within each of `clips` equal strata. Clips are shortened to
+/// their stratum if it is shorter.
+pub fn stratified_clips(frames: usize, clips: usize, clip_len: usize) -> Vec<Range<usize>> {
+    if frames == 0 || clips == 0 {
+        return Vec::new();
+    }
+    let clips = ::std::cmp::min(clips, frames);
+    let mut rng = thread_rng();
+    (0..clips)
+        .map(|i| {
+            let (start, end) = (1 + frames * i / clips, 1 + frames * (i + 1) / clips);
+            let len = ::std::cmp::min(clip_len, end - start);
+            let offset = rng.gen_range(0, end - start - len + 1);
+            (start + offset)..(start + offset + len)
+        })
+        .collect()
+}
+
+/// Returns `n` of `values`, evenly spaced and including the first and the last.
+pub fn thin<T: Copy>(values: &[T], n: usize) -> Vec<T> {
+    match n {
+        0 => Vec::new(),
+        1 => values.iter().take(1).cloned().collect(),
+        n if n >= values.len() => values.to_vec(),
+        n => (0..n).map(|i| values[i * (values.len() - 1) / (n - 1)]).collect(),
+    }
+}
+
+/// Returns the configurations with `per_dim` of the widths, skips and
+/// quantizers, evenly spaced. They always include the best configuration,
+/// which the ground truth comes from.
+pub fn sample_configurations(per_dim: usize) -> Vec<VideoConfig> {
+    let widths = thin(&helper::WIDTHS, per_dim);
+    let skips = thin(&helper::SKIPS, per_dim);
+    let quants = thin(&helper::QUANTS, per_dim);
+    iproduct!(widths, skips, quants)
+        .map(|(w, s, q)| VideoConfig::new(w, s, q))
+        .collect()
+}
+
+/// Returns the number of frames (`000001.<ext>` onwards) in `dir`.
+pub fn count_frames(dir: &str, ext: &str) -> usize {
+    (1..)
+        .take_while(|i| Path::new(&format!("{}/{:06}.{}", dir, i, ext)).exists())
+        .count()
+}
+
+/// Copies the frames of `clips` from `dir` into `outdir`, renumbered from 1,
+/// and writes `SAMPLE_FILE` next to them. Returns the number of frames copied.
+pub fn downsample(dir: &str, ext: &str, clips: &[Range<usize>], outdir: &str) -> io::Result<usize> {
+    fs::create_dir_all(outdir)?;
+    let mut writer = csv::Writer::from_path(Path::new(outdir).join(SAMPLE_FILE))?;
+    writer.serialize(("frame_num", "original"))?;
+    let originals = clips.iter().flat_map(|clip| clip.clone());
+    let mut copied = 0;
+    for (frame_num, original) in (1..).zip(originals) {
+        fs::copy(
+            format!("{}/{:06}.{}", dir, original, ext),
+            format!("{}/{:06}.{}", outdir, frame_num, ext),
+        )?;
+        writer.serialize((frame_num, original))?;
+        copied += 1;
+    }
+    writer.flush()?;
+    Ok(copied)
+}
+
+/// Writes `configurations` into `path` (see `CONFIG_FILE`).
+pub fn write_configurations<P: AsRef<Path>>(path: P,
+                                            configurations: &[VideoConfig])
+                                            -> io::Result<()> {
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_path(path)?;
+    for vc in configurations {
+        writer.serialize((vc.width, vc.skip, vc.quant))?;
+    }
+    writer.flush()
+}
+
+/// Reads the configurations in `path` (see `CONFIG_FILE`).
+pub fn read_configurations<P: AsRef<Path>>(path: P) -> csv::Result<Vec<VideoConfig>> {
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).from_path(path)?;
+    reader
+        .deserialize()
+        .map(|r| r.map(|(w, s, q)| VideoConfig::new(w, s, q)))
+        .collect()
+}
@@ -1,5 +1,14 @@
 use super::VideoConfig;
 
+/// Frame widths profiled, from the largest.
+pub const WIDTHS: [usize; 6] = [1920, 1600, 1280, 960, 640, 320];
+
+/// Skips profiled, i.e. 30, 10, 5, 3, 2 and 1 FPS.
+pub const SKIPS: [usize; 6] = [0, 2, 5, 9, 14, 29];
+
+/// Quantizers profiled, from the finest.
+pub const QUANTS: [usize; 6] = [0, 10, 20, 30, 40, 50];
+
 /// Converts skip per second to frames per second
 pub fn skip_to_fps(skip: usize) -> usize {
     ((30.0 / (skip as f64 + 1.0) * 10.0).round() / 10.0) as usize
@@ -7,12 +16,8 @@ pub fn skip_to_fps(skip: usize) -> usize {
 
 /// Returns a list of all configurations [VideoConfig](struct.VideoConfig.html).
 pub fn all_configurations() -> Vec<VideoConfig> {
-    let width = vec![1920, 1600, 1280, 960, 640, 320];
-    let skip = vec![0, 2, 5, 9, 14, 29];
-    let quant = vec![0, 10, 20, 30, 40, 50];
-
-    iproduct!(width, skip, quant)
-        .map(|(w, s, q)| {
+    iproduct!(WIDTHS.iter(), SKIPS.iter(), QUANTS.iter())
+        .map(|(&w, &s, &q)| {
             VideoConfig {
                 width: w,
                 skip: s,
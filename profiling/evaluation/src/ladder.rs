@@ -0,0 +1,76 @@
+//! Profile ladder design. Instead of taking the whole Pareto front, a
+//! deployment often wants a level at specific bandwidths (e.g. the uplinks it
+//! expects). Given these operating points, the ladder picks the most accurate
+//! configuration that fits within each point and writes it as a `pareto.csv`
+//! that the runtime can use as its profile.
+
+use super::VideoConfig;
+use csv;
+use helper;
+use profile::mean_of_configurations;
+
+/// Given the (bandwidth, accuracy) of every configuration, returns for each
+/// point the index of the most accurate configuration whose bandwidth is
+/// equal or smaller (the cheaper one on ties), or `None` if none fits.
+pub fn ladder(profile: &[(f64, f64)], points: &[f64]) -> Vec<Option<usize>> {
+    points
+        .iter()
+        .map(|&point| {
+            let mut best: Option<usize> = None;
+            for (i, &(bw, acc)) in profile.iter().enumerate() {
+                if bw > point || acc.is_nan() {
+                    continue;
+                }
+                best = match best {
+                    Some(b) if profile[b].1 > acc => Some(b),
+                    Some(b) if profile[b].1 == acc && profile[b].0 <= bw => Some(b),
+                    _ => Some(i),
+                };
+            }
+            best
+        })
+        .collect()
+}
+
+/// Parses operating points, a comma-separated list of bandwidths in kbps.
+pub fn parse_points(points: &str) -> Option<Vec<f64>> {
+    points
+        .split(',')
+        .map(|p| p.trim().parse::<f64>().ok())
+        .collect()
+}
+
+/// Searches all configurations measured in `dir` for the best one at each
+/// point (in kbps) and writes them to `outdir/pareto.csv`, one level per
+/// distinct configuration.
+pub fn summarize_ladder(dir: &str, outdir: &str, points_in_kbps: &[f64]) {
+    let configurations = helper::all_configurations();
+    let p = mean_of_configurations(dir, &configurations);
+
+    // Measured bandwidth is in Mbps.
+    let points = points_in_kbps.iter().map(|p| p / 1_000.0).collect::<Vec<_>>();
+    let mut levels: Vec<(f64, f64, VideoConfig)> = Vec::new();
+    for (point, choice) in points_in_kbps.iter().zip(ladder(&p, &points)) {
+        match choice {
+            Some(index) => {
+                let (bw, acc) = p[index];
+                let vc = configurations[index];
+                info!("{} kbps: {} ({:.1} kbps, accuracy {:.4})", point, vc, bw * 1_000.0, acc);
+                if !levels.iter().any(|l| l.2 == vc) {
+                    levels.push((bw, acc, vc));
+                }
+            }
+            None => warn!("{} kbps: no configuration fits", point),
+        }
+    }
+    levels.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let ofile = format!("{}/pareto.csv", outdir);
+    let mut writer = csv::Writer::from_path(&ofile).expect("failed to open pareto.csv");
+    let header = ("bandwidth", "width", "skip", "quant", "accuracy");
+    writer.serialize(header).expect("failed to write header");
+    for l in &levels {
+        let entry = (l.0 * 1_000.0, l.2.width, l.2.skip, l.2.quant, l.1);
+        writer.serialize(entry).expect("failed to write to csv");
+    }
+}
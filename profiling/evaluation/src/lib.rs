@@ -8,19 +8,54 @@ extern crate itertools;
 extern crate rand;
 #[macro_use]
 extern crate log;
+extern crate memmap;
 extern crate rayon;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate serde_json;
 
 mod acc;
 pub use acc::{f1, precision, recall};
 pub use acc::FrameStat;
+pub use acc::Granularity;
 pub use acc::Stat;
 pub use acc::aggregate_accuracy;
+pub use acc::aggregate_accuracy_with;
 pub use acc::extract_proc_time;
 pub use acc::get_frame_stats;
 
+mod cdf;
+pub use cdf::CdfPoint;
+pub use cdf::FRAME_FILE;
+pub use cdf::RunFrame;
+pub use cdf::cdf;
+pub use cdf::frame_cdfs;
+pub use cdf::read_frames;
+
+mod compare;
+pub use compare::RUN_FILE;
+pub use compare::RunSecond;
+pub use compare::RunSummary;
+pub use compare::align_runs;
+pub use compare::read_accuracies;
+pub use compare::read_run;
+pub use compare::summarize_run;
+
+mod decision;
+pub use decision::{LevelDecision, read_decisions, read_levels};
+
+mod downsample;
+pub use downsample::CONFIG_FILE;
+pub use downsample::SAMPLE_FILE;
+pub use downsample::count_frames;
+pub use downsample::downsample;
+pub use downsample::read_configurations;
+pub use downsample::sample_configurations;
+pub use downsample::stratified_clips;
+pub use downsample::thin;
+pub use downsample::write_configurations;
+
 mod helper;
 pub use helper::all_configurations;
 
@@ -28,11 +63,59 @@ mod profile;
 pub use profile::Configuration;
 pub use profile::Pareto;
 pub use profile::Profile;
+pub use profile::Split;
 pub use profile::get_bandwidth_accuracy_for_config;
+pub use profile::get_proc_time_for_config;
+pub use profile::mean_over;
+pub use profile::pareto_3d;
 pub use profile::summarize_profile;
+pub use profile::summarize_profile_for;
+pub use profile::summarize_profile_with_split;
+
+mod ladder;
+pub use ladder::ladder;
+pub use ladder::parse_points;
+pub use ladder::summarize_ladder;
+
+mod manifest;
+pub use manifest::MANIFEST_FILE;
+pub use manifest::Manifest;
+pub use manifest::ManifestEntry;
+pub use manifest::hash_file;
+
+mod quality;
+pub use quality::aggregate_quality;
+pub use quality::get_quality_for_config;
+
+mod motion;
+pub use motion::MotionConfig;
+pub use motion::MotionSkip;
+pub use motion::frame_difference;
+pub use motion::read_motion;
+
+mod resource;
+pub use resource::Resource;
+pub use resource::aggregate_resource;
+pub use resource::get_resource_for_config;
+pub use resource::summarize_resource;
+
+mod stat_index;
+pub use stat_index::STAT_INDEX_MAGIC;
+pub use stat_index::StatIndex;
+
+mod transfer;
+pub use transfer::Transfer;
+pub use transfer::summarize_transfer;
+pub use transfer::transfer;
 
 mod bw;
 pub use bw::aggregate_bandwidth;
+pub use bw::aggregate_frame_types;
+pub use bw::split_by_frame_type;
+
+mod wrap;
+pub use wrap::FrameRange;
+pub use wrap::Wraparound;
 
 use std::fs::File;
 
@@ -93,6 +176,32 @@ impl VideoConfig {
         format!("{}/bw-{}x{}x{}.csv", dir, self.width, self.skip, self.quant)
     }
 
+    /// Gets the filename of quality (PSNR/SSIM) file.
+    pub fn derive_quality_file(&self, dir: &str) -> String {
+        format!("{}/quality-{}x{}x{}.csv", dir, self.width, self.skip, self.quant)
+    }
+
+    /// Gets the filename of quality file aggregated into chunks.
+    pub fn derive_quality_chunk_file(&self, dir: &str) -> String {
+        format!(
+            "{}/quality-chunk-{}x{}x{}.csv",
+            dir,
+            self.width,
+            self.skip,
+            self.quant
+        )
+    }
+
+    /// Gets the filename of resource (CPU time and energy) file.
+    pub fn derive_res_file(&self, dir: &str) -> String {
+        format!("{}/res-{}x{}x{}.csv", dir, self.width, self.skip, self.quant)
+    }
+
+    /// Gets the filename of bandwidth file split by frame type.
+    pub fn derive_bw_type_file(&self, dir: &str) -> String {
+        format!("{}/bwtype-{}x{}x{}.csv", dir, self.width, self.skip, self.quant)
+    }
+
     /// Opens accuracy file.
     pub fn open_acc_file(&self, dir: &str) -> File {
         let filename = self.derive_acc_file(dir);
@@ -100,9 +209,13 @@ impl VideoConfig {
     }
 }
 
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use testing::ScratchDir;
 
     #[test]
     fn simple_pareto() {
@@ -115,4 +228,300 @@ mod tests {
         let set = pareto.set.iter().map(|i| i.param).collect::<Vec<usize>>();
         assert_eq!(vec![3, 1], set);
     }
+
+    #[test]
+    fn simple_pareto_3d() {
+        let c = |param, bandwidth, accuracy, time| {
+            Configuration {
+                param,
+                bandwidth,
+                accuracy,
+                proc_time: Some(time),
+            }
+        };
+        // 2 is dominated by 1; 3 is only better in processing time.
+        let profile = Profile::from_vec(vec![
+            c(1, 1.0, 1.1, 10.0),
+            c(2, 2.0, 1.0, 10.0),
+            c(3, 3.0, 1.0, 5.0),
+            c(4, 4.0, 2.0, 20.0),
+        ]);
+        let pareto = profile.pareto_3d();
+
+        let set = pareto.set.iter().map(|i| i.param).collect::<Vec<usize>>();
+        assert_eq!(vec![4, 3, 1], set);
+    }
+
+    #[test]
+    fn split_frame_types() {
+        // 125_000 bytes is 1 mbit
+        let data = vec![
+            (1, 250_000, true),
+            (2, 125_000, false),
+            (3, 125_000, false),
+            (4, 125_000, false),
+        ];
+        let split = split_by_frame_type(&data, 3, 1);
+        assert_eq!(split, vec![(2.0, 2.0, 1), (0.0, 1.0, 0)]);
+    }
+
+    #[test]
+    fn resource_per_second() {
+        let sample = |stage: &str, cpu, energy| (stage.to_string(), cpu, energy);
+        let samples = vec![
+            sample("encode", 100.0, Some(2000.0)),
+            sample("encode", 300.0, Some(4000.0)),
+            sample("detect", 20.0, Some(100.0)),
+        ];
+        let r = summarize_resource(&samples, 10);
+        assert_eq!(r.encode_cpu, 200.0);
+        assert_eq!(r.detect_cpu, 200.0);
+        assert_eq!(r.power, 4000.0);
+
+        // Energy is only reported if every sample has it.
+        let samples = vec![sample("encode", 100.0, None), sample("detect", 20.0, Some(100.0))];
+        assert!(summarize_resource(&samples, 10).power.is_nan());
+    }
+
+    #[test]
+    fn split_and_mean() {
+        let split = Split::parse("0:2", "2:4").unwrap();
+        assert_eq!(split.train, 0..2);
+        assert_eq!(split.test, 2..4);
+        assert!(Split::parse("2:2", "2:4").is_none());
+        assert!(Split::parse("0:2", "x").is_none());
+
+        // The last (incomplete) chunk is never included.
+        let measure = vec![(1.0, 0.5), (3.0, 0.7), (5.0, 0.9), (100.0, 0.0)];
+        assert_eq!(mean_over(&measure, &split.train), Some((2.0, 0.6)));
+        assert_eq!(mean_over(&measure, &split.test), Some((5.0, 0.9)));
+
+        // Nothing to average: no chunk in the span, or no complete chunk.
+        assert_eq!(mean_over(&measure, &(4..6)), None);
+        assert_eq!(mean_over(&measure, &(2..2)), None);
+        assert_eq!(mean_over(&measure[..1], &(0..usize::MAX)), None);
+    }
+
+    #[test]
+    fn proc_time_counts_every_frame() {
+        use std::fs;
+
+        // As `extract_proc_time` writes it: no header, NaN for missing frames.
+        let dir = ScratchDir::new("proc_time");
+        let dir_str = dir.path().to_str().unwrap();
+        let vc = VideoConfig::new(640, 0, 20);
+        fs::write(vc.derive_ts_file(dir_str), "1,10.0\n2,NaN\n3,20.0\n").unwrap();
+        assert_eq!(get_proc_time_for_config(dir_str, &vc), Some(15.0));
+        assert_eq!(get_proc_time_for_config(dir_str, &VideoConfig::new(320, 0, 20)), None);
+    }
+
+    #[test]
+    fn manifest_detects_missing_frames() {
+        use std::fs;
+        use std::io::Write;
+
+        let dir = ScratchDir::new("manifest");
+        for i in &[1, 2, 4] {
+            let mut f = fs::File::create(dir.join(format!("{:06}.jpg", i))).unwrap();
+            write!(f, "frame {}", i).unwrap();
+        }
+        let dir_str = dir.path().to_str().unwrap();
+
+        let manifest = Manifest::generate(dir_str, "jpg", 30.0, |_| Some((640, 360))).unwrap();
+        assert_eq!(manifest.len(), 3);
+        assert_eq!(manifest.problems().len(), 1);
+
+        // Renumber frame 4 to 3.
+        fs::rename(dir.join("000004.jpg"), dir.join("000003.jpg")).unwrap();
+        let manifest = Manifest::generate(dir_str, "jpg", 30.0, |_| Some((640, 360))).unwrap();
+        assert!(manifest.problems().is_empty());
+        assert!(manifest.verify(dir_str).is_empty());
+
+        // Alter a frame and add an extra one.
+        fs::File::create(dir.join("000002.jpg")).unwrap();
+        fs::File::create(dir.join("000005.jpg")).unwrap();
+        assert_eq!(manifest.verify(dir_str).len(), 2);
+    }
+
+    #[test]
+    fn motion_skips_static_frames() {
+        assert_eq!(frame_difference(&[0, 10, 20], &[3, 10, 17]), 2.0);
+
+        let mut skip = MotionSkip::new(MotionConfig {
+            threshold: 5.0,
+            max_skip: 2,
+        });
+        let kept = [f64::NAN, 1.0, 1.0, 1.0, 1.0, 4.0, 8.0]
+            .iter()
+            .map(|&m| skip.keep(m))
+            .collect::<Vec<_>>();
+        // Static frames are skipped (at most 2 in a row); motion accumulates
+        // until it crosses the threshold.
+        assert_eq!(kept, vec![true, false, false, true, false, true, true]);
+    }
+
+    #[test]
+    fn decisions_round_trip() {
+        let dir = ScratchDir::new("decisions");
+        let path = dir.join("decisions.csv");
+        let decisions = vec![
+            LevelDecision {
+                timestamp: 1504224000.5,
+                frame_num: 1,
+                level: 0,
+            },
+            LevelDecision {
+                timestamp: 1504224000.533,
+                frame_num: 2,
+                level: 3,
+            },
+        ];
+        {
+            let mut writer = csv::Writer::from_path(&path).unwrap();
+            for d in &decisions {
+                writer.serialize(d).unwrap();
+            }
+        }
+        assert_eq!(read_decisions(&path).unwrap(), decisions);
+        assert_eq!(read_levels(&path).unwrap(), vec![(1, 0), (2, 3)]);
+    }
+
+    #[test]
+    fn levels_of_old_logs() {
+        let dir = ScratchDir::new("old-decisions");
+        let path = dir.join("decisions.csv");
+        ::std::fs::write(&path, "frame_num,level\n1,0\n2,3\n").unwrap();
+        assert_eq!(read_levels(&path).unwrap(), vec![(1, 0), (2, 3)]);
+    }
+
+    #[test]
+    fn ladder_picks_best_fit() {
+        let profile = vec![(1.0, 0.5), (2.0, 0.7), (2.5, 0.7), (3.0, 0.6), (4.0, 0.9)];
+        let points = vec![0.5, 2.0, 3.0, 10.0];
+        assert_eq!(ladder(&profile, &points), vec![None, Some(1), Some(1), Some(4)]);
+        assert_eq!(parse_points("250, 500,1000"), Some(vec![250.0, 500.0, 1000.0]));
+        assert_eq!(parse_points("250,fast"), None);
+    }
+
+    #[test]
+    fn transfer_regret() {
+        // Configuration 1 is on A's front, but on B configuration 2 is more
+        // accurate at a lower bandwidth.
+        let a = vec![(1.0, 0.5), (2.0, 0.8), (2.5, 0.6)];
+        let b = vec![(1.0, 0.5), (2.0, 0.6), (1.5, 0.7)];
+        let levels = transfer(&a, &b);
+        assert_eq!(levels.iter().map(|t| t.index).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(levels[0].regret(), 0.0);
+        assert!((levels[1].regret() - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn runs_align_by_second() {
+        let second = |second, accuracy, latency| RunSecond {
+            second,
+            accuracy,
+            latency,
+            bytes: 1000,
+            shed: 0.0,
+            processing: 0.0,
+        };
+        let a = vec![second(0, 0.5, 100.0),
+                     second(1, 0.7, 200.0),
+                     second(2, ::std::f64::NAN, 50.0)];
+        let b = vec![second(1, 0.9, 10.0)];
+
+        let aligned = align_runs(&[a.clone(), b.clone()]);
+        assert_eq!(aligned.len(), 3);
+        assert_eq!(aligned[0].1, vec![Some(a[0]), None]);
+        assert_eq!(aligned[1].1, vec![Some(a[1]), Some(b[0])]);
+
+        let summary = summarize_run("a", &a);
+        assert_eq!(summary.seconds, 3);
+        assert!((summary.mean_accuracy - 0.6).abs() < 1e-9);
+        assert_eq!(summary.p95_latency, 100.0);
+        assert_eq!(summary.bytes, 3000);
+    }
+
+    #[test]
+    fn runs_from_printed_accuracies() {
+        let dir = ScratchDir::new("printed-run");
+        let path = dir.join("hls.txt");
+        ::std::fs::write(&path, "Opt { stat_path: \"stat.csv\" }\n0.5\n0.75\n").unwrap();
+        let run = read_run(&path).unwrap();
+        assert_eq!(run.iter().map(|s| (s.second, s.accuracy)).collect::<Vec<_>>(),
+                   vec![(0, 0.5), (1, 0.75)]);
+        assert!(run[0].latency.is_nan());
+    }
+
+    #[test]
+    fn cdf_over_evenly_spaced_values() {
+        let points = cdf(&[4.0, 1.0, 2.0, 2.0, ::std::f64::NAN], 4);
+        let values = points.iter().map(|p| p.value).collect::<Vec<_>>();
+        let fractions = points.iter().map(|p| p.fraction).collect::<Vec<_>>();
+        assert_eq!(values, vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(fractions, vec![0.25, 0.75, 0.75, 1.0]);
+        assert!(cdf(&[], 4).is_empty());
+
+        let frame = |latency, accuracy| RunFrame {
+            frame_num: 0,
+            level: 0,
+            latency,
+            accuracy,
+        };
+        let (latency, accuracy) = frame_cdfs(&[frame(100.0, 0.5), frame(300.0, 1.0)], 3);
+        assert_eq!(latency[1], CdfPoint { value: 200.0, fraction: 0.5 });
+        assert_eq!(accuracy[2], CdfPoint { value: 1.0, fraction: 1.0 });
+    }
+
+    #[test]
+    fn stat_index_finds_frames() {
+        let stat = |tp| Stat {
+            true_positive: tp,
+            false_positive: 1,
+            false_negative: 2,
+        };
+        let (a, b) = (VideoConfig::new(640, 0, 20), VideoConfig::new(320, 2, 30));
+        let stats = vec![
+            FrameStat::new(2, a, stat(12)),
+            FrameStat::new(1, b, stat(21)),
+            FrameStat::new(1, a, stat(11)),
+            FrameStat::new(3, a, stat(13)),
+        ];
+        let index = StatIndex::build(&stats);
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.configs(), vec![b, a]);
+        assert_eq!(index.frames(), 3);
+        for s in &stats {
+            assert_eq!(index.get(s.frame_num, s.config), Some(s.stat));
+        }
+        assert_eq!(index.get(2, b), None);
+        assert_eq!(index.get(1, VideoConfig::new(640, 0, 21)), None);
+
+        let dir = ScratchDir::new("stat-index");
+        let path = dir.join("stat.idx");
+        index.write(&path).unwrap();
+        assert_eq!(StatIndex::open(&path).unwrap(), index);
+        let bytes = ::std::fs::read(&path).unwrap();
+        assert!(StatIndex::from_bytes(bytes[..bytes.len() - 1].to_vec()).is_err());
+        assert!(StatIndex::from_bytes(b"frame,640".to_vec()).is_err());
+        assert!(StatIndex::default().is_empty());
+        assert_eq!(StatIndex::default().frames(), 0);
+    }
+
+    #[test]
+    fn clips_are_stratified() {
+        let clips = stratified_clips(100, 4, 10);
+        assert_eq!(clips.len(), 4);
+        for (i, clip) in clips.iter().enumerate() {
+            assert_eq!(clip.len(), 10);
+            assert!(clip.start >= 1 + 25 * i && clip.end <= 1 + 25 * (i + 1));
+        }
+        assert_eq!(stratified_clips(3, 4, 10), vec![1..2, 2..3, 3..4]);
+
+        assert_eq!(thin(&[1, 2, 3, 4, 5, 6], 3), vec![1, 3, 6]);
+        let configs = sample_configurations(2);
+        assert_eq!(configs.len(), 8);
+        assert!(configs.contains(&VideoConfig::new(1920, 0, 0)));
+        assert!(configs.contains(&VideoConfig::new(320, 29, 50)));
+    }
 }
@@ -0,0 +1,283 @@
+//! Dataset manifest: what a profiling dataset (a directory of numbered frames,
+//! `000001.jpg`, `000002.jpg`, ...) is expected to contain. Generating it once
+//! and checking it before profiling catches missing or renumbered frames up
+//! front, instead of having them surface as off-by-one accuracy bugs.
+//!
+//! The manifest is a JSON file (`manifest.json` in the dataset by convention)
+//! with the fps, the extension, and the resolution and hash of every frame.
+
+use super::VideoConfig;
+use csv;
+use serde_json;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::Path;
+
+/// The file name of a manifest inside the dataset directory.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// One frame of the dataset.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ManifestEntry {
+    /// Frame number, starting from 1.
+    pub frame_num: usize,
+
+    /// Frame width in pixels.
+    pub width: usize,
+
+    /// Frame height in pixels.
+    pub height: usize,
+
+    /// FNV-1a hash of the file content (hex).
+    pub hash: String,
+}
+
+/// Describes a profiling dataset.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Manifest {
+    /// Frames per second the dataset is recorded at.
+    pub fps: f64,
+
+    /// The extension of frame files, e.g. `jpg`.
+    pub ext: String,
+
+    /// All frames, ordered by frame number.
+    pub frames: Vec<ManifestEntry>,
+}
+
+/// Hashes a file with 64-bit FNV-1a. This is not meant to be cryptographic;
+/// it only needs to detect replaced or corrupted frames.
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buf = [0; 8192];
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for &b in &buf[..n] {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+/// Returns the frame number if `path` is a frame file (`<number>.<ext>`).
+fn frame_num_of(path: &Path, ext: &str) -> Option<usize> {
+    if path.extension().and_then(|e| e.to_str()) != Some(ext) {
+        return None;
+    }
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .and_then(|s| s.parse::<usize>().ok())
+}
+
+impl Manifest {
+    /// Generates the manifest by scanning all frame files in `dir`. The
+    /// resolution of each frame is obtained with `probe` (which typically
+    /// decodes the image); frames that can't be probed are reported as an
+    /// error.
+    pub fn generate<F>(dir: &str, ext: &str, fps: f64, probe: F) -> io::Result<Manifest>
+    where
+        F: Fn(&Path) -> Option<(usize, usize)>,
+    {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if let Some(frame_num) = frame_num_of(&path, ext) {
+                files.push((frame_num, path));
+            }
+        }
+        files.sort();
+
+        let mut frames = Vec::with_capacity(files.len());
+        for (frame_num, path) in files {
+            let (width, height) = probe(&path).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("failed to read resolution of {}", path.display()),
+                )
+            })?;
+            frames.push(ManifestEntry {
+                frame_num,
+                width,
+                height,
+                hash: hash_file(&path)?,
+            });
+        }
+
+        Ok(Manifest {
+            fps,
+            ext: ext.to_string(),
+            frames,
+        })
+    }
+
+    /// Loads a manifest from a JSON file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Manifest> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    /// Saves the manifest as a JSON file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = File::create(path)?;
+        Ok(serde_json::to_writer_pretty(BufWriter::new(file), self)?)
+    }
+
+    /// The number of frames in the dataset.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Whether the dataset has no frames.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// The file name of a frame.
+    pub fn file_name(&self, frame_num: usize) -> String {
+        format!("{:06}.{}", frame_num, self.ext)
+    }
+
+    /// Checks the manifest itself: frames must be numbered 1, 2, ... without
+    /// gaps and share the same resolution.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.fps <= 0.0 {
+            problems.push(format!("invalid fps {}", self.fps));
+        }
+        if self.frames.is_empty() {
+            problems.push("no frames".to_string());
+            return problems;
+        }
+
+        for (i, entry) in self.frames.iter().enumerate() {
+            if entry.frame_num != i + 1 {
+                problems.push(format!(
+                    "frame {} found at position {}: frames are missing or renumbered",
+                    entry.frame_num,
+                    i + 1
+                ));
+                break;
+            }
+        }
+
+        let first = &self.frames[0];
+        for entry in &self.frames {
+            if (entry.width, entry.height) != (first.width, first.height) {
+                problems.push(format!(
+                    "frame {} is {}x{}, but frame {} is {}x{}",
+                    entry.frame_num,
+                    entry.width,
+                    entry.height,
+                    first.frame_num,
+                    first.width,
+                    first.height
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Checks the dataset in `dir` against the manifest: every frame must be
+    /// present with the recorded content, and there must be no extra frames.
+    /// Resolutions are not checked (that requires decoding images).
+    pub fn verify(&self, dir: &str) -> Vec<String> {
+        let mut problems = self.problems();
+
+        for entry in &self.frames {
+            let path = Path::new(dir).join(self.file_name(entry.frame_num));
+            match hash_file(&path) {
+                Ok(ref hash) if *hash == entry.hash => {}
+                Ok(_) => problems.push(format!("{} has changed", path.display())),
+                Err(e) => problems.push(format!("{}: {}", path.display(), e)),
+            }
+        }
+
+        let known = self.frames
+            .iter()
+            .map(|e| e.frame_num)
+            .collect::<HashSet<_>>();
+        match fs::read_dir(dir) {
+            Ok(entries) => {
+                let mut extra = entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| frame_num_of(&e.path(), &self.ext))
+                    .filter(|n| !known.contains(n))
+                    .collect::<Vec<_>>();
+                extra.sort();
+                for n in extra {
+                    problems.push(format!("{} is not in the manifest", self.file_name(n)));
+                }
+            }
+            Err(e) => problems.push(format!("{}: {}", dir, e)),
+        }
+        problems
+    }
+
+    /// Checks measurement data in `dir` against the manifest: the groundtruth
+    /// must cover exactly the frames of the dataset, and the accuracy file of
+    /// each configuration must stay within the frames that are sent.
+    pub fn check_measurements(&self, dir: &str, configurations: &[VideoConfig]) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let groundtruth = format!("{}/groundtruth.csv", dir);
+        match frame_range(&groundtruth) {
+            Ok(Some((_, last))) if last != self.len() => {
+                problems.push(format!(
+                    "{} ends at frame {}, but the dataset has {} frames",
+                    groundtruth,
+                    last,
+                    self.len()
+                ))
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(format!("{}: {}", groundtruth, e)),
+        }
+
+        for vc in configurations {
+            let file = vc.derive_acc_file(dir);
+            let sent = (self.len() + vc.skip) / (vc.skip + 1);
+            match frame_range(&file) {
+                Ok(Some((first, last))) if first == 0 || last > sent => {
+                    problems.push(format!(
+                        "{} has frames {} to {}, but only frames 1 to {} are sent",
+                        file,
+                        first,
+                        last,
+                        sent
+                    ))
+                }
+                Ok(_) => {}
+                Err(e) => problems.push(format!("{}: {}", file, e)),
+            }
+        }
+        problems
+    }
+}
+
+/// Returns the smallest and largest frame number (the first column) of a
+/// measurement file, or `None` if it has no entries.
+fn frame_range(path: &str) -> Result<Option<(usize, usize)>, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)?;
+    let mut range: Option<(usize, usize)> = None;
+    for record in reader.records() {
+        let record = record?;
+        let frame_num = match record.get(0).map(|f| f.trim().parse::<usize>()) {
+            Some(Ok(n)) => n,
+            _ => continue,
+        };
+        range = Some(match range {
+            Some((first, last)) => (first.min(frame_num), last.max(frame_num)),
+            None => (frame_num, frame_num),
+        });
+    }
+    Ok(range)
+}
@@ -0,0 +1,96 @@
+//! Motion-triggered frame skipping. When the scene is static, frames beyond
+//! what the configuration's skip asks for are skipped too; as soon as things
+//! move again, frames are sent at the configured rate. This is a degradation
+//! dimension of its own: it saves bandwidth on static scenes without touching
+//! the profile.
+//!
+//! Motion is the mean absolute difference (0 to 255) between the pixels of
+//! consecutive frames, usually computed on small thumbnails. The video crate
+//! measures it while loading frames and can write it per frame (`motion.csv`,
+//! `frame_num, motion`) for the runtime to replay.
+
+use csv;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Returns the mean absolute difference between two frames of the same
+/// format (`NaN` if they differ in size).
+pub fn frame_difference(a: &[u8], b: &[u8]) -> f64 {
+    if a.len() != b.len() || a.is_empty() {
+        return f64::NAN;
+    }
+    let sum = a.iter()
+        .zip(b)
+        .map(|(&x, &y)| u64::from((i32::from(x) - i32::from(y)).unsigned_abs()))
+        .sum::<u64>();
+    sum as f64 / a.len() as f64
+}
+
+/// When frames are skipped for lack of motion.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct MotionConfig {
+    /// Motion (accumulated since the last frame sent) below which the scene
+    /// is considered static.
+    pub threshold: f64,
+
+    /// At most this many frames are skipped in a row, so that a static scene
+    /// is still refreshed.
+    pub max_skip: usize,
+}
+
+/// Decides, frame by frame, whether to send or skip.
+#[derive(Debug, Clone)]
+pub struct MotionSkip {
+    config: MotionConfig,
+
+    /// Motion accumulated since the last frame sent.
+    motion: f64,
+
+    /// Frames skipped since the last frame sent.
+    skipped: usize,
+}
+
+impl MotionSkip {
+    /// Creates the decision for `config`.
+    pub fn new(config: MotionConfig) -> MotionSkip {
+        MotionSkip {
+            config,
+            motion: 0.0,
+            // So that the first frame is sent.
+            skipped: config.max_skip,
+        }
+    }
+
+    /// Given the motion of a frame relative to the previous frame, returns
+    /// whether to send it. The first frame and frames of unknown motion (NaN)
+    /// are always sent.
+    pub fn keep(&mut self, motion: f64) -> bool {
+        self.motion += motion;
+        let keep = self.motion.is_nan() || self.motion >= self.config.threshold ||
+            self.skipped >= self.config.max_skip;
+        if keep {
+            self.motion = 0.0;
+            self.skipped = 0;
+        } else {
+            self.skipped += 1;
+        }
+        keep
+    }
+}
+
+/// Reads per-frame motion (`frame_num, motion`).
+pub fn read_motion<P: AsRef<Path>>(path: P) -> csv::Result<BTreeMap<usize, f64>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(path)?;
+    let mut motion = BTreeMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let frame_num = record[0].trim().parse::<usize>().ok();
+        let m = record.get(1).and_then(|m| m.trim().parse::<f64>().ok());
+        if let (Some(frame_num), Some(m)) = (frame_num, m) {
+            motion.insert(frame_num, m);
+        }
+    }
+    Ok(motion)
+}
@@ -2,11 +2,14 @@
 
 use super::VideoConfig;
 use csv;
+use quality::get_quality_for_config;
+use resource::{Resource, get_resource_for_config};
 use helper;
 use rand::{sample, thread_rng};
 use rayon::prelude::*;
 use serde::de::DeserializeOwned;
 use std::fmt::Debug;
+use std::ops::Range;
 use std::path::Path;
 /// Record is each individual rule in a profile.
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
@@ -33,6 +36,7 @@ impl<T: DeserializeOwned + Copy + Debug> Profile<T> {
                 param: record.config,
                 bandwidth: record.bandwidth,
                 accuracy: record.accuracy,
+                proc_time: None,
             };
             vec.push(config);
         }
@@ -71,23 +75,99 @@ pub fn get_bandwidth_accuracy_for_config(dir: &str, vc: &VideoConfig) -> Vec<(f6
         .collect::<Vec<_>>()
 }
 
+/// A train/test split over measurement chunks (one chunk is one second). The
+/// profile is built from chunks in `train` and evaluated on chunks in `test`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Split {
+    /// Chunks used to build the profile.
+    pub train: Range<usize>,
+
+    /// Held-out chunks used to evaluate the profile.
+    pub test: Range<usize>,
+}
+
+impl Split {
+    /// Parses a split from two ranges, each in the form of `start:end` (end is
+    /// exclusive). Returns `None` if either range is malformed or empty.
+    pub fn parse(train: &str, test: &str) -> Option<Split> {
+        let range = |s: &str| {
+            let mut iter = s.splitn(2, ':').map(|i| i.trim().parse::<usize>());
+            match (iter.next(), iter.next()) {
+                (Some(Ok(start)), Some(Ok(end))) if start < end => Some(start..end),
+                _ => None,
+            }
+        };
+        Some(Split {
+            train: range(train)?,
+            test: range(test)?,
+        })
+    }
+}
+
+/// Averages (bandwidth, accuracy) over the chunks in `range`. The last chunk of
+/// a measurement is usually incomplete and is therefore never included.
+/// Returns `None` if no chunk of `range` is left to average.
+pub fn mean_over(measure: &[(f64, f64)], range: &Range<usize>) -> Option<(f64, f64)> {
+    let end = ::std::cmp::min(range.end, measure.len().saturating_sub(1));
+    let start = ::std::cmp::min(range.start, end);
+    if start == end {
+        return None;
+    }
+    let len = (end - start) as f64;
+    let mean = measure[start..end].iter().fold((0.0, 0.0), |sum, i| {
+        (sum.0 + i.0 / len, sum.1 + i.1 / len)
+    });
+    Some(mean)
+}
+
+/// Averages (bandwidth, accuracy) of `vc` over the chunks in `range` of
+/// `measure`, which must have some.
+fn mean_of(vc: &VideoConfig, measure: &[(f64, f64)], range: &Range<usize>) -> (f64, f64) {
+    mean_over(measure, range)
+        .unwrap_or_else(|| panic!("no complete chunk of {} in {:?}", vc, range))
+}
+
+/// Averages (bandwidth, accuracy) of every configuration over all of its
+/// measurement in `dir`.
+pub fn mean_of_configurations(dir: &str, configurations: &[VideoConfig]) -> Vec<(f64, f64)> {
+    configurations
+        .par_iter()
+        .map(|vc| mean_of(vc, &get_bandwidth_accuracy_for_config(dir, vc), &(0..usize::MAX)))
+        .collect()
+}
+
 /// Summarize profile from `dir` to `outdir`. Will produce `profile.csv` and
 /// `pareto.csv`.
 pub fn summarize_profile(dir: &str, outdir: &str) {
+    summarize_profile_with_split(dir, outdir, None)
+}
+
+/// Same as `summarize_profile`, but if `split` is given, the profile is built
+/// only from the training chunks and every Pareto-optimal configuration is
+/// evaluated on the test chunks as well (written to `test.csv`).
+pub fn summarize_profile_with_split(dir: &str, outdir: &str, split: Option<&Split>) {
     let configurations = helper::all_configurations();
+    summarize_profile_for(dir, outdir, &configurations, split)
+}
+
+/// Same as `summarize_profile_with_split`, but only over `configurations`, e.g.
+/// those a quick profiling pass measures (see `sample_configurations`).
+pub fn summarize_profile_for(
+    dir: &str,
+    outdir: &str,
+    configurations: &[VideoConfig],
+    split: Option<&Split>,
+) {
     let profile = configurations
         .par_iter()
         .map(|&vc| get_bandwidth_accuracy_for_config(&dir, &vc))
         .collect::<Vec<Vec<(f64, f64)>>>();
 
+    let train = split.map_or(0..usize::MAX, |s| s.train.clone());
     let p = profile
         .iter()
-        .map(|p| {
-            let len = (p.len() - 1) as f64;
-            p.iter().take(len as usize).fold((0.0, 0.0), |sum, i| {
-                (sum.0 + i.0 / len, sum.1 + i.1 / len)
-            })
-        })
+        .zip(configurations)
+        .map(|(p, vc)| mean_of(vc, p, &train))
         .collect::<Vec<_>>();
 
     let ofile = format!("{}/profile.csv", outdir);
@@ -116,10 +196,161 @@ pub fn summarize_profile(dir: &str, outdir: &str) {
     let ofile = format!("{}/pareto.csv", outdir);
     let mut writer = csv::Writer::from_path(&ofile).expect("failed to open pareto.csv");
     writer.serialize(header).expect("failed to write header");
-    for i in pareto {
+    for i in &pareto {
         let entry = (i.0 * 1_000.0, i.2.width, i.2.skip, i.2.quant, i.1);
         writer.serialize(entry).expect("failed to write to csv");
     }
+
+    if let Some(split) = split {
+        let test = pareto
+            .iter()
+            .map(|&(bw, acc, vc)| {
+                let index = configurations.iter().position(|c| *c == vc).unwrap();
+                let (test_bw, test_acc) = mean_of(&vc, &profile[index], &split.test);
+                (bw, acc, vc, test_bw, test_acc)
+            })
+            .collect::<Vec<_>>();
+        summarize_test(outdir, &test);
+    }
+
+    summarize_proc_time(dir, outdir, configurations, &p);
+    summarize_quality(dir, outdir, configurations, &p);
+    summarize_resources(dir, outdir, configurations, &p);
+}
+
+/// If image quality (`quality-*.csv`) is available for all configurations,
+/// also produces `profile-quality.csv` which includes PSNR and SSIM.
+fn summarize_quality(dir: &str, outdir: &str, configurations: &[VideoConfig], p: &[(f64, f64)]) {
+    let quality = configurations
+        .iter()
+        .map(|vc| get_quality_for_config(dir, vc))
+        .collect::<Option<Vec<(f64, f64)>>>();
+    let quality = match quality {
+        Some(quality) => quality,
+        None => {
+            info!("no quality for all configurations, skip");
+            return;
+        }
+    };
+
+    let header = ("bandwidth", "width", "skip", "quant", "accuracy", "psnr", "ssim");
+    let ofile = format!("{}/profile-quality.csv", outdir);
+    let mut writer = csv::Writer::from_path(&ofile).expect("failed to open profile-quality.csv");
+    writer.serialize(header).expect("failed to write header");
+    for ((p, vc), q) in p.iter().zip(configurations.iter()).zip(quality.iter()) {
+        let entry = (p.0, vc.width, vc.skip, vc.quant, p.1, q.0, q.1);
+        writer.serialize(entry).expect("failed to write to csv");
+    }
+}
+
+/// If resources (`res-*.csv`) are available for all configurations, also
+/// produces `profile-resource.csv` which includes CPU time and power.
+fn summarize_resources(dir: &str, outdir: &str, configurations: &[VideoConfig], p: &[(f64, f64)]) {
+    let resources = configurations
+        .iter()
+        .map(|vc| get_resource_for_config(dir, vc))
+        .collect::<Option<Vec<Resource>>>();
+    let resources = match resources {
+        Some(resources) => resources,
+        None => {
+            info!("no resources for all configurations, skip");
+            return;
+        }
+    };
+
+    let header = (
+        "bandwidth",
+        "width",
+        "skip",
+        "quant",
+        "accuracy",
+        "encode_cpu",
+        "detect_cpu",
+        "power",
+    );
+    let ofile = format!("{}/profile-resource.csv", outdir);
+    let mut writer = csv::Writer::from_path(&ofile).expect("failed to open profile-resource.csv");
+    writer.serialize(header).expect("failed to write header");
+    for ((p, vc), r) in p.iter().zip(configurations.iter()).zip(resources.iter()) {
+        let entry = (p.0, vc.width, vc.skip, vc.quant, p.1, r.encode_cpu, r.detect_cpu, r.power);
+        writer.serialize(entry).expect("failed to write to csv");
+    }
+}
+
+/// Writes `test.csv` that compares the training estimate of each Pareto-optimal
+/// configuration with what it achieves on the held-out chunks.
+fn summarize_test(outdir: &str, test: &[(f64, f64, VideoConfig, f64, f64)]) {
+    let ofile = format!("{}/test.csv", outdir);
+    let mut writer = csv::Writer::from_path(&ofile).expect("failed to open test.csv");
+    let header = (
+        "bandwidth",
+        "width",
+        "skip",
+        "quant",
+        "accuracy",
+        "test_bandwidth",
+        "test_accuracy",
+    );
+    writer.serialize(header).expect("failed to write header");
+    for &(bw, acc, vc, test_bw, test_acc) in test {
+        let entry = (
+            bw * 1_000.0,
+            vc.width,
+            vc.skip,
+            vc.quant,
+            acc,
+            test_bw * 1_000.0,
+            test_acc,
+        );
+        writer.serialize(entry).expect("failed to write to csv");
+    }
+
+    let n = test.len() as f64;
+    let error = test.iter().map(|t| (t.1 - t.4).abs()).sum::<f64>() / n;
+    info!("mean absolute accuracy error on test chunks: {:.4}", error);
+}
+
+/// If processing time (`ts-*.csv`) is available for all configurations, also
+/// produces `profile-time.csv` and `pareto-time.csv` which include the mean
+/// processing time.
+fn summarize_proc_time(dir: &str, outdir: &str, configurations: &[VideoConfig], p: &[(f64, f64)]) {
+    let times = configurations
+        .iter()
+        .map(|vc| get_proc_time_for_config(dir, vc))
+        .collect::<Option<Vec<f64>>>();
+    let times = match times {
+        Some(times) => times,
+        None => {
+            info!("no processing time for all configurations, skip");
+            return;
+        }
+    };
+
+    let p = p.iter()
+        .zip(times.iter())
+        .map(|(p, &t)| (p.0, p.1, t))
+        .collect::<Vec<_>>();
+
+    let header = ("bandwidth", "width", "skip", "quant", "accuracy", "proc_time");
+    let ofile = format!("{}/profile-time.csv", outdir);
+    let mut writer = csv::Writer::from_path(&ofile).expect("failed to open profile-time.csv");
+    writer.serialize(header).expect("failed to write header");
+    for (p, vc) in p.iter().zip(configurations.iter()) {
+        let entry = (p.0, vc.width, vc.skip, vc.quant, p.1, p.2);
+        writer.serialize(entry).expect("failed to write to csv");
+    }
+
+    let mut pareto = pareto_3d(&p);
+    pareto.sort_by(|&a, &b| p[a].0.partial_cmp(&p[b].0).unwrap());
+
+    let ofile = format!("{}/pareto-time.csv", outdir);
+    let mut writer = csv::Writer::from_path(&ofile).expect("failed to open pareto-time.csv");
+    writer.serialize(header).expect("failed to write header");
+    for index in pareto {
+        let (vc, p) = (configurations[index], p[index]);
+        let entry = (p.0 * 1_000.0, vc.width, vc.skip, vc.quant, p.1, p.2);
+        writer.serialize(entry).expect("failed to write to csv");
+    }
 }
 
 /// Find the pareto set given a list of bandwidth and a list of acc
@@ -138,6 +369,33 @@ pub fn pareto(profile: &Vec<(f64, f64)>) -> Vec<usize> {
     p_set
 }
 
+/// Find the pareto set given a list of (bandwidth, accuracy, processing time)
+pub fn pareto_3d(profile: &[(f64, f64, f64)]) -> Vec<usize> {
+    (0..profile.len())
+        .filter(|&i| !profile.iter().any(|&c_prime| dominates_3d(c_prime, profile[i])))
+        .collect()
+}
+
+/// Given a configuration, this function reads the processing time file (which,
+/// as `extract_proc_time` writes it, has no header) and returns the mean
+/// processing time (in ms) of all frames that have one. Returns `None` if there
+/// is no processing time file.
+pub fn get_proc_time_for_config(dir: &str, vc: &VideoConfig) -> Option<f64> {
+    let tsfile = vc.derive_ts_file(dir);
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).from_path(&tsfile).ok()?;
+    let times = reader
+        .deserialize()
+        .map(|record| record.expect("unexpected data format"))
+        .map(|r: (usize, f64)| r.1)
+        .filter(|t| !t.is_nan())
+        .collect::<Vec<f64>>();
+    if times.is_empty() {
+        None
+    } else {
+        Some(times.iter().sum::<f64>() / times.len() as f64)
+    }
+}
+
 /// A profile is a list of all configuration.
 pub struct Profile<T: Copy + Clone> {
     configurations: Vec<Configuration<T>>,
@@ -161,6 +419,7 @@ impl<T: Copy + Clone> Profile<T> {
                     param: *p,
                     bandwidth: m.0,
                     accuracy: m.1,
+                    proc_time: None,
                 }
             })
             .collect::<Vec<_>>();
@@ -178,6 +437,7 @@ impl<T: Copy + Clone> Profile<T> {
             param: t,
             bandwidth: bandwidth,
             accuracy: accuracy,
+            proc_time: None,
         })
     }
 
@@ -202,6 +462,24 @@ impl<T: Copy + Clone> Profile<T> {
         Pareto { set: set }
     }
 
+    /// Returns the Pareto-set of the profile in three dimensions: bandwidth,
+    /// accuracy and processing time. Only configurations with processing time
+    /// are considered.
+    pub fn pareto_3d(&self) -> Pareto<T> {
+        let mut set = self.configurations
+            .iter()
+            .filter(|c| c.proc_time.is_some())
+            .filter(|c| !self.configurations.iter().any(|c_prime| c_prime.dominates_3d(c)))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        set.sort_by(|a, b| {
+            a.bandwidth.partial_cmp(&b.bandwidth).unwrap().reverse()
+        });
+
+        Pareto { set }
+    }
+
     /// Returns the list of all profile configurations.
     pub fn all_params(&self) -> Vec<T> {
         self.configurations
@@ -299,4 +577,29 @@ pub struct Configuration<T> {
 
     /// Accuracy
     pub accuracy: f64,
+
+    /// Mean processing time (in ms), if measured
+    pub proc_time: Option<f64>,
+}
+
+impl<T> Configuration<T> {
+    /// Returns true if `self` is at least as good as `other` in bandwidth,
+    /// accuracy and processing time, and strictly better in one of them.
+    /// Configurations without processing time never dominate (nor are
+    /// dominated).
+    pub fn dominates_3d(&self, other: &Configuration<T>) -> bool {
+        match (self.proc_time, other.proc_time) {
+            (Some(t), Some(t_other)) => dominates_3d(
+                (self.bandwidth, self.accuracy, t),
+                (other.bandwidth, other.accuracy, t_other),
+            ),
+            _ => false,
+        }
+    }
+}
+
+fn dominates_3d(a: (f64, f64, f64), b: (f64, f64, f64)) -> bool {
+    let no_worse = a.0 <= b.0 && a.1 >= b.1 && a.2 <= b.2;
+    let better = a.0 < b.0 || a.1 > b.1 || a.2 < b.2;
+    no_worse && better
 }
@@ -0,0 +1,100 @@
+//! Objective image quality (PSNR/SSIM) of each configuration. Unlike accuracy,
+//! it does not depend on the analytics, so profiles can include it as an
+//! application-agnostic column.
+//!
+//! The input (`quality-X.csv`, measured by the video crate) has one
+//! `frame_num, psnr, ssim` entry per frame; the output (`quality-chunk-X.csv`)
+//! has one `chunk, psnr, ssim` entry per chunk.
+
+use super::VideoConfig;
+use csv;
+use std::path::Path;
+
+/// PSNR of identical frames is infinite; we cap it so that averages remain
+/// meaningful.
+pub const PSNR_MAX: f64 = 100.0;
+
+/// Aggregates per-frame quality into chunks of `duration_in_sec` seconds (at
+/// 30 frames per second). Does nothing if quality is not measured.
+pub fn aggregate_quality(dir: &str, outdir: &str, vc: VideoConfig, duration_in_sec: usize) {
+    let infile = vc.derive_quality_file(dir);
+    if !Path::new(&infile).exists() {
+        info!("no quality measurement {}, skip", infile);
+        return;
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(&infile)
+        .expect("failed to open quality file");
+    // Entries are written as `frame_num, psnr, ssim`, with spaces.
+    let data = reader
+        .records()
+        .map(|record| record.expect("unexpected data format"))
+        .map(|record| {
+            let field = |i: usize| record[i].trim().parse::<f64>().expect("unexpected data format");
+            (field(1).min(PSNR_MAX), field(2))
+        })
+        .collect::<Vec<(f64, f64)>>();
+
+    let outfile = vc.derive_quality_chunk_file(outdir);
+    let mut writer = csv::Writer::from_path(outfile).expect("failed to open outfile for quality");
+    for (i, chunk) in data.chunks(duration_in_sec * 30).enumerate() {
+        let len = chunk.len() as f64;
+        let psnr = chunk.iter().map(|q| q.0).sum::<f64>() / len;
+        let ssim = chunk.iter().map(|q| q.1).sum::<f64>() / len;
+        writer.serialize((i, psnr, ssim)).expect("failed to write csv");
+    }
+}
+
+/// Given a configuration, returns the mean (PSNR, SSIM) of all chunks but the
+/// last (which is usually incomplete), or `None` if quality is not measured.
+pub fn get_quality_for_config(dir: &str, vc: &VideoConfig) -> Option<(f64, f64)> {
+    let file = vc.derive_quality_chunk_file(dir);
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(&file)
+        .ok()?;
+    let data = reader
+        .deserialize()
+        .map(|record| record.expect("unexpected data format"))
+        .map(|r: (usize, f64, f64)| (r.1, r.2))
+        .collect::<Vec<(f64, f64)>>();
+
+    let n = ::std::cmp::max(data.len(), 2) - 1;
+    let len = n as f64;
+    Some(data.iter().take(n).fold((0.0, 0.0), |sum, q| {
+        (sum.0 + q.0 / len, sum.1 + q.1 / len)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use testing::ScratchDir;
+
+    #[test]
+    fn chunks_leave_frames_alone() {
+        let dir = ScratchDir::new("quality");
+        let dir = dir.path().to_str().unwrap();
+        let vc = VideoConfig::new(640, 0, 20);
+
+        // Two chunks of 30 frames and a last, incomplete one.
+        let mut frames = String::new();
+        for i in 1..71 {
+            let psnr = if i <= 30 { "inf" } else { "30.0" };
+            frames.push_str(&format!("{}, {}, 0.9\n", i, psnr));
+        }
+        fs::write(vc.derive_quality_file(dir), &frames).unwrap();
+
+        aggregate_quality(dir, dir, vc, 1);
+        assert_eq!(fs::read_to_string(vc.derive_quality_file(dir)).unwrap(), frames);
+
+        // Infinite PSNR counts as `PSNR_MAX`; the last chunk is left out.
+        let (psnr, ssim) = get_quality_for_config(dir, &vc).unwrap();
+        assert!((psnr - (PSNR_MAX + 30.0) / 2.0).abs() < 1e-9);
+        assert!((ssim - 0.9).abs() < 1e-9);
+        assert_eq!(get_quality_for_config(dir, &VideoConfig::new(320, 0, 20)), None);
+    }
+}
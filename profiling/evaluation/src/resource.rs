@@ -0,0 +1,112 @@
+//! CPU time and energy of each configuration, for deployments where compute
+//! or battery is as scarce as bandwidth.
+//!
+//! The input (`res-X.csv`, measured by the video crate with `RESOURCE_STATS`)
+//! has `stage, cpu_ms, energy_mj` entries: an `encode` entry covers one second
+//! of (real-time) encoding and a `detect` entry covers the detection of one
+//! frame, averaged over a window of frames; `energy_mj` is empty if RAPL is not
+//! available. The output (also
+//! `res-X.csv`) is a single `encode_cpu, detect_cpu, power` entry, all per
+//! second of video: CPU time in ms and power in mW (`NaN` if not measured).
+
+use super::VideoConfig;
+use csv;
+use helper;
+use std::path::Path;
+
+/// Resources used by a configuration, per second of video.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resource {
+    /// CPU time (ms) spent in encoding.
+    pub encode_cpu: f64,
+
+    /// CPU time (ms) spent in detection.
+    pub detect_cpu: f64,
+
+    /// Power (mW) of encoding and detection, or `NaN` if not measured.
+    pub power: f64,
+}
+
+fn mean(v: &[f64]) -> f64 {
+    if v.is_empty() {
+        f64::NAN
+    } else {
+        v.iter().sum::<f64>() / v.len() as f64
+    }
+}
+
+/// Summarizes per-stage samples (`stage, cpu_ms, energy_mj`) into the
+/// resources per second of video, given the frame rate of the configuration.
+pub fn summarize_resource(samples: &[(String, f64, Option<f64>)], fps: usize) -> Resource {
+    let stage = |name: &str| {
+        samples
+            .iter()
+            .filter(|s| s.0 == name)
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let (encode, detect) = (stage("encode"), stage("detect"));
+
+    let cpu = |v: &[(String, f64, Option<f64>)]| mean(&v.iter().map(|s| s.1).collect::<Vec<_>>());
+    let energy = |v: &[(String, f64, Option<f64>)]| {
+        v.iter()
+            .map(|s| s.2)
+            .collect::<Option<Vec<_>>>()
+            .map_or(f64::NAN, |e| mean(&e))
+    };
+
+    let fps = fps as f64;
+    Resource {
+        encode_cpu: cpu(&encode),
+        detect_cpu: cpu(&detect) * fps,
+        power: energy(&encode) + energy(&detect) * fps,
+    }
+}
+
+/// Summarizes the resource measurement of a configuration. Does nothing if
+/// resources are not measured.
+pub fn aggregate_resource(dir: &str, outdir: &str, vc: VideoConfig) {
+    let infile = vc.derive_res_file(dir);
+    if !Path::new(&infile).exists() {
+        info!("no resource measurement {}, skip", infile);
+        return;
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(&infile)
+        .expect("failed to open resource file");
+    let samples = reader
+        .records()
+        .map(|record| record.expect("unexpected data format"))
+        .map(|record| {
+            let cpu = record[1].trim().parse::<f64>().expect("cpu_ms must be a number");
+            let energy = record.get(2).and_then(|e| e.trim().parse::<f64>().ok());
+            (record[0].trim().to_string(), cpu, energy)
+        })
+        .collect::<Vec<_>>();
+
+    let r = summarize_resource(&samples, helper::skip_to_fps(vc.skip));
+    let outfile = vc.derive_res_file(outdir);
+    let mut writer = csv::Writer::from_path(outfile).expect("failed to open outfile for resource");
+    writer
+        .serialize((r.encode_cpu, r.detect_cpu, r.power))
+        .expect("failed to write csv");
+}
+
+/// Given a configuration, returns the summarized resources, or `None` if
+/// resources are not measured.
+pub fn get_resource_for_config(dir: &str, vc: &VideoConfig) -> Option<Resource> {
+    let file = vc.derive_res_file(dir);
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(&file)
+        .ok()?;
+    let r: (f64, f64, f64) = reader.deserialize().next()?.expect("unexpected data format");
+    Some(Resource {
+        encode_cpu: r.0,
+        detect_cpu: r.1,
+        power: r.2,
+    })
+}
@@ -0,0 +1,255 @@
+//! A binary index of per-frame stats (`stat.csv`), so that looking up a frame
+//! is a binary search instead of a scan of every record.
+//!
+//! All numbers are little-endian `u32`. The file starts with a header (magic,
+//! version, number of configurations), followed by the offset table, one
+//! entry per configuration sorted by (width, skip, quant): the configuration,
+//! its first record and its number of records. The records follow, sorted by
+//! configuration then frame: frame number, true positive, false positive and
+//! false negative.
+//!
+//! Index files are mapped into memory rather than read, so that the server's
+//! connections (and processes on one machine) share the page cache instead of
+//! each holding a copy.
+
+use super::{FrameStat, Stat, VideoConfig};
+use memmap::Mmap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The first bytes of an index file.
+pub const STAT_INDEX_MAGIC: &[u8; 4] = b"AWSI";
+
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 12;
+const CONFIG_LEN: usize = 20;
+const RECORD_LEN: usize = 16;
+
+/// The bytes of an index, in memory or mapped from its file.
+#[derive(Debug, Clone)]
+enum Bytes {
+    Owned(Vec<u8>),
+    Mapped(Arc<Mmap>),
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Bytes::Owned(ref data) => data,
+            Bytes::Mapped(ref map) => map,
+        }
+    }
+}
+
+/// Per-frame stats, looked up by frame and configuration. Clones share the
+/// same bytes if mapped.
+#[derive(Debug, Clone)]
+pub struct StatIndex {
+    data: Bytes,
+    configs: usize,
+}
+
+impl PartialEq for StatIndex {
+    fn eq(&self, other: &StatIndex) -> bool {
+        self.data[..] == other.data[..]
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn push_u32(data: &mut Vec<u8>, v: usize) {
+    data.extend_from_slice(&(v as u32).to_le_bytes());
+}
+
+fn key(c: &VideoConfig) -> (usize, usize, usize) {
+    (c.width, c.skip, c.quant)
+}
+
+/// Binary search over `n` entries sorted by `at`.
+fn search<K: Ord, F: Fn(usize) -> K>(n: usize, at: F, target: &K) -> Option<usize> {
+    let (mut lo, mut hi) = (0, n);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match at(mid).cmp(target) {
+            ::std::cmp::Ordering::Less => lo = mid + 1,
+            ::std::cmp::Ordering::Greater => hi = mid,
+            ::std::cmp::Ordering::Equal => return Some(mid),
+        }
+    }
+    None
+}
+
+impl Default for StatIndex {
+    fn default() -> StatIndex {
+        StatIndex::build(&[])
+    }
+}
+
+impl StatIndex {
+    /// Builds the index of `stats`.
+    pub fn build(stats: &[FrameStat]) -> StatIndex {
+        let mut sorted = stats.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|s| (key(&s.config), s.frame_num));
+        sorted.dedup_by_key(|s| (key(&s.config), s.frame_num));
+
+        // (config, first, count) per configuration
+        let mut table: Vec<(VideoConfig, usize, usize)> = Vec::new();
+        for (i, s) in sorted.iter().enumerate() {
+            match table.last_mut() {
+                Some(entry) if entry.0 == s.config => entry.2 += 1,
+                _ => table.push((s.config, i, 1)),
+            }
+        }
+
+        let len = HEADER_LEN + table.len() * CONFIG_LEN + sorted.len() * RECORD_LEN;
+        let mut data = Vec::with_capacity(len);
+        data.extend_from_slice(STAT_INDEX_MAGIC);
+        push_u32(&mut data, VERSION as usize);
+        push_u32(&mut data, table.len());
+        for &(config, first, count) in &table {
+            push_u32(&mut data, config.width);
+            push_u32(&mut data, config.skip);
+            push_u32(&mut data, config.quant);
+            push_u32(&mut data, first);
+            push_u32(&mut data, count);
+        }
+        for s in sorted {
+            push_u32(&mut data, s.frame_num);
+            push_u32(&mut data, s.stat.true_positive);
+            push_u32(&mut data, s.stat.false_positive);
+            push_u32(&mut data, s.stat.false_negative);
+        }
+        StatIndex {
+            data: Bytes::Owned(data),
+            configs: table.len(),
+        }
+    }
+
+    /// Checks and wraps the bytes of an index file.
+    pub fn from_bytes(data: Vec<u8>) -> io::Result<StatIndex> {
+        StatIndex::from_data(Bytes::Owned(data))
+    }
+
+    fn from_data(data: Bytes) -> io::Result<StatIndex> {
+        if data.len() < HEADER_LEN || &data[..4] != STAT_INDEX_MAGIC {
+            return Err(invalid("not a stat index"));
+        }
+        let mut index = StatIndex { data, configs: 0 };
+        if index.u32_at(4) != VERSION as usize {
+            return Err(invalid("unsupported stat index version"));
+        }
+        index.configs = index.u32_at(8);
+        let table_end = HEADER_LEN + index.configs * CONFIG_LEN;
+        if index.data.len() < table_end || (index.data.len() - table_end) % RECORD_LEN != 0 {
+            return Err(invalid("truncated stat index"));
+        }
+        let records = (index.data.len() - table_end) / RECORD_LEN;
+        for i in 0..index.configs {
+            let (_, first, count) = index.config_at(i);
+            if first + count > records {
+                return Err(invalid("stat index points past its records"));
+            }
+        }
+        Ok(index)
+    }
+
+    /// Loads an index file into memory.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<StatIndex> {
+        StatIndex::from_bytes(fs::read(path)?)
+    }
+
+    /// Maps an index file into memory. The file must not change while mapped,
+    /// which holds for indexes as they are written once.
+    pub fn map<P: AsRef<Path>>(path: P) -> io::Result<StatIndex> {
+        let file = File::open(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+        StatIndex::from_data(Bytes::Mapped(Arc::new(map)))
+    }
+
+    /// Loads per-frame stats from an index file (mapped) or, if `path` is not
+    /// one, from a `stat.csv` file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<StatIndex> {
+        let mut magic = [0; 4];
+        let is_index = match File::open(&path)?.read_exact(&mut magic) {
+            Ok(()) => &magic == STAT_INDEX_MAGIC,
+            Err(_) => false,
+        };
+        if is_index {
+            StatIndex::map(path)
+        } else {
+            Ok(StatIndex::build(&FrameStat::from_csv(path)))
+        }
+    }
+
+    /// Writes the index into a file.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, &self.data[..])
+    }
+
+    /// Returns the stat of frame `frame_num` at `config`.
+    pub fn get(&self, frame_num: usize, config: VideoConfig) -> Option<Stat> {
+        let i = search(self.configs, |i| key(&self.config_at(i).0), &key(&config))?;
+        let (_, first, count) = self.config_at(i);
+        let j = search(count, |j| self.u32_at(self.record_pos(first + j)), &frame_num)?;
+        let pos = self.record_pos(first + j);
+        Some(Stat {
+            true_positive: self.u32_at(pos + 4),
+            false_positive: self.u32_at(pos + 8),
+            false_negative: self.u32_at(pos + 12),
+        })
+    }
+
+    /// Returns the configurations with stats.
+    pub fn configs(&self) -> Vec<VideoConfig> {
+        (0..self.configs).map(|i| self.config_at(i).0).collect()
+    }
+
+    /// Returns the number of frames covered, the largest frame number of any
+    /// configuration as frames are numbered from 1.
+    pub fn frames(&self) -> usize {
+        (0..self.configs)
+            .filter_map(|i| {
+                let (_, first, count) = self.config_at(i);
+                if count == 0 {
+                    None
+                } else {
+                    Some(self.u32_at(self.record_pos(first + count - 1)))
+                }
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the number of records.
+    pub fn len(&self) -> usize {
+        (self.data.len() - HEADER_LEN - self.configs * CONFIG_LEN) / RECORD_LEN
+    }
+
+    /// Returns true if there are no records.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn u32_at(&self, pos: usize) -> usize {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.data[pos..pos + 4]);
+        u32::from_le_bytes(bytes) as usize
+    }
+
+    fn config_at(&self, i: usize) -> (VideoConfig, usize, usize) {
+        let pos = HEADER_LEN + i * CONFIG_LEN;
+        let config = VideoConfig::new(self.u32_at(pos), self.u32_at(pos + 4), self.u32_at(pos + 8));
+        (config, self.u32_at(pos + 12), self.u32_at(pos + 16))
+    }
+
+    fn record_pos(&self, record: usize) -> usize {
+        HEADER_LEN + self.configs * CONFIG_LEN + record * RECORD_LEN
+    }
+}
@@ -0,0 +1,37 @@
+//! Helpers shared by the tests of this crate and, with the `testing` feature,
+//! of the crates that depend on it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A directory of a test's own for the files it writes, removed when dropped.
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Creates an empty directory for the test `name`. The name and the id of
+    /// this process keep tests (and concurrent runs) from sharing files.
+    pub fn new(name: &str) -> ScratchDir {
+        let path = ::std::env::temp_dir().join(format!("{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("failed to create scratch directory");
+        ScratchDir { path }
+    }
+
+    /// Returns the path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the path of `file` in the directory.
+    pub fn join<P: AsRef<Path>>(&self, file: P) -> PathBuf {
+        self.path.join(file)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,99 @@
+//! Cross-dataset profile transfer. A profile is built offline on one dataset
+//! (A) and used on another (B); how well that works tells how portable
+//! offline profiles are. For every level of A's profile we look at what the
+//! configuration achieves on B and compare it with the best configuration on
+//! B's own Pareto front within the same bandwidth. The difference in accuracy
+//! is the regret of using A's profile.
+
+use csv;
+use helper;
+use profile::{mean_of_configurations, pareto};
+
+/// One level of A's profile, evaluated on B.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transfer {
+    /// Index of the configuration.
+    pub index: usize,
+
+    /// (bandwidth, accuracy) measured on A.
+    pub a: (f64, f64),
+
+    /// (bandwidth, accuracy) measured on B.
+    pub b: (f64, f64),
+
+    /// The best accuracy on B at the same or lower bandwidth.
+    pub best: f64,
+}
+
+impl Transfer {
+    /// Accuracy lost by using A's choice instead of B's own best.
+    pub fn regret(&self) -> f64 {
+        self.best - self.b.1
+    }
+}
+
+/// Evaluates the Pareto front of `a` on `b`; both are (bandwidth, accuracy) of
+/// the same configurations. Levels are sorted by bandwidth on A.
+pub fn transfer(a: &[(f64, f64)], b: &[(f64, f64)]) -> Vec<Transfer> {
+    let front = pareto(&a.to_vec());
+    let mut levels = front
+        .into_iter()
+        .map(|index| {
+            let best = b.iter()
+                .filter(|m| m.0 <= b[index].0)
+                .map(|m| m.1)
+                .fold(b[index].1, f64::max);
+            Transfer {
+                index,
+                a: a[index],
+                b: b[index],
+                best,
+            }
+        })
+        .collect::<Vec<_>>();
+    levels.sort_by(|x, y| x.a.0.partial_cmp(&y.a.0).unwrap());
+    levels
+}
+
+/// Builds the profile on `dir_a`, evaluates it on `dir_b` (both summary data)
+/// and writes `transfer.csv` to `outdir`. Returns the mean regret.
+pub fn summarize_transfer(dir_a: &str, dir_b: &str, outdir: &str) -> f64 {
+    let configurations = helper::all_configurations();
+    let a = mean_of_configurations(dir_a, &configurations);
+    let b = mean_of_configurations(dir_b, &configurations);
+    let levels = transfer(&a, &b);
+
+    let ofile = format!("{}/transfer.csv", outdir);
+    let mut writer = csv::Writer::from_path(&ofile).expect("failed to open transfer.csv");
+    let header = (
+        "bandwidth",
+        "width",
+        "skip",
+        "quant",
+        "accuracy",
+        "b_bandwidth",
+        "b_accuracy",
+        "b_best_accuracy",
+        "regret",
+    );
+    writer.serialize(header).expect("failed to write header");
+    for t in &levels {
+        let vc = configurations[t.index];
+        let entry = (
+            t.a.0 * 1_000.0,
+            vc.width,
+            vc.skip,
+            vc.quant,
+            t.a.1,
+            t.b.0 * 1_000.0,
+            t.b.1,
+            t.best,
+            t.regret(),
+        );
+        writer.serialize(entry).expect("failed to write to csv");
+    }
+
+    let regret = levels.iter().map(Transfer::regret).sum::<f64>() / levels.len() as f64;
+    info!("mean regret of {} on {}: {:.4}", dir_a, dir_b, regret);
+    regret
+}
@@ -0,0 +1,126 @@
+//! Frame numbers past the end of a dataset. Runs often outlast the dataset
+//! they stream (the source loops, or the log comes from a longer recording),
+//! while per-frame stats only cover the dataset's frames. A `FrameRange` knows
+//! how long the dataset is, from its manifest or from the stats, and maps
+//! every frame number of a run into it: wrapping around to the first frame,
+//! or truncating the run at the last one. Frames are numbered from 1, as in
+//! the manifest.
+
+use super::Manifest;
+use std::fmt;
+use std::str::FromStr;
+
+/// What becomes of frames past the end of the dataset.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Wraparound {
+    /// Frame `len + i` is frame `i` again, as when the source loops.
+    Wrap,
+
+    /// Frames past the end are left out.
+    Truncate,
+}
+
+impl Default for Wraparound {
+    fn default() -> Wraparound {
+        Wraparound::Wrap
+    }
+}
+
+impl FromStr for Wraparound {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Wraparound, String> {
+        match s {
+            "wrap" => Ok(Wraparound::Wrap),
+            "truncate" => Ok(Wraparound::Truncate),
+            _ => Err(format!("unknown wraparound policy `{}` (wrap or truncate)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Wraparound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Wraparound::Wrap => write!(f, "wrap"),
+            Wraparound::Truncate => write!(f, "truncate"),
+        }
+    }
+}
+
+/// The frames of a dataset, `1..=len`, and what becomes of those past it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FrameRange {
+    len: usize,
+    policy: Wraparound,
+}
+
+impl FrameRange {
+    /// A dataset of `len` frames, which can't be empty.
+    pub fn new(len: usize, policy: Wraparound) -> Result<FrameRange, String> {
+        if len == 0 {
+            return Err("a dataset has at least one frame".to_string());
+        }
+        Ok(FrameRange { len, policy })
+    }
+
+    /// The dataset `manifest` describes.
+    pub fn from_manifest(manifest: &Manifest, policy: Wraparound) -> Result<FrameRange, String> {
+        FrameRange::new(manifest.len(), policy)
+    }
+
+    /// The number of frames in the dataset.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the dataset has no frames (never, see `new`).
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// What becomes of frames past the end.
+    pub fn policy(&self) -> Wraparound {
+        self.policy
+    }
+
+    /// Returns the frame of the dataset that `frame_num` stands for, or `None`
+    /// if it's past the end and truncated, or 0, which no frame is.
+    pub fn map(&self, frame_num: usize) -> Option<usize> {
+        if frame_num == 0 {
+            return None;
+        }
+        if frame_num <= self.len {
+            return Some(frame_num);
+        }
+        match self.policy {
+            Wraparound::Wrap => Some((frame_num - 1) % self.len + 1),
+            Wraparound::Truncate => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_past_the_end() {
+        let wrap = FrameRange::new(1800, Wraparound::Wrap).unwrap();
+        assert_eq!(wrap.map(0), None);
+        assert_eq!(wrap.map(1), Some(1));
+        assert_eq!(wrap.map(1800), Some(1800));
+        assert_eq!(wrap.map(1801), Some(1));
+        assert_eq!(wrap.map(3600), Some(1800));
+        assert_eq!(wrap.map(3602), Some(2));
+
+        let truncate = FrameRange::new(1800, Wraparound::Truncate).unwrap();
+        assert_eq!(truncate.map(1800), Some(1800));
+        assert_eq!(truncate.map(1801), None);
+        assert!(FrameRange::new(0, Wraparound::Wrap).is_err());
+
+        assert_eq!("truncate".parse::<Wraparound>(), Ok(Wraparound::Truncate));
+        assert!("loop".parse::<Wraparound>().is_err());
+        assert_eq!(Wraparound::default().to_string(), "wrap");
+    }
+}
@@ -0,0 +1,149 @@
+//! Encodes the same frames at two configurations and compares them frame by
+//! frame: the size ratio of the encoded frames (B over A) and the PSNR/SSIM
+//! between the decoded outputs. Useful to check whether quantizer steps behave
+//! as expected on a particular footage.
+//!
+//! ```text
+//! INPUT=<frames> EXT=jpg A=1280x0x20 B=1280x0x30 [FRAMES=300] [OUTPUT_DIR=.] \
+//!     cargo run --bin compare
+//! ```
+//!
+//! Configurations are given as `WIDTHxSKIPxQUANTIZER`. Every frame is encoded
+//! (skip only affects the caps), so that outputs align frame by frame. The
+//! encoded streams are kept in `OUTPUT_DIR` as `a.h264` and `b.h264`.
+
+extern crate cv;
+extern crate video_analytics;
+
+use cv::imgcodecs::ImreadModes::ImreadColor;
+use cv::imgproc::InterpolationFlag;
+use cv::videoio::VideoCapture;
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use video_analytics::loader::{FrameEncoder, VideoConfig};
+use video_analytics::quality::{self, mat_bytes};
+
+fn parse_config(s: &str) -> VideoConfig {
+    let errmsg = format!("invalid configuration {}, use WIDTHxSKIPxQUANTIZER", s);
+    let v = s.split('x')
+        .map(|i| i.parse::<usize>().expect(&errmsg))
+        .collect::<Vec<_>>();
+    if v.len() != 3 {
+        panic!("{}", errmsg);
+    }
+    VideoConfig {
+        width: v[0],
+        height: v[0] / 16 * 9,
+        skip: v[1],
+        quantizer: v[2],
+    }
+}
+
+fn main() {
+    let path = env::var("INPUT").expect("please specify the path for input images");
+    let ext = env::var("EXT").expect("please specify the extension for input images");
+    let a = parse_config(&env::var("A").expect("please specify configuration A"));
+    let b = parse_config(&env::var("B").expect("please specify configuration B"));
+    let frames = env::var("FRAMES")
+        .unwrap_or("300".to_string())
+        .parse::<usize>()
+        .expect("invalid FRAMES via environment variable");
+    let outdir = env::var("OUTPUT_DIR").unwrap_or(".".to_string());
+
+    // 1. Encode every frame with both configurations.
+    let mut encoder_a = FrameEncoder::new(a, None).expect("failed to create encoder A");
+    let mut encoder_b = FrameEncoder::new(b, None).expect("failed to create encoder B");
+    let file_a = format!("{}/a.h264", outdir);
+    let file_b = format!("{}/b.h264", outdir);
+    let mut sink_a = File::create(&file_a).expect("failed to create output A");
+    let mut sink_b = File::create(&file_b).expect("failed to create output B");
+
+    let mut sizes = Vec::new();
+    for i in 1..(frames + 1) {
+        let filename = format!("{}/{:06}.{}", path, i, ext);
+        let frame = match cv::Mat::from_path(&filename, ImreadColor) {
+            Ok(frame) => frame,
+            Err(_) => break,
+        };
+        let encoded_a = encoder_a.encode(&frame).expect("failed to encode A");
+        let encoded_b = encoder_b.encode(&frame).expect("failed to encode B");
+        sink_a.write_all(&encoded_a).expect("failed to write output A");
+        sink_b.write_all(&encoded_b).expect("failed to write output B");
+        sizes.push((encoded_a.len(), encoded_b.len()));
+    }
+    drop(encoder_a);
+    drop(encoder_b);
+
+    // 2. Decode both streams and compare them frame by frame.
+    let cap_a = VideoCapture::from_path(&file_a);
+    let cap_b = VideoCapture::from_path(&file_b);
+    println!("frame,size_a,size_b,size_ratio,psnr,ssim");
+    let (mut psnr_sum, mut ssim_sum, mut n, mut finite) = (0.0, 0.0, 0, 0);
+    for (i, &(size_a, size_b)) in sizes.iter().enumerate() {
+        let (decoded_a, decoded_b) = match (cap_a.read(), cap_b.read()) {
+            (Some(x), Some(y)) => (x, y),
+            _ => {
+                eprintln!("failed to decode frame {}, stop", i + 1);
+                break;
+            }
+        };
+
+        // Compare at the resolution of A.
+        let decoded_b = if (decoded_b.cols, decoded_b.rows) != (decoded_a.cols, decoded_a.rows) {
+            decoded_b.resize_to(cv::Size2i::new(decoded_a.cols, decoded_a.rows),
+                                InterpolationFlag::InterLinear)
+        } else {
+            decoded_b
+        };
+
+        let (x, y) = (mat_bytes(&decoded_a), mat_bytes(&decoded_b));
+        let psnr = quality::psnr(x, y);
+        let ssim = quality::ssim(x,
+                                 y,
+                                 decoded_a.cols as usize,
+                                 decoded_a.rows as usize,
+                                 decoded_a.channels as usize);
+        println!("{},{},{},{:.4},{:.3},{:.4}",
+                 i + 1,
+                 size_a,
+                 size_b,
+                 size_b as f64 / size_a as f64,
+                 psnr,
+                 ssim);
+        if psnr.is_finite() {
+            psnr_sum += psnr;
+            finite += 1;
+        }
+        ssim_sum += ssim;
+        n += 1;
+    }
+
+    let total_a = sizes.iter().map(|s| s.0).sum::<usize>();
+    let total_b = sizes.iter().map(|s| s.1).sum::<usize>();
+    eprintln!("frames: {}, size ratio: {:.4}, mean psnr: {:.3} dB (over {} frames that \
+               differ), mean ssim: {:.4}",
+              n,
+              total_b as f64 / total_a as f64,
+              psnr_sum / finite as f64,
+              finite,
+              ssim_sum / n as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configurations_are_width_skip_quantizer() {
+        let config = parse_config("1280x2x20");
+        assert_eq!((config.width, config.height), (1280, 720));
+        assert_eq!((config.skip, config.quantizer), (2, 20));
+    }
+
+    #[test]
+    #[should_panic(expected = "use WIDTHxSKIPxQUANTIZER")]
+    fn configurations_need_three_parts() {
+        parse_config("1280x20");
+    }
+}
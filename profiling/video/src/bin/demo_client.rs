@@ -0,0 +1,193 @@
+//! The client of the end-to-end demo: captures from a camera, encodes with the
+//! loader's x264 pipeline at the level the runtime picks, and streams the
+//! encoded frames to the server of the runtime setting (`Setting.toml` of the
+//! runtime crate, or `EXPERIMENT=<name>` of it). Run it against
+//! `demo-server`, from `runtime/` as the paths of the setting are relative to
+//! it:
+//!
+//! ```text
+//! [CAMERA=0] [PIPELINE=<template>] [SKIP_MOVE=1] \
+//!     cargo run --manifest-path ../profiling/video/Cargo.toml --bin demo-client
+//! ```
+//!
+//! The levels are those of the setting's profile; `source_path` and
+//! `stat_path` are not used. Moves of up to `SKIP_MOVE` levels (default
+//! `awstream::SKIP_TIER_MOVE`) only change how many captured frames are
+//! dropped, when a skip makes the level, and leave the encoder alone; larger
+//! ones reconfigure it (see `awstream::TwoTier`).
+
+extern crate awstream;
+extern crate env_logger;
+extern crate video_analytics;
+
+use awstream::{Adapt, Experiment, Profile, SKIP_TIER_MOVE, Setting, SimpleProfile, Subscribers,
+               TwoTier, VideoConfig, client};
+use std::env;
+use std::mem;
+use std::process;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use video_analytics::loader::{self, DEFAULT_DEPTH, LoaderConfig, LoaderHandle};
+
+/// Frames per second the loader captures, before it skips any.
+const CAMERA_FPS: f64 = 30.0;
+
+/// Frames from the camera, encoded at the current level.
+struct CameraSource {
+    profile: Profile<VideoConfig>,
+    encoded: Receiver<Vec<u8>>,
+    loader: LoaderHandle,
+
+    /// Picks between dropping frames and reconfiguring the encoder.
+    tiers: TwoTier,
+
+    /// Most levels a move may span and still only change the skip.
+    skip_move: usize,
+
+    /// The configuration the encoder runs, whose skip sets the frame period.
+    config: VideoConfig,
+
+    /// Number of frames encoded so far.
+    frame_num: usize,
+
+    /// The frame `next_datum` returned last, until it's sent.
+    pending: Vec<u8>,
+}
+
+/// The loader's configuration of a level (16:9 frames).
+fn loader_config(config: VideoConfig) -> loader::VideoConfig {
+    loader::VideoConfig {
+        width: config.width,
+        height: config.width / 16 * 9,
+        skip: config.skip,
+        quantizer: config.quant,
+    }
+}
+
+impl CameraSource {
+    fn open(device: i32, profile: Profile<VideoConfig>, skip_move: usize) -> CameraSource {
+        let lc = LoaderConfig {
+            path: String::new(),
+            ext: String::new(),
+            circular: false,
+            on_error: Some(Box::new(|msg: &str| eprintln!("encoder error: {}", msg))),
+            depth: DEFAULT_DEPTH,
+            pipeline: env::var("PIPELINE").ok(),
+            manifest: None,
+            motion: None,
+            camera: Some(device),
+        };
+        let config = profile.n_th(profile.current_level());
+        let (encoded, loader) =
+            loader::load_x264(lc, loader_config(config)).expect("failed to start the encoder");
+        let tiers = TwoTier::new(profile.current_level(), skip_move);
+        CameraSource {
+            profile,
+            encoded,
+            loader,
+            tiers,
+            skip_move,
+            config,
+            frame_num: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Has the loader follow the current level, by the skip alone if it can.
+    fn follow_level(&mut self) {
+        self.config = self.tiers.plan(&self.profile, self.profile.current_level());
+        if self.loader.send(loader_config(self.config)).is_err() {
+            eprintln!("encoder has stopped");
+        }
+    }
+}
+
+impl Adapt for CameraSource {
+    fn adapt(&mut self, bw: f64) {
+        if self.profile.adjust_config(bw).is_some() {
+            self.follow_level();
+        }
+    }
+
+    fn dec_degradation(&mut self) {
+        if self.profile.advance_config().is_some() {
+            self.follow_level();
+        }
+    }
+
+    fn set_level(&mut self, level: usize) {
+        self.profile.set_level(level);
+        self.follow_level();
+    }
+
+    /// The period of the frames the encoder puts out, so that probes paced
+    /// per tick go out at the rate they are sized for.
+    fn period_in_ms(&self) -> u64 {
+        let period = 1000.0 * (self.config.skip + 1) as f64 / CAMERA_FPS;
+        ::std::cmp::max(period.round() as u64, 1)
+    }
+
+    fn current_level(&self) -> usize {
+        self.profile.current_level()
+    }
+
+    fn simple_profile(&self) -> SimpleProfile {
+        self.profile.simplify()
+    }
+
+    fn reload(&mut self, mut profile: Profile<VideoConfig>, level: usize) {
+        profile.set_selection(self.profile.simplify().selection());
+        self.profile = profile;
+        // The levels are new: reconfigure whatever the move.
+        self.tiers = TwoTier::new(level, self.skip_move);
+        self.set_level(level);
+    }
+
+    fn configs(&self) -> Vec<VideoConfig> {
+        self.profile.records().iter().map(|r| r.config).collect()
+    }
+}
+
+impl Experiment for CameraSource {
+    /// Returns the next encoded frame, if any: a size of 0 means none yet.
+    fn next_datum(&mut self) -> Option<(usize, usize)> {
+        match self.encoded.try_recv() {
+            Ok(frame) => {
+                self.frame_num += 1;
+                self.pending = frame;
+                Some((self.pending.len(), self.frame_num))
+            }
+            Err(TryRecvError::Empty) => Some((0, self.frame_num)),
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    fn payload(&mut self, _index: usize, _size: usize) -> Vec<u8> {
+        mem::replace(&mut self.pending, Vec::new())
+    }
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let setting = Setting::init("Setting.toml").expect("failed to load Setting.toml");
+    let setting = match env::var("EXPERIMENT") {
+        Ok(name) => setting.experiment(&name).unwrap_or_else(|| {
+            eprintln!("no experiment {} in Setting.toml", name);
+            process::exit(1);
+        }),
+        Err(_) => setting,
+    };
+
+    let device = env::var("CAMERA")
+        .unwrap_or("0".to_string())
+        .parse::<i32>()
+        .expect("invalid CAMERA via environment variable");
+    let skip_move = env::var("SKIP_MOVE")
+        .map(|m| m.parse::<usize>().expect("invalid SKIP_MOVE via environment variable"))
+        .unwrap_or(SKIP_TIER_MOVE);
+    let mut profile = Profile::new(&setting.profile_path);
+    profile.set_selection(setting.level_selection);
+    let source = CameraSource::open(device, profile, skip_move);
+
+    client::run_with_source(setting, Subscribers::default(), source).unwrap();
+}
@@ -0,0 +1,99 @@
+//! The server of the end-to-end demo: runs the runtime server of the setting
+//! (`Setting.toml` of the runtime crate), decodes the frames every client
+//! streams (see `demo-client`) and runs the darknet detector on them. Every
+//! detection is printed as it is in the profiling (`darknet` of `main`),
+//! prefixed by the client and the level of the frame. From `runtime/`:
+//!
+//! ```text
+//! cargo run --manifest-path ../profiling/video/Cargo.toml --bin demo-server
+//! ```
+
+extern crate awstream;
+extern crate darknet;
+extern crate env_logger;
+extern crate futures;
+extern crate video_analytics;
+
+use awstream::{LiveFrame, Profile, Setting, VideoConfig, server};
+use darknet::Darknet;
+use futures::Stream;
+use futures::sync::mpsc::unbounded;
+use std::collections::HashMap;
+use std::thread;
+use video_analytics::decoder::FrameDecoder;
+
+/// Converts a BGR frame to darknet's planar RGB in [0, 1].
+fn bgr_to_darknet_image(data: &[u8], w: i32, h: i32) -> darknet::InputImage {
+    let c = 3;
+    let mut out = darknet::InputImage::new(w, h, c);
+    let out_data = out.data_mut();
+    let mut count = 0;
+    for k in 0..c {
+        for y in 0..h {
+            for x in 0..w {
+                // BGR in, RGB out.
+                let offset = (c * (w * y + x) + (c - 1 - k)) as usize;
+                unsafe {
+                    *out_data.offset(count) = data[offset] as f32 / 255.0;
+                }
+                count += 1;
+            }
+        }
+    }
+    out
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let setting = Setting::init("Setting.toml").expect("failed to load Setting.toml");
+
+    // Frames are decoded at the width of the highest level, 16:9.
+    let profile: Profile<VideoConfig> = Profile::new(&setting.profile_path);
+    let width = profile.records().last().expect("no configuration in profile").config.width;
+    let height = width / 16 * 9;
+
+    let (frames_tx, frames_rx) = unbounded::<(_, LiveFrame)>();
+    thread::spawn(move || {
+        let mut dn = Darknet::new(concat!(env!("CARGO_MANIFEST_DIR"), "/darknet-data/coco.data"),
+                                  concat!(env!("CARGO_MANIFEST_DIR"), "/darknet-data/yolo.cfg"),
+                                  concat!(env!("CARGO_MANIFEST_DIR"), "/darknet-data/yolo.weights"),
+                                  concat!(env!("CARGO_MANIFEST_DIR"), "/darknet-data/coco.names"));
+        let mut decoders = HashMap::new();
+        for (client, frame) in frames_rx.wait().filter_map(|frame| frame.ok()) {
+            // Data probes at other levels (see the runtime's `bbr` module)
+            // carry no encoded frame, only zeros, which would derail the
+            // decoder.
+            if frame.data.iter().all(|&b| b == 0) {
+                continue;
+            }
+            if !decoders.contains_key(&client) {
+                let decoder =
+                    FrameDecoder::new(width, height).expect("failed to start the decoder");
+                decoders.insert(client, decoder);
+            }
+            let decoded = match decoders.get_mut(&client).unwrap().decode(&frame.data) {
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("{}: failed to decode frame {}: {}", client, frame.frame_num, e);
+                    decoders.remove(&client);
+                    continue;
+                }
+            };
+            let image = bgr_to_darknet_image(&decoded, width as i32, height as i32);
+            let detections = dn.detect(image);
+            for i in 0..detections.num {
+                let ref d = detections.detections[i];
+                println!("{}, {:06}, {}, {:.02}, {}",
+                         client,
+                         frame.frame_num,
+                         frame.level,
+                         detections.proc_time_in_ms,
+                         d.csv());
+            }
+        }
+    });
+
+    server::server_with_hooks(setting, Vec::new(), vec![frames_tx]);
+}
@@ -0,0 +1,79 @@
+//! Generates or checks the manifest of a profiling dataset (see
+//! `evaluation::Manifest`).
+//!
+//! ```text
+//! INPUT=<frames> EXT=jpg [FPS=30] [MANIFEST=<INPUT>/manifest.json] \
+//!     cargo run --bin manifest -- generate
+//! INPUT=<frames> [MANIFEST=<INPUT>/manifest.json] cargo run --bin manifest -- check
+//! ```
+//!
+//! `check` decodes every frame to also verify its resolution, and exits with 1
+//! if the dataset does not match the manifest.
+
+extern crate cv;
+extern crate evaluation;
+
+use cv::imgcodecs::ImreadModes::ImreadColor;
+use evaluation::{MANIFEST_FILE, Manifest};
+use std::env;
+use std::path::Path;
+
+fn resolution(path: &Path) -> Option<(usize, usize)> {
+    cv::Mat::from_path(path, ImreadColor)
+        .ok()
+        .map(|mat| (mat.cols as usize, mat.rows as usize))
+}
+
+fn main() {
+    let path = env::var("INPUT").expect("please specify the path for input images");
+    let manifest_path = env::var("MANIFEST").unwrap_or(format!("{}/{}", path, MANIFEST_FILE));
+
+    match env::args().nth(1).as_ref().map(|s| s.as_str()) {
+        Some("generate") => {
+            let ext = env::var("EXT").expect("please specify the extension for input images");
+            let fps = env::var("FPS")
+                .unwrap_or("30".to_string())
+                .parse::<f64>()
+                .expect("invalid FPS via environment variable");
+            let manifest = Manifest::generate(&path, &ext, fps, resolution)
+                .expect("failed to generate manifest");
+            manifest.save(&manifest_path).expect("failed to save manifest");
+            println!("{} frames written to {}", manifest.len(), manifest_path);
+            for problem in manifest.problems() {
+                eprintln!("warning: {}", problem);
+            }
+        }
+        Some("check") => {
+            let manifest = Manifest::load(&manifest_path).expect("failed to load manifest");
+            let mut problems = manifest.verify(&path);
+            for entry in &manifest.frames {
+                let file = Path::new(&path).join(manifest.file_name(entry.frame_num));
+                match resolution(&file) {
+                    Some(r) if r == (entry.width, entry.height) => {}
+                    Some((w, h)) => {
+                        problems.push(format!("{} is {}x{}, expected {}x{}",
+                                              file.display(),
+                                              w,
+                                              h,
+                                              entry.width,
+                                              entry.height))
+                    }
+                    None => problems.push(format!("failed to decode {}", file.display())),
+                }
+            }
+
+            if problems.is_empty() {
+                println!("{} frames ok", manifest.len());
+            } else {
+                for problem in &problems {
+                    eprintln!("{}", problem);
+                }
+                ::std::process::exit(1);
+            }
+        }
+        _ => {
+            eprintln!("usage: manifest generate|check");
+            ::std::process::exit(2);
+        }
+    }
+}
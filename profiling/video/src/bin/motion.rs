@@ -0,0 +1,36 @@
+//! Measures the motion of every frame of a dataset relative to the previous
+//! frame, so that the runtime can replay motion-triggered frame skipping.
+//!
+//! ```text
+//! INPUT=<frames> EXT=jpg [OUTPUT=<INPUT>/motion.csv] cargo run --bin motion
+//! ```
+//!
+//! Writes `frame_num, motion` entries, starting from frame 2.
+
+extern crate cv;
+extern crate video_analytics;
+
+use cv::imgcodecs::ImreadModes::ImreadColor;
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use video_analytics::motion::MotionDetector;
+
+fn main() {
+    let path = env::var("INPUT").expect("please specify the path for input images");
+    let ext = env::var("EXT").expect("please specify the extension for input images");
+    let output = env::var("OUTPUT").unwrap_or(format!("{}/motion.csv", path));
+
+    let mut f = File::create(&output).expect("failed to create output");
+    let mut detector = MotionDetector::new();
+    let mut frame_num = 1;
+    while let Ok(frame) = cv::Mat::from_path(&format!("{}/{:06}.{}", path, frame_num, ext),
+                                             ImreadColor) {
+        let motion = detector.measure(&frame);
+        if frame_num > 1 {
+            writeln!(f, "{}, {:.3}", frame_num, motion).expect("failed to write motion");
+        }
+        frame_num += 1;
+    }
+    println!("{} frames written to {}", frame_num - 1, output);
+}
@@ -0,0 +1,91 @@
+//! Measures the objective quality (PSNR/SSIM) of a configuration: every source
+//! frame is compared with what the receiver would show, i.e. the latest
+//! decoded frame, upscaled to the source resolution. Skipped frames are
+//! therefore compared with the last frame that was sent.
+//!
+//! ```text
+//! INPUT=<frames> EXT=jpg WIDTH=1280 SKIP=0 Q=20 [FRAMES=300] [OUTPUT_DIR=.] \
+//!     cargo run --bin quality
+//! ```
+//!
+//! Writes `quality-WIDTHxSKIPxQ.csv` with `frame_num, psnr, ssim` entries,
+//! which the evaluation crate aggregates with `aggregate_quality`.
+
+extern crate cv;
+extern crate video_analytics;
+
+use cv::imgcodecs::ImreadModes::ImreadColor;
+use cv::imgproc::InterpolationFlag;
+use cv::videoio::VideoCapture;
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use video_analytics::loader::{FrameEncoder, VideoConfig};
+use video_analytics::quality::{self, mat_bytes};
+
+fn env_usize(name: &str, default: &str) -> usize {
+    env::var(name)
+        .unwrap_or(default.to_string())
+        .parse::<usize>()
+        .expect(&format!("invalid {} via environment variable", name))
+}
+
+fn main() {
+    let path = env::var("INPUT").expect("please specify the path for input images");
+    let ext = env::var("EXT").expect("please specify the extension for input images");
+    let width = env_usize("WIDTH", "1920");
+    let skip = env_usize("SKIP", "0");
+    let quantizer = env_usize("Q", "20");
+    let frames = env_usize("FRAMES", "300");
+    let outdir = env::var("OUTPUT_DIR").unwrap_or(".".to_string());
+
+    let config = VideoConfig {
+        width: width,
+        height: width / 16 * 9,
+        skip: skip,
+        quantizer: quantizer,
+    };
+    let load = |i: usize| cv::Mat::from_path(&format!("{}/{:06}.{}", path, i, ext), ImreadColor);
+
+    // 1. Encode the frames that are sent (one out of every `skip + 1`).
+    let encoded = format!("{}/quality-{}x{}x{}.h264", outdir, width, skip, quantizer);
+    let mut encoder = FrameEncoder::new(config, None).expect("failed to create encoder");
+    let mut sink = File::create(&encoded).expect("failed to create encoded output");
+    let mut total = 0;
+    for i in 1..(frames + 1) {
+        let frame = match load(i) {
+            Ok(frame) => frame,
+            Err(_) => break,
+        };
+        if (i - 1) % (skip + 1) == 0 {
+            let data = encoder.encode(&frame).expect("failed to encode");
+            sink.write_all(&data).expect("failed to write encoded output");
+        }
+        total = i;
+    }
+    drop(encoder);
+
+    // 2. Compare every source frame with the latest decoded frame.
+    let cap = VideoCapture::from_path(&encoded);
+    let outfile = format!("{}/quality-{}x{}x{}.csv", outdir, width, skip, quantizer);
+    let mut out = File::create(&outfile).expect("failed to create quality file");
+    let mut shown = None;
+    for i in 1..(total + 1) {
+        let reference = load(i).expect("failed to reload frame");
+        if (i - 1) % (skip + 1) == 0 {
+            let decoded = cap.read().expect("failed to decode frame");
+            let size = cv::Size2i::new(reference.cols, reference.rows);
+            shown = Some(decoded.resize_to(size, InterpolationFlag::InterLinear));
+        }
+        let shown = shown.as_ref().expect("the first frame is always sent");
+
+        let (x, y) = (mat_bytes(&reference), mat_bytes(shown));
+        let psnr = quality::psnr(x, y);
+        let ssim = quality::ssim(x,
+                                 y,
+                                 reference.cols as usize,
+                                 reference.rows as usize,
+                                 reference.channels as usize);
+        writeln!(out, "{}, {:.3}, {:.4}", i, psnr, ssim).expect("failed to write quality");
+    }
+}
@@ -0,0 +1,109 @@
+//! Decodes the x264 stream of the loader (see `load_x264`) back into raw
+//! frames, e.g. on the receiving end of a stream before running a detector.
+//! Frames come out scaled to a fixed size whatever the configuration they were
+//! encoded at, so that the stream may change its width on the fly.
+
+use std::sync::mpsc::{Receiver, RecvTimeoutError, sync_channel};
+use std::thread;
+use std::time::Duration;
+use std::ptr::copy;
+
+use gst::{self, AppSink, AppSrc, Buffer, Caps, MainLoop, Pipeline};
+
+use super::errors::*;
+use pipeline::teardown;
+
+/// The decoding pipeline. `{width}` and `{height}` are the size of the frames
+/// that come out (BGR).
+pub const DECODER_PIPELINE: &'static str = "appsrc name=appsrc0 ! h264parse ! avdec_h264 ! \
+                                            videoconvert ! videoscale ! \
+                                            video/x-raw,format=BGR,width={width},\
+                                            height={height} ! appsink name=appsink0";
+
+/// How long (ms) `decode` waits for the decoder to return a frame.
+const DECODE_TIMEOUT: u64 = 1000;
+
+/// `FrameDecoder` decodes encoded frames one by one.
+pub struct FrameDecoder {
+    mainloop: MainLoop,
+    pipeline: Pipeline,
+    appsrc: AppSrc,
+    frames: Receiver<Vec<u8>>,
+}
+
+impl FrameDecoder {
+    /// Creates a decoder whose frames are `width` x `height`, BGR.
+    pub fn new(width: usize, height: usize) -> Result<FrameDecoder> {
+        gst::init();
+        let mut mainloop = MainLoop::new();
+        mainloop.spawn();
+
+        let pipeline_str = DECODER_PIPELINE.replace("{width}", &width.to_string())
+            .replace("{height}", &height.to_string());
+        debug!("Decoder: creating pipeline `{}`", pipeline_str);
+        let mut pipeline = Pipeline::new_from_str(&pipeline_str)?;
+
+        let appsrc = pipeline.get_by_name("appsrc0").ok_or("pipeline has no element `appsrc0`")?;
+        let mut appsrc = AppSrc::new_from_element(appsrc);
+        let caps = Caps::from_string("video/x-h264,stream-format=byte-stream,alignment=au")
+            .expect("failed to create caps from string");
+        appsrc.set_caps(&caps);
+
+        let appsink = pipeline.get_by_name("appsink0").ok_or("pipeline has no element `appsink0`")?;
+        let appsink = AppSink::new_from_element(appsink);
+
+        let (tx, rx) = sync_channel(1);
+        thread::spawn(move || loop {
+            match appsink.recv() {
+                Ok(gst::appsink::Message::NewSample(sample)) => {
+                    let buffer = sample.buffer().expect("extracting buffer");
+                    let size = buffer.size() as usize;
+                    let mut vec = Vec::<u8>::with_capacity(size);
+                    buffer.map_read(|mapping| unsafe {
+                            vec.set_len(size);
+                            copy(mapping.data, vec.as_mut_ptr(), size);
+                        })
+                        .expect("failed to read data");
+                    if tx.send(vec).is_err() {
+                        debug!("Decoder: decoder has been dropped, quitting");
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    debug!("Decoder: appsink closed, quitting");
+                    break;
+                }
+            }
+        });
+
+        pipeline.play();
+        Ok(FrameDecoder {
+            mainloop: mainloop,
+            pipeline: pipeline,
+            appsrc: appsrc,
+            frames: rx,
+        })
+    }
+
+    /// Decodes an encoded frame. Returns `None` if the decoder holds it back,
+    /// e.g. while waiting for a keyframe after a frame was lost.
+    pub fn decode(&mut self, encoded: &[u8]) -> Result<Option<Vec<u8>>> {
+        // A frame that came out after its timeout belongs to no one now.
+        while self.frames.try_recv().is_ok() {}
+        let buffer = Buffer::new_from_vec(encoded.to_vec()).ok_or("failed to allocate a buffer")?;
+        self.appsrc.push_buffer(buffer);
+        match self.frames.recv_timeout(Duration::from_millis(DECODE_TIMEOUT)) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => bail!("decoder pipeline stopped"),
+        }
+    }
+}
+
+impl Drop for FrameDecoder {
+    fn drop(&mut self) {
+        teardown(&mut self.pipeline);
+        self.mainloop.quit();
+    }
+}
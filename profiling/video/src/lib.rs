@@ -17,7 +17,9 @@
 //! schedule_recv => frame_loader => gstreamer => x264_loader => APP
 //! ```
 //!
-//! We also support directly loading x264 encoded stream from file.
+//! We also support directly loading x264 encoded stream from file, capturing
+//! from a camera instead (`LoaderConfig::camera`), and decoding the stream
+//! back into frames (`decoder`).
 
 #[macro_use]
 extern crate error_chain;
@@ -27,9 +29,14 @@ extern crate cv;
 extern crate gst;
 extern crate schedule_recv;
 extern crate csv;
+extern crate evaluation;
 
+pub mod decoder;
 pub mod loader;
+pub mod motion;
 mod pipeline;
+pub mod quality;
+pub mod resource;
 
 mod errors {
     use gst;
@@ -50,6 +57,10 @@ mod errors {
                 description("end of stream")
                 display("end of stream")
             }
+            Manifest(t: String) {
+                description("dataset does not match the manifest")
+                display("dataset does not match the manifest: {}", t)
+            }
         }
     }
 
@@ -1,24 +1,60 @@
 use std::path::Path;
-use std::sync::mpsc::{Sender, Receiver, channel};
+use std::sync::mpsc::{Sender, SyncSender, Receiver, TrySendError, channel, sync_channel};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::ptr::copy;
 use std::io::Read;
 
 use csv;
+use evaluation::Manifest;
+use motion::{MotionConfig, MotionDetector, MotionSkip};
 use cv::imgcodecs::ImreadModes::ImreadColor;
 use cv::imgproc::InterpolationFlag;
 use cv;
 use gst;
-use pipeline::{create_caps, gst_main_loop};
+use pipeline::{create_caps, gst_main_loop, set_quantizer, teardown, GstHandle, PipelineEvent};
+pub use pipeline::{DEFAULT_PIPELINE, render_pipeline};
 use schedule_recv;
 
 use super::errors::*;
 use super::skip_to_fps;
 
+/// Called with the error message whenever the encoding pipeline fails. The
+/// pipeline is rebuilt with the last known configuration afterwards.
+pub type ErrorCallback = Box<Fn(&str) + Send>;
+
+/// A reasonable `LoaderConfig::depth`: a few frames of slack.
+pub const DEFAULT_DEPTH: usize = 8;
+
 pub struct LoaderConfig {
     pub path: String,
     pub ext: String,
     pub circular: bool,
+    pub on_error: Option<ErrorCallback>,
+
+    /// How many items each channel between loader threads holds. When a
+    /// channel of raw frames is full, new frames are dropped so that the
+    /// source keeps its real-time pace. When a channel of encoded data is
+    /// full, the producer blocks because dropping encoded data corrupts the
+    /// stream.
+    pub depth: usize,
+
+    /// A custom gstreamer pipeline for encoding (see `render_pipeline` for
+    /// the placeholders and the required elements). Uses `DEFAULT_PIPELINE`
+    /// if not set.
+    pub pipeline: Option<String>,
+
+    /// A dataset manifest (see `evaluation::Manifest`). If set, the frames in
+    /// `path` are checked against it before loading starts.
+    pub manifest: Option<String>,
+
+    /// If set, frames of static scenes are skipped on top of the configured
+    /// skip (see `evaluation::MotionSkip`).
+    pub motion: Option<MotionConfig>,
+
+    /// If set, frames are captured from this camera (device index) instead
+    /// of loaded from `path`, and paced by the camera.
+    pub camera: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -35,7 +71,7 @@ pub fn load_encoded(lc: LoaderConfig,
     let mut frame_num = 1;
 
     let (loader_handle, _loader_rx) = channel::<VideoConfig>();
-    let (tx, rx) = channel();
+    let (tx, rx) = sync_channel(lc.depth);
 
     ::std::thread::spawn(move || {
         let path = lc.path;
@@ -79,7 +115,7 @@ pub fn load_simulated(lc: LoaderConfig,
                       mut vc: VideoConfig)
                       -> Result<(Receiver<Vec<u8>>, LoaderHandle)> {
     let (loader_handle, loader_rx) = channel::<VideoConfig>();
-    let (tx, rx) = channel();
+    let (tx, rx) = sync_channel(lc.depth);
 
     ::std::thread::spawn(move || {
         'outer: loop {
@@ -133,14 +169,35 @@ pub fn load_simulated(lc: LoaderConfig,
     Ok((rx, loader_handle))
 }
 
+/// Checks the frames in `path` against the manifest, so that missing or
+/// renumbered frames are reported before anything is loaded.
+pub fn check_manifest(manifest: &str, path: &str, ext: &str) -> Result<()> {
+    let manifest = Manifest::load(manifest)?;
+    let mut problems = manifest.verify(path);
+    if manifest.ext != ext {
+        problems.push(format!("frames are {}, but {} is requested", manifest.ext, ext));
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ErrorKind::Manifest(problems.join("; ")).into())
+    }
+}
+
 pub fn load_frame(lc: LoaderConfig, vc: VideoConfig) -> Result<(Receiver<cv::Mat>, LoaderHandle)> {
+    if let (Some(manifest), None) = (lc.manifest.as_ref(), lc.camera) {
+        check_manifest(manifest, &lc.path, &lc.ext)?;
+    }
+
     let (loader_handle, loader_rx) = channel();
-    let (tx, rx) = channel();
+    let (tx, rx) = sync_channel(lc.depth);
 
     // Perform all tasks in a thread so that we can return the `rx`.
     thread::spawn(move || {
-        let metadata = ::std::fs::metadata(&lc.path).expect("wrong path provided");
-        let result = {
+        let result = if let Some(device) = lc.camera {
+            camera_loader(tx, loader_rx, device, lc, vc)
+        } else {
+            let metadata = ::std::fs::metadata(&lc.path).expect("wrong path provided");
             if metadata.is_dir() {
                 frame_loader(tx, loader_rx, lc, vc)
             } else {
@@ -174,11 +231,56 @@ pub fn load_frame(lc: LoaderConfig, vc: VideoConfig) -> Result<(Receiver<cv::Mat
     Ok((rx, loader_handle))
 }
 
+/// Per-frame encoding statistics.
+#[derive(Copy, Clone, Debug)]
+pub struct EncodeStat {
+    /// Sequence number of the encoded frame (starting from 1).
+    pub frame: usize,
+
+    /// Time between pushing the raw frame and receiving the encoded frame.
+    pub latency: Duration,
+
+    /// Size of the encoded frame in bytes.
+    pub size: usize,
+
+    /// Whether the encoded frame is a keyframe (IDR).
+    pub keyframe: bool,
+}
+
+impl EncodeStat {
+    /// Returns the encode latency in milliseconds.
+    pub fn latency_in_ms(&self) -> f64 {
+        self.latency.as_secs() as f64 * 1_000.0 +
+        self.latency.subsec_nanos() as f64 / 1_000_000.0
+    }
+}
+
+/// Returns true if an H.264 byte stream (Annex B) contains an IDR slice, i.e.
+/// the encoded frame is a keyframe.
+pub fn is_keyframe(encoded: &[u8]) -> bool {
+    const NAL_IDR_SLICE: u8 = 5;
+    encoded.windows(4)
+        .any(|w| w[0] == 0 && w[1] == 0 && w[2] == 1 && w[3] & 0x1f == NAL_IDR_SLICE)
+}
+
 pub fn load_x264(lc: LoaderConfig,
                  config: VideoConfig)
                  -> Result<(Receiver<Vec<u8>>, LoaderHandle)> {
+    let (loader, _stats, handle) = load_x264_with_stats(lc, config)?;
+    Ok((loader, handle))
+}
+
+/// Same as `load_x264`, but also returns a channel of per-frame encoding
+/// statistics. Statistics are dropped if the channel is full.
+pub fn load_x264_with_stats(mut lc: LoaderConfig,
+                            config: VideoConfig)
+                            -> Result<(Receiver<Vec<u8>>, Receiver<EncodeStat>, LoaderHandle)> {
+    let on_error = lc.on_error.take();
+    let depth = lc.depth;
+    let template = lc.pipeline.take().unwrap_or_else(|| DEFAULT_PIPELINE.to_string());
     let (frame_loader, frame_loader_handle) = load_frame(lc, config)?;
-    let (loader, gstreamer_handle) = x264_encoder(frame_loader, config)?;
+    let (loader, stats, gstreamer_handle) =
+        x264_encoder(frame_loader, config, template, depth, on_error)?;
 
     let (tx, rx) = channel();
     thread::spawn(move || loop {
@@ -190,14 +292,14 @@ pub fn load_x264(lc: LoaderConfig,
             Err(_) => warn!("The controller to video loader has been dropped!"),
         }
     });
-    Ok((loader, tx))
+    Ok((loader, stats, tx))
 }
 
 fn load_video_file(_config: VideoConfig) -> Result<()> {
     unimplemented!();
 }
 
-fn frame_loader(tx: Sender<cv::Mat>,
+fn frame_loader(tx: SyncSender<cv::Mat>,
                 loader_rx: Receiver<VideoConfig>,
                 lc: LoaderConfig,
                 mut vc: VideoConfig)
@@ -205,6 +307,7 @@ fn frame_loader(tx: Sender<cv::Mat>,
     let mut frame_num = 1;
     let path = lc.path;
     let extension = lc.ext;
+    let mut motion = lc.motion.map(|config| (MotionDetector::new(), MotionSkip::new(config)));
 
     'outer: loop {
         let fps = skip_to_fps(vc.skip);
@@ -228,7 +331,21 @@ fn frame_loader(tx: Sender<cv::Mat>,
             let filename = format!("{}/{:06}.{}", &path, frame_num, extension);
             frame_num += vc.skip + 1;
             match cv_load_image(filename) {
-                Ok(image) => tx.send(image).chain_err(|| "faild to send")?,
+                Ok(image) => {
+                    if let Some((ref mut detector, ref mut skip)) = motion {
+                        if !skip.keep(detector.measure(&image)) {
+                            trace!("frame_loader: static scene, skipping a frame");
+                            continue;
+                        }
+                    }
+                    match tx.try_send(image) {
+                        Ok(_) => {}
+                        Err(TrySendError::Full(_)) => {
+                            debug!("frame_loader: consumer falls behind, dropping a frame");
+                        }
+                        Err(TrySendError::Disconnected(_)) => bail!("faild to send"),
+                    }
+                }
                 Err(_) => {
                     if lc.circular {
                         frame_num = 1;
@@ -241,6 +358,50 @@ fn frame_loader(tx: Sender<cv::Mat>,
     }
 }
 
+/// Captures frames from camera `device`, keeping one in every `skip + 1`. The
+/// camera paces the frames, so unlike `frame_loader` there is no tick.
+fn camera_loader(tx: SyncSender<cv::Mat>,
+                 loader_rx: Receiver<VideoConfig>,
+                 device: i32,
+                 lc: LoaderConfig,
+                 mut vc: VideoConfig)
+                 -> Result<()> {
+    let cap = cv::videoio::VideoCapture::new(device);
+    if !cap.is_open() {
+        bail!("failed to open camera {}", device);
+    }
+    let mut motion = lc.motion.map(|config| (MotionDetector::new(), MotionSkip::new(config)));
+
+    let mut captured = 0;
+    loop {
+        if let Ok(new_config) = loader_rx.try_recv() {
+            vc = new_config;
+        }
+
+        let image = match cap.read() {
+            Some(image) => image,
+            None => return Err(ErrorKind::EndStream.into()),
+        };
+        captured += 1;
+        if (captured - 1) % (vc.skip + 1) != 0 {
+            continue;
+        }
+        if let Some((ref mut detector, ref mut skip)) = motion {
+            if !skip.keep(detector.measure(&image)) {
+                trace!("camera_loader: static scene, skipping a frame");
+                continue;
+            }
+        }
+        match tx.try_send(image) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                debug!("camera_loader: consumer falls behind, dropping a frame");
+            }
+            Err(TrySendError::Disconnected(_)) => bail!("faild to send"),
+        }
+    }
+}
+
 fn cv_load_image<P: AsRef<Path>>(path: P) -> Result<cv::Mat> {
     trace!("cv_load_image from {:?}", path.as_ref());
     if path.as_ref().metadata().is_ok() {
@@ -254,38 +415,157 @@ fn cv_load_image<P: AsRef<Path>>(path: P) -> Result<cv::Mat> {
 
 pub type LoaderHandle = Sender<VideoConfig>;
 
+/// `FrameEncoder` encodes frames one by one and synchronously: every call to
+/// `encode` returns the encoded bytes of exactly that frame. Unlike
+/// `load_x264`, nothing is paced or dropped, which makes it suitable for
+/// frame-accurate comparisons.
+pub struct FrameEncoder {
+    handle: GstHandle,
+    config: VideoConfig,
+}
+
+impl FrameEncoder {
+    /// Creates an encoder with `template` (see `render_pipeline`), or
+    /// `DEFAULT_PIPELINE` if not given.
+    pub fn new(config: VideoConfig, template: Option<&str>) -> Result<FrameEncoder> {
+        let handle = gst_main_loop(config, template.unwrap_or(DEFAULT_PIPELINE))?;
+        Ok(FrameEncoder {
+            handle: handle,
+            config: config,
+        })
+    }
+
+    /// Resizes `frame` to the configured size and encodes it.
+    pub fn encode(&mut self, frame: &cv::Mat) -> Result<Vec<u8>> {
+        if let Ok(PipelineEvent::Error(msg)) = self.handle.events.try_recv() {
+            bail!("pipeline error: {}", msg);
+        }
+
+        let (width, height) = (self.config.width, self.config.height);
+        let frame = frame.resize_to(cv::Size2i::new(width as i32, height as i32),
+                                    InterpolationFlag::InterLinear);
+        let mut buffer = self.handle
+            .buffer_pool
+            .acquire_buffer()
+            .ok_or("failed to acquire a buffer")?;
+        buffer.map_write(|mapping| {
+                unsafe { copy(frame.data(), mapping.data, height * width * 3) };
+            })
+            .unwrap();
+        self.handle.appsrc.push_buffer(buffer);
+
+        // With `tune=zerolatency`, every frame in yields one frame out.
+        loop {
+            match self.handle.appsink.recv() {
+                Ok(gst::appsink::Message::NewSample(sample)) => {
+                    let buffer = sample.buffer().expect("extracting buffer");
+                    let size = buffer.size() as usize;
+                    let mut vec = Vec::<u8>::with_capacity(size);
+                    buffer.map_read(|mapping| unsafe {
+                            vec.set_len(size);
+                            copy(mapping.data, vec.as_mut_ptr(), size);
+                        })
+                        .expect("failed to read data");
+                    return Ok(vec);
+                }
+                Ok(_) => {}
+                Err(_) => bail!("appsink closed"),
+            }
+        }
+    }
+}
+
+impl Drop for FrameEncoder {
+    fn drop(&mut self) {
+        teardown(&mut self.handle.pipeline);
+    }
+}
+
 fn x264_encoder(sched_rx: Receiver<cv::Mat>,
-                config: VideoConfig)
-                -> Result<(Receiver<Vec<u8>>, LoaderHandle)> {
-    let (out_tx, out_rx) = channel();
+                config: VideoConfig,
+                template: String,
+                depth: usize,
+                on_error: Option<ErrorCallback>)
+                -> Result<(Receiver<Vec<u8>>, Receiver<EncodeStat>, LoaderHandle)> {
+    let (out_tx, out_rx) = sync_channel(depth);
+    let (stats_tx, stats_rx) = sync_channel(depth);
+
+    // Push time of each raw frame, paired with encoded frames by the appsink
+    // thread. x264enc with `tune=zerolatency` has no frame reordering.
+    let (mut pushed_tx, pushed_rx) = channel();
 
     // loader_tx is returned so that applications can use it to control the
     // loader's behavior.
     let (loader_tx, loader_rx) = channel();
 
     // Create gstreamer loop
-    let gst_handle = gst_main_loop(config)?;
-
-    let (mut appsrc, appsink, mut buffer_pool) = gst_handle.to_tuple();
-
-    let old_config = config.clone();
+    let GstHandle { mut pipeline,
+                    mut appsrc,
+                    appsink,
+                    mut buffer_pool,
+                    mut encoder,
+                    mut events,
+                    mut watch } = gst_main_loop(config, &template)?;
+    appsink_loop(appsink, out_tx.clone(), pushed_rx, stats_tx.clone());
 
     // AppSrc thread
     thread::spawn(move || {
+        // The last known config, used to rebuild the pipeline after errors.
+        let mut current = config;
         let mut height = config.height;
         let mut width = config.width;
         let mut target_size = cv::Size2i::new(width as i32, height as i32);
         loop {
+            if let Ok(PipelineEvent::Error(msg)) = events.try_recv() {
+                warn!("Appsrc: pipeline error ({}), rebuilding with {:?}", msg, current);
+                if let Some(ref callback) = on_error {
+                    callback(&msg);
+                }
+
+                teardown(&mut pipeline);
+                watch.join();
+                match gst_main_loop(current, &template) {
+                    Ok(handle) => {
+                        appsrc = handle.appsrc;
+                        buffer_pool = handle.buffer_pool;
+                        pipeline = handle.pipeline;
+                        encoder = handle.encoder;
+                        events = handle.events;
+                        watch = handle.watch;
+
+                        // Frames in the old pipeline are lost; start over.
+                        let (new_pushed_tx, pushed_rx) = channel();
+                        pushed_tx = new_pushed_tx;
+                        appsink_loop(handle.appsink, out_tx.clone(), pushed_rx, stats_tx.clone());
+                    }
+                    Err(e) => {
+                        error!("Appsrc: failed to rebuild pipeline: {}", e);
+                        if let Some(ref callback) = on_error {
+                            callback(&format!("failed to rebuild pipeline: {}", e));
+                        }
+                        break;
+                    }
+                }
+            }
+
             match loader_rx.try_recv() {
                 Ok(new_config) => {
-                    // Only change the configuration if it's really new
-                    if new_config != old_config {
+                    // Only change the caps if the frame size is really new;
+                    // the quantizer is a property of the running encoder. A
+                    // new skip leaves the encoder alone: the loader drops the
+                    // frames, and at a fixed quantizer the framerate of the
+                    // caps makes no difference to the frames encoded.
+                    if (new_config.width, new_config.height) != (current.width, current.height) {
                         let caps = create_caps(new_config);
                         appsrc.set_caps(&caps);
                         height = new_config.height;
                         width = new_config.width;
                         target_size = cv::Size2i::new(width as i32, height as i32);
                     }
+                    if new_config.quantizer != current.quantizer {
+                        set_quantizer(&mut encoder, new_config.quantizer);
+                    }
+                    current = new_config;
                 }
                 Err(_) => {
                     trace!("nothing on the channel");
@@ -299,6 +579,7 @@ fn x264_encoder(sched_rx: Receiver<cv::Mat>,
                                 unsafe { copy(frame.data(), mapping.data, height * width * 3) };
                             })
                             .unwrap();
+                        let _ = pushed_tx.send(Instant::now());
                         appsrc.push_buffer(buffer);
                         debug!("appsrc: new sample with size {}x{}", frame.cols, frame.rows);
                     }
@@ -314,9 +595,18 @@ fn x264_encoder(sched_rx: Receiver<cv::Mat>,
                 break;
             }
         }
+        watch.join();
     });
 
-    // Appsink handling
+    Ok((out_rx, stats_rx, loader_tx))
+}
+
+/// Spawns the thread that forwards encoded samples from `appsink`. The thread
+/// quits once the appsink is gone, e.g. when its pipeline is torn down.
+fn appsink_loop(appsink: gst::AppSink,
+                out_tx: SyncSender<Vec<u8>>,
+                pushed_rx: Receiver<Instant>,
+                stats_tx: SyncSender<EncodeStat>) {
     thread::spawn(move || {
         let mut sink_count = 0;
         loop {
@@ -336,6 +626,16 @@ fn x264_encoder(sched_rx: Receiver<cv::Mat>,
                             }
                         })
                         .expect("failed to read data");
+                    if let Ok(pushed) = pushed_rx.try_recv() {
+                        let stat = EncodeStat {
+                            frame: sink_count + 1,
+                            latency: pushed.elapsed(),
+                            size: size,
+                            keyframe: is_keyframe(&vec),
+                        };
+                        trace!("Appsink: {:?}", stat);
+                        let _ = stats_tx.try_send(stat);
+                    }
                     match out_tx.send(vec) {
                         Ok(_) => {
                             sink_count += 1;
@@ -358,6 +658,4 @@ fn x264_encoder(sched_rx: Receiver<cv::Mat>,
             }
         }
     });
-
-    Ok((out_rx, loader_tx))
 }
@@ -7,10 +7,24 @@ use cv::cuda::GpuHog as Hog;
 use cv::objdetect::{HogParams, ObjectDetect, SvmDetector};
 use darknet::*;
 use std::env;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::Write;
 
 use video_analytics::loader::*;
+use video_analytics::motion::MotionConfig;
+use video_analytics::resource::{DETECT_WINDOW, ResourceMeter, Window};
+
+/// Opens the file set by `RESOURCE_STATS` (if any) for appending, so that
+/// encoding and detection of the same configuration can share one file.
+fn resource_stats() -> Option<File> {
+    env::var("RESOURCE_STATS").ok().map(|path| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .expect("failed to open resource stats file")
+    })
+}
 
 fn main() {
     env_logger::init().unwrap();
@@ -54,6 +68,21 @@ fn main() {
         path: path,
         ext: ext,
         circular: false,
+        on_error: Some(Box::new(|msg: &str| eprintln!("encoder error: {}", msg))),
+        depth: DEFAULT_DEPTH,
+        pipeline: env::var("PIPELINE").ok(),
+        manifest: env::var("MANIFEST").ok(),
+        motion: env::var("MOTION_THRESHOLD").ok().map(|threshold| {
+            MotionConfig {
+                threshold: threshold.parse::<f64>()
+                    .expect("invalid MOTION_THRESHOLD via environment variable"),
+                max_skip: env::var("MOTION_MAX_SKIP")
+                    .unwrap_or("10".to_string())
+                    .parse::<usize>()
+                    .expect("invalid MOTION_MAX_SKIP via environment variable"),
+            }
+        }),
+        camera: None,
     };
 
     let config = VideoConfig {
@@ -62,7 +91,35 @@ fn main() {
         skip: skip,
         quantizer: quantizer,
     };
-    let (loader, _loader_ctl) = load_x264(lc, config).unwrap();
+    let (loader, stats, _loader_ctl) = load_x264_with_stats(lc, config).unwrap();
+
+    // Optionally record per-frame encode latency and size.
+    if let Ok(stats_file) = env::var("ENCODE_STATS") {
+        ::std::thread::spawn(move || {
+            let mut f = File::create(&stats_file).expect("failed to create stats file");
+            for stat in stats.iter() {
+                writeln!(f,
+                         "{}, {:.3}, {}, {}",
+                         stat.frame,
+                         stat.latency_in_ms(),
+                         stat.size,
+                         stat.keyframe as u8)
+                    .expect("failed to write stats");
+            }
+        });
+    }
+
+    // Optionally sample CPU time and energy of encoding every second. Frames
+    // are encoded in real time, so each entry covers one second of video.
+    if let Some(mut f) = resource_stats() {
+        ::std::thread::spawn(move || {
+            let mut meter = ResourceMeter::start();
+            loop {
+                ::std::thread::sleep(::std::time::Duration::from_secs(1));
+                meter.restart().write_to(&mut f, "encode").expect("failed to write resource stats");
+            }
+        });
+    }
 
     let mut i = 1;
     let mut sink_file = File::create(&format!("{}", fname)).unwrap();
@@ -70,7 +127,7 @@ fn main() {
         // println!("{} ms", elapsed.subsec_nanos() / 1_000_000);
         let encoded = loader.recv().expect("failed to receive encoded");
         sink_file.write(&encoded).expect("failed to write to file sink");
-        println!("{}, {}", i, encoded.len());
+        println!("{}, {}, {}", i, encoded.len(), is_keyframe(&encoded) as u8);
         i += 1;
     }
 }
@@ -110,6 +167,8 @@ fn pedestrian() {
     let detector = SvmDetector::default_people_detector();
     hog.set_svm_detector(detector);
 
+    let mut resources = resource_stats();
+    let mut window = Window::new(DETECT_WINDOW);
     let mut frame_no = 1;
     for i in 1..8000 {
         // while let Some(image) = cap.read() {
@@ -120,10 +179,16 @@ fn pedestrian() {
         //    while let Some(image) = cap.read() {
         //        let image = image.cvt_color(cv::imgproc::ColorConversionCodes::BGR2GRAY);
         let time = ::std::time::Instant::now();
+        let meter = ResourceMeter::start();
         // Result is a vector of tuple (Rect, conf: f64). See documentation
         // of hog detection if you are confused.
         let result = hog.detect(&image);
         let elapsed = time.elapsed();
+        if let Some(ref mut f) = resources {
+            if let Some(mean) = window.add(meter.usage()) {
+                mean.write_to(f, "detect").expect("failed to write resource stats");
+            }
+        }
         let proc_time = elapsed.as_secs() as f64 * 1_000.0 +
                         elapsed.subsec_nanos() as f64 / 1_000_000.0;
 
@@ -153,6 +218,8 @@ fn darknet() {
                               concat!(env!("CARGO_MANIFEST_DIR"), "/darknet-data/yolo.weights"),
                               concat!(env!("CARGO_MANIFEST_DIR"), "/darknet-data/coco.names"));
 
+    let mut resources = resource_stats();
+    let mut window = Window::new(DETECT_WINDOW);
     let mut frame_no = 1;
     for index in 1..20000 {
         // while let Some(image) = cap.read() {
@@ -160,7 +227,13 @@ fn darknet() {
         let image = cv::Mat::from_path(&f, cv::imgcodecs::ImreadModes::ImreadColor).unwrap();
         let image = image.cvt_color(cv::imgproc::ColorConversionCodes::BGR2RGB);
         let image = cv_mat_to_darknet_image(&image);
+        let meter = ResourceMeter::start();
         let detections = dn.detect(image);
+        if let Some(ref mut f) = resources {
+            if let Some(mean) = window.add(meter.usage()) {
+                mean.write_to(f, "detect").expect("failed to write resource stats");
+            }
+        }
         for i in 0..detections.num {
             let ref d = detections.detections[i];
             println!("{:06}, {:.02}, {}",
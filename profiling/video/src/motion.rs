@@ -0,0 +1,44 @@
+//! Motion detection by frame differencing, for motion-triggered frame skipping
+//! (see `evaluation::MotionSkip`). Frames are compared as small thumbnails,
+//! which is cheap and smooths out sensor noise.
+
+use cv;
+use cv::imgproc::InterpolationFlag;
+use evaluation::frame_difference;
+use quality::mat_bytes;
+pub use evaluation::{MotionConfig, MotionSkip};
+
+/// The size frames are compared at.
+const THUMBNAIL_WIDTH: i32 = 64;
+const THUMBNAIL_HEIGHT: i32 = 36;
+
+fn thumbnail(frame: &cv::Mat) -> Vec<u8> {
+    let small = frame.resize_to(cv::Size2i::new(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT),
+                                InterpolationFlag::InterLinear);
+    mat_bytes(&small).to_vec()
+}
+
+/// Measures the motion of every frame relative to the previous one.
+#[derive(Default)]
+pub struct MotionDetector {
+    previous: Option<Vec<u8>>,
+}
+
+impl MotionDetector {
+    /// Creates a detector that has seen no frame yet.
+    pub fn new() -> MotionDetector {
+        MotionDetector::default()
+    }
+
+    /// Returns the motion of `frame` relative to the previous frame (the mean
+    /// absolute pixel difference, 0 to 255), or `NaN` for the first frame.
+    pub fn measure(&mut self, frame: &cv::Mat) -> f64 {
+        let current = thumbnail(frame);
+        let motion = match self.previous {
+            Some(ref previous) => frame_difference(previous, &current),
+            None => ::std::f64::NAN,
+        };
+        self.previous = Some(current);
+        motion
+    }
+}
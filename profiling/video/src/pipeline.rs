@@ -1,32 +1,95 @@
-use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
+use std::thread::JoinHandle;
+use std::time::Duration;
 use errors::*;
-use gst::{self, MainLoop, AppSrc, AppSink, Message, BufferPool, Caps, Pipeline};
+use gst::{self, MainLoop, AppSrc, AppSink, Message, BufferPool, Caps, Pipeline, Element};
 
 use super::skip_to_fps;
 use super::loader::VideoConfig;
 
+/// The pipeline used unless `LoaderConfig::pipeline` says otherwise. See
+/// `render_pipeline` for the placeholders.
+pub const DEFAULT_PIPELINE: &'static str = "appsrc name=appsrc0 ! videoconvert ! \
+                                            x264enc name=x264enc0 tune=zerolatency pass=5 \
+                                            speed-preset=1 quantizer={quantizer} threads=4 \
+                                            bitrate=2048000 ! appsink name=appsink0";
+
+/// How often (ms) the bus watcher checks whether it has been stopped.
+const WATCH_POLL: u64 = 100;
+
+/// Events on the pipeline bus that the owner of the pipeline reacts to.
+pub enum PipelineEvent {
+    /// An element reports an error; the pipeline needs to be rebuilt.
+    Error(String),
+}
+
 pub struct GstHandle {
-    appsrc: AppSrc,
-    appsink: AppSink,
-    buffer_pool: BufferPool,
+    pub pipeline: Pipeline,
+    pub appsrc: AppSrc,
+    pub appsink: AppSink,
+    pub buffer_pool: BufferPool,
+
+    /// The x264 encoder element, kept to update its properties in place.
+    pub encoder: Element,
+
+    pub events: Receiver<PipelineEvent>,
+
+    /// The thread that watches the bus and runs the main loop.
+    pub watch: BusWatch,
 }
 
-impl GstHandle {
-    pub fn to_tuple(self) -> (AppSrc, AppSink, BufferPool) {
-        (self.appsrc, self.appsink, self.buffer_pool)
+/// The thread that watches the bus of a pipeline. It is stopped and joined
+/// when dropped, so that it doesn't outlive its pipeline.
+#[derive(Default)]
+pub struct BusWatch {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BusWatch {
+    /// Stops the thread and waits for it to quit.
+    pub fn join(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Main: bus watcher panicked");
+            }
+        }
     }
 }
 
-pub fn gst_main_loop(config: VideoConfig) -> Result<GstHandle> {
+impl Drop for BusWatch {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// Stops a pipeline so that it can be dropped and rebuilt.
+pub fn teardown(pipeline: &mut Pipeline) {
+    debug!("Main: tearing down pipeline");
+    pipeline.set_null_state();
+}
+
+pub fn gst_main_loop(config: VideoConfig, template: &str) -> Result<GstHandle> {
     gst::init();
     let mut mainloop = MainLoop::new();
     mainloop.spawn();
 
-    let (handle, bus_recv) = create_pipeline(config)?;
+    let (events_tx, events_rx) = channel();
+    let (mut handle, bus_recv) = create_pipeline(config, template, events_rx)?;
 
-    ::std::thread::spawn(move || {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let thread = ::std::thread::spawn(move || {
         // Here runs the main loop
-        for message in bus_recv.iter() {
+        while !stopped.load(Ordering::SeqCst) {
+            let message = match bus_recv.recv_timeout(Duration::from_millis(WATCH_POLL)) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
             match message.parse() {
                 gst::Message::StateChangedParsed { ref old, ref new, .. } => {
                     debug!("Main: element `{}` changed from {:?} to {:?}",
@@ -35,11 +98,15 @@ pub fn gst_main_loop(config: VideoConfig) -> Result<GstHandle> {
                            new);
                 }
                 gst::Message::ErrorParsed { ref error, ref debug, .. } => {
-                    debug!("Main: error msg from element `{}`: {}, {}. Quitting",
+                    debug!("Main: error msg from element `{}`: {}, {}",
                            message.src_name(),
                            error.message(),
                            debug);
-                    break;
+                    // Let the owner tear down and rebuild the pipeline. If
+                    // nobody is listening, there is nothing to recover.
+                    if !notify(&events_tx, &message.src_name(), &error.message()) {
+                        break;
+                    }
                 }
                 gst::Message::Eos(_) => {
                     debug!("Main: eos received quiting");
@@ -55,9 +122,18 @@ pub fn gst_main_loop(config: VideoConfig) -> Result<GstHandle> {
 
         mainloop.quit();
     });
+    handle.watch = BusWatch {
+        stop: stop,
+        thread: Some(thread),
+    };
     Ok(handle)
 }
 
+fn notify(tx: &Sender<PipelineEvent>, element: &str, error: &str) -> bool {
+    let msg = format!("element `{}`: {}", element, error);
+    tx.send(PipelineEvent::Error(msg)).is_ok()
+}
+
 fn fps_to_string(fps: f64) -> String {
     let fps = (fps * 10.0).round() / 10.0;
     let str = {
@@ -84,23 +160,43 @@ fn fps_to_string(fps: f64) -> String {
     String::from(str)
 }
 
-pub fn create_caps(config: VideoConfig) -> Caps {
+/// Updates the quantizer of a running encoder. x264enc picks up the new value
+/// from the next frame on, so the pipeline keeps running without a restart.
+pub fn set_quantizer(encoder: &mut Element, quantizer: usize) {
+    debug!("Main: updating quantizer to {}", quantizer);
+    encoder.set("quantizer", quantizer as u32);
+}
+
+fn caps_string(config: VideoConfig) -> String {
     let fps = skip_to_fps(config.skip);
-    let caps = format!("video/x-raw,format=BGR,width={},height={},framerate={}",
-                       config.width,
-                       config.height,
-                       fps_to_string(fps));
+    format!("video/x-raw,format=BGR,width={},height={},framerate={}",
+            config.width,
+            config.height,
+            fps_to_string(fps))
+}
+
+pub fn create_caps(config: VideoConfig) -> Caps {
+    let caps = caps_string(config);
     trace!("Created pipeline with caps: {}", caps);
     Caps::from_string(&caps).expect("failed to create caps from string")
 }
 
-pub fn create_pipeline(config: VideoConfig) -> Result<(GstHandle, Receiver<Message>)> {
+/// Fills in a pipeline template. `{quantizer}` is replaced by the quantizer
+/// and `{caps}` by the caps of the raw frames pushed into `appsrc0`. The
+/// template must contain elements named `appsrc0`, `x264enc0` and `appsink0`;
+/// anything may be inserted in between (e.g. `deinterlace`, `videobalance`).
+pub fn render_pipeline(template: &str, config: VideoConfig) -> String {
+    template.replace("{quantizer}", &config.quantizer.to_string())
+        .replace("{caps}", &caps_string(config))
+}
+
+pub fn create_pipeline(config: VideoConfig,
+                       template: &str,
+                       events: Receiver<PipelineEvent>)
+                       -> Result<(GstHandle, Receiver<Message>)> {
     let caps = create_caps(config);
-    let quantizer = config.quantizer;
-    let pipeline_str = format!("appsrc name=appsrc0 ! videoconvert ! x264enc tune=zerolatency \
-                                pass=5 speed-preset=1 quantizer={} threads=4 bitrate=2048000 ! \
-                                appsink name=appsink0",
-                               quantizer);
+    let pipeline_str = render_pipeline(template, config);
+    debug!("Main: creating pipeline `{}`", pipeline_str);
 
     // Create the pipeline
     let mut pipeline = Pipeline::new_from_str(&pipeline_str)?;
@@ -108,13 +204,15 @@ pub fn create_pipeline(config: VideoConfig) -> Result<(GstHandle, Receiver<Messa
     let bus_recv = bus.receiver();
 
     // Bind appsrc
-    let appsrc = pipeline.get_by_name("appsrc0").expect("failed to find appsrc");
+    let appsrc = pipeline.get_by_name("appsrc0").ok_or("pipeline has no element `appsrc0`")?;
     let mut appsrc = AppSrc::new_from_element(appsrc);
     appsrc.set_caps(&caps);
 
-    let appsink = pipeline.get_by_name("appsink0").expect("failed to find appsink");
+    let appsink = pipeline.get_by_name("appsink0").ok_or("pipeline has no element `appsink0`")?;
     let appsink = AppSink::new_from_element(appsink);
 
+    let encoder = pipeline.get_by_name("x264enc0").ok_or("pipeline has no element `x264enc0`")?;
+
     let buf_size = config.width * config.height * 3;
     let mut bufferpool = BufferPool::new().expect("failed to allocate buffer");
     bufferpool.set_params(&caps, (buf_size) as u32, 0, 0);
@@ -122,9 +220,13 @@ pub fn create_pipeline(config: VideoConfig) -> Result<(GstHandle, Receiver<Messa
 
     pipeline.play();
     let handle = GstHandle {
+        pipeline: pipeline,
         appsrc: appsrc,
         appsink: appsink,
         buffer_pool: bufferpool,
+        encoder: encoder,
+        events: events,
+        watch: BusWatch::default(),
     };
     Ok((handle, bus_recv))
 }
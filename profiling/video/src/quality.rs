@@ -0,0 +1,129 @@
+//! Objective image quality metrics between a degraded frame and a reference
+//! frame. Frames are raw, interleaved pixels (e.g. BGR) of the same size.
+
+use cv;
+
+/// Returns the pixels of `mat`, interleaved as they are stored.
+pub fn mat_bytes(mat: &cv::Mat) -> &[u8] {
+    let len = (mat.rows * mat.cols * mat.channels) as usize;
+    unsafe { ::std::slice::from_raw_parts(mat.data(), len) }
+}
+
+/// Mean squared error over all samples.
+pub fn mse(a: &[u8], b: &[u8]) -> f64 {
+    assert_eq!(a.len(), b.len(), "frames differ in size");
+    let sum = a.iter()
+        .zip(b.iter())
+        .map(|(&x, &y)| {
+            let d = x as f64 - y as f64;
+            d * d
+        })
+        .sum::<f64>();
+    sum / a.len() as f64
+}
+
+/// Peak signal-to-noise ratio (in dB). Identical frames yield infinity.
+pub fn psnr(a: &[u8], b: &[u8]) -> f64 {
+    let mse = mse(a, b);
+    if mse == 0.0 {
+        ::std::f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+}
+
+/// Converts interleaved pixels into luma. BGR (3 channels) uses the BT.601
+/// weights; a single channel is taken as is.
+fn luma(frame: &[u8], channels: usize) -> Vec<f64> {
+    match channels {
+        1 => frame.iter().map(|&v| v as f64).collect(),
+        3 => {
+            frame.chunks(3)
+                .map(|p| 0.114 * p[0] as f64 + 0.587 * p[1] as f64 + 0.299 * p[2] as f64)
+                .collect()
+        }
+        _ => panic!("unsupported number of channels {}", channels),
+    }
+}
+
+/// Side of the (non-overlapping) windows SSIM is computed on.
+const SSIM_WINDOW: usize = 8;
+
+/// Structural similarity on luma, averaged over 8x8 windows. Returns a value
+/// in [-1, 1], where 1 means identical.
+pub fn ssim(a: &[u8], b: &[u8], width: usize, height: usize, channels: usize) -> f64 {
+    assert_eq!(a.len(), b.len(), "frames differ in size");
+    assert_eq!(a.len(), width * height * channels, "unexpected frame size");
+    let (a, b) = (luma(a, channels), luma(b, channels));
+    let c1 = (0.01 * 255.0f64).powi(2);
+    let c2 = (0.03 * 255.0f64).powi(2);
+
+    let mut sum = 0.0;
+    let mut windows = 0;
+    for y0 in (0..height).step_by(SSIM_WINDOW) {
+        for x0 in (0..width).step_by(SSIM_WINDOW) {
+            let ys = y0..::std::cmp::min(y0 + SSIM_WINDOW, height);
+            let xs = x0..::std::cmp::min(x0 + SSIM_WINDOW, width);
+            let n = (ys.len() * xs.len()) as f64;
+            let index = |x: usize, y: usize| y * width + x;
+
+            let (mut mean_a, mut mean_b) = (0.0, 0.0);
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    mean_a += a[index(x, y)];
+                    mean_b += b[index(x, y)];
+                }
+            }
+            mean_a /= n;
+            mean_b /= n;
+
+            let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    let da = a[index(x, y)] - mean_a;
+                    let db = b[index(x, y)] - mean_b;
+                    var_a += da * da;
+                    var_b += db * db;
+                    cov += da * db;
+                }
+            }
+            var_a /= n;
+            var_b /= n;
+            cov /= n;
+
+            sum += ((2.0 * mean_a * mean_b + c1) * (2.0 * cov + c2)) /
+                   ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+            windows += 1;
+        }
+    }
+    sum / windows as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn psnr_of_known_error() {
+        let reference = [0u8; 16];
+        assert_eq!(psnr(&reference, &reference), ::std::f64::INFINITY);
+
+        // Every sample off by 10: MSE 100, PSNR 10 * log10(255^2 / 100).
+        let degraded = [10u8; 16];
+        assert_eq!(mse(&reference, &degraded), 100.0);
+        assert!((psnr(&reference, &degraded) - 28.1308).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ssim_ranks_degradation() {
+        let (width, height) = (16, 16);
+        let reference = (0..width * height * 3).map(|i| (i * 7 % 256) as u8).collect::<Vec<_>>();
+        assert!((ssim(&reference, &reference, width, height, 3) - 1.0).abs() < 1e-9);
+
+        let noisy = reference.iter().map(|&v| v.saturating_add(8)).collect::<Vec<_>>();
+        let inverted = reference.iter().map(|&v| 255 - v).collect::<Vec<_>>();
+        let slight = ssim(&reference, &noisy, width, height, 3);
+        let severe = ssim(&reference, &inverted, width, height, 3);
+        assert!(slight < 1.0 && slight > severe);
+    }
+}
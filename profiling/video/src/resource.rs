@@ -0,0 +1,175 @@
+//! Samples the resources used by this process: CPU time (all threads,
+//! including gstreamer's) and, where RAPL is available, the energy consumed by
+//! the CPU package. Both are read from Linux's `/proc` and `/sys`; on other
+//! platforms CPU time reads as zero and energy as unavailable.
+//!
+//! CPU time comes from `/proc/self/stat` in clock ticks (10 ms), so samples
+//! should cover at least a few hundred milliseconds of work. Work shorter than
+//! that (e.g. detecting one frame) goes through `Window`, which reports the
+//! mean of many.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// Clock ticks per second (`USER_HZ`), which is 100 on all platforms Linux
+/// exposes through `/proc`.
+const USER_HZ: u64 = 100;
+
+/// Number of detections `detect` entries are averaged over. A detection takes
+/// a few clock ticks of CPU time, so a single one reads a tick off at random.
+pub const DETECT_WINDOW: usize = 30;
+
+/// The RAPL domain of the first CPU package.
+const RAPL_DIR: &'static str = "/sys/class/powercap/intel-rapl:0";
+
+fn read_to_string(path: &str) -> io::Result<String> {
+    let mut s = String::new();
+    File::open(path)?.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+/// User plus system CPU time of this process.
+fn process_cpu_time() -> Duration {
+    let stat = match read_to_string("/proc/self/stat") {
+        Ok(stat) => stat,
+        Err(_) => return Duration::from_secs(0),
+    };
+    // The command name (in parentheses) may contain spaces; fields after it
+    // are space separated, utime and stime being the 12th and 13th.
+    let fields = match stat.rfind(')') {
+        Some(i) => stat[i + 1..].split_whitespace().collect::<Vec<_>>(),
+        None => return Duration::from_secs(0),
+    };
+    let ticks = fields
+        .iter()
+        .skip(11)
+        .take(2)
+        .filter_map(|f| f.parse::<u64>().ok())
+        .sum::<u64>();
+    Duration::from_millis(ticks * 1000 / USER_HZ)
+}
+
+/// Cumulative energy counter (µJ) and its range, if RAPL is readable.
+fn rapl_energy() -> Option<(u64, u64)> {
+    let read = |name: &str| {
+        read_to_string(&format!("{}/{}", RAPL_DIR, name))
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+    };
+    match (read("energy_uj"), read("max_energy_range_uj")) {
+        (Some(energy), Some(range)) => Some((energy, range)),
+        _ => None,
+    }
+}
+
+/// Resources used over a period.
+#[derive(Copy, Clone, Debug)]
+pub struct Usage {
+    /// CPU time of the process.
+    pub cpu: Duration,
+
+    /// Energy of the CPU package in µJ, if RAPL is available. This includes
+    /// other processes running at the same time.
+    pub energy: Option<u64>,
+}
+
+impl Usage {
+    pub fn cpu_in_ms(&self) -> f64 {
+        self.cpu.as_secs() as f64 * 1000.0 + self.cpu.subsec_nanos() as f64 / 1_000_000.0
+    }
+
+    pub fn energy_in_mj(&self) -> Option<f64> {
+        self.energy.map(|e| e as f64 / 1000.0)
+    }
+
+    /// Writes the usage as a `stage, cpu_ms, energy_mj` entry; `energy_mj` is
+    /// left empty if it's not available.
+    pub fn write_to<W: Write>(&self, w: &mut W, stage: &str) -> io::Result<()> {
+        match self.energy_in_mj() {
+            Some(energy) => writeln!(w, "{}, {:.3}, {:.3}", stage, self.cpu_in_ms(), energy),
+            None => writeln!(w, "{}, {:.3},", stage, self.cpu_in_ms()),
+        }
+    }
+}
+
+/// Measures the resources used since it started (or last restarted).
+pub struct ResourceMeter {
+    cpu: Duration,
+    energy: Option<(u64, u64)>,
+}
+
+impl ResourceMeter {
+    pub fn start() -> ResourceMeter {
+        ResourceMeter {
+            cpu: process_cpu_time(),
+            energy: rapl_energy(),
+        }
+    }
+
+    /// Returns the resources used since the meter started.
+    pub fn usage(&self) -> Usage {
+        let cpu = process_cpu_time();
+        let energy = match (self.energy, rapl_energy()) {
+            // The counter wraps around at `range`.
+            (Some((start, range)), Some((now, _))) if now < start => Some(range - start + now),
+            (Some((start, _)), Some((now, _))) => Some(now - start),
+            _ => None,
+        };
+        Usage {
+            cpu: if cpu > self.cpu { cpu - self.cpu } else { Duration::from_secs(0) },
+            energy: energy,
+        }
+    }
+
+    /// Returns the resources used since the meter started and starts over.
+    pub fn restart(&mut self) -> Usage {
+        let usage = self.usage();
+        *self = ResourceMeter::start();
+        usage
+    }
+}
+
+/// Accumulates the usage of short, repeated work (e.g. the detection of every
+/// frame) and reports the mean per item over a window of items, which smooths
+/// out the clock ticks CPU time is counted in.
+pub struct Window {
+    len: usize,
+    count: usize,
+    cpu: Duration,
+    energy: Option<u64>,
+}
+
+impl Window {
+    /// Creates a window of `len` items.
+    pub fn new(len: usize) -> Window {
+        assert!(len > 0, "a window holds at least one item");
+        Window {
+            len: len,
+            count: 0,
+            cpu: Duration::from_secs(0),
+            energy: Some(0),
+        }
+    }
+
+    /// Adds the usage of one item. Once the window is full, returns the mean
+    /// usage per item and starts over.
+    pub fn add(&mut self, usage: Usage) -> Option<Usage> {
+        self.count += 1;
+        self.cpu += usage.cpu;
+        self.energy = match (self.energy, usage.energy) {
+            (Some(sum), Some(e)) => Some(sum + e),
+            _ => None,
+        };
+        if self.count < self.len {
+            return None;
+        }
+        let len = self.len as u32;
+        let mean = Usage {
+            cpu: self.cpu / len,
+            energy: self.energy.map(|e| e / self.len as u64),
+        };
+        *self = Window::new(self.len);
+        Some(mean)
+    }
+}
@@ -0,0 +1,152 @@
+//! Accuracy models, for applications whose analytics is not object detection.
+//! Instead of looking up what the analytics achieves on every frame (which
+//! takes per-frame stats), the server asks a model what a frame achieves given
+//! its level and metadata.
+
+use csv;
+use errors::*;
+use std::collections::BTreeMap;
+
+/// Which accuracy model the server uses.
+///
+/// ```toml
+/// accuracy_model = { table = "accuracy.csv" }
+/// accuracy_model = { linear = { intercept = 0.4, level = 0.05, size = 0.0 } }
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccuracyModelConfig {
+    /// Path to a CSV file of the accuracy of every level (`level, accuracy`).
+    /// Accuracies must be numbers.
+    Table(String),
+
+    /// A linear model over the level and the size of the frame.
+    Linear(LinearModel),
+}
+
+/// Accuracy as `intercept + level * level + size * size` (in kB), clamped to
+/// [0, 1].
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct LinearModel {
+    /// Accuracy of the lowest level at no size.
+    #[serde(default)]
+    pub intercept: f64,
+
+    /// Accuracy every level adds.
+    #[serde(default)]
+    pub level: f64,
+
+    /// Accuracy every kB of the frame adds.
+    #[serde(default)]
+    pub size: f64,
+}
+
+/// What the server knows about a frame it received.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameMeta {
+    /// The frame number.
+    pub frame_num: usize,
+
+    /// The level the frame was sent at.
+    pub level: usize,
+
+    /// Size (bytes) of the frame.
+    pub size: usize,
+}
+
+/// A loaded accuracy model.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AccuracyModel {
+    /// Accuracy per level. Levels without an entry take that of the closest
+    /// level below.
+    Table(BTreeMap<usize, f64>),
+
+    /// See `LinearModel`.
+    Linear(LinearModel),
+}
+
+impl AccuracyModel {
+    /// Loads the model `config` describes.
+    pub fn load(config: &AccuracyModelConfig) -> Result<AccuracyModel> {
+        match *config {
+            AccuracyModelConfig::Table(ref path) => {
+                let mut rdr = csv::Reader::from_path(path)?;
+                let mut table = BTreeMap::new();
+                for record in rdr.deserialize() {
+                    let (level, accuracy): (usize, f64) = record?;
+                    // One NaN would make every total it goes into NaN.
+                    if accuracy.is_nan() {
+                        bail!("accuracy of level {} in {} is not a number", level, path);
+                    }
+                    table.insert(level, accuracy);
+                }
+                Ok(AccuracyModel::Table(table))
+            }
+            AccuracyModelConfig::Linear(model) => Ok(AccuracyModel::Linear(model)),
+        }
+    }
+
+    /// Returns the accuracy of `frame`, or NaN if the model doesn't cover it.
+    pub fn accuracy(&self, frame: &FrameMeta) -> f64 {
+        match *self {
+            AccuracyModel::Table(ref table) => {
+                table
+                    .range(..=frame.level)
+                    .next_back()
+                    .map_or(::std::f64::NAN, |(_, &accuracy)| accuracy)
+            }
+            AccuracyModel::Linear(m) => {
+                let kb = frame.size as f64 / 1000.0;
+                let accuracy = m.intercept + m.level * frame.level as f64 + m.size * kb;
+                accuracy.max(0.0).min(1.0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evaluation::testing::ScratchDir;
+    use std::fs;
+
+    fn frame(level: usize, size: usize) -> FrameMeta {
+        FrameMeta {
+            frame_num: 0,
+            level,
+            size,
+        }
+    }
+
+    #[test]
+    fn table_falls_back_to_lower_level() {
+        let table = vec![(1, 0.5), (3, 0.9)].into_iter().collect();
+        let model = AccuracyModel::Table(table);
+        assert!(model.accuracy(&frame(0, 0)).is_nan());
+        assert_eq!(model.accuracy(&frame(2, 0)), 0.5);
+        assert_eq!(model.accuracy(&frame(4, 0)), 0.9);
+    }
+
+    #[test]
+    fn table_rejects_nan() {
+        let dir = ScratchDir::new("accuracy-table");
+        let path = dir.join("accuracy.csv");
+        fs::write(&path, "level,accuracy\n0,0.5\n1,NaN\n").unwrap();
+        let config = AccuracyModelConfig::Table(path.to_str().unwrap().to_string());
+        assert!(AccuracyModel::load(&config).is_err());
+
+        fs::write(&path, "level,accuracy\n0,0.5\n1,0.7\n").unwrap();
+        assert_eq!(AccuracyModel::load(&config).unwrap().accuracy(&frame(1, 0)), 0.7);
+    }
+
+    #[test]
+    fn linear_is_clamped() {
+        let model = AccuracyModel::Linear(LinearModel {
+            intercept: 0.2,
+            level: 0.1,
+            size: 0.01,
+        });
+        assert!((model.accuracy(&frame(2, 10_000)) - 0.5).abs() < 1e-9);
+        assert_eq!(model.accuracy(&frame(20, 0)), 1.0);
+    }
+}
@@ -1,153 +1,290 @@
-//! Adapatation algorithm implementation (described as in Figure 6).
+//! Adaptation algorithm. The state machine lives in `awstream_core` so that it
+//! can be shared with other senders; this wrapper logs every transition and
+//! publishes it to subscribers as an `AdaptEvent`, and, once the level the
+//! decision leads to is known, to a decision log as an `AdaptDecision`.
+//!
+//! Knowing the level also lets it hold each level for a minimum dwell time:
+//! until then, the state machine is told the configuration is at its maximum,
+//! so that it neither advances nor probes, while it still degrades at once.
+//! This keeps bursty networks from flapping between levels every few seconds,
+//! which costs the encoder a key frame every time.
 
-/// Signal
-#[derive(Debug, Clone, Copy)]
-pub enum Signal {
-    /// QueueCongest signal carries the outgoing rate and the estimated latency.
-    QueueCongest(f64, f64),
+pub use awstream_core::adaptation::{Action, Signal, State, StraySignal};
+use awstream_core::adaptation::Adaptation as Inner;
+use chrono::{DateTime, Utc};
+use futures::sync::mpsc::UnboundedSender;
+use std::time::{Duration, Instant};
 
-    /// Queue is empty, try to be aggressive.
-    QueueEmpty,
+/// One decision of the adaptation algorithm: the signal it reacted to, the
+/// state before and after, and the action taken.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct AdaptEvent {
+    /// When the decision was made.
+    pub ts: DateTime<Utc>,
 
-    /// Congestion signal from the remote.
-    RemoteCongest(f64, f64),
+    /// The signal that triggered the decision.
+    pub signal: Signal,
 
-    /// Probe done
-    ProbeDone,
-}
+    /// Whether the configuration was at its maximum.
+    pub max_config: bool,
+
+    /// State before the transition.
+    pub from: State,
 
-#[derive(Debug, Clone, Copy)]
-pub enum Action {
-    NoOp,
-    AdvanceConfig,
+    /// State after the transition.
+    pub to: State,
 
-    /// When the action is `AdjustConfig`, we inform the estimated outgoing rate
-    AdjustConfig(f64),
+    /// The action taken.
+    pub action: Action,
 
-    /// Start the probe with a target bandwidth (in kbps)
-    StartProbe,
-    IncreaseProbePace,
-    StopProbe,
+    /// Set if the signal does not apply to the state (and is ignored).
+    pub warning: Option<StraySignal>,
 }
 
-#[derive(Debug, Clone, Copy)]
-/// States of the rate adaptation algorithm.
-enum State {
-    Startup,
-    Degrade,
-    Steady,
-    Probe,
+/// One row of the decision log: a decision of the adaptation algorithm along
+/// with the level (and its rate) it leads to. Signals and actions are written
+/// as they are debug-printed, e.g. `QueueCongest(812.5, 120.0)`, so that a
+/// row stays one CSV record.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AdaptDecision {
+    /// When the decision was made (seconds since the Unix epoch).
+    pub timestamp: f64,
+
+    /// State after the transition.
+    pub state: String,
+
+    /// The signal that triggered the decision.
+    pub signal: String,
+
+    /// The action taken.
+    pub action: String,
+
+    /// The level after the action.
+    pub level: usize,
+
+    /// The rate (kbps) of that level.
+    pub rate: f64,
 }
 
+/// The client's adaptation state machine, which turns signals into actions
+/// and publishes every transition (see `AdaptEvent`).
+#[derive(Default)]
 pub struct Adaptation {
-    state: State,
-    steady_count: usize,
-    startup_congest: usize,
+    inner: Inner,
+
+    /// Receivers of every `AdaptEvent`. Dropped subscribers are removed.
+    subscribers: Vec<UnboundedSender<AdaptEvent>>,
+
+    /// Receiver of every `AdaptDecision`, and the last event, waiting for the
+    /// level it leads to.
+    decisions: Option<UnboundedSender<AdaptDecision>>,
+    undecided: Option<AdaptEvent>,
+
+    /// How long a level is held before advancing, and the level decided last
+    /// with when it was reached.
+    min_dwell: Option<Duration>,
+    level: Option<(usize, Instant)>,
 }
 
-impl Default for Adaptation {
-    fn default() -> Adaptation {
+impl Adaptation {
+    /// Congestion signals tolerated during startup by default.
+    pub const STARTUP_CONGEST_ENOUGH: usize = Inner::STARTUP_CONGEST_ENOUGH;
+
+    /// `QueueEmpty` signals needed before probing by default.
+    pub const STEADY_ENOUGH: usize = Inner::STEADY_ENOUGH;
+
+    /// Creates a state machine with the given thresholds (see
+    /// `awstream_core::adaptation::Adaptation::with_params`).
+    pub fn with_params(startup_congest_enough: usize, steady_enough: usize) -> Adaptation {
         Adaptation {
-            state: State::Startup,
-            steady_count: 0,
-            startup_congest: 0,
+            inner: Inner::with_params(startup_congest_enough, steady_enough),
+            ..Default::default()
         }
     }
-}
 
-impl Adaptation {
-    /// Allow (transit) congestion during the startup phase as TCP is adjusting
-    const STARTUP_CONGEST_ENOUGH: usize = 3;
+    /// Publishes all future decisions to `subscriber`.
+    pub fn subscribe(&mut self, subscriber: UnboundedSender<AdaptEvent>) {
+        self.subscribers.push(subscriber);
+    }
+
+    /// Writes all future decisions, with the level each leads to, to `log`.
+    pub fn log_decisions(&mut self, log: UnboundedSender<AdaptDecision>) {
+        self.decisions = Some(log);
+    }
 
-    /// Only start probing if we are steady enough (that is, enough Q_E).
-    const STEADY_ENOUGH: usize = 3;
+    /// Holds every level for at least `dwell` before advancing from it.
+    pub fn set_min_dwell(&mut self, dwell: Duration) {
+        self.min_dwell = Some(dwell);
+    }
+
+    /// Returns the current state.
+    pub fn state(&self) -> State {
+        self.inner.state()
+    }
 
+    /// Whether the level is held (see `set_min_dwell`) at `now`. Startup ramps
+    /// up unhindered.
+    fn dwelling(&self, now: Instant) -> bool {
+        match (self.min_dwell, self.level) {
+            (Some(dwell), Some((_, since))) => {
+                self.inner.state() != State::Startup && now - since < dwell
+            }
+            _ => false,
+        }
+    }
+
+    /// Reacts to `signal`, given whether the current level is the highest
+    /// (or held, see `set_min_dwell`), and returns the action to take.
     pub fn transit(&mut self, signal: Signal, max_config: bool) -> Action {
+        let max_config = if !max_config && self.dwelling(Instant::now()) {
+            debug!("holding the level for its minimum dwell time");
+            true
+        } else {
+            max_config
+        };
+        let from = self.inner.state();
         info!(
             "state: {:?}, signal: {:?}, max?: {}",
-            self.state,
+            from,
             signal,
             max_config
         );
-        let action = match (self.state, signal, max_config) {
-            (State::Startup, Signal::QueueEmpty, false) => {
-                // transition 1
-                self.startup_congest = 0;
-                Action::AdvanceConfig
-            }
-            (State::Startup, Signal::QueueEmpty, true) => {
-                // transition 2, queue is empty and config at max
-                self.startup_congest = 0;
-                self.state = State::Steady;
-                Action::NoOp
-            }
-            (State::Startup, Signal::QueueCongest(rate, _latency), _) |
-            (State::Startup, Signal::RemoteCongest(rate, _latency), _) => {
-                // transition 3
-                // transition 7
-                if self.startup_congest > Adaptation::STARTUP_CONGEST_ENOUGH {
-                    self.startup_congest = 0;
-                    self.state = State::Degrade;
-                    Action::AdjustConfig(rate)
-                } else {
-                    self.startup_congest += 1;
-                    Action::NoOp
-                }
-            }
-            (State::Degrade, Signal::QueueCongest(rate, _latency), _) |
-            (State::Degrade, Signal::RemoteCongest(rate, _latency), _) => {
-                // transition 4
-                self.state = State::Degrade;
-                Action::AdjustConfig(rate)
-            }
-            (State::Degrade, Signal::QueueEmpty, _) => {
-                // transition 5
-                self.state = State::Steady;
-                Action::NoOp
-            }
-            (State::Steady, Signal::QueueCongest(rate, _latency), _) |
-            (State::Steady, Signal::RemoteCongest(rate, _latency), _) => {
-                // transition 6
-                self.steady_count = 0;
-                self.state = State::Degrade;
-                Action::AdjustConfig(rate)
-            }
-            (State::Steady, Signal::QueueEmpty, false) => {
-                // transition 7
-                if self.steady_count > Adaptation::STEADY_ENOUGH {
-                    self.steady_count = 0;
-                    self.state = State::Probe;
-                    Action::StartProbe
-                } else {
-                    self.steady_count += 1;
-                    Action::NoOp
-                }
-            }
-            (State::Probe, Signal::QueueCongest(_rate, _latency), _) |
-            (State::Probe, Signal::RemoteCongest(_rate, _latency), _) => {
-                // transtion 8
-                self.state = State::Steady;
-                Action::StopProbe
-            }
-            (State::Probe, Signal::ProbeDone, _) => {
-                // transition 9
-                self.state = State::Steady;
-                Action::AdvanceConfig
-            }
-            (State::Probe, Signal::QueueEmpty, _) => {
-                // transition 10
-                Action::IncreaseProbePace
-            }
-            (State::Steady, Signal::QueueEmpty, true) => {
-                // The right state to stay in for as long as possible
-                Action::NoOp
-            }
-            _ => {
-                error!("Unhandled state {:?} and signal {:?}", self.state, signal);
-                unimplemented!{}
-            }
+        let (action, warning) = self.inner.react(signal, max_config);
+        if let Some(stray) = warning {
+            warn!("ignored {:?} in state {:?}", stray.signal, stray.state);
+        }
+        info!("state: {:?}, action: {:?}", self.inner.state(), action);
+
+        let event = AdaptEvent {
+            ts: Utc::now(),
+            signal,
+            max_config,
+            from,
+            to: self.inner.state(),
+            action,
+            warning,
         };
-        info!("state: {:?}, action: {:?}", self.state, action);
+        self.subscribers.retain(|s| s.unbounded_send(event).is_ok());
+        self.undecided = Some(event);
         action
     }
+
+    /// Completes the last decision with the `level` (of `rate` kbps) it leads
+    /// to, and writes it to the decision log, if any.
+    pub fn decided(&mut self, level: usize, rate: f64) {
+        if self.level.map_or(true, |(last, _)| last != level) {
+            self.level = Some((level, Instant::now()));
+        }
+        let event = match self.undecided.take() {
+            Some(event) => event,
+            None => return,
+        };
+        let millis = f64::from(event.ts.timestamp_subsec_millis());
+        let decision = AdaptDecision {
+            timestamp: event.ts.timestamp() as f64 + millis / 1000.0,
+            state: format!("{:?}", event.to),
+            signal: format!("{:?}", event.signal),
+            action: format!("{:?}", event.action),
+            level,
+            rate,
+        };
+        let stopped = match self.decisions {
+            Some(ref log) => log.unbounded_send(decision).is_err(),
+            None => false,
+        };
+        if stopped {
+            error!("decision log has stopped");
+            self.decisions = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+    use futures::sync::mpsc::unbounded;
+
+    #[test]
+    fn subscribers_receive_events() {
+        let mut adaptation = Adaptation::default();
+        let (tx, rx) = unbounded();
+        adaptation.subscribe(tx);
+
+        adaptation.transit(Signal::QueueEmpty, false);
+        adaptation.transit(Signal::QueueEmpty, true);
+        drop(adaptation);
+
+        let events = rx.wait().map(|e| e.unwrap()).collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        match (events[0].from, events[0].action) {
+            (State::Startup, Action::AdvanceConfig) => {}
+            other => panic!("unexpected event {:?}", other),
+        }
+        match (events[1].to, events[1].action) {
+            (State::Steady, Action::NoOp) => {}
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decisions_carry_the_level() {
+        let mut adaptation = Adaptation::default();
+        let (tx, rx) = unbounded();
+        adaptation.log_decisions(tx);
+
+        // Only decisions completed with their level are written.
+        adaptation.transit(Signal::QueueEmpty, false);
+        adaptation.decided(1, 500.0);
+        adaptation.decided(2, 1000.0);
+        adaptation.transit(Signal::ComputeCongest(400.0, 80.0), false);
+        adaptation.decided(0, 200.0);
+        adaptation.transit(Signal::QueueEmpty, false);
+        drop(adaptation);
+
+        let decisions = rx.wait().map(|d| d.unwrap()).collect::<Vec<_>>();
+        let rows = decisions
+            .iter()
+            .map(|d| (d.state.as_str(), d.signal.as_str(), d.action.as_str(), d.level, d.rate))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![
+                ("Startup", "QueueEmpty", "AdvanceConfig", 1, 500.0),
+                ("Degrade", "ComputeCongest(400.0, 80.0)", "AdjustConfig(400.0)", 0, 200.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn holds_a_level_for_its_dwell_time() {
+        let mut adaptation = Adaptation::default();
+        adaptation.set_min_dwell(Duration::from_secs(60));
+        let congest = Signal::ComputeCongest(400.0, 80.0);
+
+        // Startup advances on every empty queue.
+        for level in 1..4 {
+            match adaptation.transit(Signal::QueueEmpty, false) {
+                Action::AdvanceConfig => adaptation.decided(level, 0.0),
+                action => panic!("unexpected {:?}", action),
+            }
+        }
+
+        // Once degraded, the new level is held: no probe however steady.
+        adaptation.transit(congest, false);
+        adaptation.decided(1, 0.0);
+        for _ in 0..10 {
+            match adaptation.transit(Signal::QueueEmpty, false) {
+                Action::NoOp => adaptation.decided(1, 0.0),
+                action => panic!("unexpected {:?}", action),
+            }
+        }
+        assert_eq!(adaptation.state(), State::Steady);
+
+        // It still degrades at once.
+        match adaptation.transit(congest, false) {
+            Action::AdjustConfig(_) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+    }
 }
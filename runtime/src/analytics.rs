@@ -1,86 +1,281 @@
+use super::AccuracyReport;
+use super::accuracy_model::{AccuracyModel, AccuracyModelConfig, FrameMeta};
 use super::errors::*;
-use super::evaluation::{self, FrameStat, f1, precision, recall};
+use super::evaluation::{FrameRange, Manifest, Stat, StatIndex, Wraparound, f1, precision, recall};
 use super::profile::Profile;
-use super::video::{self, VideoConfig};
+use super::video::VideoConfig;
+use csv;
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::vec::Vec;
 
 #[derive(Clone)]
 pub struct VideoAnalytics {
     inner: Arc<Mutex<Inner>>,
 }
 
+/// What the analytics achieves on a frame: its accuracy and, unless an
+/// accuracy model scores it, how its detections compare with the ground truth.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameResult {
+    /// Accuracy (F1, or what the model scores) of the frame.
+    pub accuracy: f64,
+
+    /// How the detections compare with the ground truth.
+    pub stat: Option<Stat>,
+}
+
+/// What the analytics achieves on several frames: F1 over their summed
+/// statistics or, if a model scores them, their mean accuracy. Frames the
+/// model doesn't cover (NaN) are left out of the mean.
+#[derive(Clone, Copy)]
+struct Tally {
+    frames: usize,
+    stat: Stat,
+    scored: f64,
+    scored_frames: usize,
+}
+
+impl Default for Tally {
+    fn default() -> Tally {
+        Tally {
+            frames: 0,
+            stat: empty_stat(),
+            scored: 0.0,
+            scored_frames: 0,
+        }
+    }
+}
+
+impl Tally {
+    fn add(&mut self, result: FrameResult) {
+        self.frames += 1;
+        match result.stat {
+            Some(stat) => accumulate(&mut self.stat, stat),
+            None if result.accuracy.is_nan() => {}
+            None => {
+                self.scored += result.accuracy;
+                self.scored_frames += 1;
+            }
+        }
+    }
+
+    fn accuracy(&self, model: bool) -> f64 {
+        if model {
+            self.scored / self.scored_frames as f64
+        } else {
+            stat_to_f1(self.stat)
+        }
+    }
+}
+
 struct Inner {
-    frame_stats: Vec<FrameStat>,
+    /// Per-frame statistics, looked up by frame and configuration. Empty with
+    /// an accuracy model.
+    frame_stats: StatIndex,
+    model: Option<AccuracyModel>,
     profile: Profile<VideoConfig>,
 
-    logs: Vec<(usize, usize)>,
+    /// The frames of the dataset, and what becomes of those past its end.
+    /// Unknown with an accuracy model and no manifest.
+    range: Option<FrameRange>,
+
+    /// What the entries received since the last `accuracy` call achieve.
+    achieved: Tally,
+
+    /// Accumulated statistics the client expects (attached to the data).
+    expected: Stat,
+
+    /// What the frames achieve per level over the whole run.
+    levels: BTreeMap<usize, Tally>,
+
+    /// What the frames achieve per level since the last `level_accuracy`
+    /// call.
+    recent: BTreeMap<usize, Tally>,
 }
 
-/// This is a temporary hack to match two types (despite they have the same
-/// fields).
-fn match_config(a: video::VideoConfig, b: evaluation::VideoConfig) -> bool {
-    a.width == b.width && a.skip == b.skip && a.quant == b.quant
+fn empty_stat() -> Stat {
+    Stat {
+        true_positive: 0,
+        false_positive: 0,
+        false_negative: 0,
+    }
+}
+
+fn accumulate(sum: &mut Stat, stat: Stat) {
+    sum.true_positive += stat.true_positive;
+    sum.false_positive += stat.false_positive;
+    sum.false_negative += stat.false_negative;
+}
+
+pub(crate) fn stat_to_f1(s: Stat) -> f64 {
+    let p = precision(s.true_positive, s.false_positive);
+    let r = recall(s.true_positive, s.false_negative);
+    f1(p, r)
 }
 
 impl VideoAnalytics {
-    pub fn new<P: AsRef<Path>>(profile: P, stat: P) -> VideoAnalytics {
-        let frame_stats: Vec<FrameStat> = FrameStat::from_csv(stat);
+    /// Creates the analytics of a connection. With an accuracy `model`, the
+    /// per-frame stats (`stat`) are not loaded. The dataset is as long as the
+    /// `manifest` tells, or the stats cover; frames past it follow `policy`.
+    pub fn new<P: AsRef<Path>>(
+        profile: P,
+        stat: P,
+        model: Option<&AccuracyModelConfig>,
+        manifest: Option<P>,
+        policy: Wraparound,
+    ) -> VideoAnalytics {
+        let (frame_stats, model) = match model {
+            Some(config) => {
+                let model = AccuracyModel::load(config).unwrap_or_else(|e| {
+                    panic!("failed to load accuracy model {:?}: {}", config, e)
+                });
+                (StatIndex::default(), Some(model))
+            }
+            None => {
+                let frame_stats = StatIndex::open(&stat).unwrap_or_else(|e| {
+                    panic!("failed to load stats {:?}: {}", stat.as_ref(), e)
+                });
+                (frame_stats, None)
+            }
+        };
+        let range = match manifest {
+            Some(path) => {
+                let manifest = Manifest::load(&path).unwrap_or_else(|e| {
+                    panic!("failed to load manifest {:?}: {}", path.as_ref(), e)
+                });
+                let range = FrameRange::from_manifest(&manifest, policy);
+                Some(range.unwrap_or_else(|e| panic!("manifest {:?}: {}", path.as_ref(), e)))
+            }
+            None if model.is_none() => {
+                let range = FrameRange::new(frame_stats.frames(), policy);
+                Some(range.unwrap_or_else(|e| panic!("stats {:?}: {}", stat.as_ref(), e)))
+            }
+            None => None,
+        };
         let profile: Profile<VideoConfig> = Profile::new(profile);
         let inner = Inner {
-            frame_stats: frame_stats,
-            profile: profile,
-            logs: Vec::new(),
+            frame_stats,
+            model,
+            profile,
+            range,
+            achieved: Tally::default(),
+            expected: empty_stat(),
+            levels: BTreeMap::new(),
+            recent: BTreeMap::new(),
         };
 
         VideoAnalytics { inner: Arc::new(Mutex::new(inner)) }
     }
 
-    pub fn add(&mut self, frame_num: usize, level: usize) -> Result<()> {
+    /// Processes frame `frame_num` of `size` bytes at `level` and returns what
+    /// the analytics achieves on it, or `None` if the frame is past the end of
+    /// the dataset and truncated.
+    pub fn add(
+        &mut self,
+        frame_num: usize,
+        level: usize,
+        size: usize,
+    ) -> Result<Option<FrameResult>> {
         let mut m = self.inner.lock()?;
-        (*m).logs.push((frame_num, level));
-        Ok(())
+        let frame_num = match m.range.map(|range| range.map(frame_num)) {
+            Some(Some(frame_num)) => frame_num,
+            Some(None) => return Ok(None),
+            None => frame_num,
+        };
+        let result = match m.model {
+            Some(ref model) => FrameResult {
+                accuracy: model.accuracy(&FrameMeta { frame_num, level, size }),
+                stat: None,
+            },
+            None => {
+                let config = m.profile.n_th(level);
+                let stat = m.frame_stats.get(frame_num, config.into()).expect(
+                    "failed to find",
+                );
+                FrameResult {
+                    accuracy: stat_to_f1(stat),
+                    stat: Some(stat),
+                }
+            }
+        };
+        m.achieved.add(result);
+        m.levels.entry(level).or_insert_with(Tally::default).add(result);
+        m.recent.entry(level).or_insert_with(Tally::default).add(result);
+        Ok(Some(result))
+    }
+
+    /// Returns the accuracy achieved at each level since the last call.
+    pub fn level_accuracy(&self) -> Result<AccuracyReport> {
+        let mut m = self.inner.lock()?;
+        let model = m.model.is_some();
+        let recent = ::std::mem::take(&mut m.recent);
+        let levels = recent
+            .into_iter()
+            .map(|(level, tally)| (level, tally.accuracy(model)))
+            .collect();
+        Ok(AccuracyReport { levels })
     }
 
+    /// Returns the accuracy achieved since the last call.
     pub fn accuracy(&self) -> Result<f64> {
         let mut m = self.inner.lock()?;
-        Ok((*m).accuracy())
+        let achieved = ::std::mem::take(&mut m.achieved);
+        Ok(achieved.accuracy(m.model.is_some()))
     }
-}
 
-impl Inner {
-    pub fn accuracy(&mut self) -> f64 {
-        // for each log entry, find stat according to the profile
-        let per_frame_stats = self.logs
-            .iter()
-            .map(|entry| {
-                let (frame, level) = *entry;
-                let config = self.profile.n_th(level);
-
-                let frame_stat = self.frame_stats.iter().find(|i| {
-                    i.frame_num == frame && match_config(config, i.config)
-                });
-                frame_stat.expect("failed to find").stat
-            })
-            .collect::<Vec<_>>();
-        let true_positive = per_frame_stats
-            .iter()
-            .map(|i| i.true_positive)
-            .sum::<usize>();
-        let false_positive = per_frame_stats
-            .iter()
-            .map(|i| i.false_positive)
-            .sum::<usize>();
-        let false_negative = per_frame_stats
-            .iter()
-            .map(|i| i.false_negative)
-            .sum::<usize>();
-
-        let p = precision(true_positive, false_positive);
-        let r = recall(true_positive, false_negative);
-
-        self.logs.clear();
-        f1(p, r)
+    /// Adds the accuracy statistics the client expects for a received datum.
+    pub fn add_expected(&mut self, stat: Stat) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        accumulate(&mut m.expected, stat);
+        Ok(())
+    }
+
+    /// Returns the accuracy the client expects since the last call.
+    pub fn expected_accuracy(&self) -> Result<f64> {
+        let mut m = self.inner.lock()?;
+        let e = ::std::mem::replace(&mut m.expected, empty_stat());
+        Ok(stat_to_f1(e))
+    }
+
+    /// Returns how many frames are received at each level so far.
+    pub fn level_counts(&self) -> Result<BTreeMap<usize, usize>> {
+        let m = self.inner.lock()?;
+        Ok(m.levels.iter().map(|(&level, tally)| (level, tally.frames)).collect())
+    }
+
+    /// Writes how many frames are received at each level and the accuracy
+    /// each level delivers over the whole run.
+    pub fn write_summary<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let m = self.inner.lock()?;
+        let total = m.levels.values().map(|tally| tally.frames).sum::<usize>();
+
+        let mut writer = csv::Writer::from_path(path)?;
+        let header = (
+            "level",
+            "width",
+            "skip",
+            "quant",
+            "frames",
+            "fraction",
+            "accuracy",
+        );
+        writer.serialize(header)?;
+        for (&level, tally) in &m.levels {
+            let config = m.profile.n_th(level);
+            let fraction = tally.frames as f64 / total as f64;
+            let entry = (
+                level,
+                config.width,
+                config.skip,
+                config.quant,
+                tally.frames,
+                fraction,
+                tally.accuracy(m.model.is_some()),
+            );
+            writer.serialize(entry)?;
+        }
+        writer.flush()?;
+        Ok(())
     }
 }
@@ -0,0 +1,256 @@
+//! Probing in the style of BBR. The dummy probes of `ProbeTracker` carry
+//! nothing; this prober probes with video instead. The client keeps windowed
+//! estimates of the bottleneck bandwidth (the max delivery rate) and of the
+//! round trip (the min RTT) from the receiver's delivery acks. While probing,
+//! the source cycles a pacing gain over these estimates, each phase lasting a
+//! round trip: it sends a higher level than its own to probe, a lower one to
+//! drain the queue it may have built, and its own otherwise. The probe is done
+//! once the estimated bandwidth fits the level probed for.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Pacing gains of one cycle, as BBR's ProbeBW.
+pub const PACING_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+
+/// Probing with video (see the `bbr` module).
+///
+/// ```toml
+/// [probing.bbr]
+/// bw_window = 2000
+/// rtt_window = 10000
+/// ```
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct BbrConfig {
+    /// Window (ms) of the max delivery rate.
+    #[serde(default = "default_bw_window")]
+    pub bw_window: u64,
+
+    /// Window (ms) of the min RTT.
+    #[serde(default = "default_rtt_window")]
+    pub rtt_window: u64,
+}
+
+fn default_bw_window() -> u64 {
+    2000
+}
+
+fn default_rtt_window() -> u64 {
+    10_000
+}
+
+impl Default for BbrConfig {
+    fn default() -> BbrConfig {
+        BbrConfig {
+            bw_window: default_bw_window(),
+            rtt_window: default_rtt_window(),
+        }
+    }
+}
+
+/// The max (or min) of the samples within a window of time.
+struct WindowedFilter {
+    window: Duration,
+    max: bool,
+    samples: VecDeque<(Instant, f64)>,
+}
+
+impl WindowedFilter {
+    fn new(window: Duration, max: bool) -> WindowedFilter {
+        WindowedFilter {
+            window,
+            max,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Samples dominated by a newer one can never be the best again, so only
+    /// the others are kept.
+    fn add(&mut self, now: Instant, value: f64) {
+        let max = self.max;
+        let dominated = |v: f64| if max { v <= value } else { v >= value };
+        while self.samples.back().map_or(false, |&(_, v)| dominated(v)) {
+            self.samples.pop_back();
+        }
+        self.samples.push_back((now, value));
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.samples.front().map_or(false, |&(at, _)| now - at > self.window) {
+            self.samples.pop_front();
+        }
+    }
+
+    fn best(&self) -> Option<f64> {
+        self.samples.front().map(|&(_, v)| v)
+    }
+}
+
+struct Estimates {
+    bw: WindowedFilter,
+    rtt: WindowedFilter,
+}
+
+/// The bandwidth and RTT estimates, shared between the monitor that samples
+/// them and the source that paces on them.
+#[derive(Clone)]
+pub struct BbrEstimate {
+    inner: Arc<Mutex<Estimates>>,
+}
+
+impl BbrEstimate {
+    pub fn new(config: &BbrConfig) -> BbrEstimate {
+        let estimates = Estimates {
+            bw: WindowedFilter::new(Duration::from_millis(config.bw_window), true),
+            rtt: WindowedFilter::new(Duration::from_millis(config.rtt_window), false),
+        };
+        BbrEstimate { inner: Arc::new(Mutex::new(estimates)) }
+    }
+
+    /// Adds a delivery rate (kbps) and, if known, an RTT (ms) sampled at
+    /// `now`.
+    pub fn add(&self, now: Instant, rate: f64, rtt: Option<f64>) {
+        let mut estimates = self.inner.lock().expect("failed to update bbr estimates");
+        estimates.bw.add(now, rate);
+        match rtt {
+            Some(rtt) => estimates.rtt.add(now, rtt),
+            None => estimates.rtt.expire(now),
+        }
+    }
+
+    /// Returns the max delivery rate (kbps) within the window.
+    pub fn max_bw(&self) -> Option<f64> {
+        self.inner.lock().expect("failed to read bbr estimates").bw.best()
+    }
+
+    /// Returns the min RTT (ms) within the window.
+    pub fn min_rtt(&self) -> Option<f64> {
+        self.inner.lock().expect("failed to read bbr estimates").rtt.best()
+    }
+}
+
+/// The probing side: cycles the pacing gain while a probe is on, and picks the
+/// level every datum is sent at.
+pub struct BbrProber {
+    estimate: BbrEstimate,
+
+    /// The rate (kbps) the probe is for, i.e. of the level probed for.
+    target: Option<f64>,
+
+    /// Index into `PACING_GAINS`, and when its phase started.
+    phase: usize,
+    phase_start: Instant,
+}
+
+impl BbrProber {
+    pub fn new(estimate: BbrEstimate) -> BbrProber {
+        BbrProber {
+            estimate,
+            target: None,
+            phase: 0,
+            phase_start: Instant::now(),
+        }
+    }
+
+    /// Probes for `additional` kbps above `current` (kbps), the rate of the
+    /// current level.
+    pub fn start_probe(&mut self, current: f64, additional: f64, now: Instant) {
+        self.target = Some(current + additional);
+        self.phase = 0;
+        self.phase_start = now;
+    }
+
+    pub fn stop_probe(&mut self) {
+        self.target = None;
+    }
+
+    pub fn is_probing(&self) -> bool {
+        self.target.is_some()
+    }
+
+    /// Whether the probe goes on, i.e. the estimated bandwidth doesn't fit
+    /// the level probed for yet.
+    pub fn inc_pace(&self) -> bool {
+        match (self.target, self.estimate.max_bw()) {
+            (Some(target), Some(bw)) => bw < target,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Returns the pacing gain at `now`, moving to the next phase after a
+    /// round trip (or a tick of `period` ms, if longer).
+    pub fn gain(&mut self, now: Instant, period: u64) -> f64 {
+        if self.target.is_none() {
+            return 1.0;
+        }
+        let rtt = self.estimate.min_rtt().unwrap_or(0.0).max(period as f64);
+        if now - self.phase_start >= Duration::from_millis(rtt as u64) {
+            self.phase = (self.phase + 1) % PACING_GAINS.len();
+            self.phase_start = now;
+        }
+        PACING_GAINS[self.phase]
+    }
+
+    /// Returns the level to send at, given the rates (kbps) of all levels, the
+    /// current one and the pacing gain. A gain above 1 sends at least a level
+    /// higher, one below 1 at most a level lower, so that levels coarser than
+    /// the gain still probe and drain.
+    pub fn level(&self, rates: &[f64], current: usize, gain: f64) -> usize {
+        let bw = match self.estimate.max_bw() {
+            Some(bw) if gain != 1.0 => bw,
+            _ => return current,
+        };
+        let highest = rates.len().saturating_sub(1);
+        let fit = rates.iter().rposition(|&r| r <= gain * bw).unwrap_or(0);
+        if gain > 1.0 {
+            fit.max(current + 1).min(highest)
+        } else {
+            fit.min(current).max(current.saturating_sub(1))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gain_cycles_over_windowed_estimates() {
+        let config = BbrConfig {
+            bw_window: 1000,
+            rtt_window: 1000,
+        };
+        let estimate = BbrEstimate::new(&config);
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        // The max and min hold within their windows, and expire after.
+        estimate.add(at(0), 1000.0, Some(80.0));
+        estimate.add(at(100), 600.0, Some(120.0));
+        assert_eq!(estimate.max_bw(), Some(1000.0));
+        assert_eq!(estimate.min_rtt(), Some(80.0));
+        estimate.add(at(1050), 800.0, None);
+        assert_eq!(estimate.max_bw(), Some(800.0));
+        assert_eq!(estimate.min_rtt(), Some(120.0));
+
+        // 1.25 x 800 kbps fits level 2; each phase lasts a round trip.
+        let rates = [200.0, 500.0, 1000.0, 2000.0];
+        let mut prober = BbrProber::new(estimate.clone());
+        assert_eq!(prober.gain(at(1050), 100), 1.0);
+        prober.start_probe(500.0, 500.0, at(1050));
+        let gain = prober.gain(at(1100), 100);
+        assert_eq!((gain, prober.level(&rates, 1, gain)), (1.25, 2));
+        let gain = prober.gain(at(1200), 100);
+        assert_eq!((gain, prober.level(&rates, 2, gain)), (0.75, 1));
+        let gain = prober.gain(at(1330), 100);
+        assert_eq!((gain, prober.level(&rates, 1, gain)), (1.0, 1));
+
+        // Done once the delivery rate fits the level probed for.
+        assert!(prober.inc_pace());
+        estimate.add(at(1330), 1000.0, None);
+        assert!(!prober.inc_pace());
+    }
+}
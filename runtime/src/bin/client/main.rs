@@ -14,6 +14,7 @@ extern crate log;
 
 use awstream::*;
 use std::env;
+use std::process;
 
 pub fn main() {
     let format = |record: &log::LogRecord| {
@@ -35,7 +36,34 @@ pub fn main() {
 
     builder.init().unwrap();
 
-    // Client runs
     let setting = Setting::init("Setting.toml").unwrap();
+
+    // Connect to one of the experiments the server hosts with
+    // `EXPERIMENT=<name>`.
+    let setting = match env::var("EXPERIMENT") {
+        Ok(name) => setting.experiment(&name).unwrap_or_else(|| {
+            eprintln!("no experiment {} in Setting.toml", name);
+            process::exit(1);
+        }),
+        Err(_) => setting,
+    };
+
+    // `client validate` only checks the experiment files and exits.
+    if env::args().nth(1).map_or(false, |arg| arg == "validate") {
+        let summary = validate::validate(&setting);
+        println!("{}", summary);
+        if !summary.is_ok() {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // `client load` runs the clients of the `[load]` section at once.
+    if env::args().nth(1).map_or(false, |arg| arg == "load") {
+        client::run_load(setting).unwrap();
+        return;
+    }
+
+    // Client runs
     client::run(setting).unwrap();
 }
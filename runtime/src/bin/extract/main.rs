@@ -0,0 +1,57 @@
+//! Extracts a time range of what the client has recorded (see `recorder` in
+//! the setting) into one file, in the format of a segment.
+//!
+//! ```text
+//! extract <dir> <from> <to> <out>
+//! ```
+//!
+//! `from` and `to` are unix timestamps (s), like those of the level log.
+
+extern crate awstream;
+extern crate chrono;
+
+use awstream::*;
+use chrono::{DateTime, TimeZone, Utc};
+use std::env;
+use std::process;
+
+fn parse_time(s: &str) -> DateTime<Utc> {
+    let secs = s.parse::<f64>().unwrap_or_else(|e| {
+        eprintln!("invalid timestamp {}: {}", s, e);
+        process::exit(2);
+    });
+    let nanos = (secs.fract() * 1e9) as u32;
+    Utc.timestamp_opt(secs.trunc() as i64, nanos).single().unwrap_or_else(|| {
+        eprintln!("timestamp out of range: {}", s);
+        process::exit(2);
+    })
+}
+
+pub fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 5 {
+        eprintln!("usage: {} <dir> <from> <to> <out>", args[0]);
+        process::exit(2);
+    }
+    let (from, to) = (parse_time(&args[2]), parse_time(&args[3]));
+    let recordings = extract_recordings(&args[1], from, to).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", args[1], e);
+        process::exit(1);
+    });
+    if let Err(e) = write_recordings(&args[4], &recordings) {
+        eprintln!("failed to write {}: {}", args[4], e);
+        process::exit(1);
+    }
+    let bytes = recordings.iter().map(|r| r.data.len()).sum::<usize>();
+    match (recordings.first(), recordings.last()) {
+        (Some(first), Some(last)) => println!(
+            "{} frames ({} to {}, {} bytes) written to {}",
+            recordings.len(),
+            first.frame_num,
+            last.frame_num,
+            bytes,
+            args[4]
+        ),
+        _ => println!("nothing recorded from {} to {}", from, to),
+    }
+}
@@ -0,0 +1,24 @@
+//! Packs a source file into a binary file that the client maps instead of
+//! parsing, so that clients on one machine share it (see `pack_source`).
+//!
+//! ```text
+//! pack <source.csv> <source.bin>
+//! ```
+
+extern crate awstream;
+
+use awstream::*;
+use std::env;
+use std::process;
+
+pub fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 3 {
+        eprintln!("usage: {} <source.csv> <source.bin>", args[0]);
+        process::exit(2);
+    }
+    if let Err(e) = pack_source(&args[1], &args[2]) {
+        eprintln!("failed to pack {}: {}", args[1], e);
+        process::exit(1);
+    }
+}
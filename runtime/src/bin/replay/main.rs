@@ -0,0 +1,70 @@
+//! Replays the server's report decisions (logged with `decision_dir`) against
+//! another threshold and trigger, to tune them offline.
+//!
+//! ```text
+//! replay decisions.csv [threshold.toml]
+//! ```
+//!
+//! `threshold.toml` has the fields of `report_threshold` (`bands` and
+//! `otherwise`) and optionally `report_trigger`; the server's defaults are
+//! used if not given.
+
+extern crate awstream;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+
+use awstream::*;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::process;
+
+#[derive(Deserialize, Default)]
+struct Tuning {
+    #[serde(flatten)]
+    threshold: ReportThreshold,
+
+    #[serde(default)]
+    report_trigger: ReportTrigger,
+}
+
+fn read_tuning(path: &str) -> Tuning {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .unwrap_or_else(|e| {
+            eprintln!("failed to read {}: {}", path, e);
+            process::exit(1);
+        });
+    toml::from_str(&contents).unwrap_or_else(|e| {
+        eprintln!("failed to parse {}: {}", path, e);
+        process::exit(1);
+    })
+}
+
+pub fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: {} <decisions.csv> [threshold.toml]", args[0]);
+        process::exit(2);
+    }
+    let tuning = match args.get(2) {
+        Some(path) => read_tuning(path),
+        None => Tuning::default(),
+    };
+    let decisions = read_decisions(&args[1]).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", args[1], e);
+        process::exit(1);
+    });
+
+    let (recorded, replayed, flipped) =
+        replay(&decisions, &tuning.threshold, tuning.report_trigger);
+    println!("threshold: {:?}", tuning.threshold);
+    println!("trigger: {:?}", tuning.report_trigger);
+    println!("{:>10} {:>10} {:>10} {:>10}", "", "decisions", "high", "reports");
+    for &(name, s) in &[("recorded", recorded), ("replayed", replayed)] {
+        println!("{:>10} {:>10} {:>10} {:>10}", name, s.decisions, s.high, s.reports);
+    }
+    println!("{} decisions flipped", flipped);
+}
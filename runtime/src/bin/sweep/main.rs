@@ -0,0 +1,147 @@
+//! Sweeps the controller's parameters over a simulation of a bandwidth trace
+//! (see `simulate`), to tune them offline. Writes one CSV row per combination
+//! of parameters, with the accuracy and latency it achieves, to stdout.
+//!
+//! ```text
+//! sweep <profile.csv> <trace.csv> [grid.toml]
+//! ```
+//!
+//! `trace.csv` has one `second, kbps` row per second, or is the name of a
+//! preset (e.g. `step_drop`, see `Preset`). `grid.toml` lists the values to
+//! try for every parameter, e.g. `steady = [1, 3, 5]`; parameters it does not
+//! list are swept over a few values around the client's. The PID controller
+//! with its default gains (see `simulate_pid`) is simulated on the same trace
+//! for comparison.
+
+extern crate awstream;
+extern crate csv;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+
+use awstream::*;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::process;
+
+#[derive(Deserialize)]
+struct Grid {
+    #[serde(default = "default_counts")]
+    startup_congest: Vec<usize>,
+
+    #[serde(default = "default_counts")]
+    steady: Vec<usize>,
+
+    #[serde(default = "default_counts")]
+    adjust_sticky: Vec<usize>,
+
+    #[serde(default = "default_probe_extra")]
+    probe_extra: Vec<f64>,
+
+    #[serde(default = "default_monitor_interval")]
+    monitor_interval: Vec<u64>,
+}
+
+fn default_counts() -> Vec<usize> {
+    vec![1, 3, 5]
+}
+
+fn default_probe_extra() -> Vec<f64> {
+    vec![1.0, 1.05, 1.2]
+}
+
+fn default_monitor_interval() -> Vec<u64> {
+    vec![50, 100, 200]
+}
+
+impl Grid {
+    /// Returns every combination of parameters.
+    fn params(&self) -> Vec<ControllerParams> {
+        let mut all = Vec::new();
+        for &startup_congest in &self.startup_congest {
+            for &steady in &self.steady {
+                for &adjust_sticky in &self.adjust_sticky {
+                    for &probe_extra in &self.probe_extra {
+                        for &monitor_interval in &self.monitor_interval {
+                            all.push(ControllerParams {
+                                startup_congest,
+                                steady,
+                                adjust_sticky,
+                                probe_extra,
+                                monitor_interval,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        all
+    }
+}
+
+fn read_grid(path: Option<&String>) -> Grid {
+    let mut contents = String::new();
+    if let Some(path) = path {
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .unwrap_or_else(|e| {
+                eprintln!("failed to read {}: {}", path, e);
+                process::exit(1);
+            });
+    }
+    toml::from_str(&contents).unwrap_or_else(|e| {
+        eprintln!("failed to parse grid: {}", e);
+        process::exit(1);
+    })
+}
+
+pub fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("usage: {} <profile.csv> <trace.csv> [grid.toml]", args[0]);
+        process::exit(2);
+    }
+    let (rates, accuracies) = read_profile(&args[1]);
+    let trace = read_trace(&args[2]).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", args[2], e);
+        process::exit(1);
+    });
+    let grid = read_grid(args.get(3));
+
+    let mut writer = csv::Writer::from_writer(io::stdout());
+    writer
+        .write_record(&[
+            "startup_congest",
+            "steady",
+            "adjust_sticky",
+            "probe_extra",
+            "monitor_interval",
+            "accuracy",
+            "latency",
+            "p95_latency",
+            "level_changes",
+        ])
+        .expect("failed to write header");
+    for p in grid.params() {
+        let r = simulate(&rates, &accuracies, &trace, &p);
+        let row = (
+            p.startup_congest,
+            p.steady,
+            p.adjust_sticky,
+            p.probe_extra,
+            p.monitor_interval,
+            r.accuracy,
+            r.latency,
+            r.p95_latency,
+            r.level_changes,
+        );
+        writer.serialize(row).expect("failed to write row");
+    }
+    writer.flush().expect("failed to write rows");
+    eprintln!("default: {:?}", ControllerParams::default());
+    let gains = PidGains::default();
+    let interval = ControllerParams::default().monitor_interval;
+    let pid = simulate_pid(&rates, &accuracies, &trace, gains, interval);
+    eprintln!("pid {:?}: {:?}", gains, pid);
+}
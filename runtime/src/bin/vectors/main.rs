@@ -0,0 +1,52 @@
+//! Writes or checks the golden test vectors of the codec (see
+//! `write_vectors`), for other implementations to validate against.
+//!
+//! ```text
+//! vectors write <dir>
+//! vectors check <dir>
+//! ```
+//!
+//! `check` prints what doesn't match and fails if anything doesn't.
+
+extern crate awstream;
+
+use awstream::*;
+use std::env;
+use std::process;
+
+fn usage(program: &str) -> ! {
+    eprintln!("usage: {} write|check <dir>", program);
+    process::exit(2);
+}
+
+pub fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 3 {
+        usage(&args[0]);
+    }
+    let dir = &args[2];
+    match args[1].as_str() {
+        "write" => match write_vectors(dir) {
+            Ok(vectors) => println!("{} vectors written into {}", vectors.len(), dir),
+            Err(e) => {
+                eprintln!("failed to write vectors into {}: {}", dir, e);
+                process::exit(1);
+            }
+        },
+        "check" => match check_vectors(dir) {
+            Ok(ref mismatches) if mismatches.is_empty() => println!("all vectors in {} match", dir),
+            Ok(mismatches) => {
+                for m in &mismatches {
+                    println!("{}", m);
+                }
+                eprintln!("{} vectors do not match", mismatches.len());
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("failed to check vectors in {}: {}", dir, e);
+                process::exit(1);
+            }
+        },
+        _ => usage(&args[0]),
+    }
+}
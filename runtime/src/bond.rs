@@ -0,0 +1,368 @@
+//! Bonding: the client sends over several connections at once (e.g. over the
+//! cellular and the WiFi interface) and stripes live data across them; the
+//! server merges what its other connections receive into the primary one's,
+//! back in sequence.
+//!
+//! Every connection of a bond starts with a join datum (see `AsDatum::join`)
+//! with the ID of the bond. Control data stay on the primary connection, which
+//! also carries the server's reports.
+
+use super::AsDatum;
+use super::AsDatumType;
+use errors::*;
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Out-of-order data held per stream before giving up on the gap (which may
+/// be data the client dropped) and passing them on.
+pub const REASSEMBLY_WINDOW: usize = 16;
+
+/// How long a bond waits for its primary connection before it's given up on
+/// and what its other connections have received is lost.
+pub const BOND_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Returns true if `d` is striped across the connections of a bond: live and
+/// historical data.
+pub fn striped(d: &AsDatum) -> bool {
+    match d.datum_type() {
+        AsDatumType::Historical(_, _) => true,
+        _ => d.live_payload(),
+    }
+}
+
+/// Sends over several paths. Striped data (see `striped`) go to the paths in
+/// turn, skipping those that are full; everything else goes to the first
+/// (primary) path.
+pub struct Striped<S> {
+    paths: Vec<S>,
+    next: usize,
+}
+
+impl<S> Striped<S> {
+    /// Creates a sink over `paths`, the primary one first.
+    pub fn new(paths: Vec<S>) -> Striped<S> {
+        assert!(!paths.is_empty(), "no path to send over");
+        Striped { paths, next: 0 }
+    }
+}
+
+impl<S> Sink for Striped<S>
+where
+    S: Sink<SinkItem = AsDatum, SinkError = Error>,
+{
+    type SinkItem = AsDatum;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: AsDatum) -> StartSend<AsDatum, Error> {
+        if !striped(&item) {
+            return self.paths[0].start_send(item);
+        }
+        let n = self.paths.len();
+        let mut item = item;
+        for i in 0..n {
+            let path = (self.next + i) % n;
+            match self.paths[path].start_send(item)? {
+                AsyncSink::Ready => {
+                    self.next = (path + 1) % n;
+                    return Ok(AsyncSink::Ready);
+                }
+                AsyncSink::NotReady(back) => item = back,
+            }
+        }
+        Ok(AsyncSink::NotReady(item))
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        let mut ready = true;
+        for path in &mut self.paths {
+            ready &= path.poll_complete()?.is_ready();
+        }
+        if ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+struct Bond {
+    tx: UnboundedSender<AsDatum>,
+    rx: Option<UnboundedReceiver<AsDatum>>,
+    since: Instant,
+}
+
+impl Bond {
+    fn new() -> Bond {
+        let (tx, rx) = unbounded();
+        Bond {
+            tx,
+            rx: Some(rx),
+            since: Instant::now(),
+        }
+    }
+}
+
+/// The bonds of an experiment's connections, by ID. Connections of a bond may
+/// join in any order, but bonds whose primary connection doesn't come within
+/// a deadline are evicted and counted as lost.
+#[derive(Clone)]
+pub struct Bonds {
+    bonds: Arc<Mutex<HashMap<u64, Bond>>>,
+    deadline: Duration,
+    lost: Arc<AtomicUsize>,
+}
+
+impl Default for Bonds {
+    fn default() -> Bonds {
+        Bonds::with_deadline(BOND_DEADLINE)
+    }
+}
+
+impl Bonds {
+    /// Creates an empty set of bonds.
+    pub fn new() -> Bonds {
+        Bonds::default()
+    }
+
+    /// Creates an empty set of bonds that wait for their primary connection
+    /// for `deadline`.
+    pub fn with_deadline(deadline: Duration) -> Bonds {
+        Bonds {
+            bonds: Arc::new(Mutex::new(HashMap::new())),
+            deadline,
+            lost: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the number of bonds evicted without a primary connection.
+    pub fn lost(&self) -> usize {
+        self.lost.load(Ordering::SeqCst)
+    }
+
+    /// Evicts the bonds that have waited for their primary connection past
+    /// the deadline. Their other connections fail to hand on what they
+    /// receive, and end.
+    fn evict(&self, bonds: &mut HashMap<u64, Bond>) {
+        let deadline = self.deadline;
+        let before = bonds.len();
+        bonds.retain(|id, bond| {
+            let incomplete = bond.rx.is_some() && bond.since.elapsed() >= deadline;
+            if incomplete {
+                warn!("bond {} has no primary connection after {:?}, evict it", id, deadline);
+            }
+            !incomplete
+        });
+        self.lost.fetch_add(before - bonds.len(), Ordering::SeqCst);
+    }
+
+    /// Returns what the other connections of bond `id` receive, for its
+    /// primary connection. The bond ends when that is dropped.
+    pub fn primary(&self, id: u64) -> Result<Bonded> {
+        let mut bonds = self.bonds.lock()?;
+        self.evict(&mut bonds);
+        let rx = bonds
+            .entry(id)
+            .or_insert_with(Bond::new)
+            .rx
+            .take()
+            .unwrap_or_else(|| {
+                warn!("bond {} already has a primary connection", id);
+                unbounded().1
+            });
+        Ok(Bonded {
+            id,
+            bonds: self.clone(),
+            rx,
+        })
+    }
+
+    /// Returns where another connection of bond `id` hands what it receives.
+    pub fn join(&self, id: u64) -> Result<UnboundedSender<AsDatum>> {
+        let mut bonds = self.bonds.lock()?;
+        self.evict(&mut bonds);
+        Ok(bonds.entry(id).or_insert_with(Bond::new).tx.clone())
+    }
+
+    fn leave(&self, id: u64) -> Result<()> {
+        self.bonds.lock()?.remove(&id);
+        Ok(())
+    }
+}
+
+/// What the other connections of a bond receive (see `Bonds::primary`).
+pub struct Bonded {
+    id: u64,
+    bonds: Bonds,
+    rx: UnboundedReceiver<AsDatum>,
+}
+
+impl Stream for Bonded {
+    type Item = AsDatum;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<AsDatum>, ()> {
+        self.rx.poll()
+    }
+}
+
+impl Drop for Bonded {
+    fn drop(&mut self) {
+        if let Err(e) = self.bonds.leave(self.id) {
+            error!("bond {} fails to leave: {}", self.id, e);
+        }
+    }
+}
+
+/// Puts the live data of every stream back in sequence (see `AsDatum::seq`).
+/// Data arriving behind a gap are held until it fills, or until more than
+/// `REASSEMBLY_WINDOW` of them wait; other data pass through. Sequences start
+/// at 0, so that data overtaking the first of their stream wait for it too.
+pub struct Reassemble<S> {
+    inner: S,
+    next: HashMap<u32, u64>,
+    pending: HashMap<u32, BTreeMap<u64, AsDatum>>,
+    ready: VecDeque<AsDatum>,
+    done: bool,
+}
+
+impl<S> Reassemble<S> {
+    /// Puts the data of `inner` back in sequence.
+    pub fn new(inner: S) -> Reassemble<S> {
+        Reassemble {
+            inner,
+            next: HashMap::new(),
+            pending: HashMap::new(),
+            ready: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn add(&mut self, datum: AsDatum) {
+        if !datum.live_payload() {
+            self.ready.push_back(datum);
+            return;
+        }
+        let (stream, seq) = (datum.stream_id(), datum.seq());
+        let next = *self.next.entry(stream).or_insert(0);
+        if seq < next {
+            // Behind a gap given up on already.
+            self.ready.push_back(datum);
+            return;
+        }
+        let pending = self.pending.entry(stream).or_insert_with(BTreeMap::new);
+        pending.insert(seq, datum);
+
+        let mut next = next;
+        loop {
+            let first = match pending.keys().next() {
+                Some(&first) => first,
+                None => break,
+            };
+            if first != next && pending.len() <= REASSEMBLY_WINDOW {
+                break;
+            }
+            let datum = pending.remove(&first).unwrap();
+            self.ready.push_back(datum);
+            next = first + 1;
+        }
+        self.next.insert(stream, next);
+    }
+
+    fn flush(&mut self) {
+        for (_, pending) in self.pending.drain() {
+            self.ready.extend(pending.into_iter().map(|(_, datum)| datum));
+        }
+    }
+}
+
+impl<S> Stream for Reassemble<S>
+where
+    S: Stream<Item = AsDatum>,
+{
+    type Item = AsDatum;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<AsDatum>, S::Error> {
+        loop {
+            if let Some(datum) = self.ready.pop_front() {
+                return Ok(Async::Ready(Some(datum)));
+            }
+            if self.done {
+                return Ok(Async::Ready(None));
+            }
+            match try_ready!(self.inner.poll()) {
+                Some(datum) => self.add(datum),
+                None => {
+                    self.done = true;
+                    self.flush();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, stream};
+
+    fn live(seq: u64) -> AsDatum {
+        let mut d = AsDatum::new(0, seq as usize, vec![0; 10]);
+        d.set_seq(seq);
+        d
+    }
+
+    #[test]
+    fn reassembles_in_sequence() {
+        let mut order = vec![0, 2, 1, 3, 5];
+        order.extend(6..(7 + REASSEMBLY_WINDOW as u64));
+        let data = order.into_iter().map(live).collect::<Vec<_>>();
+        let merged = Reassemble::new(stream::iter_ok::<_, ()>(data)).collect().wait().unwrap();
+        let seqs = merged.iter().map(|d| d.seq()).collect::<Vec<_>>();
+
+        // 4 never arrives: the gap is given up on once the window is full.
+        let mut expected = vec![0, 1, 2, 3];
+        expected.extend(5..(7 + REASSEMBLY_WINDOW as u64));
+        assert_eq!(seqs, expected);
+    }
+
+    #[test]
+    fn data_overtaking_the_first_wait_for_it() {
+        let data = vec![live(1), live(2), live(0)];
+        let merged = Reassemble::new(stream::iter_ok::<_, ()>(data)).collect().wait().unwrap();
+        assert_eq!(merged.iter().map(|d| d.seq()).collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn bonds_join_in_any_order() {
+        let bonds = Bonds::new();
+        let early = bonds.join(7).unwrap();
+        early.unbounded_send(live(1)).unwrap();
+        let bonded = bonds.primary(7).unwrap();
+        bonds.join(7).unwrap().unbounded_send(live(2)).unwrap();
+        drop(early);
+        let (first, bonded) = bonded.into_future().wait().map_err(|_| ()).unwrap();
+        assert_eq!(first.map(|d| d.seq()), Some(1));
+        drop(bonded);
+        assert!(bonds.bonds.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn incomplete_bonds_are_evicted() {
+        let bonds = Bonds::with_deadline(Duration::from_millis(0));
+        let orphan = bonds.join(1).unwrap();
+        orphan.unbounded_send(live(1)).unwrap();
+
+        let _bonded = bonds.primary(2).unwrap();
+        assert_eq!(bonds.lost(), 1);
+        assert!(orphan.unbounded_send(live(2)).is_err());
+
+        // A bond with its primary connection stays.
+        bonds.join(2).unwrap().unbounded_send(live(1)).unwrap();
+        assert_eq!(bonds.lost(), 1);
+    }
+}
@@ -0,0 +1,123 @@
+//! Budgets that change over the day, e.g. to conserve bandwidth during
+//! business hours. Every window of the schedule limits the levels from a time
+//! of day (local) until another; windows may wrap around midnight. Where
+//! windows overlap, the tightest limit applies. The client checks the
+//! schedule as it adapts, and limits its profile with
+//! `SimpleProfile::set_max_level`, so that the levels come back once the
+//! window ends.
+
+use chrono::NaiveTime;
+use profile::SimpleProfile;
+
+/// A window of the day with a limit on the levels.
+///
+/// ```toml
+/// [[budget_schedule]]
+/// from = "09:00:00"
+/// to = "17:00:00"
+/// max_rate = 1000.0
+/// ```
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct BudgetWindow {
+    /// Local time the window starts at (inclusive).
+    pub from: NaiveTime,
+
+    /// Local time the window ends at (exclusive), before `from` if it wraps
+    /// around midnight.
+    pub to: NaiveTime,
+
+    /// If set, no level above this rate (kbps) is used, but the lowest.
+    #[serde(default)]
+    pub max_rate: Option<f64>,
+
+    /// If set, no level above this one is used.
+    #[serde(default)]
+    pub max_level: Option<usize>,
+}
+
+impl BudgetWindow {
+    /// Whether the window covers the time of day `time`.
+    pub fn covers(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+
+    /// Returns the highest level of `profile` allowed in the window.
+    fn max_level(&self, profile: &SimpleProfile) -> usize {
+        let by_rate = self.max_rate.map_or(profile.len(), |max_rate| {
+            (0..profile.len())
+                .take_while(|&level| profile.rate_of(level).map_or(false, |r| r <= max_rate))
+                .count()
+        });
+        let by_level = self.max_level.map_or(profile.len(), |max| max + 1);
+        ::std::cmp::max(::std::cmp::min(by_rate, by_level), 1) - 1
+    }
+}
+
+/// Returns the highest level of `profile` the windows of `schedule` covering
+/// `time` allow, `None` if none covers it.
+pub fn max_level(
+    schedule: &[BudgetWindow],
+    time: NaiveTime,
+    profile: &SimpleProfile,
+) -> Option<usize> {
+    schedule
+        .iter()
+        .filter(|window| window.covers(time))
+        .map(|window| window.max_level(profile))
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    #[derive(Deserialize)]
+    struct Schedule {
+        budget_schedule: Vec<BudgetWindow>,
+    }
+
+    #[test]
+    fn windows_limit_levels_by_time_of_day() {
+        let schedule: Schedule = toml::from_str(
+            r#"
+            [[budget_schedule]]
+            from = "09:00:00"
+            to = "17:00:00"
+            max_rate = 250.0
+
+            [[budget_schedule]]
+            from = "22:00:00"
+            to = "06:00:00"
+            max_level = 0
+
+            [[budget_schedule]]
+            from = "12:00:00"
+            to = "13:00:00"
+            max_level = 2
+            "#,
+        ).unwrap();
+        let schedule = schedule.budget_schedule;
+        let profile = SimpleProfile::new(vec![100.0, 200.0, 400.0, 800.0]);
+        let at = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+
+        assert_eq!(max_level(&schedule, at(8, 59), &profile), None);
+        assert_eq!(max_level(&schedule, at(9, 0), &profile), Some(1));
+        // The tightest limit applies where windows overlap.
+        assert_eq!(max_level(&schedule, at(12, 30), &profile), Some(1));
+        assert_eq!(max_level(&schedule, at(17, 0), &profile), None);
+        assert_eq!(max_level(&schedule, at(23, 0), &profile), Some(0));
+        assert_eq!(max_level(&schedule, at(5, 59), &profile), Some(0));
+
+        // The lowest level stays, even above the budget.
+        let tight = BudgetWindow {
+            max_rate: Some(50.0),
+            ..schedule[0]
+        };
+        assert_eq!(max_level(&[tight], at(10, 0), &profile), Some(0));
+    }
+}
@@ -1,6 +1,8 @@
+use super::{ComputeReport, LatencyBreakdown, SequenceStats};
 use errors::*;
+use histogram::{Histogram, Snapshot};
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::{Arc, Mutex};
-use std::vec::Vec;
 
 #[derive(Clone)]
 pub struct BwMonitor {
@@ -41,41 +43,406 @@ impl BwMonitor {
     }
 }
 
+/// Latencies of an interval, kept in a histogram. `update` closes the
+/// interval; closed intervals accumulate into a window until `snapshot` takes
+/// it.
 #[derive(Clone)]
 pub struct LatencyMonitor {
     inner: Arc<Mutex<LatencyInner>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct LatencyInner {
-    sample: Vec<f64>,
-    rate: f64,
+    interval: Histogram,
+    last: Histogram,
+    window: Histogram,
 }
 
 impl LatencyMonitor {
     pub fn new() -> LatencyMonitor {
-        let inner = LatencyInner {
-            sample: Vec::with_capacity(32),
-            rate: 0.0,
-        };
-        LatencyMonitor { inner: Arc::new(Mutex::new(inner)) }
+        LatencyMonitor { inner: Arc::new(Mutex::new(LatencyInner::default())) }
     }
 
     pub fn add(&mut self, sample: f64) -> Result<()> {
         let mut m = self.inner.lock()?;
-        (*m).sample.push(sample);
+        m.interval.record(sample);
         Ok(())
     }
 
+    /// Mean latency of the last interval.
     pub fn rate(&self) -> Result<f64> {
         let m = self.inner.lock()?;
-        Ok(m.rate)
+        Ok(m.last.mean())
+    }
+
+    /// Latency `p` percent (0 to 100) of the last interval are at most.
+    pub fn percentile(&self, p: f64) -> Result<f64> {
+        let m = self.inner.lock()?;
+        Ok(m.last.percentile(p))
+    }
+
+    /// Maximum latency of the last interval.
+    pub fn max(&self) -> Result<f64> {
+        let m = self.inner.lock()?;
+        Ok(m.last.max())
+    }
+
+    pub fn update(&mut self) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        let m = &mut *m;
+        m.window.merge(&m.interval);
+        ::std::mem::swap(&mut m.last, &mut m.interval);
+        m.interval.clear();
+        Ok(())
+    }
+
+    /// Returns the latencies of the intervals closed since the last snapshot,
+    /// or `None` if there are none.
+    pub fn snapshot(&mut self) -> Result<Option<Snapshot>> {
+        let mut m = self.inner.lock()?;
+        if m.window.count() == 0 {
+            return Ok(None);
+        }
+        let snapshot = m.window.snapshot();
+        m.window.clear();
+        Ok(Some(snapshot))
+    }
+}
+
+/// Latency of every datum type a connection receives.
+#[derive(Clone)]
+pub struct DatumLatency {
+    /// Live data.
+    pub live: LatencyMonitor,
+
+    /// Bandwidth probes.
+    pub dummy: LatencyMonitor,
+
+    /// Latency probes.
+    pub probe: LatencyMonitor,
+}
+
+impl DatumLatency {
+    pub fn new() -> DatumLatency {
+        DatumLatency {
+            live: LatencyMonitor::new(),
+            dummy: LatencyMonitor::new(),
+            probe: LatencyMonitor::new(),
+        }
+    }
+
+    pub fn update(&mut self) -> Result<()> {
+        self.live.update()?;
+        self.dummy.update()?;
+        self.probe.update()
+    }
+
+    /// Takes the snapshot of every datum type that has latencies, with the
+    /// name of the datum type.
+    pub fn snapshots(&mut self) -> Result<Vec<(&'static str, Snapshot)>> {
+        let mut snapshots = Vec::new();
+        let monitors = [
+            ("live", &mut self.live),
+            ("dummy", &mut self.dummy),
+            ("latency_probe", &mut self.probe),
+        ];
+        for (name, monitor) in monitors {
+            if let Some(snapshot) = monitor.snapshot()? {
+                snapshots.push((name, snapshot));
+            }
+        }
+        Ok(snapshots)
+    }
+}
+
+/// Averages each component of the latency breakdown over an interval.
+#[derive(Clone)]
+pub struct BreakdownMonitor {
+    queue: LatencyMonitor,
+    network: LatencyMonitor,
+    processing: LatencyMonitor,
+}
+
+impl BreakdownMonitor {
+    pub fn new() -> BreakdownMonitor {
+        BreakdownMonitor {
+            queue: LatencyMonitor::new(),
+            network: LatencyMonitor::new(),
+            processing: LatencyMonitor::new(),
+        }
+    }
+
+    pub fn add(&mut self, sample: LatencyBreakdown) -> Result<()> {
+        self.queue.add(sample.queue)?;
+        self.network.add(sample.network)?;
+        self.processing.add(sample.processing)
+    }
+
+    pub fn rate(&self) -> Result<LatencyBreakdown> {
+        Ok(LatencyBreakdown {
+            queue: self.queue.rate()?,
+            network: self.network.rate()?,
+            processing: self.processing.rate()?,
+        })
     }
 
     pub fn update(&mut self) -> Result<()> {
+        self.queue.update()?;
+        self.network.update()?;
+        self.processing.update()
+    }
+}
+
+/// Tracks the analytics stage of the receiver: how many data wait to be
+/// processed, how long they take from receipt until processed, and the rate
+/// (kbps and frames a second) of data processed.
+#[derive(Clone)]
+pub struct ComputeMonitor {
+    pending: Arc<Mutex<usize>>,
+    latency: LatencyMonitor,
+    processing: LatencyMonitor,
+    processed: BwMonitor,
+
+    /// Data processed in the current interval, and a second over the last.
+    frames: Arc<Mutex<(usize, f64)>>,
+}
+
+impl ComputeMonitor {
+    pub fn new() -> ComputeMonitor {
+        ComputeMonitor {
+            pending: Arc::new(Mutex::new(0)),
+            latency: LatencyMonitor::new(),
+            processing: LatencyMonitor::new(),
+            processed: BwMonitor::new(),
+            frames: Arc::new(Mutex::new((0, 0.0))),
+        }
+    }
+
+    /// A datum enters the analytics queue. It's counted before it's handed
+    /// over, so that the analytics never finishes a datum not counted yet.
+    pub fn enqueue(&mut self) -> Result<()> {
+        *self.pending.lock()? += 1;
+        Ok(())
+    }
+
+    fn leave(&mut self) -> Result<()> {
+        let mut pending = self.pending.lock()?;
+        *pending = pending.saturating_sub(1);
+        Ok(())
+    }
+
+    /// A datum counted by `enqueue` doesn't enter the analytics queue after
+    /// all, e.g. as the queue is full.
+    pub fn cancel(&mut self) -> Result<()> {
+        self.leave()
+    }
+
+    /// A datum leaves the analytics queue to wait in a jitter buffer. It
+    /// enters again (see `enqueue`) once released, so that waiting there
+    /// doesn't count as a backlog.
+    pub fn hold(&mut self) -> Result<()> {
+        self.leave()
+    }
+
+    /// A datum of `size` bytes is processed, `latency` ms after it's received,
+    /// of which the server spent `processing` ms decoding and analyzing it.
+    pub fn done(&mut self, size: usize, latency: f64, processing: f64) -> Result<()> {
+        self.leave()?;
+        self.latency.add(latency)?;
+        self.processing.add(processing)?;
+        self.frames.lock()?.0 += 1;
+        self.processed.add(size)
+    }
+
+    /// Number of data waiting to be processed.
+    pub fn depth(&self) -> Result<usize> {
+        Ok(*self.pending.lock()?)
+    }
+
+    /// Mean time (ms) the server spent processing a datum over the last
+    /// interval, waiting for the analytics aside.
+    pub fn processing(&self) -> Result<f64> {
+        self.processing.rate()
+    }
+
+    pub fn report(&self) -> Result<ComputeReport> {
+        Ok(ComputeReport {
+            queue_depth: self.depth()?,
+            latency: self.latency.rate()?,
+            rate: self.processed.rate()?,
+            fps: self.frames.lock()?.1,
+        })
+    }
+
+    pub fn update(&mut self, time_in_ms: usize) -> Result<()> {
+        self.latency.update()?;
+        self.processing.update()?;
+        {
+            let mut frames = self.frames.lock()?;
+            *frames = (0, frames.0 as f64 * 1000.0 / time_in_ms as f64);
+        }
+        self.processed.update(time_in_ms)
+    }
+}
+
+/// What a connection receives on one stream over the last interval.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct StreamStats {
+    /// Level of the latest datum.
+    pub level: usize,
+
+    /// Data received.
+    pub frames: usize,
+
+    /// Goodput (kbps).
+    pub goodput: f64,
+
+    /// Mean latency (ms).
+    pub latency: f64,
+}
+
+/// Live data per stream (see `AsDatum::stream_id`), so that every stream
+/// sharing a connection is accounted for apart.
+#[derive(Clone, Default)]
+pub struct StreamMonitor {
+    inner: Arc<Mutex<BTreeMap<u32, StreamInner>>>,
+}
+
+#[derive(Default)]
+struct StreamInner {
+    level: usize,
+    frames: usize,
+    bytes: usize,
+    latency: Histogram,
+    last: StreamStats,
+}
+
+impl StreamMonitor {
+    pub fn new() -> StreamMonitor {
+        StreamMonitor::default()
+    }
+
+    /// A datum of `size` bytes at `level` arrives on `stream` after `latency`
+    /// ms.
+    pub fn add(&mut self, stream: u32, level: usize, size: usize, latency: f64) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        let s = m.entry(stream).or_default();
+        s.level = level;
+        s.frames += 1;
+        s.bytes += size;
+        s.latency.record(latency);
+        Ok(())
+    }
+
+    /// Stats of every stream seen so far, over the last interval.
+    pub fn report(&self) -> Result<BTreeMap<u32, StreamStats>> {
+        let m = self.inner.lock()?;
+        Ok(m.iter().map(|(&stream, s)| (stream, s.last)).collect())
+    }
+
+    pub fn update(&mut self, time_in_ms: usize) -> Result<()> {
         let mut m = self.inner.lock()?;
-        (*m).rate = (*m).sample.iter().sum::<f64>() / (*m).sample.len() as f64;
-        (*m).sample.clear();
+        for s in m.values_mut() {
+            s.last = StreamStats {
+                level: s.level,
+                frames: s.frames,
+                goodput: s.bytes as f64 * 8.0 / time_in_ms as f64,
+                latency: s.latency.mean(),
+            };
+            s.frames = 0;
+            s.bytes = 0;
+            s.latency.clear();
+        }
         Ok(())
     }
 }
+
+/// How far (in sequence numbers) behind the latest datum a missing one may
+/// still arrive and count as reordered instead of lost.
+const REORDER_WINDOW: u64 = 1024;
+
+/// Sequence numbers of live data per stream (see `AsDatum::seq`), to count
+/// data lost or reordered on the way.
+#[derive(Clone, Default)]
+pub struct SequenceMonitor {
+    inner: Arc<Mutex<BTreeMap<u32, SequenceTracker>>>,
+}
+
+#[derive(Default)]
+struct SequenceTracker {
+    /// Sequence number expected next.
+    next: u64,
+
+    /// Sequence numbers skipped within `REORDER_WINDOW`, which may still
+    /// arrive.
+    missing: BTreeSet<u64>,
+
+    stats: SequenceStats,
+}
+
+impl SequenceTracker {
+    fn add(&mut self, seq: u64) {
+        if seq >= self.next {
+            self.stats.lost += seq - self.next;
+            let start = ::std::cmp::max(self.next, seq.saturating_sub(REORDER_WINDOW));
+            self.missing.extend(start..seq);
+            self.next = seq + 1;
+            let oldest = self.next.saturating_sub(REORDER_WINDOW);
+            while self.missing.first().is_some_and(|&s| s < oldest) {
+                self.missing.pop_first();
+            }
+        } else if self.missing.remove(&seq) {
+            self.stats.lost -= 1;
+            self.stats.reordered += 1;
+        }
+    }
+}
+
+impl SequenceMonitor {
+    pub fn new() -> SequenceMonitor {
+        SequenceMonitor::default()
+    }
+
+    /// A datum numbered `seq` arrives on `stream`.
+    pub fn add(&mut self, stream: u32, seq: u64) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        m.entry(stream).or_default().add(seq);
+        Ok(())
+    }
+
+    /// Data lost and reordered on all streams so far.
+    pub fn total(&self) -> Result<SequenceStats> {
+        let m = self.inner.lock()?;
+        Ok(m.values().fold(SequenceStats::default(), |total, t| SequenceStats {
+            lost: total.lost + t.stats.lost,
+            reordered: total.reordered + t.stats.reordered,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_gaps() {
+        let mut sequence = SequenceMonitor::new();
+        for &seq in &[0, 1, 4, 2, 5, 2] {
+            sequence.add(0, seq).unwrap();
+        }
+        // 3 is lost; 2 comes late, then again.
+        assert_eq!(sequence.total().unwrap(), SequenceStats { lost: 1, reordered: 1 });
+
+        // Streams are numbered apart, and gaps too old are lost for good.
+        sequence.add(1, 0).unwrap();
+        sequence.add(1, REORDER_WINDOW + 2).unwrap();
+        sequence.add(1, 1).unwrap();
+        assert_eq!(sequence.total().unwrap().lost, 1 + REORDER_WINDOW + 1);
+        sequence.add(1, 3).unwrap();
+        assert_eq!(sequence.total().unwrap(),
+                   SequenceStats {
+                       lost: 1 + REORDER_WINDOW,
+                       reordered: 2,
+                   });
+    }
+}
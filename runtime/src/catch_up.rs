@@ -0,0 +1,137 @@
+//! Time-shifted catch-up. Live data favor freshness: frames are degraded to
+//! lower levels or dropped once they are late. For applications that also want
+//! every frame eventually, the client remembers the frames it missed in a ring
+//! buffer and uploads them again at a good level, marked as historical, once
+//! the link has capacity to spare.
+
+use super::{AsDatum, Experiment};
+use errors::*;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Which frames are uploaded again.
+///
+/// ```toml
+/// [catch_up]
+/// capacity = 300
+/// level = 3
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct CatchUpConfig {
+    /// The most frames remembered; the oldest are forgotten first. 300 if not
+    /// set.
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+
+    /// Level frames are uploaded again at; frames sent at this level or above
+    /// are not missed. The highest level if not set.
+    #[serde(default)]
+    pub level: Option<usize>,
+}
+
+fn default_capacity() -> usize {
+    300
+}
+
+/// The ring buffer of missed frames, shared between the source, which degrades
+/// or filters frames, and the queue, which drops late ones.
+#[derive(Clone)]
+pub struct CatchUp {
+    capacity: usize,
+    level: usize,
+    missed: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl CatchUp {
+    /// Creates an empty buffer for a source with `levels` levels.
+    pub fn new(config: &CatchUpConfig, levels: usize) -> CatchUp {
+        let highest = levels.saturating_sub(1);
+        CatchUp {
+            capacity: config.capacity,
+            level: config.level.map_or(highest, |level| ::std::cmp::min(level, highest)),
+            missed: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Returns the level frames are uploaded again at.
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Remembers frame `frame_num` as missed, unless it already is.
+    pub fn miss(&self, frame_num: usize) -> Result<()> {
+        let mut missed = self.missed.lock()?;
+        if missed.contains(&frame_num) {
+            return Ok(());
+        }
+        if missed.len() >= self.capacity {
+            missed.pop_front();
+        }
+        missed.push_back(frame_num);
+        Ok(())
+    }
+
+    /// Returns the number of frames remembered.
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.missed.lock()?.len())
+    }
+
+    /// Takes the oldest missed frame that `source` knows at the catch-up
+    /// level and returns it as a historical datum.
+    pub fn next_datum<E: Experiment>(&self, source: &mut E) -> Result<Option<AsDatum>> {
+        loop {
+            let frame_num = match self.missed.lock()?.pop_front() {
+                Some(frame_num) => frame_num,
+                None => return Ok(None),
+            };
+            let size = match source.datum_size_at(self.level, frame_num) {
+                Some(size) if size > 0 => size,
+                _ => continue,
+            };
+            let mut datum = AsDatum::historical(self.level, frame_num, vec![0; size]);
+            if let Some(stat) = source.expected_stat_at(self.level, frame_num) {
+                datum.set_expected(stat);
+            }
+            return Ok(Some(datum));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AsDatumType;
+
+    struct Sizes;
+
+    impl Experiment for Sizes {
+        fn next_datum(&mut self) -> Option<(usize, usize)> {
+            None
+        }
+
+        fn datum_size_at(&mut self, level: usize, index: usize) -> Option<usize> {
+            if index == 2 { None } else { Some(level * 100 + index) }
+        }
+    }
+
+    #[test]
+    fn oldest_missed_frames_first() {
+        let config = CatchUpConfig {
+            capacity: 3,
+            level: Some(9),
+        };
+        let catch_up = CatchUp::new(&config, 5);
+        assert_eq!(catch_up.level(), 4);
+        for &frame_num in &[1, 2, 2, 3, 4] {
+            catch_up.miss(frame_num).unwrap();
+        }
+        // Frame 1 is forgotten and frame 2 unknown at the catch-up level.
+        assert_eq!(catch_up.len().unwrap(), 3);
+        let datum = catch_up.next_datum(&mut Sizes).unwrap().unwrap();
+        assert_eq!(datum.datum_type(), AsDatumType::Historical(4, 3));
+        assert_eq!(datum.mem.len(), 403);
+        assert_eq!(catch_up.next_datum(&mut Sizes).unwrap().map(|d| d.datum_type()),
+                   Some(AsDatumType::Historical(4, 4)));
+        assert!(catch_up.next_datum(&mut Sizes).unwrap().is_none());
+    }
+}
@@ -2,51 +2,349 @@
 //! event loop (`tokio_core::Core`). The loop selects the next available event
 //! and reacts accordingly.
 
-use super::{Adapt, AdaptAction, AsCodec, ReceiverReport};
-use super::adaptation::{Action, Adaptation, Signal};
-use super::controller::Monitor;
+use super::{AccuracyReport, Adapt, AdaptAction, AsCodec, AsDatum, AsDatumType, Compression,
+            ComputeReport, ControlMessage, Detections, Experiment};
+use super::adaptation::{Action, AdaptEvent, Adaptation, Signal, State};
+use super::bbr::BbrEstimate;
+use super::bond::Striped;
+use super::budget;
+use super::catch_up::CatchUp;
+use super::coalesce::Coalescer;
+use super::contention::{Bottleneck, Throttled};
+use super::setting::AdaptationPolicy;
+use super::controller::{Acknowledger, Delivery, MONITOR_INTERVAL, Monitor};
+use super::detector::{self, Measurement};
 use super::errors::*;
-use super::profile::SimpleProfile;
-use super::setting::Setting;
-use super::socket::{FramedRead, Socket};
+use super::filter::{FrameFilter, MotionFilter};
+use super::profile::{Profile, Selection, SimpleProfile};
+use super::recorder;
+use super::reload;
+use super::setting::{Probing, Setting, TlsSetting, Transport};
+use super::socket::{FramedRead, Socket, merge_until_done, skip_corrupt};
 use super::source::TimerSource;
-use super::video::VideoSource;
-use futures::{Future, Sink, Stream};
+use super::thumbnail;
+use super::tls::{self, Conn};
+use super::udp::SplitSocket;
+use super::utils::{spawn_csv_log, spawn_json_log};
+use super::video::{self, VideoConfig, VideoSource};
+use awstream_core::aimd::Aimd;
+use awstream_core::pid::Pid;
+use evaluation::LevelDecision;
+use chrono::{DateTime, Local, Utc};
+use futures::{Future, Poll, Sink, StartSend, Stream, stream};
 
-use futures::sync::mpsc::UnboundedSender;
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
 use futures_cpupool::CpuPool;
-use std::net::SocketAddr;
+use net2::TcpBuilder;
+use std::net::{IpAddr, SocketAddr};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::Core;
-use tokio_io::AsyncRead;
 
-const PROBE_EXTRA: f64 = 1.05;
+pub(crate) const PROBE_EXTRA: f64 = 1.05;
 
-fn connect(server: &str, port: u16, core: &mut Core) -> Result<TcpStream> {
+/// Data from all streams on their way to the socket.
+type DataStream = Box<dyn Stream<Item = AsDatum, Error = Error> + Send>;
+
+/// Where the data plane sends to, over TCP only or split with UDP.
+type DataSink = Box<dyn Sink<SinkItem = AsDatum, SinkError = Error> + Send>;
+
+/// Logs the level of every frame of the primary stream as `inner` takes it
+/// (see `Setting::level_log`).
+struct LevelLogged {
+    inner: DataSink,
+    log: UnboundedSender<LevelDecision>,
+}
+
+impl LevelLogged {
+    fn new(inner: DataSink, log: UnboundedSender<LevelDecision>) -> LevelLogged {
+        LevelLogged { inner, log }
+    }
+}
+
+impl Sink for LevelLogged {
+    type SinkItem = AsDatum;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: AsDatum) -> StartSend<AsDatum, Error> {
+        let frames = if item.stream_id() == 0 {
+            item.live_frames()
+        } else {
+            Vec::new()
+        };
+        let sent = self.inner.start_send(item)?;
+        if sent.is_ready() && !frames.is_empty() {
+            let now = Utc::now();
+            let timestamp = now.timestamp() as f64 +
+                            f64::from(now.timestamp_subsec_millis()) / 1000.0;
+            for (level, frame_num) in frames {
+                let decision = LevelDecision {
+                    timestamp,
+                    frame_num,
+                    level,
+                };
+                if self.log.unbounded_send(decision).is_err() {
+                    error!("level log has stopped");
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        self.inner.poll_complete()
+    }
+}
+
+/// Connects to the server, from `local` if set (e.g. to go over a given
+/// interface).
+fn connect(
+    server: &str,
+    port: u16,
+    local: Option<IpAddr>,
+    tls_setting: Option<&TlsSetting>,
+    core: &mut Core,
+) -> Result<Conn> {
     let handle = core.handle();
     let ip = server.parse().unwrap();
     let address = SocketAddr::new(ip, port);
 
-    let work = TcpStream::connect(&address, &handle);
-    let tcp = core.run(work)?;
+    let tcp = match local {
+        Some(local) => {
+            let builder = if local.is_ipv4() {
+                TcpBuilder::new_v4()?
+            } else {
+                TcpBuilder::new_v6()?
+            };
+            builder.bind(SocketAddr::new(local, 0))?;
+            TcpStream::from_stream(builder.connect(address)?, &handle)?
+        }
+        None => core.run(TcpStream::connect(&address, &handle))?,
+    };
     // tcp.set_nodelay(true).expect("failed to set TCP NODELAY");
     // tcp.set_send_buffer_size(64 * 1_024).expect("failed to set send buffer");
-    Ok(tcp)
+    match tls_setting {
+        Some(tls_setting) => Ok(core.run(tls::connect(tcp, tls_setting)?)?),
+        None => {
+            warn!("the connection to {} is plaintext", address);
+            Ok(Conn::Plain(tcp))
+        }
+    }
+}
+
+/// Returns an ID for the bond of this client's connections, unlikely to be
+/// another client's.
+fn bond_id() -> u64 {
+    let now = Utc::now();
+    let pid = u64::from(process::id());
+    ((now.timestamp() as u64) << 32) ^ u64::from(now.timestamp_subsec_nanos()) ^ (pid << 16)
+}
+
+/// A change of the level the client sends at, e.g. for an on-screen display
+/// or a local recorder that follows the quality.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct LevelChange {
+    /// When the level changed.
+    pub ts: DateTime<Utc>,
+
+    /// The signal that triggered the change, if any: a pin, a reloaded
+    /// profile or a budget window changes the level without one.
+    pub signal: Option<Signal>,
+
+    /// Level before the change.
+    pub from: usize,
+
+    /// Level after the change.
+    pub to: usize,
+
+    /// Configuration before the change.
+    pub from_config: VideoConfig,
+
+    /// Configuration after the change.
+    pub to_config: VideoConfig,
+}
+
+/// Receivers of what the client does. Dropped receivers are removed.
+#[derive(Default)]
+pub struct Subscribers {
+    /// If set, pins the level (`Some`) or unpins it (`None`) from outside the
+    /// client, as the server can (see `Setting::pin_level`).
+    pub pins: Option<UnboundedReceiver<Option<usize>>>,
+
+    /// If set, reloads the profile from `Setting::profile_path` whenever it
+    /// receives, whether the file has changed or not.
+    pub reloads: Option<UnboundedReceiver<()>>,
+
+    /// Receivers of every adaptation decision.
+    pub adaptation: Vec<UnboundedSender<AdaptEvent>>,
+
+    /// Receivers of every level change.
+    pub levels: Vec<UnboundedSender<LevelChange>>,
+
+    /// Receivers of what the server's analytics detects in every frame, if
+    /// the server sends it (see `Setting::send_detections`).
+    pub detections: Vec<UnboundedSender<Detections>>,
 }
 
 /// Run client
 pub fn run(setting: Setting) -> Result<()> {
+    run_with_subscribers(setting, Subscribers::default())
+}
+
+/// Run client and publish adaptation decisions, level changes and the
+/// detections the server sends back to `subscribers`.
+pub fn run_with_subscribers(setting: Setting, subscribers: Subscribers) -> Result<()> {
+    let filter = match setting.motion {
+        Some(ref motion) => {
+            let filter = MotionFilter::load(&motion.path, motion.config)?;
+            info!("skip static frames, {:?}", motion.config);
+            Some(Box::new(filter) as Box<dyn FrameFilter>)
+        }
+        None => None,
+    };
+    run_with_hooks(setting, subscribers, filter)
+}
+
+/// Run client as `run_with_subscribers` does; in addition, every frame passes
+/// `filter` (if set) before it is sent.
+pub fn run_with_hooks(
+    setting: Setting,
+    subscribers: Subscribers,
+    filter: Option<Box<dyn FrameFilter>>,
+) -> Result<()> {
+    run_throttled(setting, subscribers, filter, None)
+}
+
+/// Runs the clients of `setting.load` at once, each in its own thread, and
+/// returns once all are done. Their data share the load's bottleneck, if set.
+pub fn run_load(setting: Setting) -> Result<()> {
+    let load = match setting.load {
+        Some(ref load) => load.clone(),
+        None => bail!("no [load] section in the setting"),
+    };
+    let bottleneck = match (load.preset, load.bottleneck) {
+        (Some(preset), _) => {
+            info!("{} clients share a bottleneck following {}", load.clients, preset);
+            Some(Bottleneck::scheduled(&preset.schedule())?)
+        }
+        (None, Some(kbps)) => {
+            info!("{} clients share a bottleneck of {:.1} kbps", load.clients, kbps);
+            Some(Bottleneck::new(kbps))
+        }
+        (None, None) => None,
+    };
+    let clients = (0..load.clients)
+        .map(|i| {
+            let client = load.client(&setting, i);
+            let bottleneck = bottleneck.clone();
+            let (tx, rx) = unbounded();
+            let subscribers = Subscribers {
+                levels: vec![tx],
+                ..Subscribers::default()
+            };
+            let name = client.name.clone();
+            thread::spawn(move || for change in rx.wait().filter_map(|change| change.ok()) {
+                info!("{}: level {} -> {}", name, change.from, change.to);
+            });
+            thread::spawn(move || run_throttled(client, subscribers, None, bottleneck))
+        })
+        .collect::<Vec<_>>();
+    for (i, client) in clients.into_iter().enumerate() {
+        match client.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("client {} failed: {}", i, e),
+            Err(_) => error!("client {} panicked", i),
+        }
+    }
+    Ok(())
+}
+
+/// Run client as `run_with_subscribers` does, but send the data of `source`
+/// (e.g. frames from a camera) instead of replaying `setting.source_path`;
+/// `source_path` and `stat_path` are then ignored.
+pub fn run_with_source<As>(setting: Setting, subscribers: Subscribers, source: As) -> Result<()>
+where
+    As: Adapt + Experiment + 'static,
+{
+    run_source(setting, subscribers, source, None, None)
+}
+
+/// Run client as `run_with_hooks` does, sending through `bottleneck` if set.
+fn run_throttled(
+    setting: Setting,
+    subscribers: Subscribers,
+    filter: Option<Box<dyn FrameFilter>>,
+    bottleneck: Option<Bottleneck>,
+) -> Result<()> {
+    let mut video_source =
+        VideoSource::new(setting.source_path.clone(), setting.profile_path.clone());
+    video_source.set_repeat(setting.repeat);
+    video_source.set_ttl(setting.ttl.map(Duration::from_millis));
+    if let Some(fps) = setting.fps {
+        video_source.set_fps(fps);
+    }
+    video_source.load_stats(&setting.stat_path);
+    // Recalibrated accuracies only matter when levels are picked by accuracy.
+    let selection = match setting.recalibration {
+        Some(_) => Selection::Accuracy,
+        None => setting.level_selection,
+    };
+    video_source.set_selection(selection);
+    run_source(setting, subscribers, video_source, filter, bottleneck)
+}
+
+/// Run client with the data of `source`.
+fn run_source<As>(
+    setting: Setting,
+    subscribers: Subscribers,
+    mut source: As,
+    filter: Option<Box<dyn FrameFilter>>,
+    bottleneck: Option<Bottleneck>,
+) -> Result<()>
+where
+    As: Adapt + Experiment + 'static,
+{
     let pool = CpuPool::new_num_cpus();
 
     // Setting up the reactor core
     let mut core = Core::new().unwrap();
 
-    // Creates the TCP connection (this is synchronous!)
-    let tcp = connect(&setting.server, setting.port, &mut core)?;
+    // Local addresses of bonded connections, if any (checked with the setting)
+    let local_addrs = setting
+        .bonding
+        .as_ref()
+        .map(|bonding| bonding.local_addrs.iter().map(|a| a.parse().unwrap()).collect())
+        .unwrap_or_else(Vec::new);
+
+    // Creates the TCP connection, and the TLS session if any (this is synchronous!)
+    let tcp = connect(
+        &setting.server,
+        setting.port,
+        local_addrs.first().cloned(),
+        setting.tls.as_ref(),
+        &mut core,
+    )?;
     info!("conected to server: {}:{}", setting.server, setting.port);
 
-    let video_source = VideoSource::new(setting.source_path, setting.profile_path);
-    let mut profile = video_source.simple_profile();
+    let start = setting.startup.level(source.simple_profile().len());
+    source.set_level(start);
+    info!("start at level {} ({:?})", start, setting.startup);
+    let mut profile = source.simple_profile();
+    if let Some(max_rate) = setting.max_rate {
+        let dropped = profile.cap(max_rate);
+        info!("budget of {:.1} kbps, drop the {} levels above", max_rate, dropped);
+        if profile.current() != source.current_level() {
+            source.set_level(profile.current());
+        }
+    }
+    let mut configs = source.configs();
+
+    let (capacity, backpressure) = setting.buffer.unwrap_or_default().sizes();
+    info!("send buffer of {} bytes, backpressure at {} bytes", capacity, backpressure);
 
     /////////////////////////////////////////////////////////////////
     //
@@ -56,18 +354,167 @@ pub fn run(setting: Setting) -> Result<()> {
 
     // 1. Creates source
     let handle = core.handle();
-    let (src_ctrl, src_data, src_stat) = TimerSource::spawn(video_source, handle);
+    let coalescer = setting.coalesce.clone().map(Coalescer::new);
+    let catch_up = setting.catch_up.as_ref().map(|config| {
+        let catch_up = CatchUp::new(config, profile.len());
+        info!("catch up on up to {} frames at level {}", config.capacity, catch_up.level());
+        catch_up
+    });
+    let recorder = match setting.recorder {
+        Some(ref config) => {
+            info!("record the last {} minutes into {}", config.minutes, config.dir);
+            Some(recorder::spawn(config)?)
+        }
+        None => None,
+    };
+    let bbr = match setting.probing {
+        Probing::Dummy => None,
+        Probing::Bbr(ref config) => {
+            info!(
+                "probe with data ({} ms bandwidth window, {} ms rtt window)",
+                config.bw_window,
+                config.rtt_window
+            );
+            Some(BbrEstimate::new(config))
+        }
+    };
+    let (src_ctrl, src_data, src_stat) = TimerSource::spawn(
+        source,
+        filter,
+        coalescer,
+        catch_up,
+        recorder,
+        bbr.clone(),
+        handle.clone(),
+    );
+
+    // Secondary streams share the connection (and the count of bytes
+    // produced) with the primary one, at a fixed level.
+    let mut secondary = Vec::new();
+    for stream in &setting.streams {
+        let mut source = VideoSource::new(stream.source_path.clone(), stream.profile_path.clone());
+        source.set_repeat(setting.repeat);
+        source.set_ttl(setting.ttl.map(Duration::from_millis));
+        source.load_stats(&stream.stat_path);
+        source.set_level(stream.level);
+        info!("stream {} at level {}", stream.id, stream.level);
+        let stat = src_stat.clone();
+        let data = TimerSource::spawn_secondary(source, stream.id, stat, handle.clone());
+        secondary.push(data);
+    }
 
     // 2. Creates sink (socket)
+    let (local, peer) = (tcp.tcp().local_addr()?, tcp.tcp().peer_addr()?);
     let (tcp_read, tcp_write) = tcp.split();
-    let (socket, out_bytes) = Socket::new(tcp_write);
+    let mut encoder = AsCodec::default();
+    encoder.set_format(setting.wire_format);
+    let (mut socket, out_bytes) = Socket::with_codec(tcp_write, encoder);
+    socket.set_buffers(capacity, backpressure);
+    let sent = socket.stats();
+    let buffered = socket.buffer_stats();
+    let socket: DataSink = match setting.transport {
+        Transport::Tcp => Box::new(socket),
+        Transport::Udp => {
+            info!("send live data over udp from {}", local);
+            Box::new(SplitSocket::bind(socket, local, peer, out_bytes.clone(), &handle)?)
+        }
+    };
+
+    // With bonding, the other connections take turns with the primary one at
+    // live and historical data, counted into the same bytes sent. Each starts
+    // by joining the bond; only the primary one sees the compression handshake.
+    // Joins count as produced, as the socket counts them sent.
+    let join = |bond, primary| -> Result<AsDatum> {
+        let join = AsDatum::join(bond, primary)?;
+        src_stat.data.fetch_add(join.net_len(), Ordering::SeqCst);
+        Ok(join)
+    };
+    let socket: DataSink = match setting.bonding {
+        Some(ref bonding) => {
+            let bond = bond_id();
+            let primary = core.run(socket.send(join(bond, true)?))?;
+            let mut paths = vec![primary];
+            for i in 1..bonding.connections() {
+                let local = local_addrs.get(i).cloned();
+                let tls = setting.tls.as_ref();
+                let conn = connect(&setting.server, setting.port, local, tls, &mut core)?;
+                info!("bond {}: connection {} from {}", bond, i, conn.tcp().local_addr()?);
+                let (_, write) = conn.split();
+                let mut encoder = AsCodec::default();
+                encoder.set_format(setting.wire_format);
+                let mut path = Socket::sharing(write, encoder, out_bytes.clone());
+                path.set_buffers(capacity, backpressure);
+                let path = core.run(path.send(join(bond, false)?))?;
+                paths.push(Box::new(path) as DataSink);
+            }
+            Box::new(Striped::new(paths))
+        }
+        None => socket,
+    };
+
+    // Clients of a load share an emulated bottleneck on top of the link.
+    let socket: DataSink = match bottleneck {
+        Some(bottleneck) => Box::new(Throttled::new(socket, bottleneck)),
+        None => socket,
+    };
+
+    // Nothing goes out above the budget, probes included.
+    let socket: DataSink = match setting.max_rate {
+        Some(max_rate) => Box::new(Throttled::new(socket, Bottleneck::new(max_rate))),
+        None => socket,
+    };
+
+    // Frames are logged at their level as the socket takes them.
+    let socket: DataSink = match setting.level_log {
+        Some(ref path) => Box::new(LevelLogged::new(socket, spawn_csv_log(path)?)),
+        None => socket,
+    };
 
     // 3. Forward all source data to socket
-    let s = src_data.map_err(|_| Error::from_kind(ErrorKind::SourceData));
+    let occupancy = src_data.occupancy();
+    let latest = Arc::new(AtomicUsize::new(0));
+    let latest_clone = latest.clone();
+    let s = src_data
+        .inspect(move |datum| if let Some(&(_, frame_num)) = datum.live_frames().last() {
+            latest_clone.store(frame_num, Ordering::SeqCst);
+        })
+        .map_err(|_| Error::from_kind(ErrorKind::SourceData));
+    let s = secondary.into_iter().fold(Box::new(s) as DataStream, |s, data| {
+        let data = data.map_err(|_| Error::from_kind(ErrorKind::SourceData));
+        Box::new(s.select(data))
+    });
+    let handshake = match setting.compression {
+        Compression::None => None,
+        compression => Some(AsDatum::handshake(compression)?),
+    };
+    if let Some(ref handshake) = handshake {
+        src_stat.data.fetch_add(handshake.net_len(), Ordering::SeqCst);
+    }
+    let s = stream::iter_ok(handshake).chain(s);
+
+    // Thumbnails of the frame last sent go out at their own fixed pace until
+    // the data are done.
+    let s: DataStream = match setting.thumbnail {
+        Some(ref thumbnail) => {
+            let thumbnails = thumbnail::stream(thumbnail, latest, src_stat.clone())?;
+            Box::new(merge_until_done(s, thumbnails))
+        }
+        None => Box::new(s),
+    };
+
+    // With a reverse channel, the client acknowledges what it receives along
+    // with its data, counted as produced so that the monitor sees all that
+    // goes into the socket.
+    let (ack_tx, ack_rx) = unbounded();
+    let mut acks = setting.reverse.as_ref().map(|reverse| {
+        info!("reverse channel from {}", reverse.source_path);
+        (Acknowledger::new(), ack_tx, src_stat.clone())
+    });
+    let ack_rx = ack_rx.map_err(|_| Error::from_kind(ErrorKind::SourceData));
+    let s = merge_until_done(s, ack_rx);
     let socket_work = socket.send_all(s).map(|_| ()).map_err(|_| ());
 
     let data_plane = pool.spawn(socket_work);
-    core.handle().spawn(data_plane);
 
     //////////////////////////////////////////////////////////////////
     //
@@ -75,71 +522,494 @@ pub fn run(setting: Setting) -> Result<()> {
     //
     //////////////////////////////////////////////////////////////////
     let mut adaptation = Adaptation::default();
+    for subscriber in subscribers.adaptation {
+        adaptation.subscribe(subscriber);
+    }
+    let mut level_subscribers = subscribers.levels;
+    let mut detection_subscribers = subscribers.detections;
+    let pins = subscribers
+        .pins
+        .unwrap_or_else(|| unbounded().1)
+        .map(Control::Pin)
+        .map_err(|_| Error::from_kind(ErrorKind::ControlPlane));
+    if let Some(interval) = setting.profile_reload {
+        info!("reload {} if changed, checked every {} ms", setting.profile_path, interval);
+    }
+    let triggers = subscribers.reloads.unwrap_or_else(|| unbounded().1);
+    let reloads = reload::watch(&setting.profile_path, setting.profile_reload, triggers)
+        .map(Control::Reload);
+    if let Some(ref path) = setting.event_log {
+        adaptation.subscribe(spawn_json_log(path)?);
+    }
+    if let Some(ref path) = setting.decision_log {
+        adaptation.log_decisions(spawn_csv_log(path)?);
+    }
+    if let Some(dwell) = setting.min_dwell {
+        adaptation.set_min_dwell(Duration::from_millis(dwell));
+    }
 
-    let remote = FramedRead::new(tcp_read, AsCodec::default())
-        .map(|as_datum| {
+    let delivery = Delivery::new();
+    let delivered = delivery.clone();
+    let decoder = AsCodec::default();
+    let received = decoder.stats();
+    let remote = skip_corrupt(FramedRead::new(tcp_read, decoder))
+        .filter_map(move |as_datum| {
             let errmsg = "failed to parse mem into report";
-            let report = ReceiverReport::from_mem(&as_datum.mem).expect(&errmsg);
-            Signal::RemoteCongest(report.throughput, report.latency)
+            if let Some((ref mut acker, ref tx, ref produced)) = acks {
+                if let Some(ack) = acker.add(as_datum.sent_len()).expect("failed to encode ack") {
+                    produced.data.fetch_add(ack.net_len(), Ordering::SeqCst);
+                    if tx.unbounded_send(ack).is_err() {
+                        warn!("data plane has stopped, drop ack");
+                    }
+                }
+            }
+            // Data of the reverse channel, accounted for in the wire stats.
+            if let AsDatumType::Live(level, frame_num) = as_datum.datum_type() {
+                trace!("reverse datum, level: {}, frame: {}", level, frame_num);
+                return None;
+            }
+            match ControlMessage::from_datum(&as_datum).expect(errmsg)? {
+                ControlMessage::Delivered(bytes) => {
+                    delivered.ack(bytes);
+                    None
+                }
+                ControlMessage::Accuracy(report) => Some(Control::Accuracy(report)),
+                ControlMessage::Compute(report) => {
+                    info!("remote compute congest, {}", report);
+                    Some(Control::Compute(report))
+                }
+                ControlMessage::PinLevel(level) => Some(Control::Pin(level)),
+                ControlMessage::Receiver(report) => {
+                    info!(
+                        "remote congest, latency {:.3} ms ({}), {}, remote processing {:.3} ms",
+                        report.latency,
+                        report.breakdown(),
+                        report.sequence(),
+                        report.processing()
+                    );
+                    Some(Control::Signal(Signal::RemoteCongest(report.throughput, report.latency)))
+                }
+                ControlMessage::Detections(detections) => {
+                    trace!("detections {:?}", detections);
+                    detection_subscribers.retain(|s| s.unbounded_send(detections).is_ok());
+                    None
+                }
+                ControlMessage::Drops(_) => None,
+            }
         })
         .map_err(|_| Error::from_kind(ErrorKind::RemotePeer));
 
     let (src_tx, src_rx) = src_ctrl;
-    let monitor = Monitor::new(src_stat, out_bytes).skip(1);
-    let probing = src_rx.map_err(|_| Error::from_kind(ErrorKind::RemotePeer));
+    let mut monitor = Monitor::new(
+        src_stat,
+        out_bytes,
+        delivery,
+        occupancy,
+        detector::build(setting.detector),
+    );
+    if let Some(estimate) = bbr {
+        monitor.set_bbr(estimate);
+    }
+    if let Some(ref path) = setting.probe_log {
+        monitor.log_probe_overhead(spawn_csv_log(path)?);
+    }
+    let (measurement_tx, measurement_rx) = unbounded();
+    if let AdaptationPolicy::Pid(_) = setting.policy {
+        monitor.set_measurements(measurement_tx);
+    }
+    let measurements = measurement_rx
+        .map(Control::Measurement)
+        .map_err(|_| Error::from_kind(ErrorKind::ControlPlane));
+    let monitor = monitor.skip(1).map(Control::Signal);
+    let probing = src_rx
+        .map(Control::Signal)
+        .map_err(|_| Error::from_kind(ErrorKind::RemotePeer));
+
+    let mut aimd = match setting.policy {
+        AdaptationPolicy::Awstream => None,
+        AdaptationPolicy::Aimd { increase, decrease } => {
+            let rate = profile.rate_of(profile.current()).unwrap_or(0.0);
+            let max_rate = profile.rate_of(profile.len().saturating_sub(1)).unwrap_or(0.0);
+            info!("aimd from {:.1} kbps (+{} kbps, x{})", rate, increase, decrease);
+            Some(Aimd::with_params(rate, max_rate, increase, decrease))
+        }
+        AdaptationPolicy::Pid(_) => None,
+    };
+
+    let floor = setting.floor_rate.unwrap_or(0.0);
+    if floor > 0.0 {
+        info!("guaranteed rate {:.1} kbps", floor);
+    }
+
+    let mut pid = match setting.policy {
+        AdaptationPolicy::Pid(gains) => {
+            let rate = profile.rate_of(profile.current()).unwrap_or(0.0);
+            let min_rate = profile.rate_of(0).unwrap_or(0.0).max(floor);
+            let max_rate = profile.rate_of(profile.len().saturating_sub(1)).unwrap_or(0.0);
+            info!("pid from {:.1} kbps ({:?})", rate, gains);
+            Some((Pid::new(rate, min_rate, max_rate, gains), gains.setpoint))
+        }
+        _ => None,
+    };
 
+    let recalibration = setting.recalibration;
+    let fps = setting.fps.unwrap_or(video::DEFAULT_FPS);
+    let max_rate = setting.max_rate;
+    let startup = setting.startup;
+    let budget_schedule = setting.budget_schedule.clone();
+    let mut max_level = None;
+    let mut last_good = None;
+    let mut pinned = setting.pin_level.map(|level| profile.set_level(level));
+    if let Some(level) = pinned {
+        info!("pin level {}", level);
+        block_send(src_tx.clone(), AdaptAction::PinLevel(level));
+    }
     let control_plane = monitor
         .select(probing)
         .select(remote)
-        .for_each(move |signal| {
-            core_adapt(signal, &mut adaptation, &mut profile, src_tx.clone());
+        .select(measurements)
+        .select(pins)
+        .select(reloads)
+        .for_each(move |event| {
+            let limit = budget::max_level(&budget_schedule, Local::now().time(), &profile);
+            if pinned.is_none() && limit != max_level {
+                max_level = limit;
+                info!("budget window changes, max level: {:?}", limit);
+                let from = profile.current();
+                let to = profile.set_max_level(limit);
+                if to != from {
+                    block_send(src_tx.clone(), AdaptAction::ToLevel(to));
+                    let (from, to) = ((from, configs[from]), (to, configs[to]));
+                    publish_level(&mut level_subscribers, None, from, to);
+                }
+            }
+            let from = profile.current();
+            let event = match event {
+                Control::Compute(report) => {
+                    Control::Signal(compute_congest(&report, &profile, &configs, fps))
+                }
+                event => event,
+            };
+            let signal = match event {
+                Control::Pin(Some(level)) => {
+                    let level = profile.set_level(level);
+                    pinned = Some(level);
+                    info!("pin level {}", level);
+                    block_send(src_tx.clone(), AdaptAction::PinLevel(level));
+                    let (from, to) = ((from, configs[from]), (level, configs[level]));
+                    publish_level(&mut level_subscribers, None, from, to);
+                    return Ok(());
+                }
+                Control::Pin(None) => {
+                    // Unpinning leaves the level as it is until the next signal.
+                    pinned = None;
+                    info!("unpin level {}", profile.current());
+                    block_send(src_tx.clone(), AdaptAction::Unpin);
+                    return Ok(());
+                }
+                Control::Reload(reloaded) => {
+                    // The level of the new profile nearest in bandwidth,
+                    // within the same limits as the old one.
+                    let rate = profile.rate_of(from).unwrap_or(0.0);
+                    let mut levels = reloaded.simplify();
+                    levels.set_selection(profile.selection());
+                    if let Some(max_rate) = max_rate {
+                        levels.cap(max_rate);
+                    }
+                    if pinned.is_none() {
+                        let now = Local::now().time();
+                        max_level = budget::max_level(&budget_schedule, now, &levels);
+                        levels.set_max_level(max_level);
+                    }
+                    let level = levels.set_level(reloaded.nearest_level(rate));
+                    if pinned.is_some() {
+                        pinned = Some(level);
+                    }
+                    info!("profile reloaded, {} levels, level {} -> {}", levels.len(), from, level);
+                    let from = (from, configs[from]);
+                    profile = levels;
+                    configs = reloaded.records().iter().map(|r| r.config).collect();
+                    block_send(src_tx.clone(), AdaptAction::Reload(reloaded, level));
+                    publish_level(&mut level_subscribers, None, from, (level, configs[level]));
+                    return Ok(());
+                }
+                // Pinned, nothing adapts.
+                Control::Signal(_) | Control::Measurement(_) if pinned.is_some() => return Ok(()),
+                Control::Signal(signal) => {
+                    match aimd {
+                        Some(ref mut aimd) => {
+                            aimd_adapt(signal, aimd, &mut profile, floor, src_tx.clone())
+                        }
+                        // The PID controller only follows measurements.
+                        None if pid.is_some() => {}
+                        None => {
+                            core_adapt(signal, &mut adaptation, &mut profile, floor, src_tx.clone())
+                        }
+                    }
+                    signal
+                }
+                Control::Measurement(m) => {
+                    let setpoint = match pid {
+                        Some((ref mut pid, setpoint)) => {
+                            pid_adapt(m, pid, &mut profile, src_tx.clone());
+                            setpoint
+                        }
+                        None => return Ok(()),
+                    };
+                    // What the measurement amounts to, for level changes.
+                    if m.latency > setpoint {
+                        Signal::QueueCongest(m.rate, m.latency)
+                    } else {
+                        Signal::QueueEmpty
+                    }
+                }
+                Control::Compute(_) => unreachable!("compute reports are signals by now"),
+                Control::Accuracy(report) => {
+                    if let Some(weight) = recalibration {
+                        for &(level, accuracy) in &report.levels {
+                            profile.recalibrate(level, accuracy, weight);
+                        }
+                        debug!("recalibrated accuracy with {:?}", report.levels);
+                    }
+                    return Ok(());
+                }
+            };
+
+            let to = profile.current();
+            let (from, to) = ((from, configs[from]), (to, configs[to]));
+            publish_level(&mut level_subscribers, Some(signal), from, to);
+
+            // Remember the level we are steady at for the next run. The rate
+            // controllers don't drive the state machine, so any level they
+            // settle on counts.
+            let steady = match adaptation.state() {
+                State::Steady => true,
+                _ => aimd.is_some() || pid.is_some(),
+            };
+            if steady {
+                let level = profile.current();
+                if last_good != Some(level) {
+                    last_good = Some(level);
+                    if let Err(e) = startup.save(level) {
+                        warn!("failed to save last good level: {}", e);
+                    }
+                }
+            }
             Ok(())
         })
         .map_err(|_| Error::from_kind(ErrorKind::ControlPlane));
 
     let control_plane = pool.spawn(control_plane);
-    core.run(control_plane)?;
+
+    // The data plane completes once the source reaches the end of data and
+    // everything has been sent, at which point the client shuts down.
+    let data_plane = data_plane.map_err(|_| Error::from_kind(ErrorKind::DataPlane));
+    let work = control_plane.select(data_plane).map(|_| ()).map_err(|(e, _)| e);
+    core.run(work)?;
+    for (name, stats) in sent.snapshot() {
+        info!(
+            "sent {}: {} data, {} bytes, {} too large for a datagram",
+            name,
+            stats.encoded,
+            stats.encoded_bytes,
+            stats.oversized
+        );
+    }
+    let b = buffered.snapshot();
+    info!(
+        "send buffer: high watermark {} bytes (backpressure at {}), {} sends rejected",
+        b.high_watermark,
+        b.backpressure,
+        b.rejected
+    );
+    for (name, stats) in received.snapshot() {
+        info!(
+            "received {}: {} data, {} bytes, {} errors",
+            name,
+            stats.decoded,
+            stats.decoded_bytes,
+            stats.decode_errors
+        );
+    }
+    info!("client finishes");
 
     Ok(())
 }
 
+/// What drives the control plane.
+enum Control {
+    /// A signal for the adaptation.
+    Signal(Signal),
+
+    /// The receiver's analytics can't keep up.
+    Compute(ComputeReport),
+
+    /// Accuracy the receiver achieves per level.
+    Accuracy(AccuracyReport),
+
+    /// What the monitor measured in the last interval, for a PID controller.
+    Measurement(Measurement),
+
+    /// Pins the level, or unpins it.
+    Pin(Option<usize>),
+
+    /// A profile reloaded from the file.
+    Reload(Profile<VideoConfig>),
+}
+
+/// Turns what the receiver's analytics reports into a signal. What it
+/// processes in kbps says little about what it can take, as its cost is per
+/// frame: the signal carries the rate of the highest level whose frame rate,
+/// of a source of `fps`, it keeps up with.
+fn compute_congest(
+    report: &ComputeReport,
+    profile: &SimpleProfile,
+    configs: &[VideoConfig],
+    fps: f64,
+) -> Signal {
+    let level = video::sustainable_level(configs, fps, report.fps);
+    let rate = profile.rate_of(level).unwrap_or(0.0);
+    debug!("analytics keeps up with level {} ({:.1} kbps)", level, rate);
+    Signal::ComputeCongest(rate, report.latency)
+}
+
+/// Publishes the change of level `from` to level `to`, each with its
+/// configuration, to `subscribers`, unless they are the same.
+fn publish_level(
+    subscribers: &mut Vec<UnboundedSender<LevelChange>>,
+    signal: Option<Signal>,
+    from: (usize, VideoConfig),
+    to: (usize, VideoConfig),
+) {
+    if from == to {
+        return;
+    }
+    let change = LevelChange {
+        ts: Utc::now(),
+        signal,
+        from: from.0,
+        to: to.0,
+        from_config: from.1,
+        to_config: to.1,
+    };
+    subscribers.retain(|s| s.unbounded_send(change).is_ok());
+}
+
 fn block_send<T>(tx: UnboundedSender<T>, item: T) {
     let errmsg = "failed to control source";
     tx.send(item).wait().expect(&errmsg);
 }
 
-fn core_adapt(
+/// Never adapts below `floor` (kbps), the rate the network guarantees.
+pub(crate) fn core_adapt(
     signal: Signal,
     adaptation: &mut Adaptation,
     profile: &mut SimpleProfile,
+    floor: f64,
     src_ctrl: UnboundedSender<AdaptAction>,
 ) {
+    if let Some(action) = core_action(signal, adaptation, profile, floor, PROBE_EXTRA) {
+        block_send(src_ctrl, action);
+    }
+}
+
+/// Moves `profile` as the adaptation decides on `signal`, and returns what
+/// the source is to do. Probes target `probe_extra` times the rate the next
+/// level needs. Shared with the simulation (see `simulation::simulate`).
+pub(crate) fn core_action(
+    signal: Signal,
+    adaptation: &mut Adaptation,
+    profile: &mut SimpleProfile,
+    floor: f64,
+    probe_extra: f64,
+) -> Option<AdaptAction> {
     let action = adaptation.transit(signal, profile.is_max());
-    match action {
-        Action::NoOp => {}
+    let to_source = match action {
+        Action::NoOp => None,
         Action::AdjustConfig(rate) => {
+            if rate < floor {
+                debug!("rate {:.1} kbps is below the guaranteed rate", rate);
+            }
+            let rate = rate.max(floor);
+            // The source follows the level chosen here, which accounts for
+            // recalibrated accuracy, but only once it changes.
+            let from = profile.current();
             let level = profile.adjust_level(rate);
-            block_send(src_ctrl, AdaptAction::ToRate(rate));
             info!("adjust config, level: {:?}, rate: {}", level, rate);
+            match level {
+                Some(level) if level != from => Some(AdaptAction::ToLevel(level)),
+                _ => Some(AdaptAction::StopProbe),
+            }
         }
         Action::AdvanceConfig => {
+            // The level the profile here allows, e.g. under a budget, rather
+            // than the next one of the source's own profile.
             let level = profile.advance_level();
-            block_send(src_ctrl, AdaptAction::DecreaseDegradation);
             info!("advance config to {:?}", level);
+            level.map(AdaptAction::ToLevel)
         }
         Action::StartProbe => {
             let delta = profile.next_rate_delta().expect("Must not at max config");
-            let target = PROBE_EXTRA * delta; // probe more space than needed
-            block_send(src_ctrl, AdaptAction::StartProbe(target));
+            let target = probe_extra * delta; // probe more space than needed
             info!("start probing for {:?}", target);
+            Some(AdaptAction::StartProbe(target))
         }
         Action::IncreaseProbePace => {
-            block_send(src_ctrl, AdaptAction::IncreaseProbePace);
             info!("increase probe pace");
+            Some(AdaptAction::IncreaseProbePace)
         }
         Action::StopProbe => {
-            block_send(src_ctrl, AdaptAction::StopProbe);
             info!("stop probe pace");
+            Some(AdaptAction::StopProbe)
+        }
+    };
+    let level = profile.current();
+    adaptation.decided(level, profile.rate_of(level).unwrap_or(0.0));
+    to_source
+}
+
+/// Follows the AIMD target rate (but not below `floor`, the rate the network
+/// guarantees) with the highest level that fits within it.
+fn aimd_adapt(
+    signal: Signal,
+    aimd: &mut Aimd,
+    profile: &mut SimpleProfile,
+    floor: f64,
+    src_ctrl: UnboundedSender<AdaptAction>,
+) {
+    if let Some(rate) = aimd.transit(signal) {
+        let level = profile.level_for_rate(rate.max(floor));
+        if level != profile.current() {
+            profile.set_level(level);
+            block_send(src_ctrl, AdaptAction::ToLevel(level));
         }
+        info!("aimd rate: {:.1} kbps, level: {}", rate, level);
+    }
+}
+
+/// Moves the PID target with the queue latency of `m` and sends it to the
+/// source, which follows it with the highest level within it.
+fn pid_adapt(
+    m: Measurement,
+    pid: &mut Pid,
+    profile: &mut SimpleProfile,
+    src_ctrl: UnboundedSender<AdaptAction>,
+) {
+    // Nothing went out in the interval, so the latency is unknown.
+    if !m.latency.is_finite() {
+        return;
+    }
+    let rate = pid.update(m.latency, MONITOR_INTERVAL as f64 / 1000.0);
+    let level = profile.level_for_rate(rate);
+    if level != profile.current() {
+        profile.set_level(level);
+        info!("pid rate: {:.1} kbps, latency: {:.1} ms, level: {}", rate, m.latency, level);
     }
+    // The source picks the level for the rate from all its levels, so the
+    // rate stays within the highest one allowed (see `budget`).
+    let rate = match profile.rate_of(level) {
+        Some(top) if profile.is_max() => rate.min(top),
+        _ => rate,
+    };
+    block_send(src_ctrl, AdaptAction::ToRate(rate));
 }
@@ -0,0 +1,158 @@
+//! Frame coalescing. At high skip levels frames can be so small that the
+//! per-datum overhead (header, length prefix, one write each) dominates;
+//! coalescing several consecutive small frames into one datum amortizes it on
+//! constrained links.
+
+use super::AsDatum;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// When frames are coalesced.
+///
+/// ```toml
+/// [coalesce]
+/// below = 2000
+/// max_frames = 4
+/// max_delay_ms = 200
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct CoalesceConfig {
+    /// Frames smaller than this (bytes) are held back and sent together, until
+    /// they add up to this size.
+    pub below: usize,
+
+    /// At most this many frames go into one datum, which bounds the delay
+    /// coalescing adds. 4 if not set.
+    #[serde(default = "default_max_frames")]
+    pub max_frames: usize,
+
+    /// No frame is held back for longer than this (ms), however few and
+    /// small the frames are. 200 if not set.
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+fn default_max_frames() -> usize {
+    4
+}
+
+fn default_max_delay_ms() -> u64 {
+    200
+}
+
+/// Collects small live data and releases them as one coalesced datum.
+pub struct Coalescer {
+    config: CoalesceConfig,
+    pending: Vec<AsDatum>,
+    bytes: usize,
+
+    /// When the oldest pending datum was held back.
+    since: Option<Instant>,
+}
+
+impl Coalescer {
+    /// Creates a coalescer with nothing pending.
+    pub fn new(config: CoalesceConfig) -> Coalescer {
+        Coalescer {
+            config,
+            pending: Vec::new(),
+            bytes: 0,
+            since: None,
+        }
+    }
+
+    /// Takes a live datum and returns what is ready to send: the datum itself
+    /// if it is large enough and nothing is pending, the pending data
+    /// coalesced once they are large or many enough or have waited too long,
+    /// or nothing.
+    pub fn push(&mut self, datum: AsDatum) -> Option<AsDatum> {
+        if self.pending.is_empty() && datum.mem.len() >= self.config.below {
+            return Some(datum);
+        }
+        let now = Instant::now();
+        self.since.get_or_insert(now);
+        self.bytes += datum.mem.len();
+        self.pending.push(datum);
+        if self.bytes >= self.config.below || self.pending.len() >= self.config.max_frames {
+            self.flush()
+        } else {
+            self.due(now)
+        }
+    }
+
+    /// Releases the pending data if the oldest of them has waited for
+    /// `max_delay_ms` by `now`, so that data never wait for a batch to fill.
+    pub fn due(&mut self, now: Instant) -> Option<AsDatum> {
+        let deadline = Duration::from_millis(self.config.max_delay_ms);
+        match self.since {
+            Some(since) if now.duration_since(since) >= deadline => self.flush(),
+            _ => None,
+        }
+    }
+
+    /// Releases whatever is pending. A single frame is sent as is.
+    pub fn flush(&mut self) -> Option<AsDatum> {
+        self.bytes = 0;
+        self.since = None;
+        let mut pending = mem::take(&mut self.pending);
+        match pending.len() {
+            0 => None,
+            1 => pending.pop(),
+            _ => Some(AsDatum::coalesce(pending).expect("failed to coalesce live data")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AsDatumType;
+
+    #[test]
+    fn coalesces_small_frames() {
+        let mut c = Coalescer::new(CoalesceConfig {
+            below: 100,
+            max_frames: 3,
+            max_delay_ms: 1000,
+        });
+
+        // Large frames pass straight through.
+        let large = c.push(AsDatum::new(0, 1, vec![0; 150])).unwrap();
+        assert_eq!(large.datum_type(), AsDatumType::Live(0, 1));
+
+        // Small ones wait until they add up to `below`...
+        assert!(c.push(AsDatum::new(0, 2, vec![0; 40])).is_none());
+        assert!(c.push(AsDatum::new(0, 3, vec![0; 40])).is_none());
+        let d = c.push(AsDatum::new(0, 4, vec![0; 40])).unwrap();
+        assert_eq!(d.live_frames(), vec![(0, 2), (0, 3), (0, 4)]);
+
+        // ...or to `max_frames`.
+        for i in 5..7 {
+            assert!(c.push(AsDatum::new(0, i, vec![0; 10])).is_none());
+        }
+        assert_eq!(c.push(AsDatum::new(0, 7, vec![0; 10])).unwrap().live_frames().len(), 3);
+
+        // A lone pending frame is flushed as is.
+        assert!(c.push(AsDatum::new(0, 8, vec![0; 10])).is_none());
+        assert_eq!(c.flush().unwrap().datum_type(), AsDatumType::Live(0, 8));
+        assert!(c.flush().is_none());
+    }
+
+    #[test]
+    fn pending_frames_have_a_deadline() {
+        let mut c = Coalescer::new(CoalesceConfig {
+            below: 100,
+            max_frames: 3,
+            max_delay_ms: 50,
+        });
+        assert!(c.due(Instant::now() + Duration::from_secs(1)).is_none());
+
+        assert!(c.push(AsDatum::new(0, 1, vec![0; 10])).is_none());
+        assert!(c.push(AsDatum::new(0, 2, vec![0; 10])).is_none());
+        assert!(c.due(Instant::now()).is_none());
+
+        let late = Instant::now() + Duration::from_millis(60);
+        assert_eq!(c.due(late).unwrap().live_frames(), vec![(0, 1), (0, 2)]);
+        assert!(c.due(late).is_none());
+    }
+}
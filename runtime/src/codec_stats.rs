@@ -0,0 +1,94 @@
+//! Wire-level accounting of `AsCodec`: how many data of each type went over
+//! the wire and how many bytes they took (including framing), e.g. how much
+//! goes to probes versus live data versus control, without instrumenting the
+//! call sites that send them.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Name under which errors are counted when the datum type is unknown, e.g.
+/// a frame that fails its checksum.
+pub const UNKNOWN: &str = "unknown";
+
+/// Counters of one datum type.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct TypeStats {
+    /// Data encoded.
+    pub encoded: u64,
+
+    /// Bytes encoded.
+    pub encoded_bytes: u64,
+
+    /// Data decoded.
+    pub decoded: u64,
+
+    /// Bytes decoded.
+    pub decoded_bytes: u64,
+
+    /// Data that failed to encode.
+    pub encode_errors: u64,
+
+    /// Data that failed to decode.
+    pub decode_errors: u64,
+
+    /// Data too large for a datagram, sent over TCP instead (see
+    /// `udp::SplitSocket`).
+    pub oversized: u64,
+}
+
+/// A handle to the counters of a codec, per datum type (see
+/// `AsDatumType::name`). Clones share the counters.
+#[derive(Debug, Clone, Default)]
+pub struct CodecStats {
+    inner: Arc<Mutex<BTreeMap<&'static str, TypeStats>>>,
+}
+
+impl CodecStats {
+    fn update<F: FnOnce(&mut TypeStats)>(&self, name: &'static str, f: F) {
+        let mut m = self.inner.lock().expect("failed to update codec stats");
+        f(m.entry(name).or_default());
+    }
+
+    /// Counts a datum of type `name` encoded into `bytes`.
+    pub fn encoded(&self, name: &'static str, bytes: usize) {
+        self.update(name, |s| {
+            s.encoded += 1;
+            s.encoded_bytes += bytes as u64;
+        });
+    }
+
+    /// Counts a datum of type `name` decoded from `bytes`.
+    pub fn decoded(&self, name: &'static str, bytes: usize) {
+        self.update(name, |s| {
+            s.decoded += 1;
+            s.decoded_bytes += bytes as u64;
+        });
+    }
+
+    /// Counts a datum of type `name` that failed to encode.
+    pub fn encode_error(&self, name: &'static str) {
+        self.update(name, |s| s.encode_errors += 1);
+    }
+
+    /// Counts a datum of type `name` that failed to decode.
+    pub fn decode_error(&self, name: &'static str) {
+        self.update(name, |s| s.decode_errors += 1);
+    }
+
+    /// Counts a datum of type `name` too large for a datagram.
+    pub fn oversized(&self, name: &'static str) {
+        self.update(name, |s| s.oversized += 1);
+    }
+
+    /// Returns the counters of datum type `name`.
+    pub fn get(&self, name: &str) -> TypeStats {
+        let m = self.inner.lock().expect("failed to read codec stats");
+        m.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Returns the counters of every datum type seen so far.
+    pub fn snapshot(&self) -> BTreeMap<String, TypeStats> {
+        let m = self.inner.lock().expect("failed to read codec stats");
+        m.iter().map(|(&name, &s)| (name.to_string(), s)).collect()
+    }
+}
@@ -0,0 +1,134 @@
+//! Per-connection event log of the server. Every connection gets its own file
+//! with one JSON object per line, e.g.
+//!
+//! ```text
+//! {"ts":"2017-09-01T00:00:00.000Z","event":"connect","client":"1.2.3.4:5678",...}
+//! {"ts":"2017-09-01T00:00:00.100Z","event":"first_datum","level":0,"frame_num":1}
+//! {"ts":"2017-09-01T00:00:01.000Z","event":"levels","frames":{"0":12,"1":18}}
+//! {"ts":"2017-09-01T00:00:05.000Z","event":"report","report":{...}}
+//! {"ts":"2017-09-01T00:00:10.000Z","event":"latency","datum_type":"live","latency":{...}}
+//! {"ts":"2017-09-01T00:00:10.000Z","event":"streams","streams":{"0":{"level":3,...},"1":{...}}}
+//! {"ts":"2017-09-01T00:01:00.000Z","event":"wire","stats":{"live":{"decoded":1800,...},...}}
+//! {"ts":"2017-09-01T00:01:00.000Z","event":"disconnect","reason":"closed by client"}
+//! ```
+
+use super::{BufferSnapshot, ComputeReport, ReceiverReport, TypeStats};
+use bw_monitor::StreamStats;
+use chrono::{DateTime, Utc};
+use futures::sync::mpsc::UnboundedSender;
+use histogram::Snapshot;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use utils::spawn_json_log;
+
+/// What happens on a connection.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ConnEvent {
+    /// A client connects.
+    Connect { experiment: String, client: String },
+
+    /// The first live datum arrives.
+    FirstDatum { level: usize, frame_num: usize },
+
+    /// A congestion report is sent to the client.
+    Report { report: ReceiverReport },
+
+    /// A compute congestion report is sent to the client.
+    ComputeReport { report: ComputeReport },
+
+    /// The distribution of data received over levels in the last interval
+    /// differs from the previous interval (frames per level).
+    Levels { frames: BTreeMap<usize, usize> },
+
+    /// Latency of a datum type over the last snapshot interval.
+    Latency {
+        datum_type: String,
+        latency: Snapshot,
+    },
+
+    /// What each stream received over the last interval, once the
+    /// connection carries more than the primary stream.
+    Streams { streams: BTreeMap<u32, StreamStats> },
+
+    /// What went over the wire on the connection, per datum type.
+    Wire { stats: BTreeMap<String, TypeStats> },
+
+    /// How full the send buffer of the connection has been.
+    Buffer { stats: BufferSnapshot },
+
+    /// The connection closes.
+    Disconnect { reason: String },
+}
+
+#[derive(Serialize)]
+struct Entry {
+    ts: DateTime<Utc>,
+    #[serde(flatten)]
+    event: ConnEvent,
+}
+
+/// Writes `ConnEvent`s of a connection; does nothing if the server has no
+/// event log configured.
+#[derive(Clone)]
+pub struct ConnLog {
+    tx: Option<UnboundedSender<Entry>>,
+}
+
+impl ConnLog {
+    /// Creates the log for a connection from `addr` in directory `dir`. If
+    /// the file can't be created, the error is logged and events are dropped.
+    pub fn new(dir: Option<&str>, experiment: &str, addr: SocketAddr) -> ConnLog {
+        let tx = dir.and_then(|dir| {
+            let path = format!(
+                "{}/events-{}-{}-{}.jsonl",
+                dir,
+                experiment,
+                addr.ip(),
+                addr.port()
+            );
+            match spawn_json_log(&path) {
+                Ok(tx) => Some(tx),
+                Err(e) => {
+                    error!("failed to create event log {}: {}", path, e);
+                    None
+                }
+            }
+        });
+        ConnLog { tx }
+    }
+
+    pub fn log(&self, event: ConnEvent) {
+        if let Some(ref tx) = self.tx {
+            let entry = Entry {
+                ts: Utc::now(),
+                event,
+            };
+            if tx.unbounded_send(entry).is_err() {
+                error!("connection event log has stopped");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn entries_are_flat() {
+        let entry = Entry {
+            ts: Utc::now(),
+            event: ConnEvent::FirstDatum {
+                level: 2,
+                frame_num: 7,
+            },
+        };
+        let json: serde_json::Value = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["event"], "first_datum");
+        assert_eq!(json["level"], 2);
+        assert_eq!(json["frame_num"], 7);
+        assert!(json["ts"].is_string());
+    }
+}
@@ -0,0 +1,249 @@
+//! Emulated contention: one process runs several adaptive clients (see
+//! `client::run_load`), each with its own source and profile, whose data all
+//! go through one emulated bottleneck. This tests how the server scales and
+//! how fairly clients share a link, without a machine per client. The
+//! bottleneck may follow a canned schedule (see `presets`) instead of a fixed
+//! rate.
+
+use super::AsDatum;
+use super::presets::Preset;
+use super::setting::Setting;
+use errors::*;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_timer::{self, Sleep, Timer};
+
+/// How long a burst the bottleneck lets through at its rate (ms).
+const BURST_MS: f64 = 50.0;
+
+/// The clients one process runs.
+///
+/// ```toml
+/// [load]
+/// clients = 8
+/// bottleneck = 5000.0
+/// preset = "step_drop"
+///
+/// [[load.client]]
+/// source_path = "a.source.csv"
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct LoadConfig {
+    /// Number of clients.
+    pub clients: usize,
+
+    /// If set, the rate (kbps) of the bottleneck all clients share. Only the
+    /// actual link otherwise.
+    #[serde(default)]
+    pub bottleneck: Option<f64>,
+
+    /// If set, the bottleneck follows this preset's schedule instead, and
+    /// keeps its last rate once the schedule is over.
+    #[serde(default)]
+    pub preset: Option<Preset>,
+
+    /// What the clients send, in turn (`[[load.client]]` sections). Fields not
+    /// set are inherited from the top-level setting, which all clients send if
+    /// there is none.
+    #[serde(default, rename = "client")]
+    pub per_client: Vec<LoadClient>,
+}
+
+/// What one of the clients sends.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct LoadClient {
+    /// Path to the profile.
+    #[serde(default)]
+    pub profile_path: Option<String>,
+
+    /// Path to source.
+    #[serde(default)]
+    pub source_path: Option<String>,
+
+    /// Path to stat.
+    #[serde(default)]
+    pub stat_path: Option<String>,
+}
+
+impl LoadConfig {
+    /// Returns the setting of client `i`. Its logs get the index of the
+    /// client appended, so that clients don't write into the same file.
+    pub fn client(&self, setting: &Setting, i: usize) -> Setting {
+        let or = |v: &Option<String>, default: &str| {
+            v.clone().unwrap_or_else(|| default.to_string())
+        };
+        let suffixed = |path: &Option<String>| path.as_ref().map(|p| format!("{}.{}", p, i));
+        let mut client = Setting {
+            name: format!("{}-{}", setting.name, i),
+            level_log: suffixed(&setting.level_log),
+            event_log: suffixed(&setting.event_log),
+            decision_log: suffixed(&setting.decision_log),
+            probe_log: suffixed(&setting.probe_log),
+            load: None,
+            ..setting.clone()
+        };
+        if !self.per_client.is_empty() {
+            let c = &self.per_client[i % self.per_client.len()];
+            client.profile_path = or(&c.profile_path, &setting.profile_path);
+            client.source_path = or(&c.source_path, &setting.source_path);
+            client.stat_path = or(&c.stat_path, &setting.stat_path);
+        }
+        client
+    }
+}
+
+struct Bucket {
+    /// Bytes that may go out now; negative after a datum larger than what was
+    /// available.
+    tokens: f64,
+    last: Instant,
+}
+
+/// A token bucket shared by the clients: a datum goes out once the bytes
+/// before it have drained at the bottleneck's rate.
+#[derive(Clone)]
+pub struct Bottleneck {
+    /// Rates in bytes per second, one per second since `start`; the last one
+    /// holds after.
+    schedule: Arc<Vec<f64>>,
+    start: Instant,
+    bucket: Arc<Mutex<Bucket>>,
+    timer: Timer,
+}
+
+impl Bottleneck {
+    /// Creates a bottleneck of `kbps`.
+    pub fn new(kbps: f64) -> Bottleneck {
+        Bottleneck::with_rates(&[kbps])
+    }
+
+    /// Creates a bottleneck that follows `schedule` (kbps, one per second),
+    /// which needs at least one rate.
+    pub fn scheduled(schedule: &[f64]) -> Result<Bottleneck> {
+        if schedule.is_empty() {
+            bail!("the bandwidth schedule is empty");
+        }
+        Ok(Bottleneck::with_rates(schedule))
+    }
+
+    fn with_rates(schedule: &[f64]) -> Bottleneck {
+        let timer = tokio_timer::wheel()
+            .tick_duration(Duration::from_millis(1))
+            .build();
+        let now = Instant::now();
+        Bottleneck {
+            schedule: Arc::new(schedule.iter().map(|kbps| kbps * 1000.0 / 8.0).collect()),
+            start: now,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: 0.0,
+                last: now,
+            })),
+            timer,
+        }
+    }
+
+    /// Returns the rate (bytes per second) at `now`.
+    fn rate(&self, now: Instant) -> f64 {
+        let second = now.duration_since(self.start).as_secs() as usize;
+        let last = self.schedule.len() - 1;
+        self.schedule[second.min(last)]
+    }
+
+    /// Returns how long until the next datum may go out, or `None` if it may
+    /// now.
+    fn wait(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let rate = self.rate(now);
+        let elapsed = now.duration_since(bucket.last);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate * BURST_MS / 1000.0);
+        bucket.last = now;
+        if bucket.tokens >= 0.0 {
+            None
+        } else {
+            let ms = (-bucket.tokens / rate * 1000.0).ceil() as u64;
+            Some(Duration::from_millis(ms))
+        }
+    }
+
+    fn take(&self, bytes: usize) {
+        self.bucket.lock().unwrap().tokens -= bytes as f64;
+    }
+}
+
+/// Sends through `inner` no faster than the bottleneck lets it.
+pub struct Throttled<S> {
+    inner: S,
+    bottleneck: Bottleneck,
+    sleep: Option<Sleep>,
+}
+
+impl<S> Throttled<S> {
+    /// Throttles `inner` by `bottleneck`.
+    pub fn new(inner: S, bottleneck: Bottleneck) -> Throttled<S> {
+        Throttled {
+            inner,
+            bottleneck,
+            sleep: None,
+        }
+    }
+}
+
+impl<S> Sink for Throttled<S>
+where
+    S: Sink<SinkItem = AsDatum, SinkError = Error>,
+{
+    type SinkItem = AsDatum;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: AsDatum) -> StartSend<AsDatum, Error> {
+        loop {
+            if let Some(ref mut sleep) = self.sleep {
+                if let Async::NotReady = sleep.poll()? {
+                    return Ok(AsyncSink::NotReady(item));
+                }
+            }
+            self.sleep = self.bottleneck.wait().map(|wait| self.bottleneck.timer.sleep(wait));
+            if self.sleep.is_none() {
+                break;
+            }
+        }
+        let len = item.net_len();
+        let sent = self.inner.start_send(item)?;
+        if sent.is_ready() {
+            self.bottleneck.take(len);
+        }
+        Ok(sent)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        self.inner.poll_complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bottleneck_drains_at_its_rate() {
+        // 8 kbps is 1000 bytes per second.
+        let bottleneck = Bottleneck::new(8.0);
+        assert_eq!(bottleneck.wait(), None);
+        bottleneck.take(500);
+        let wait = bottleneck.wait().unwrap();
+        assert!(wait <= Duration::from_millis(500) && wait >= Duration::from_millis(450));
+    }
+
+    #[test]
+    fn bottleneck_follows_its_schedule() {
+        assert!(Bottleneck::scheduled(&[]).is_err());
+        let bottleneck = Bottleneck::scheduled(&[8.0, 16.0]).unwrap();
+        let start = bottleneck.start;
+        assert_eq!(bottleneck.rate(start), 1000.0);
+        assert_eq!(bottleneck.rate(start + Duration::from_millis(1500)), 2000.0);
+        assert_eq!(bottleneck.rate(start + Duration::from_secs(60)), 2000.0);
+    }
+}
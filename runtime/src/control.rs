@@ -0,0 +1,190 @@
+//! Typed control messages, for everything that controls a peer rather than
+//! carries data. Messages that predate this module each have a datum type of
+//! their own (e.g. `ReceiverCongest`) and keep it, so that old peers still
+//! understand them; `ControlMessage::from_datum` reads those too.
+//!
+//! New messages go in a `Control` datum instead, whose payload is the code of
+//! the message kind (u16) and the version of its encoding (u8), both as in
+//! `CONTROL_KINDS`, followed by the body. A peer skips kinds it doesn't know
+//! and versions newer than it reads, rather than failing the connection, so
+//! that messages (e.g. a profile push or a pause) can be added without
+//! breaking the peers built before them. A kind whose encoding changes gets a
+//! new version, and the decoder keeps reading the old ones.
+
+use super::{AccuracyReport, AsDatum, AsDatumType, ComputeReport, Detections, DropReport,
+            ReceiverReport};
+use bincode;
+use byteorder::{BigEndian, ByteOrder};
+use errors::*;
+
+/// A kind of control message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlKind {
+    /// Code of the kind in a `Control` datum. Codes are never reused.
+    pub code: u16,
+
+    /// Name of the kind, e.g. for logs.
+    pub name: &'static str,
+
+    /// Latest version of the encoding, which is what is written.
+    pub version: u8,
+}
+
+/// Every kind of control message this peer reads and writes.
+pub const CONTROL_KINDS: [ControlKind; 7] = [
+    ControlKind { code: 1, name: "receiver_report", version: 1 },
+    ControlKind { code: 2, name: "compute_report", version: 1 },
+    ControlKind { code: 3, name: "accuracy_report", version: 1 },
+    ControlKind { code: 4, name: "drop_report", version: 1 },
+    ControlKind { code: 5, name: "delivery_ack", version: 1 },
+    ControlKind { code: 6, name: "detections", version: 1 },
+    ControlKind { code: 7, name: "pin_level", version: 1 },
+];
+
+/// Bytes of the code and the version in front of the body.
+const ENVELOPE_LEN: usize = 3;
+
+/// A control message.
+#[derive(Debug, Clone)]
+pub enum ControlMessage {
+    /// The receiver detects congestion.
+    Receiver(ReceiverReport),
+
+    /// The receiver's analytics can't keep up.
+    Compute(ComputeReport),
+
+    /// Accuracy the receiver achieves per level.
+    Accuracy(AccuracyReport),
+
+    /// Data the sender has dropped.
+    Drops(DropReport),
+
+    /// Bytes the receiver has received so far.
+    Delivered(u64),
+
+    /// What the receiver's analytics detects in a frame.
+    Detections(Detections),
+
+    /// Pins the receiver's level, or unpins it if `None`.
+    PinLevel(Option<usize>),
+}
+
+impl ControlMessage {
+    /// Returns the kind of this message.
+    pub fn kind(&self) -> ControlKind {
+        let code = match *self {
+            ControlMessage::Receiver(_) => 1,
+            ControlMessage::Compute(_) => 2,
+            ControlMessage::Accuracy(_) => 3,
+            ControlMessage::Drops(_) => 4,
+            ControlMessage::Delivered(_) => 5,
+            ControlMessage::Detections(_) => 6,
+            ControlMessage::PinLevel(_) => 7,
+        };
+        CONTROL_KINDS[code as usize - 1]
+    }
+
+    /// Encodes the message in the latest version of its kind, with the code
+    /// and the version in front.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let body = match *self {
+            ControlMessage::Receiver(ref report) => report.to_mem()?,
+            ControlMessage::Compute(ref report) => report.to_mem()?,
+            ControlMessage::Accuracy(ref report) => report.to_mem()?,
+            ControlMessage::Drops(ref report) => report.to_mem()?,
+            ControlMessage::Delivered(bytes) => bincode::serialize(&bytes, bincode::Infinite)?,
+            ControlMessage::Detections(ref detections) => detections.to_mem()?,
+            ControlMessage::PinLevel(level) => {
+                bincode::serialize(&level.map(|l| l as u64), bincode::Infinite)?
+            }
+        };
+        let kind = self.kind();
+        let mut mem = vec![0; ENVELOPE_LEN];
+        BigEndian::write_u16(&mut mem[..2], kind.code);
+        mem[2] = kind.version;
+        mem.extend_from_slice(&body);
+        Ok(mem)
+    }
+
+    /// Decodes a message encoded by `encode`. Returns `None` for kinds this
+    /// peer doesn't know, or versions newer than it reads.
+    pub fn decode(mem: &[u8]) -> Result<Option<ControlMessage>> {
+        if mem.len() < ENVELOPE_LEN {
+            bail!("control message of {} bytes", mem.len());
+        }
+        let (code, version) = (BigEndian::read_u16(&mem[..2]), mem[2]);
+        let body = &mem[ENVELOPE_LEN..];
+        let message = match (code, version) {
+            (1, 1) => ControlMessage::Receiver(ReceiverReport::from_mem(body)?),
+            (2, 1) => ControlMessage::Compute(ComputeReport::from_mem(body)?),
+            (3, 1) => ControlMessage::Accuracy(AccuracyReport::from_mem(body)?),
+            (4, 1) => ControlMessage::Drops(DropReport::from_mem(body)?),
+            (5, 1) => ControlMessage::Delivered(bincode::deserialize(body)?),
+            (6, 1) => ControlMessage::Detections(Detections::from_mem(body)?),
+            (7, 1) => {
+                let level: Option<u64> = bincode::deserialize(body)?;
+                ControlMessage::PinLevel(level.map(|l| l as usize))
+            }
+            _ => {
+                debug!("skip control message {} of version {}", code, version);
+                return Ok(None);
+            }
+        };
+        Ok(Some(message))
+    }
+
+    /// Returns the message `d` carries, in a `Control` datum or in a datum
+    /// type of its own; `None` if `d` carries data, or a message this peer
+    /// doesn't know.
+    pub fn from_datum(d: &AsDatum) -> Result<Option<ControlMessage>> {
+        let mem = &d.mem[..];
+        let message = match d.datum_type() {
+            AsDatumType::Control => return ControlMessage::decode(mem),
+            AsDatumType::ReceiverCongest => {
+                ControlMessage::Receiver(ReceiverReport::from_mem(mem)?)
+            }
+            AsDatumType::ComputeCongest => ControlMessage::Compute(ComputeReport::from_mem(mem)?),
+            AsDatumType::AccuracyFeedback => {
+                ControlMessage::Accuracy(AccuracyReport::from_mem(mem)?)
+            }
+            AsDatumType::SenderDrops => ControlMessage::Drops(DropReport::from_mem(mem)?),
+            AsDatumType::DeliveryAck => ControlMessage::Delivered(d.delivered()?),
+            AsDatumType::Detections => ControlMessage::Detections(Detections::from_mem(mem)?),
+            _ => return Ok(None),
+        };
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_messages_are_versioned() {
+        for (i, kind) in CONTROL_KINDS.iter().enumerate() {
+            assert_eq!(kind.code as usize, i + 1);
+        }
+
+        let d = AsDatum::control(&ControlMessage::PinLevel(Some(2))).unwrap();
+        assert_eq!(d.datum_type(), AsDatumType::Control);
+        match ControlMessage::from_datum(&d).unwrap() {
+            Some(ControlMessage::PinLevel(level)) => assert_eq!(level, Some(2)),
+            other => panic!("unexpected {:?}", other),
+        }
+        let ack = AsDatum::delivery_ack(42).unwrap();
+        match ControlMessage::from_datum(&ack).unwrap() {
+            Some(ControlMessage::Delivered(bytes)) => assert_eq!(bytes, 42),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(ControlMessage::from_datum(&AsDatum::latency_probe()).unwrap().is_none());
+
+        // Unknown kinds and newer versions are skipped, short ones fail.
+        let mut mem = ControlMessage::Delivered(42).encode().unwrap();
+        mem[2] = 2;
+        assert!(ControlMessage::decode(&mem).unwrap().is_none());
+        mem[..3].copy_from_slice(&[0xff, 0xff, 1]);
+        assert!(ControlMessage::decode(&mem).unwrap().is_none());
+        assert!(ControlMessage::decode(&[0, 1]).is_err());
+    }
+}
@@ -1,45 +1,171 @@
+use AsDatum;
 use adaptation::Signal;
+use bbr::BbrEstimate;
+use chrono::Utc;
+use detector::{CongestionDetector, Measurement};
 use errors::*;
 use futures::{Async, Poll, Stream};
-use std::sync::Arc;
+use futures::sync::mpsc::UnboundedSender;
+use queue::Occupancy;
+use source::SourceStat;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio_timer::{self, Interval};
 use utils::ExponentialSmooth;
 
-const ALPHA_RATE: f64 = 0.9;
+/// The latest delivery ack from the receiver: when it arrived and the bytes
+/// it acknowledges.
+#[derive(Clone, Default)]
+pub struct Delivery {
+    latest: Arc<Mutex<Option<(Instant, u64)>>>,
+}
+
+impl Delivery {
+    pub fn new() -> Self {
+        Delivery::default()
+    }
+
+    /// Records that the receiver has received `bytes` so far.
+    pub fn ack(&self, bytes: u64) {
+        let mut latest = self.latest.lock().expect("failed to record delivery ack");
+        *latest = Some((Instant::now(), bytes));
+    }
+
+    fn latest(&self) -> Option<(Instant, u64)> {
+        *self.latest.lock().expect("failed to read delivery ack")
+    }
+}
+
+/// Minimum interval (ms) between two delivery acks.
+const DELIVERY_ACK_INTERVAL: u64 = 100;
+
+/// The receiving end of delivery acks: counts the bytes received on a
+/// connection and acknowledges them at most every `DELIVERY_ACK_INTERVAL`, so
+/// that the sender learns the rate the network delivers.
+pub struct Acknowledger {
+    /// Bytes received on the connection, as the sender counts them.
+    received: u64,
+    last_ack: Instant,
+}
+
+impl Acknowledger {
+    pub fn new() -> Self {
+        Acknowledger {
+            received: 0,
+            last_ack: Instant::now(),
+        }
+    }
+
+    /// Counts `size` bytes as received and returns an ack of what has been
+    /// received so far, if one is due. Sizes are counted as the sender counts
+    /// what it sends (see `AsDatum::sent_len`).
+    pub fn add(&mut self, size: usize) -> Result<Option<AsDatum>> {
+        self.received += size as u64;
+        if self.last_ack.elapsed() < Duration::from_millis(DELIVERY_ACK_INTERVAL) {
+            return Ok(None);
+        }
+        self.last_ack = Instant::now();
+        AsDatum::delivery_ack(self.received).map(Some)
+    }
+}
+
+/// Bandwidth spent on probes over one second, one row of the probe log (see
+/// `Setting::probe_log`).
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct ProbeOverhead {
+    /// End of the second (seconds since the Unix epoch).
+    pub timestamp: f64,
+
+    /// Rate (kbps) of probes.
+    pub kbps: f64,
+
+    /// Share of the bytes sent that are probes.
+    pub share: f64,
+}
 
 pub struct Monitor {
     /// Fires to estimate outgoing bandwidth and expected latency
     timer: Interval,
 
-    /// My Reference to the data being generated.
-    produced_bytes: Arc<AtomicUsize>,
+    /// My Reference to the data (and probes) being generated.
+    produced: SourceStat,
+
+    /// Probe bytes produced in the current second.
+    probe_bytes: usize,
+
+    /// Bytes sent in the current second.
+    sent_bytes: usize,
+
+    /// Monitor ticks in the current second.
+    ticks: usize,
 
     /// My Reference to the data being consumed.
     consumed_bytes: Arc<AtomicUsize>,
 
+    /// Bytes the receiver acknowledges.
+    delivery: Delivery,
+
+    /// The delivery ack the last rate sample was taken from.
+    last_ack: Option<(Instant, u64)>,
+
+    /// The last rate sample (bytes per `MONITOR_INTERVAL`) from delivery acks.
+    ack_sample: f64,
+
     /// The estimated consumption rate.
     rate: ExponentialSmooth,
 
     /// Queued bytes.
     queued: usize,
 
-    /// Empty counts.
-    empty_count: usize,
+    /// Occupancy of the source queue (bytes and age of the oldest datum).
+    occupancy: Occupancy,
+
+    /// Turns rate and latency into congestion signals.
+    detector: Box<dyn CongestionDetector + Send>,
 
     /// Remembers if timer has fired or not. We delay `react_to_timer` to avoid
     /// the race with `socket`.
     timer_fired: bool,
+
+    /// Estimates for probing with data, sampled from delivery acks.
+    bbr: Option<BbrEstimate>,
+
+    /// Bytes sent in total at every tick within the last two `ACK_TIMEOUT`,
+    /// to tell when the bytes an ack acknowledges were sent, and which bytes
+    /// are overdue.
+    sent_log: VecDeque<(Instant, u64)>,
+    sent_total: u64,
+
+    /// Bytes sent over `ACK_TIMEOUT` ago, and those of them not acknowledged,
+    /// at the last loss sample.
+    overdue: Option<(u64, u64)>,
+
+    /// The last share of bytes lost.
+    loss: f64,
+
+    /// Receives every measurement, if anything does.
+    measurements: Option<UnboundedSender<Measurement>>,
+
+    /// Receives the probe overhead of every second, if anything does.
+    probe_log: Option<UnboundedSender<ProbeOverhead>>,
 }
 
-/// QUEUE_EMPTY_REQUIRED * MONITOR_INTERVAL => 1 seconds for each Q_E
-const QUEUE_EMPTY_REQUIRED: usize = 20;
+pub(crate) const MONITOR_INTERVAL: u64 = 100;
 
-const MONITOR_INTERVAL: u64 = 100;
+/// Delivery acks older than this (ms) no longer tell the delivery rate, and
+/// the rate falls back to how fast the socket drains.
+const ACK_TIMEOUT: u64 = 1000;
 
 impl Monitor {
-    pub fn new(producer: Arc<AtomicUsize>, consumer: Arc<AtomicUsize>) -> Self {
+    pub fn new(
+        producer: SourceStat,
+        consumer: Arc<AtomicUsize>,
+        delivery: Delivery,
+        occupancy: Occupancy,
+        detector: Box<dyn CongestionDetector + Send>,
+    ) -> Self {
         let timer = tokio_timer::wheel()
             .tick_duration(Duration::from_millis(50))
             .build()
@@ -47,46 +173,224 @@ impl Monitor {
 
         Monitor {
             timer: timer,
-            produced_bytes: producer,
+            produced: producer,
+            probe_bytes: 0,
+            sent_bytes: 0,
+            ticks: 0,
             consumed_bytes: consumer,
+            delivery,
+            last_ack: None,
+            ack_sample: 0.0,
             rate: ExponentialSmooth::new(0.5),
             queued: 0,
-            empty_count: 0,
+            occupancy,
+            detector,
             timer_fired: false,
+            bbr: None,
+            sent_log: VecDeque::new(),
+            sent_total: 0,
+            overdue: None,
+            loss: 0.0,
+            measurements: None,
+            probe_log: None,
+        }
+    }
+
+    /// Sends the measurement of every interval to `tx` too, for controllers
+    /// that adapt on measurements rather than signals.
+    pub fn set_measurements(&mut self, tx: UnboundedSender<Measurement>) {
+        self.measurements = Some(tx);
+    }
+
+    /// Sends the probe overhead of every second to `tx`.
+    pub fn log_probe_overhead(&mut self, tx: UnboundedSender<ProbeOverhead>) {
+        self.probe_log = Some(tx);
+    }
+
+    /// Samples the delivery rate and the RTT into `estimate` at every new
+    /// delivery ack. RTTs are only as precise as `MONITOR_INTERVAL`.
+    pub fn set_bbr(&mut self, estimate: BbrEstimate) {
+        self.bbr = Some(estimate);
+    }
+
+    /// Logs the bytes sent so far, for the RTT and the loss of later acks.
+    fn log_sent(&mut self, consumed: usize) {
+        let now = Instant::now();
+        self.sent_total += consumed as u64;
+        self.sent_log.push_back((now, self.sent_total));
+        while self.sent_log.front().map_or(false, |&(at, _)| {
+            now - at > Duration::from_millis(2 * ACK_TIMEOUT)
+        })
+        {
+            self.sent_log.pop_front();
         }
     }
 
-    fn react_to_timer(&mut self) -> Option<Signal> {
+    /// Returns the time (ms) from the tick that had sent `bytes` to `at`.
+    fn rtt(&self, at: Instant, bytes: u64) -> Option<f64> {
+        self.sent_log
+            .iter()
+            .find(|&&(_, total)| total >= bytes)
+            .filter(|&&(sent, _)| at > sent)
+            .map(|&(sent, _)| {
+                let rtt = at - sent;
+                rtt.as_secs() as f64 * 1000.0 + f64::from(rtt.subsec_nanos()) / 1_000_000.0
+            })
+    }
+
+    /// Reports the bandwidth spent on probes once per second, as kbps and as a
+    /// share of what has been sent, to the probe log (if any).
+    fn report_probe_overhead(&mut self, probe: usize, sent: usize) {
+        self.probe_bytes += probe;
+        self.sent_bytes += sent;
+        self.ticks += 1;
+        if self.ticks * MONITOR_INTERVAL as usize >= 1000 {
+            let now = Utc::now();
+            let overhead = ProbeOverhead {
+                timestamp: now.timestamp() as f64 + f64::from(now.timestamp_subsec_millis()) / 1e3,
+                kbps: self.probe_bytes as f64 * 8.0 / 1000.0,
+                share: self.probe_bytes as f64 / ::std::cmp::max(self.sent_bytes, 1) as f64,
+            };
+            if self.probe_bytes > 0 {
+                info!(
+                    "probe overhead: {:.1} kbps ({:.1}% of sent)",
+                    overhead.kbps,
+                    overhead.share * 100.0
+                );
+            }
+            if self.probe_log.as_ref().map_or(false, |tx| tx.unbounded_send(overhead).is_err()) {
+                error!("probe log has stopped");
+                self.probe_log = None;
+            }
+            self.probe_bytes = 0;
+            self.sent_bytes = 0;
+            self.ticks = 0;
+        }
+    }
+
+    /// Returns the bytes delivered per `MONITOR_INTERVAL` according to the
+    /// receiver's acks, or `None` if the acks are missing or too old. Without
+    /// a new ack since the last tick, the last sample stands.
+    fn delivered(&mut self) -> Option<f64> {
+        let (at, bytes) = match self.delivery.latest() {
+            Some(ack) if ack.0.elapsed() < Duration::from_millis(ACK_TIMEOUT) => ack,
+            _ => {
+                self.last_ack = None;
+                return None;
+            }
+        };
+        let sample = match self.last_ack {
+            Some((last_at, last_bytes)) if at > last_at => {
+                let elapsed = at - last_at;
+                let ms = elapsed.as_secs() as f64 * 1000.0 +
+                    f64::from(elapsed.subsec_nanos()) / 1_000_000.0;
+                let delivered = bytes.saturating_sub(last_bytes) as f64;
+                self.ack_sample = delivered * MONITOR_INTERVAL as f64 / ms;
+                if let Some(ref estimate) = self.bbr {
+                    let rate = self.ack_sample * 8.0 / MONITOR_INTERVAL as f64;
+                    estimate.add(at, rate, self.rtt(at, bytes));
+                }
+                Some(self.ack_sample)
+            }
+            Some(_) => Some(self.ack_sample),
+            None => None,
+        };
+        self.last_ack = Some((at, bytes));
+        sample
+    }
+
+    /// Returns the share of bytes lost, given the bytes `acked` so far: bytes
+    /// still not acknowledged `ACK_TIMEOUT` after they were sent are taken as
+    /// lost. It is 0 without acks. Without new overdue bytes since the last
+    /// tick, the last sample stands.
+    fn lost(&mut self, acked: Option<u64>) -> f64 {
+        let acked = match acked {
+            Some(acked) => acked,
+            None => {
+                self.overdue = None;
+                self.loss = 0.0;
+                return self.loss;
+            }
+        };
+        let now = Instant::now();
+        let sent = match self.sent_log.iter().rev().find(|&&(at, _)| {
+            now - at >= Duration::from_millis(ACK_TIMEOUT)
+        }) {
+            Some(&(_, sent)) => sent,
+            None => return self.loss,
+        };
+        let missing = sent.saturating_sub(acked);
+        if let Some((last_sent, last_missing)) = self.overdue {
+            if sent > last_sent {
+                let lost = missing.saturating_sub(last_missing) as f64;
+                self.loss = (lost / (sent - last_sent) as f64).min(1.0);
+            }
+        }
+        self.overdue = Some((sent, missing));
+        self.loss
+    }
+
+    fn react_to_timer(&mut self) -> Result<Option<Signal>> {
         trace!("monitor timer ticks");
 
         // timer fired, we check the produced and consumed bytes
-        let produced = self.produced_bytes.swap(0, Ordering::SeqCst);
+        let data = self.produced.data.swap(0, Ordering::SeqCst);
+        let probe = self.produced.probe.swap(0, Ordering::SeqCst);
+        let produced = data + probe;
         let consumed = self.consumed_bytes.swap(0, Ordering::SeqCst);
+        self.report_probe_overhead(probe, consumed);
+        self.log_sent(consumed);
 
-        self.queued = self.queued + produced - consumed;
-        self.rate.add(consumed as f64);
+        // Data dropped past their deadline never reach the socket, and data
+        // grow a little as they leave the queue (see `Occupancy::take_grown`).
+        let dropped = self.occupancy.take_dropped()?;
+        let grown = self.occupancy.take_grown()?;
+        self.queued = (self.queued + produced + grown).saturating_sub(consumed + dropped);
+
+        // The socket drains into the kernel buffer; what the receiver
+        // acknowledges is what the network actually delivers.
+        let (sample, source) = match self.delivered() {
+            Some(delivered) => (delivered, "acked"),
+            None => (consumed as f64, "drained"),
+        };
+        self.rate.add(sample);
+        let acked = self.last_ack.map(|(_, bytes)| bytes);
+        let loss = self.lost(acked);
 
         // self.rate tracks the amount of bytes sent over the last
         // MONITOR_INTERVAL (in ms). The division results in kbps.
         let rate = self.rate.val() * 8.0 / (MONITOR_INTERVAL as f64);
         let latency = self.queued as f64 * 8.0 / rate; // queued is bytes
+
+        // The byte-based estimate assumes the queue drains at `rate`; the age
+        // of the oldest datum is what it has actually waited so far.
+        let oldest = self.occupancy
+            .oldest_age()?
+            .map(|age| {
+                age.as_secs() as f64 * 1000.0 + age.subsec_nanos() as f64 / 1_000_000.0
+            })
+            .unwrap_or(0.0);
+        let latency = latency.max(oldest);
+        let in_queue = self.occupancy.bytes()?;
         info!(
-            "queued: {:?} kbytes, rate: {:.1} kbps, latency: {:.1} ms",
+            "queued: {:?} kbytes, in queue: {:?} kbytes, rate: {:.1} kbps ({}), latency: {:.1} ms, \
+             loss: {:.1}%",
             self.queued / 1000,
+            in_queue / 1000,
             rate,
-            latency
+            source,
+            latency,
+            loss * 100.0
         );
-        if latency > 1.0 {
-            self.empty_count = 0;
-            return Some(Signal::QueueCongest(ALPHA_RATE * rate, latency));
-        } else {
-            self.empty_count += 1;
-            if self.empty_count > QUEUE_EMPTY_REQUIRED {
-                self.empty_count = 0;
-                return Some(Signal::QueueEmpty);
-            }
+        let m = Measurement {
+            rate,
+            latency,
+            loss,
+        };
+        if self.measurements.as_ref().map_or(false, |tx| tx.unbounded_send(m).is_err()) {
+            self.measurements = None;
         }
-        return None;
+        Ok(self.detector.detect(m))
     }
 }
 
@@ -103,7 +407,7 @@ impl Stream for Monitor {
         loop {
             if self.timer_fired {
                 self.timer_fired = false;
-                match self.react_to_timer() {
+                match self.react_to_timer()? {
                     Some(s) => return Ok(Async::Ready(Some(s))),
                     None => {}
                 }
@@ -122,3 +426,190 @@ impl Stream for Monitor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AsCodec, Compression, ControlMessage};
+    use detector;
+    use futures::{Future, Sink, Stream, stream};
+    use futures::sync::mpsc::unbounded;
+    use queue::queue;
+    use setting::DetectorKind;
+    use socket::{FramedRead, Socket};
+    use tls::Conn;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+
+    fn monitor(
+        produced: &SourceStat,
+        consumed: &Arc<AtomicUsize>,
+        occupancy: Occupancy,
+    ) -> Monitor {
+        Monitor::new(
+            produced.clone(),
+            consumed.clone(),
+            Delivery::new(),
+            occupancy,
+            detector::build(DetectorKind::QueueLatency),
+        )
+    }
+
+    #[test]
+    fn queue_estimate_counts_what_data_gain_in_the_queue() {
+        let produced = SourceStat {
+            data: Arc::new(AtomicUsize::new(0)),
+            probe: Arc::new(AtomicUsize::new(0)),
+        };
+        let consumed = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = queue();
+        let mut monitor = monitor(&produced, &consumed, rx.occupancy());
+
+        let datum = AsDatum::new(0, 1, vec![0; 100]);
+        produced.data.fetch_add(datum.net_len(), Ordering::SeqCst);
+        tx.send(datum).unwrap();
+        let sent = rx.wait().next().unwrap().unwrap();
+        consumed.fetch_add(sent.net_len(), Ordering::SeqCst);
+
+        monitor.react_to_timer().unwrap();
+        assert_eq!(monitor.queued, 0);
+    }
+
+    #[test]
+    fn queue_estimate_drains_with_compression() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let connect = TcpStream::connect(&listener.local_addr().unwrap(), &handle);
+        let accept = listener.incoming().into_future().map_err(|(e, _)| e);
+        let (tcp, (peer, _)) = core.run(connect.join(accept)).unwrap();
+        let (_, write) = Conn::Plain(tcp).split();
+        let (socket, consumed) = Socket::with_codec(write, AsCodec::default());
+        let wire = socket.stats();
+
+        // What the producers count: the handshake and data that compress well.
+        let produced = SourceStat {
+            data: Arc::new(AtomicUsize::new(0)),
+            probe: Arc::new(AtomicUsize::new(0)),
+        };
+        let mut data = vec![AsDatum::handshake(Compression::Lz4).unwrap()];
+        data.extend((1..10).map(|i| AsDatum::new(0, i, vec![0; 10_000])));
+        let live = data[1..].iter().map(|d| d.net_len()).sum::<usize>();
+        for d in &data {
+            produced.data.fetch_add(d.net_len(), Ordering::SeqCst);
+        }
+        let (_, rx) = queue();
+        let mut monitor = monitor(&produced, &consumed, rx.occupancy());
+
+        let (_socket, _) = core.run(socket.send_all(stream::iter_ok::<_, Error>(data))).unwrap();
+        assert!(wire.get("live").encoded_bytes < live as u64 / 10);
+        monitor.react_to_timer().unwrap();
+        assert_eq!(monitor.queued, 0);
+        drop(peer);
+    }
+
+    fn acked(datum: Option<AsDatum>) -> Option<u64> {
+        match ControlMessage::from_datum(&datum?).unwrap() {
+            Some(ControlMessage::Delivered(bytes)) => Some(bytes),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn acks_count_what_consumed_counts() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let connect = TcpStream::connect(&listener.local_addr().unwrap(), &handle);
+        let accept = listener.incoming().into_future().map_err(|(e, _)| e);
+        let (tcp, (peer, _)) = core.run(connect.join(accept)).unwrap();
+        let (_, write) = Conn::Plain(tcp).split();
+        let (socket, consumed) = Socket::with_codec(write, AsCodec::default());
+
+        // Compressed on the wire, the data are acked as the sender counts
+        // them, not as they travel.
+        let mut data = vec![AsDatum::handshake(Compression::Lz4).unwrap()];
+        data.extend((1..10).map(|i| AsDatum::new(0, i, vec![0; 10_000])));
+        let count = data.len();
+        let sent = core.run(socket.send_all(stream::iter_ok::<_, Error>(data)));
+        let (_socket, _) = sent.unwrap();
+
+        let (read, _) = Conn::Plain(peer.unwrap().0).split();
+        let received = FramedRead::new(read, AsCodec::default()).take(count as u64).collect();
+        let mut acks = Acknowledger::new();
+        let mut last = None;
+        for datum in core.run(received).unwrap() {
+            acks.last_ack -= Duration::from_millis(DELIVERY_ACK_INTERVAL);
+            last = acked(acks.add(datum.sent_len()).unwrap());
+        }
+        assert_eq!(last, Some(consumed.load(Ordering::SeqCst) as u64));
+    }
+
+    #[test]
+    fn acks_are_due_every_interval() {
+        let mut acks = Acknowledger::new();
+        assert_eq!(acked(acks.add(100).unwrap()), None);
+        assert_eq!(acked(acks.add(100).unwrap()), None);
+        acks.last_ack -= Duration::from_millis(DELIVERY_ACK_INTERVAL);
+        assert_eq!(acked(acks.add(50).unwrap()), Some(250));
+        assert_eq!(acked(acks.add(50).unwrap()), None);
+    }
+
+    #[test]
+    fn rate_follows_acks_until_they_time_out() {
+        let produced = SourceStat {
+            data: Arc::new(AtomicUsize::new(0)),
+            probe: Arc::new(AtomicUsize::new(0)),
+        };
+        let consumed = Arc::new(AtomicUsize::new(0));
+        let (_, rx) = queue();
+        let mut monitor = monitor(&produced, &consumed, rx.occupancy());
+        let delivery = monitor.delivery.clone();
+        let ack = |at, bytes| *delivery.latest.lock().unwrap() = Some((at, bytes));
+        let start = Instant::now() - Duration::from_millis(300);
+
+        // The first ack only sets where the next one counts from.
+        ack(start, 1000);
+        assert_eq!(monitor.delivered(), None);
+        ack(start + Duration::from_millis(200), 5000);
+        assert_eq!(monitor.delivered(), Some(2000.0));
+
+        // Without a new ack, the last sample stands.
+        assert_eq!(monitor.delivered(), Some(2000.0));
+
+        // Once acks time out, the rate is how fast the socket drains.
+        ack(Instant::now() - Duration::from_millis(ACK_TIMEOUT), 5000);
+        assert_eq!(monitor.delivered(), None);
+        assert_eq!(monitor.last_ack, None);
+        let (tx, measurements) = unbounded();
+        monitor.set_measurements(tx);
+        consumed.fetch_add(1000, Ordering::SeqCst);
+        monitor.react_to_timer().unwrap();
+        drop(monitor);
+        let m = measurements.wait().next().unwrap().unwrap();
+        assert_eq!(m.rate, 1000.0 * 0.5 * 8.0 / MONITOR_INTERVAL as f64);
+    }
+
+    #[test]
+    fn probe_overhead_is_logged_every_second() {
+        let produced = SourceStat {
+            data: Arc::new(AtomicUsize::new(0)),
+            probe: Arc::new(AtomicUsize::new(0)),
+        };
+        let consumed = Arc::new(AtomicUsize::new(0));
+        let (_, rx) = queue();
+        let mut monitor = monitor(&produced, &consumed, rx.occupancy());
+        let (tx, log) = unbounded();
+        monitor.log_probe_overhead(tx);
+
+        let ticks = 1000 / MONITOR_INTERVAL as usize;
+        for _ in 0..2 * ticks {
+            monitor.report_probe_overhead(100, 400);
+        }
+        monitor.report_probe_overhead(100, 400);
+        drop(monitor);
+        let rows = log.wait().map(|o| o.unwrap()).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[1].kbps, rows[1].share), (ticks as f64 * 0.8, 0.25));
+    }
+}
@@ -0,0 +1,102 @@
+//! Per-connection decode workers on the server. The reactor only splits what a
+//! connection receives into frames, which is cheap; checking, deserializing
+//! and decompressing them happens on a thread of the connection's own. Both
+//! sides talk through bounded mailboxes: a client that sends more than its
+//! worker keeps up with is no longer read from (and slowed down by TCP),
+//! instead of stalling the reports of the other connections on the reactor.
+
+use super::{AsCodec, AsDatum, RawFrame};
+use errors::*;
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc::channel;
+use socket::FramedRead;
+use std::thread;
+use tokio_core::reactor::Handle;
+use tokio_io::AsyncRead;
+use tokio_io::codec::Decoder;
+
+/// Frames (or data) a mailbox holds before the side filling it waits.
+pub const DECODE_MAILBOX: usize = 32;
+
+/// Splits frames off the wire without decoding them.
+#[derive(Default)]
+struct Frames(AsCodec);
+
+impl Decoder for Frames {
+    type Item = RawFrame;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut ::bytes::BytesMut) -> Result<Option<RawFrame>> {
+        self.0.split_frame(buf)
+    }
+}
+
+/// Reads the frames of `read` on the reactor of `handle` and decodes them with
+/// `codec` on a worker thread. Returns the data in the order they arrive,
+/// with an error for every frame that fails to decode, as `AsCodec` does.
+pub fn spawn<R>(
+    read: R,
+    codec: AsCodec,
+    handle: &Handle,
+) -> impl Stream<Item = AsDatum, Error = Error>
+where
+    R: AsyncRead + 'static,
+{
+    let (frame_tx, frame_rx) = channel::<Result<RawFrame>>(DECODE_MAILBOX);
+    let (datum_tx, datum_rx) = channel::<Result<AsDatum>>(DECODE_MAILBOX);
+
+    // Read errors go to the worker too, which passes them on and stops.
+    let frames = FramedRead::new(read, Frames::default()).then(Ok::<_, ()>);
+    handle.spawn(frames.forward(frame_tx.sink_map_err(|_| ())).then(|_| Ok(())));
+
+    let mut codec = codec;
+    thread::spawn(move || {
+        let mut datum_tx = datum_tx;
+        for frame in frame_rx.wait() {
+            let (datum, last) = match frame {
+                Ok(Ok(frame)) => (codec.decode_raw(frame), false),
+                Ok(Err(e)) => (Err(e), true),
+                Err(()) => break,
+            };
+            datum_tx = match datum_tx.send(datum).wait() {
+                Ok(tx) => tx,
+                Err(_) => break,
+            };
+            if last {
+                break;
+            }
+        }
+    });
+
+    datum_rx
+        .map_err(|_| Error::from_kind(ErrorKind::DataPlane))
+        .and_then(|datum| datum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use std::io::Cursor;
+    use tokio_core::reactor::Core;
+    use tokio_io::codec::Encoder;
+
+    #[test]
+    fn worker_decodes_in_order() {
+        let mut buf = BytesMut::new();
+        let mut codec = AsCodec::default();
+        let data = (0..5).map(|i| AsDatum::new(0, i, vec![i as u8; 1000 * i])).collect::<Vec<_>>();
+        for d in &data {
+            codec.encode(d.clone(), &mut buf).unwrap();
+        }
+
+        let mut core = Core::new().unwrap();
+        let read = Cursor::new(buf.freeze().to_vec());
+        let decoded = core.run(spawn(read, AsCodec::default(), &core.handle()).collect()).unwrap();
+        assert_eq!(decoded.len(), data.len());
+        for (a, b) in decoded.iter().zip(&data) {
+            assert_eq!(a.datum_type(), b.datum_type());
+            assert_eq!(a.mem, b.mem);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Congestion detectors. Every monitor interval, the `Monitor` measures the
+//! outgoing rate and the latency of its queue; a `CongestionDetector` turns
+//! these measurements into signals for the adaptation.
+//!
+//! * `QueueLatency` (the default) signals congestion whenever data waits in
+//!   the queue.
+//! * `DelayGradient` signals congestion only while the latency grows, so that
+//!   a queue that is already draining does not cause further degradation.
+//! * `Hybrid` signals congestion if the latency grows or has become too large,
+//!   so that it reacts early but still catches a standing queue.
+//! * `Fused` scores the latency and the share of bytes lost together, so that
+//!   it backs off on loss even while the latency stays flat, as on cellular
+//!   links until their throughput drops all at once.
+
+use adaptation::Signal;
+use setting::DetectorKind;
+use utils::ExponentialSmooth;
+
+/// Congestion signals carry a slightly lower rate than measured, so that the
+/// queue drains.
+const ALPHA_RATE: f64 = 0.9;
+
+/// Empty intervals, past which the queue is signaled empty: with a
+/// `MONITOR_INTERVAL` of 100 ms, a `QueueEmpty` about every 2 seconds.
+const QUEUE_EMPTY_REQUIRED: usize = 20;
+
+/// Latency (ms) above which data is considered queued.
+const QUEUED_LATENCY: f64 = 1.0;
+
+/// Growth of the (smoothed) latency, in ms per monitor interval, above which
+/// `DelayGradient` signals congestion.
+const GRADIENT_THRESHOLD: f64 = 1.0;
+
+/// Latency (ms) above which `Hybrid` signals congestion even if the latency
+/// does not grow.
+const HYBRID_LATENCY_MAX: f64 = 100.0;
+
+/// Latency (ms) that alone makes a `Fused` score of 1, i.e. congestion.
+const FUSED_LATENCY: f64 = 100.0;
+
+/// Share of bytes lost that alone makes a `Fused` score of 1.
+const FUSED_LOSS: f64 = 0.05;
+
+/// Share of bytes lost below which `Fused` still counts the link as empty:
+/// bytes acked late count as lost at first (see `Monitor`).
+const FUSED_LOSS_NOISE: f64 = 0.01;
+
+/// What the monitor measured in one interval.
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    /// Outgoing rate (kbps).
+    pub rate: f64,
+
+    /// Estimated latency of the queue (ms).
+    pub latency: f64,
+
+    /// Estimated share of the bytes sent that the network lost (0 without
+    /// delivery acks).
+    pub loss: f64,
+}
+
+/// Decides, from the measurements of every monitor interval, when to signal
+/// congestion and when the queue has been empty long enough.
+pub trait CongestionDetector {
+    /// Returns the signal for this interval, if any.
+    fn detect(&mut self, m: Measurement) -> Option<Signal>;
+}
+
+/// Creates the detector of `kind`.
+pub fn build(kind: DetectorKind) -> Box<dyn CongestionDetector + Send> {
+    match kind {
+        DetectorKind::QueueLatency => Box::new(QueueLatency::default()),
+        DetectorKind::DelayGradient => Box::new(DelayGradient::default()),
+        DetectorKind::Hybrid => Box::new(Hybrid::default()),
+        DetectorKind::Fused => Box::new(Fused::default()),
+    }
+}
+
+/// Counts intervals without queued data and signals `QueueEmpty` once there
+/// have been enough.
+#[derive(Default)]
+struct EmptyCount {
+    count: usize,
+}
+
+impl EmptyCount {
+    fn reset(&mut self) {
+        self.count = 0;
+    }
+
+    fn tick(&mut self) -> Option<Signal> {
+        self.count += 1;
+        if self.count > QUEUE_EMPTY_REQUIRED {
+            self.count = 0;
+            Some(Signal::QueueEmpty)
+        } else {
+            None
+        }
+    }
+}
+
+/// Signals congestion whenever data is queued.
+#[derive(Default)]
+pub struct QueueLatency {
+    empty: EmptyCount,
+}
+
+impl CongestionDetector for QueueLatency {
+    fn detect(&mut self, m: Measurement) -> Option<Signal> {
+        if m.latency > QUEUED_LATENCY {
+            self.empty.reset();
+            Some(Signal::QueueCongest(ALPHA_RATE * m.rate, m.latency))
+        } else {
+            self.empty.tick()
+        }
+    }
+}
+
+/// Signals congestion while the latency grows.
+pub struct DelayGradient {
+    previous: Option<f64>,
+    gradient: ExponentialSmooth,
+    empty: EmptyCount,
+}
+
+impl Default for DelayGradient {
+    fn default() -> DelayGradient {
+        DelayGradient {
+            previous: None,
+            gradient: ExponentialSmooth::new(0.5),
+            empty: EmptyCount::default(),
+        }
+    }
+}
+
+impl DelayGradient {
+    /// Returns whether the latency grows.
+    fn growing(&mut self, latency: f64) -> bool {
+        if let Some(previous) = self.previous {
+            self.gradient.add(latency - previous);
+        }
+        self.previous = Some(latency);
+        latency > QUEUED_LATENCY && self.gradient.val() > GRADIENT_THRESHOLD
+    }
+}
+
+impl CongestionDetector for DelayGradient {
+    fn detect(&mut self, m: Measurement) -> Option<Signal> {
+        if self.growing(m.latency) {
+            self.empty.reset();
+            Some(Signal::QueueCongest(ALPHA_RATE * m.rate, m.latency))
+        } else if m.latency > QUEUED_LATENCY {
+            self.empty.reset();
+            None
+        } else {
+            self.empty.tick()
+        }
+    }
+}
+
+/// Signals congestion while the latency grows or once it is too large.
+#[derive(Default)]
+pub struct Hybrid {
+    gradient: DelayGradient,
+}
+
+impl CongestionDetector for Hybrid {
+    fn detect(&mut self, m: Measurement) -> Option<Signal> {
+        let growing = self.gradient.growing(m.latency);
+        if growing || m.latency > HYBRID_LATENCY_MAX {
+            self.gradient.empty.reset();
+            Some(Signal::QueueCongest(ALPHA_RATE * m.rate, m.latency))
+        } else if m.latency > QUEUED_LATENCY {
+            self.gradient.empty.reset();
+            None
+        } else {
+            self.gradient.empty.tick()
+        }
+    }
+}
+
+/// Signals congestion once latency and loss together score 1 or more (see
+/// `congestion_score`), as loss if it weighs more.
+#[derive(Default)]
+pub struct Fused {
+    empty: EmptyCount,
+}
+
+/// Scores `latency` (ms) and `loss` (share of bytes), each relative to what
+/// alone is congestion.
+pub fn congestion_score(latency: f64, loss: f64) -> f64 {
+    latency / FUSED_LATENCY + loss / FUSED_LOSS
+}
+
+impl CongestionDetector for Fused {
+    fn detect(&mut self, m: Measurement) -> Option<Signal> {
+        if congestion_score(m.latency, m.loss) >= 1.0 {
+            self.empty.reset();
+            if m.loss / FUSED_LOSS > m.latency / FUSED_LATENCY {
+                Some(Signal::LossCongest(ALPHA_RATE * m.rate, m.loss))
+            } else {
+                Some(Signal::QueueCongest(ALPHA_RATE * m.rate, m.latency))
+            }
+        } else if m.latency > QUEUED_LATENCY || m.loss > FUSED_LOSS_NOISE {
+            self.empty.reset();
+            None
+        } else {
+            self.empty.tick()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signals(detector: &mut dyn CongestionDetector, latencies: &[f64]) -> Vec<bool> {
+        latencies
+            .iter()
+            .map(|&latency| {
+                let m = Measurement {
+                    rate: 1000.0,
+                    latency,
+                    loss: 0.0,
+                };
+                match detector.detect(m) {
+                    Some(Signal::QueueCongest(_, _)) => true,
+                    _ => false,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detectors_differ_on_draining_queues() {
+        // The queue builds up, then drains from a large backlog.
+        let latencies = [0.0, 10.0, 20.0, 250.0, 200.0, 160.0, 130.0, 90.0];
+        let congested = |kind: DetectorKind| signals(&mut *build(kind), &latencies);
+
+        assert_eq!(
+            congested(DetectorKind::QueueLatency),
+            vec![false, true, true, true, true, true, true, true]
+        );
+        assert_eq!(
+            congested(DetectorKind::DelayGradient),
+            vec![false, true, true, true, true, false, false, false]
+        );
+        assert_eq!(
+            congested(DetectorKind::Hybrid),
+            vec![false, true, true, true, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn empty_queue_is_signaled_after_two_seconds() {
+        let mut detector = DelayGradient::default();
+        let m = Measurement {
+            rate: 1000.0,
+            latency: 0.0,
+            loss: 0.0,
+        };
+        let empty = (0..QUEUE_EMPTY_REQUIRED + 1)
+            .filter_map(|_| detector.detect(m))
+            .collect::<Vec<_>>();
+        assert_eq!(empty, vec![Signal::QueueEmpty]);
+    }
+
+    #[test]
+    fn fused_backs_off_on_loss_at_flat_latency() {
+        let mut detector = Fused::default();
+        let mut detect = |latency, loss| {
+            detector.detect(Measurement {
+                rate: 1000.0,
+                latency,
+                loss,
+            })
+        };
+        // Loss alone, then both half way, then latency alone.
+        assert_eq!(detect(0.0, 0.02), None);
+        assert_eq!(detect(5.0, 0.06), Some(Signal::LossCongest(900.0, 0.06)));
+        assert_eq!(detect(60.0, 0.025), Some(Signal::QueueCongest(900.0, 60.0)));
+        assert_eq!(detect(120.0, 0.0), Some(Signal::QueueCongest(900.0, 120.0)));
+    }
+}
@@ -0,0 +1,39 @@
+//! Accounting for data that is dropped instead of sent.
+
+use super::{DropReason, DropReport};
+use errors::*;
+use std::sync::{Arc, Mutex};
+
+/// `DropCounter` counts dropped datums by reason and level. It is shared by
+/// whoever drops data and whoever reports it.
+#[derive(Clone)]
+pub struct DropCounter {
+    /// Drops since the last `take`.
+    inner: Arc<Mutex<DropReport>>,
+}
+
+impl DropCounter {
+    pub fn new() -> DropCounter {
+        DropCounter { inner: Arc::new(Mutex::new(DropReport::default())) }
+    }
+
+    /// Records `count` drops of data at `level` because of `reason`.
+    pub fn add(&self, reason: DropReason, level: usize, count: usize) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        m.add(reason, level, count);
+        Ok(())
+    }
+
+    /// Merges a report (e.g. one received from the peer).
+    pub fn merge(&self, report: &DropReport) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        m.merge(report);
+        Ok(())
+    }
+
+    /// Returns the drops since the last call and resets them.
+    pub fn take(&self) -> Result<DropReport> {
+        let mut m = self.inner.lock()?;
+        Ok(::std::mem::replace(&mut *m, DropReport::default()))
+    }
+}
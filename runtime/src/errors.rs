@@ -1,37 +1,56 @@
-//! Error types for AWStream.
+//! Error types for AWStream, which every fallible function of the crate
+//! returns.
 
 /// Creates the Error, ErrorKind, ResultExt, and Result types
 error_chain!{
     errors {
+        /// The source fails to produce data.
         SourceData {
             description("error in generating source data")
         }
+        /// The peer's reports can't be received.
         RemotePeer {
             description("error in receiving reports from peer")
         }
+        /// The control plane (monitor, adaptation) has stopped.
         ControlPlane {
             description("error in control plane")
         }
+        /// The data plane (source, queue, socket) has stopped.
         DataPlane {
             description("error in data plane communication")
         }
+        /// The server can't reply to its client.
         ReplyChannel {
             description("error in replying to client")
         }
+        /// A datum can't be encoded.
         EncodeError {
             description("error in encoding the data")
         }
-        DecodeError {
-            description("error in decoding the data")
+        /// A frame fails its checksum, with the checksum expected and the one
+        /// computed.
+        DecodeError(expected: u32, actual: u32) {
+            description("frame fails its checksum")
+            display("frame fails its checksum: expected {:08x}, got {:08x}", expected, actual)
         }
+        /// A frame claims a length above `framing::MAX_FRAME_LEN`; the stream
+        /// can't be framed past it.
+        FrameTooLong(len: u64) {
+            description("frame is too long")
+            display("frame of {} bytes is too long", len)
+        }
+        /// A lock is poisoned by a thread that panicked while holding it.
         SyncPoisonError(t: String) {
         }
     }
 
     foreign_links {
-        Io(::std::io::Error);
-        Timer(::tokio_timer::TimerError);
-        Bincode(::bincode::Error);
+        Io(::std::io::Error) #[doc = "An I/O error."];
+        Timer(::tokio_timer::TimerError) #[doc = "A timer error."];
+        Bincode(::bincode::Error) #[doc = "A bincode error."];
+        Csv(::csv::Error) #[doc = "A CSV error."];
+        Json(::serde_json::Error) #[doc = "A JSON error."];
     }
 }
 
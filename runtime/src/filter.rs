@@ -0,0 +1,161 @@
+//! Source-side frame filtering. Applications can veto or downgrade individual
+//! data before they are sent (e.g. skip frames in which nothing moves), which
+//! saves bandwidth independently of the profile-driven degradation.
+
+use super::Experiment;
+use evaluation::{self, MotionConfig, MotionSkip};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// What to do with a datum.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// Send it as is.
+    Send,
+
+    /// Don't send it.
+    Drop,
+
+    /// Send it at a lower level instead. Ignored if the level is not lower
+    /// than the current level.
+    Downgrade(usize),
+}
+
+/// Decides on every datum before it is sent. Closures taking the level, the
+/// index and the size of the datum are filters too.
+pub trait FrameFilter {
+    /// Decides on datum `index` that is about to be sent at `level` with
+    /// `size` bytes.
+    fn filter(&mut self, level: usize, index: usize, size: usize) -> Verdict;
+}
+
+impl<F> FrameFilter for F
+where
+    F: FnMut(usize, usize, usize) -> Verdict,
+{
+    fn filter(&mut self, level: usize, index: usize, size: usize) -> Verdict {
+        self(level, index, size)
+    }
+}
+
+/// Skips frames of static scenes (see `evaluation::MotionSkip`), replaying
+/// per-frame motion measured by the video crate.
+pub struct MotionFilter {
+    motion: BTreeMap<usize, f64>,
+    skip: MotionSkip,
+
+    /// The last frame seen.
+    last: usize,
+}
+
+impl MotionFilter {
+    /// Loads per-frame motion (`frame_num, motion`) from `path`.
+    pub fn load<P: AsRef<Path>>(path: P, config: MotionConfig) -> ::csv::Result<MotionFilter> {
+        Ok(MotionFilter {
+            motion: evaluation::read_motion(path)?,
+            skip: MotionSkip::new(config),
+            last: 0,
+        })
+    }
+}
+
+impl FrameFilter for MotionFilter {
+    fn filter(&mut self, _level: usize, index: usize, _size: usize) -> Verdict {
+        // The motion since the last frame seen; frames in between (skipped by
+        // the configuration) count too. The source starts over after a loop.
+        let from = if index > self.last { self.last + 1 } else { index };
+        let motion = self.motion
+            .range(from..index + 1)
+            .map(|(_, m)| m)
+            .sum::<f64>();
+        self.last = index;
+        if self.skip.keep(motion) {
+            Verdict::Send
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+/// Applies `filter` to datum `index` at `level`. Returns the level and size
+/// to send it with, or `None` if it is dropped.
+pub fn apply<E: Experiment>(
+    filter: &mut dyn FrameFilter,
+    source: &mut E,
+    level: usize,
+    index: usize,
+    size: usize,
+) -> Option<(usize, usize)> {
+    match filter.filter(level, index, size) {
+        Verdict::Send => Some((level, size)),
+        Verdict::Drop => None,
+        Verdict::Downgrade(lower) if lower < level => {
+            match source.datum_size_at(lower, index) {
+                Some(lower_size) => Some((lower, lower_size)),
+                None => {
+                    warn!("no size of datum {} at level {}, keep level {}", index, lower, level);
+                    Some((level, size))
+                }
+            }
+        }
+        Verdict::Downgrade(_) => Some((level, size)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evaluation::testing::ScratchDir;
+
+    /// Datum sizes are `(level + 1) * 100`, for frames up to 10.
+    struct Fixed;
+
+    impl Experiment for Fixed {
+        fn next_datum(&mut self) -> Option<(usize, usize)> {
+            None
+        }
+
+        fn datum_size_at(&mut self, level: usize, index: usize) -> Option<usize> {
+            if index <= 10 { Some((level + 1) * 100) } else { None }
+        }
+    }
+
+    #[test]
+    fn verdicts_are_applied() {
+        // Drops odd frames and downgrades large ones to level 0.
+        let mut filter = |_level: usize, index: usize, size: usize| if index % 2 == 1 {
+            Verdict::Drop
+        } else if size > 200 {
+            Verdict::Downgrade(0)
+        } else {
+            Verdict::Send
+        };
+
+        assert_eq!(apply(&mut filter, &mut Fixed, 2, 1, 300), None);
+        assert_eq!(apply(&mut filter, &mut Fixed, 1, 2, 200), Some((1, 200)));
+        assert_eq!(apply(&mut filter, &mut Fixed, 2, 2, 300), Some((0, 100)));
+
+        // Unknown sizes and non-lower levels keep the datum as is.
+        assert_eq!(apply(&mut filter, &mut Fixed, 2, 12, 300), Some((2, 300)));
+        assert_eq!(apply(&mut filter, &mut Fixed, 0, 2, 300), Some((0, 300)));
+    }
+
+    #[test]
+    fn motion_filter_accumulates_skipped_frames() {
+        let dir = ScratchDir::new("motion-filter");
+        let path = dir.join("motion.csv");
+        ::std::fs::write(&path, "1, 0.0\n2, 1.0\n3, 1.0\n4, 1.0\n5, 0.5\n").unwrap();
+        let config = MotionConfig {
+            threshold: 2.0,
+            max_skip: 5,
+        };
+        let mut filter = MotionFilter::load(&path, config).unwrap();
+
+        // Frame 3 carries the motion of frame 2, which the configuration
+        // skipped.
+        assert_eq!(filter.filter(0, 1, 100), Verdict::Send);
+        assert_eq!(filter.filter(0, 3, 100), Verdict::Send);
+        assert_eq!(filter.filter(0, 4, 100), Verdict::Drop);
+        assert_eq!(filter.filter(0, 5, 100), Verdict::Drop);
+    }
+}
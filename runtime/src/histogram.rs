@@ -0,0 +1,323 @@
+//! A latency histogram in the spirit of HDR histograms: values (recorded in
+//! microseconds) fall into buckets whose width grows with the magnitude of
+//! the value, so that any percentile is reported within about 1.6% of the
+//! true value (64 sub-buckets per power of two), with memory that only grows
+//! with the logarithm of the largest value.
+
+use lz4_flex;
+
+/// Sub-buckets per power of two, as bits.
+const SUB_BUCKET_BITS: u32 = 6;
+
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// Index of the bucket that holds `v`.
+fn index_of(v: u64) -> usize {
+    if v < SUB_BUCKETS {
+        return v as usize;
+    }
+    let shift = 63 - v.leading_zeros() - SUB_BUCKET_BITS;
+    ((u64::from(shift) + 1) * SUB_BUCKETS + (v >> shift) - SUB_BUCKETS) as usize
+}
+
+/// The highest value that falls into bucket `index`.
+fn highest_of(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let low = (index % SUB_BUCKETS + SUB_BUCKETS) << shift;
+    low + (1 << shift) - 1
+}
+
+fn us_to_ms(us: u64) -> f64 {
+    us as f64 / 1000.0
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn get_varint(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut v = 0;
+    for shift in (0..64).step_by(7) {
+        let b = *bytes.get(*pos)?;
+        *pos += 1;
+        v |= u64::from(b & 0x7F) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(encoded.len() * 3 / 4);
+    let (mut n, mut bits) = (0u32, 0);
+    for &c in encoded {
+        n = ((n << 6) | BASE64.iter().position(|&b| b == c)? as u32) & 0xFFFF;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Encodes non-empty buckets compactly: the gap of every index to the one
+/// before and its count as varints, compressed with LZ4 and in base64 so
+/// that it fits in a JSON log.
+fn encode_buckets(buckets: &[(usize, u64)]) -> String {
+    let mut bytes = Vec::new();
+    let mut last = 0;
+    for &(index, n) in buckets {
+        put_varint(&mut bytes, (index - last) as u64);
+        put_varint(&mut bytes, n);
+        last = index;
+    }
+    base64_encode(&lz4_flex::compress_prepend_size(&bytes))
+}
+
+fn decode_buckets(encoded: &str) -> Option<Vec<(usize, u64)>> {
+    let bytes = lz4_flex::decompress_size_prepended(&base64_decode(encoded)?).ok()?;
+    let (mut pos, mut last) = (0, 0);
+    let mut buckets = Vec::new();
+    while pos < bytes.len() {
+        last += get_varint(&bytes, &mut pos)? as usize;
+        buckets.push((last, get_varint(&bytes, &mut pos)?));
+    }
+    Some(buckets)
+}
+
+/// Latencies (ms), with a resolution of 1 us.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum: f64,
+    max: u64,
+}
+
+impl Histogram {
+    /// Records a latency (ms). Negative latencies (clock skew between client
+    /// and server) count as zero.
+    pub fn record(&mut self, latency: f64) {
+        if latency.is_nan() {
+            return;
+        }
+        let us = (latency.max(0.0) * 1000.0).round() as u64;
+        self.record_n(index_of(us), 1);
+        self.sum += latency.max(0.0);
+        self.max = ::std::cmp::max(self.max, us);
+    }
+
+    fn record_n(&mut self, index: usize, n: u64) {
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += n;
+        self.total += n;
+    }
+
+    /// Number of latencies recorded.
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Mean latency, or NaN if nothing has been recorded.
+    pub fn mean(&self) -> f64 {
+        self.sum / self.total as f64
+    }
+
+    /// Maximum latency, or NaN if nothing has been recorded.
+    pub fn max(&self) -> f64 {
+        if self.total == 0 {
+            return f64::NAN;
+        }
+        us_to_ms(self.max)
+    }
+
+    /// The latency `p` percent (0 to 100) of the recorded latencies are at
+    /// most, or NaN if nothing has been recorded.
+    pub fn percentile(&self, p: f64) -> f64 {
+        if self.total == 0 {
+            return f64::NAN;
+        }
+        let rank = ((p / 100.0 * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return us_to_ms(::std::cmp::min(highest_of(index), self.max));
+            }
+        }
+        us_to_ms(self.max)
+    }
+
+    /// Adds the latencies recorded in `other`.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (index, &n) in other.counts.iter().enumerate().filter(|&(_, &n)| n > 0) {
+            self.record_n(index, n);
+        }
+        self.sum += other.sum;
+        self.max = ::std::cmp::max(self.max, other.max);
+    }
+
+    /// Forgets all latencies.
+    pub fn clear(&mut self) {
+        *self = Histogram::default();
+    }
+
+    /// Returns a summary with the histogram in compressed form.
+    pub fn snapshot(&self) -> Snapshot {
+        let buckets = self.counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &n)| n > 0)
+            .map(|(index, &n)| (index, n))
+            .collect::<Vec<_>>();
+        Snapshot {
+            count: self.total,
+            mean: self.mean(),
+            p50: self.percentile(50.0),
+            p90: self.percentile(90.0),
+            p99: self.percentile(99.0),
+            max: self.max(),
+            buckets: encode_buckets(&buckets),
+        }
+    }
+}
+
+/// Percentiles and max of a `Histogram`, with its non-empty buckets in
+/// compressed form so that histograms can be merged offline.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    /// Number of latencies.
+    pub count: u64,
+
+    /// Mean latency (ms).
+    pub mean: f64,
+
+    /// Median latency (ms).
+    pub p50: f64,
+
+    /// 90th percentile latency (ms).
+    pub p90: f64,
+
+    /// 99th percentile latency (ms).
+    pub p99: f64,
+
+    /// Maximum latency (ms).
+    pub max: f64,
+
+    /// Non-empty buckets, compressed (see `histogram`).
+    pub buckets: String,
+}
+
+impl Snapshot {
+    /// Restores the histogram, or `None` if its buckets are corrupt. The sum
+    /// and max of the latencies are taken from the summary.
+    pub fn histogram(&self) -> Option<Histogram> {
+        let mut h = Histogram::default();
+        for (index, n) in decode_buckets(&self.buckets)? {
+            h.record_n(index, n);
+        }
+        if self.count > 0 {
+            h.sum = self.mean * self.count as f64;
+            h.max = (self.max * 1000.0).round() as u64;
+        }
+        Some(h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn buckets_cover_values() {
+        for &v in &[0, 1, 63, 64, 65, 127, 128, 129, 1000, 123_456, 1 << 40] {
+            let index = index_of(v);
+            assert!(highest_of(index) >= v);
+            assert!(index == 0 || highest_of(index - 1) < v);
+        }
+    }
+
+    #[test]
+    fn percentiles_are_close() {
+        let mut h = Histogram::default();
+        for i in 1..1001 {
+            h.record(i as f64);
+        }
+        assert_eq!(h.count(), 1000);
+        assert_eq!(h.mean(), 500.5);
+        assert_eq!(h.max(), 1000.0);
+        for &(p, v) in &[(50.0, 500.0), (90.0, 900.0), (99.0, 990.0), (100.0, 1000.0)] {
+            let error = (h.percentile(p) - v) / v;
+            assert!(error >= 0.0 && error < 1.0 / SUB_BUCKETS as f64);
+        }
+
+        assert!(Histogram::default().percentile(50.0).is_nan());
+    }
+
+    #[test]
+    fn snapshots_merge() {
+        let mut a = Histogram::default();
+        let mut b = Histogram::default();
+        a.record(10.0);
+        b.record(20.0);
+        b.record(-5.0);
+        a.merge(&b);
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.percentile(0.0), 0.0);
+        assert_eq!(a.max(), 20.0);
+
+        let json = serde_json::to_string(&a.snapshot()).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(decode_buckets(&snapshot.buckets).unwrap().len(), 3);
+        assert_eq!(snapshot.histogram(), Some(a));
+    }
+
+    #[test]
+    fn snapshots_are_compressed() {
+        let mut h = Histogram::default();
+        for i in 0..10_000 {
+            h.record(f64::from(i % 500) / 10.0);
+        }
+        let snapshot = h.snapshot();
+        let sparse = serde_json::to_string(&decode_buckets(&snapshot.buckets)).unwrap();
+        assert!(snapshot.buckets.len() < sparse.len());
+        let restored = snapshot.histogram().unwrap();
+        assert_eq!(restored.count(), h.count());
+        assert_eq!(restored.percentile(99.0), h.percentile(99.0));
+
+        assert_eq!(base64_decode(&base64_encode(b"awstream")).unwrap(), b"awstream");
+        assert!(decode_buckets("not base64!").is_none());
+    }
+}
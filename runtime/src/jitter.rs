@@ -0,0 +1,167 @@
+//! A jitter buffer in front of the server's analytics. The network delays
+//! data unevenly, so they arrive in bursts and gaps (and sometimes out of
+//! order); analytics whose results depend on timing (e.g. tracking or
+//! counting per second) would rather see them at the cadence they were
+//! captured at. The buffer holds every datum until a fixed delay after its
+//! capture, relative to the first datum, so that a constant offset between
+//! the clocks of client and server doesn't matter. Data arriving after their
+//! time are released at once.
+
+use chrono::{DateTime, Utc};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+/// How long the server holds data before its analytics.
+///
+/// ```toml
+/// [jitter_buffer]
+/// delay = 200
+/// ```
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct JitterBufferConfig {
+    /// Delay (ms) from the arrival of the first datum until its release;
+    /// later data are released as long after it as they were captured.
+    #[serde(default = "default_delay")]
+    pub delay: u64,
+}
+
+fn default_delay() -> u64 {
+    200
+}
+
+/// A datum held until `release`. The heap pops the earliest release first,
+/// and data released together in the order they arrived.
+struct Held<T> {
+    release: Instant,
+    arrival: Instant,
+    order: u64,
+    item: T,
+}
+
+impl<T> Held<T> {
+    fn key(&self) -> (Instant, u64) {
+        (self.release, self.order)
+    }
+}
+
+impl<T> PartialEq for Held<T> {
+    fn eq(&self, other: &Held<T>) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<T> Eq for Held<T> {}
+
+impl<T> PartialOrd for Held<T> {
+    fn partial_cmp(&self, other: &Held<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Held<T> {
+    fn cmp(&self, other: &Held<T>) -> Ordering {
+        other.key().cmp(&self.key())
+    }
+}
+
+pub struct JitterBuffer<T> {
+    delay: Duration,
+
+    /// When the first datum arrived, and when it was captured.
+    base: Option<(Instant, DateTime<Utc>)>,
+    held: BinaryHeap<Held<T>>,
+    pushed: u64,
+
+    /// Data that arrived after their release.
+    late: usize,
+}
+
+impl<T> JitterBuffer<T> {
+    pub fn new(config: &JitterBufferConfig) -> JitterBuffer<T> {
+        JitterBuffer {
+            delay: Duration::from_millis(config.delay),
+            base: None,
+            held: BinaryHeap::new(),
+            pushed: 0,
+            late: 0,
+        }
+    }
+
+    /// Holds `item`, captured at `captured`, which arrives `now`.
+    pub fn push(&mut self, item: T, captured: DateTime<Utc>, now: Instant) {
+        let (base_arrival, base_capture) = *self.base.get_or_insert((now, captured));
+        // Data captured before the first one are due with it.
+        let offset = captured
+            .signed_duration_since(base_capture)
+            .to_std()
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        let mut release = base_arrival + self.delay + offset;
+        if release < now {
+            self.late += 1;
+            release = now;
+        }
+        self.held.push(Held {
+            release,
+            arrival: now,
+            order: self.pushed,
+            item,
+        });
+        self.pushed += 1;
+    }
+
+    /// Returns when the next datum is due, if any is held.
+    pub fn next_release(&self) -> Option<Instant> {
+        self.held.peek().map(|h| h.release)
+    }
+
+    /// Returns the next datum due by `now` and how long it was held.
+    pub fn pop(&mut self, now: Instant) -> Option<(T, Duration)> {
+        if self.next_release().map_or(true, |release| release > now) {
+            return None;
+        }
+        self.flush(now)
+    }
+
+    /// Returns the next datum, due or not, and how long it was held until
+    /// `now`, e.g. when no more data arrive.
+    pub fn flush(&mut self, now: Instant) -> Option<(T, Duration)> {
+        self.held.pop().map(|h| (h.item, now - h.arrival))
+    }
+
+    /// Number of data that arrived after their release.
+    pub fn late(&self) -> usize {
+        self.late
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as Span;
+
+    #[test]
+    fn releases_at_capture_cadence() {
+        let mut buffer = JitterBuffer::new(&JitterBufferConfig { delay: 100 });
+        let captured = Utc::now();
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        // Captured every 40 ms, they arrive in a burst, the third first.
+        buffer.push(0, captured, at(0));
+        buffer.push(2, captured + Span::milliseconds(80), at(5));
+        buffer.push(1, captured + Span::milliseconds(40), at(10));
+        assert_eq!(buffer.next_release(), Some(at(100)));
+        assert!(buffer.pop(at(99)).is_none());
+        assert_eq!(buffer.pop(at(100)), Some((0, Duration::from_millis(100))));
+        assert!(buffer.pop(at(139)).is_none());
+        assert_eq!(buffer.pop(at(140)).map(|p| p.0), Some(1));
+        assert_eq!(buffer.pop(at(180)).map(|p| p.0), Some(2));
+
+        // Too late for its time, it goes at once.
+        buffer.push(3, captured + Span::milliseconds(120), at(300));
+        assert_eq!(buffer.late(), 1);
+        assert_eq!(buffer.pop(at(300)).map(|p| p.0), Some(3));
+        assert!(buffer.flush(at(300)).is_none());
+    }
+}
@@ -4,10 +4,37 @@
 //! Wide-Area Streaming Analytics", Figure 5.
 //!
 //! Key data structures are prefixed with `As`.
+//!
+//! # Embedding
+//!
+//! Besides the binaries, the crate is a library. What it exports at its root
+//! is its public API:
+//!
+//! * `client::run_with_subscribers` and `server::server_with_subscribers` run a
+//!   client or a server from a `Setting`; `Subscribers` publishes what the
+//!   client decides and pins its level from outside.
+//! * `client::run_with_source` sends the data of a source of one's own (e.g.
+//!   a camera) implementing `Adapt` and `Experiment`, and
+//!   `server::server_with_hooks` hands every `LiveFrame` received to the
+//!   application (e.g. to decode and run a detector on). `TwoTier` moves
+//!   such a source between levels by the skip alone when it can, sparing the
+//!   encoder a reconfiguration.
+//! * `AsDatum`, `AsCodec`, `FramedRead` and `skip_corrupt` put data on the
+//!   wire and read them back; `ControlMessage` is every control message.
+//! * `Profile`, `SimpleProfile`, `Adaptation` and the congestion detectors
+//!   (`CongestionDetector`) are the adaptation, to drive a sender of one's
+//!   own with.
+//! * `errors` holds the error types every fallible function returns.
+//!
+//! The queue, the monitor and the socket that tie these together in the
+//! client are internal on purpose: their shape follows the runtime, and they
+//! change with it.
 #![recursion_limit = "1024"]
 #![deny(missing_docs)]
 
 extern crate toml;
+extern crate awstream_core;
+extern crate base64;
 extern crate bincode;
 extern crate byteorder;
 extern crate bytes;
@@ -19,14 +46,23 @@ extern crate evaluation;
 #[macro_use]
 extern crate futures;
 extern crate futures_cpupool;
+extern crate iovec;
 #[macro_use]
 extern crate log;
+extern crate lz4_flex;
+extern crate memmap;
+extern crate net2;
+extern crate rustls;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+extern crate sha1;
 extern crate tokio_core;
 extern crate tokio_io;
+extern crate tokio_rustls;
 extern crate tokio_timer;
+extern crate webpki;
 
 /// A convenience macro for working with `io::Result<T>` from the `Read` and
 /// `Write` traits.
@@ -46,39 +82,122 @@ macro_rules! try_nb {
 }
 
 // mod online;
+mod accuracy_model;
 mod adaptation;
 mod analytics;
+mod bbr;
+mod bond;
+mod budget;
 mod bw_monitor;
+mod catch_up;
+mod coalesce;
+mod codec_stats;
+mod contention;
+mod control;
+mod conn_log;
 mod controller;
-mod errors;
+mod decode_worker;
+mod detector;
+mod drops;
+mod filter;
+mod histogram;
 mod interval;
+mod jitter;
+mod owd;
+mod presets;
 mod profile;
 mod queue;
+mod recorder;
+mod reload;
+mod report;
+mod reverse;
 mod setting;
+mod shed;
+mod simulation;
 mod socket;
 mod source;
+mod thumbnail;
+mod tls;
+mod two_tier;
+mod udp;
 mod utils;
+mod vectors;
 mod video;
+mod websocket;
+mod wire;
 pub mod client;
+pub mod errors;
 pub mod server;
+pub mod validate;
 
-use byteorder::{BigEndian, ReadBytesExt};
-use bytes::{BufMut, BytesMut};
+use awstream_core::framing;
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{BufMut, Bytes, BytesMut};
 use errors::*;
-use profile::SimpleProfile;
-pub use setting::Setting;
+use evaluation::Stat;
+pub use accuracy_model::{AccuracyModel, AccuracyModelConfig, FrameMeta, LinearModel};
+pub use adaptation::{Action, AdaptDecision, AdaptEvent, Adaptation, Signal, State, StraySignal};
+pub use awstream_core::pid::PidGains;
+pub use awstream_core::profile::Selection;
+pub use bbr::BbrConfig;
+pub use budget::BudgetWindow;
+pub use client::{LevelChange, Subscribers};
+pub use catch_up::CatchUpConfig;
+pub use coalesce::CoalesceConfig;
+pub use codec_stats::{CodecStats, TypeStats};
+pub use contention::{LoadClient, LoadConfig};
+pub use control::{CONTROL_KINDS, ControlKind, ControlMessage};
+pub use detector::{CongestionDetector, DelayGradient, Fused, Hybrid, Measurement, QueueLatency};
+pub use filter::{FrameFilter, MotionFilter, Verdict};
+pub use histogram::{Histogram, Snapshot};
+pub use jitter::JitterBufferConfig;
+pub use owd::OwdGradientConfig;
+pub use presets::{PRESETS, Preset};
+pub use profile::{Profile, Record, SimpleProfile};
+pub use recorder::{RecorderConfig, Recording, extract_recordings, write_recordings};
+pub use report::{Band, LateWindow, LossWindow, ReplaySummary, ReportDecision, ReportThreshold,
+                 ReportTrigger, read_decisions, replay};
+pub use server::LiveFrame;
+pub use shed::ShedConfig;
+pub use socket::{BufferConfig, BufferSnapshot, BufferStats, FramedRead, skip_corrupt};
+pub use simulation::{ControllerParams, SimulationResult, read_profile, read_trace, simulate,
+                     simulate_pid};
+pub use setting::{AdaptationPolicy, BondingSetting, DetectorKind, ExperimentSetting, MotionSetting,
+                  Probing, Setting, StartupPolicy, ReverseSetting, StreamSetting, ThumbnailSetting,
+                  TlsSetting, Transport};
+use std::collections::BTreeMap;
 use std::io::{self, Cursor};
 use std::mem;
+use std::time::Duration;
+pub use two_tier::{SKIP_TIER_MOVE, TwoTier};
+pub use vectors::{MANIFEST, Vector, check_vectors, reference_data, write_vectors};
+pub use video::{VideoConfig, pack_source};
+pub use wire::WireFormat;
 use tokio_io::codec::{Decoder, Encoder};
 
+/// The stream of data unless set otherwise (see `AsDatum::set_stream_id`),
+/// which is the one the client adapts.
+pub const PRIMARY_STREAM: u32 = 0;
+
+/// The largest payload a compressed payload may decompress to, so that a
+/// peer can't make the decoder allocate without bound.
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// LZ4 expands no byte of its input into more than 255 bytes of output.
+const LZ4_MAX_RATIO: usize = 255;
+
 /// Actions for adaptation.
 pub enum AdaptAction {
-    /// Adapts to a designated bandwidth in kbps.
+    /// Adapts to the highest level within a bandwidth in kbps, in either
+    /// direction.
     ToRate(f64),
 
     /// Decreases the adaptation level.
     DecreaseDegradation,
 
+    /// Switches to a level, in either direction.
+    ToLevel(usize),
+
     /// Starts probing with target bandwidth in kbps.
     StartProbe(f64),
 
@@ -87,6 +206,17 @@ pub enum AdaptAction {
 
     /// Stops the probing.
     StopProbe,
+
+    /// Switches to a level and stays there, whatever else the source is
+    /// asked, until unpinned.
+    PinLevel(usize),
+
+    /// Lets the source adapt again after `PinLevel`.
+    Unpin,
+
+    /// Swaps the profile of the source for a reloaded one, at a level of the
+    /// new profile. A pinned source stays pinned, at that level.
+    Reload(Profile<VideoConfig>, usize),
 }
 
 /// The core trait that a struct should react by changing levels.
@@ -97,7 +227,10 @@ pub trait Adapt {
     /// Decreases the current degradation level.
     fn dec_degradation(&mut self);
 
-    /// Period
+    /// Switches to `level` (or the highest level if it is beyond).
+    fn set_level(&mut self, level: usize);
+
+    /// Period (ms) between two data at the current level.
     fn period_in_ms(&self) -> u64;
 
     /// Report the current level.
@@ -105,30 +238,139 @@ pub trait Adapt {
 
     /// Return a simple profile
     fn simple_profile(&self) -> SimpleProfile;
+
+    /// Swaps the profile for `profile`, switching to `level` of it. A source
+    /// keeps its profile if it can't play the new one.
+    fn reload(&mut self, profile: Profile<VideoConfig>, level: usize);
+
+    /// Return the configuration of every level.
+    fn configs(&self) -> Vec<VideoConfig>;
 }
 
 /// For experiment
 pub trait Experiment {
-    /// Return the size of next datum and its index.
-    fn next_datum(&mut self) -> (usize, usize);
+    /// Return the size of next datum and its index, or `None` when there is
+    /// no more data.
+    fn next_datum(&mut self) -> Option<(usize, usize)>;
+
+    /// Return the accuracy statistics expected for a datum at the current
+    /// level, if known.
+    fn expected_stat(&self, _index: usize) -> Option<Stat> {
+        None
+    }
+
+    /// Return the size of datum `index` at `level`, if known. Used when a
+    /// `FrameFilter` downgrades a datum.
+    fn datum_size_at(&mut self, _level: usize, _index: usize) -> Option<usize> {
+        None
+    }
+
+    /// Return the accuracy statistics expected for datum `index` at `level`,
+    /// if known.
+    fn expected_stat_at(&self, _level: usize, _index: usize) -> Option<Stat> {
+        None
+    }
+
+    /// Return how long a datum stays useful to the receiver after it's
+    /// created, if it expires.
+    fn ttl(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Return the bytes of datum `index` at the current level, `size` of
+    /// them. Zeros unless the source has the actual data (e.g. frames from a
+    /// camera), since experiments only replay sizes.
+    fn payload(&mut self, _index: usize, size: usize) -> Vec<u8> {
+        vec![0; size]
+    }
+
+    /// Return how many data the source skipped (e.g. frames a configuration
+    /// leaves out) since last asked, so that they are reported as drops.
+    fn take_skipped(&mut self) -> usize {
+        0
+    }
 }
 
 #[derive(Debug)]
 enum CodecState {
     Len,
-    Payload { len: u64 },
+    Payload { len: u64, crc: u32 },
 }
 
 impl Default for AsCodec {
     fn default() -> Self {
-        AsCodec { state: CodecState::Len }
+        AsCodec::with_stats(CodecStats::default())
     }
 }
 
 #[derive(Debug)]
-/// A wrapping codec to use Tokio.
+/// A wrapping codec to use Tokio. A datum goes on the wire as its length
+/// (8 bytes, big endian), its header in the stable wire schema (see `wire`),
+/// and then its payload as is, so that the payload is never serialized or
+/// copied apart. Headers in bincode, which peers built before the schema
+/// write, are decoded too, and written with `WireFormat::Legacy`.
+///
+/// Live payloads are compressed once a handshake datum (see
+/// `AsDatum::handshake`) announces a compression: the encoder switches after
+/// writing it and the decoder after reading it, so both ends of a connection
+/// agree without further coordination.
 pub struct AsCodec {
     state: CodecState,
+    compression: Compression,
+    format: WireFormat,
+    stats: CodecStats,
+}
+
+/// How live payloads are compressed on the wire.
+///
+/// ```toml
+/// compression = "lz4"
+/// ```
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// Payloads are sent as is.
+    #[default]
+    None,
+
+    /// Payloads are compressed with LZ4, which is cheap enough for every frame.
+    Lz4,
+}
+
+impl Compression {
+    fn compress(&self, payload: &Bytes) -> Bytes {
+        match *self {
+            Compression::None => payload.clone(),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(payload).into(),
+        }
+    }
+
+    /// Decompresses a payload. The size it announces is checked before
+    /// anything is allocated: it can't exceed `MAX_DECOMPRESSED_SIZE`, nor
+    /// what LZ4 can expand the payload to.
+    fn decompress(&self, payload: BytesMut) -> io::Result<Bytes> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        match *self {
+            Compression::None => Ok(payload.freeze()),
+            Compression::Lz4 => {
+                if payload.len() < 4 {
+                    return Err(invalid("compressed payload is truncated".into()));
+                }
+                let size = LittleEndian::read_u32(&payload[..4]) as usize;
+                let compressed = &payload[4..];
+                let limit = ::std::cmp::min(compressed.len() * LZ4_MAX_RATIO,
+                                            MAX_DECOMPRESSED_SIZE);
+                if size > limit {
+                    return Err(invalid(format!("compressed payload of {} bytes claims {}",
+                                               compressed.len(),
+                                               size)));
+                }
+                lz4_flex::decompress(compressed, size)
+                    .map(Bytes::from)
+                    .map_err(|e| invalid(e.to_string()))
+            }
+        }
+    }
 }
 
 impl AsDatum {
@@ -138,7 +380,12 @@ impl AsDatum {
         let mut d = AsDatum {
             t: AsDatumType::Live(level, frame_num),
             ts: now,
-            mem: data,
+            mem: data.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
             len: 0,
         };
         d.update_len();
@@ -151,20 +398,103 @@ impl AsDatum {
         let mut d = AsDatum {
             t: AsDatumType::Dummy,
             ts: now,
-            mem: vec![0; size],
+            mem: vec![0; size].into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
             len: 0,
         };
         d.update_len();
         d
     }
 
+    /// Creates a new `AsDatum` object that announces how the sender compresses
+    /// live payloads from now on.
+    pub fn handshake(compression: Compression) -> Result<AsDatum> {
+        let now = chrono::Utc::now();
+        let mem = bincode::serialize(&compression, bincode::Infinite)?;
+        let mut d = AsDatum {
+            t: AsDatumType::Handshake,
+            ts: now,
+            mem: mem.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
+            len: 0,
+        };
+        d.update_len();
+        Ok(d)
+    }
+
+    /// Creates a new `AsDatum` object that joins its connection to bond `bond`
+    /// (see `BondingSetting`), as its primary connection or not.
+    pub fn join(bond: u64, primary: bool) -> Result<AsDatum> {
+        let now = chrono::Utc::now();
+        let mem = bincode::serialize(&(bond, primary), bincode::Infinite)?;
+        let mut d = AsDatum {
+            t: AsDatumType::Join,
+            ts: now,
+            mem: mem.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
+            len: 0,
+        };
+        d.update_len();
+        Ok(d)
+    }
+
+    /// Returns the bond a join datum joins, and whether its connection is the
+    /// primary one.
+    pub fn bond(&self) -> Result<(u64, bool)> {
+        let bond = bincode::deserialize(&self.mem[..])?;
+        Ok(bond)
+    }
+
+    /// Creates a new `AsDatum` object that carries `message` in a `Control`
+    /// datum, which peers that don't know its kind skip.
+    pub fn control(message: &ControlMessage) -> Result<AsDatum> {
+        let now = chrono::Utc::now();
+        let mem = message.encode()?;
+        let mut d = AsDatum {
+            t: AsDatumType::Control,
+            ts: now,
+            mem: mem.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
+            len: 0,
+        };
+        d.update_len();
+        Ok(d)
+    }
+
+    /// Returns the compression a handshake datum announces.
+    pub fn compression(&self) -> Result<Compression> {
+        let compression = bincode::deserialize(&self.mem[..])?;
+        Ok(compression)
+    }
+
     /// Creates a new `AsDatum` object for probing RTT.
     pub fn latency_probe() -> AsDatum {
         let now = chrono::Utc::now();
         let mut d = AsDatum {
             t: AsDatumType::LatencyProbe,
             ts: now,
-            mem: vec![0; 0],
+            mem: Bytes::new(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
             len: 0,
         };
         d.update_len();
@@ -178,7 +508,241 @@ impl AsDatum {
         let mut d = AsDatum {
             t: AsDatumType::ReceiverCongest,
             ts: now,
-            mem: mem,
+            mem: mem.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
+            len: 0,
+        };
+        d.update_len();
+        Ok(d)
+    }
+
+    /// Creates a new `AsDatum` object that acknowledges how many bytes the
+    /// receiver has received on the connection so far.
+    pub fn delivery_ack(received: u64) -> Result<AsDatum> {
+        let now = chrono::Utc::now();
+        let mem = bincode::serialize(&received, bincode::Infinite)?;
+        let mut d = AsDatum {
+            t: AsDatumType::DeliveryAck,
+            ts: now,
+            mem: mem.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
+            len: 0,
+        };
+        d.update_len();
+        Ok(d)
+    }
+
+    /// Returns the bytes a delivery ack acknowledges.
+    pub fn delivered(&self) -> Result<u64> {
+        let received = bincode::deserialize(&self.mem[..])?;
+        Ok(received)
+    }
+
+    /// Creates a new `AsDatum` object that reports the receiver's analytics
+    /// can't keep up.
+    pub fn compute_report(report: &ComputeReport) -> Result<AsDatum> {
+        let now = chrono::Utc::now();
+        let mem = report.to_mem()?;
+        let mut d = AsDatum {
+            t: AsDatumType::ComputeCongest,
+            ts: now,
+            mem: mem.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
+            len: 0,
+        };
+        d.update_len();
+        Ok(d)
+    }
+
+    /// Creates a new `AsDatum` object that reports the accuracy the receiver
+    /// achieves per level.
+    pub fn accuracy_report(report: &AccuracyReport) -> Result<AsDatum> {
+        let now = chrono::Utc::now();
+        let mem = report.to_mem()?;
+        let mut d = AsDatum {
+            t: AsDatumType::AccuracyFeedback,
+            ts: now,
+            mem: mem.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
+            len: 0,
+        };
+        d.update_len();
+        Ok(d)
+    }
+
+    /// Creates a new `AsDatum` object that carries what the receiver's
+    /// analytics detects in a frame.
+    pub fn detections(detections: &Detections) -> Result<AsDatum> {
+        let now = chrono::Utc::now();
+        let mem = detections.to_mem()?;
+        let mut d = AsDatum {
+            t: AsDatumType::Detections,
+            ts: now,
+            mem: mem.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
+            len: 0,
+        };
+        d.update_len();
+        Ok(d)
+    }
+
+    /// Creates a new `AsDatum` object for a frame uploaded again after it was
+    /// missed live (see `CatchUpConfig`).
+    pub fn historical(level: usize, frame_num: usize, data: Vec<u8>) -> AsDatum {
+        let mut d = AsDatum::new(level, frame_num, data);
+        d.t = AsDatumType::Historical(level, frame_num);
+        d.update_len();
+        d
+    }
+
+    /// Creates a new `AsDatum` object that carries a thumbnail (a small JPEG)
+    /// of what the sender sees.
+    pub fn thumbnail(jpeg: Vec<u8>) -> AsDatum {
+        let now = chrono::Utc::now();
+        let mut d = AsDatum {
+            t: AsDatumType::Thumbnail,
+            ts: now,
+            mem: jpeg.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
+            len: 0,
+        };
+        d.update_len();
+        d
+    }
+
+    /// Coalesces live data of one stream into one datum. Its memory starts
+    /// with an index of the frames, followed by their data. The datum takes
+    /// the timestamp and TTL of the first (oldest) frame.
+    pub fn coalesce(data: Vec<AsDatum>) -> Result<AsDatum> {
+        let index = data.iter()
+            .filter_map(|d| match d.t {
+                AsDatumType::Live(level, frame_num) => Some(CoalescedFrame {
+                    level,
+                    frame_num,
+                    size: d.mem.len(),
+                    expected: d.expected,
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if index.len() != data.len() || data.is_empty() {
+            bail!("only live data can be coalesced");
+        }
+        if data.iter().any(|d| d.stream_id != data[0].stream_id) {
+            bail!("only data of one stream can be coalesced");
+        }
+        let mut mem = bincode::serialize(&index, bincode::Infinite)?;
+        for d in &data {
+            mem.extend_from_slice(&d.mem);
+        }
+        let mut d = AsDatum {
+            t: AsDatumType::Coalesced,
+            ts: data[0].ts,
+            mem: mem.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: data[0].ttl,
+            stream_id: data[0].stream_id,
+            seq: 0,
+            len: 0,
+        };
+        d.update_len();
+        Ok(d)
+    }
+
+    /// Returns the index of a coalesced datum.
+    pub fn coalesced_frames(&self) -> Result<Vec<CoalescedFrame>> {
+        let index = bincode::deserialize_from(&mut Cursor::new(&self.mem), bincode::Infinite)?;
+        Ok(index)
+    }
+
+    /// Splits a coalesced datum into its live data, which share its
+    /// timestamp, TTL, queue delay and stream.
+    pub fn uncoalesce(self) -> Result<Vec<AsDatum>> {
+        let mut cursor = Cursor::new(&self.mem);
+        let index: Vec<CoalescedFrame> =
+            bincode::deserialize_from(&mut cursor, bincode::Infinite)?;
+        let mut offset = cursor.position() as usize;
+        let mut data = Vec::with_capacity(index.len());
+        for frame in index {
+            let end = offset + frame.size;
+            if end > self.mem.len() {
+                bail!("coalesced datum is truncated");
+            }
+            let mut d = AsDatum {
+                t: AsDatumType::Live(frame.level, frame.frame_num),
+                ts: self.ts,
+                mem: self.mem.slice(offset, end),
+                expected: frame.expected,
+                queue_delay: self.queue_delay,
+                ttl: self.ttl,
+                stream_id: self.stream_id,
+                seq: self.seq,
+                len: 0,
+            };
+            d.update_len();
+            data.push(d);
+            offset = end;
+        }
+        Ok(data)
+    }
+
+    /// Returns true if the payload of this datum is live data, which the
+    /// codec compresses.
+    fn live_payload(&self) -> bool {
+        matches!(self.t, AsDatumType::Live(_, _) | AsDatumType::Coalesced)
+    }
+
+    /// Returns (level, frame number) of every live frame in this datum.
+    pub fn live_frames(&self) -> Vec<(usize, usize)> {
+        match self.t {
+            AsDatumType::Live(level, frame_num) => vec![(level, frame_num)],
+            AsDatumType::Coalesced => {
+                self.coalesced_frames()
+                    .map(|index| index.iter().map(|f| (f.level, f.frame_num)).collect())
+                    .unwrap_or_default()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Creates a new `AsDatum` object that reports drops on the sender.
+    pub fn drop_report(report: &DropReport) -> Result<AsDatum> {
+        let now = chrono::Utc::now();
+        let mem = report.to_mem()?;
+        let mut d = AsDatum {
+            t: AsDatumType::SenderDrops,
+            ts: now,
+            mem: mem.into(),
+            expected: None,
+            queue_delay: None,
+            ttl: None,
+            stream_id: PRIMARY_STREAM,
+            seq: 0,
             len: 0,
         };
         d.update_len();
@@ -186,14 +750,22 @@ impl AsDatum {
     }
 
     fn update_len(&mut self) {
-        // effective length includes the encoding of the length itself.
-        self.len = bincode::serialized_size(self);
+        // the header is serialized, the payload follows as is. Headers in
+        // bincode (`WireFormat::Legacy`) differ in size by a few bytes.
+        self.len = wire::header_len(self) as u64 + self.mem.len() as u64;
     }
 
     /// Returns the effective length (in bytes) for network transmission.
     pub fn net_len(&self) -> usize {
-        // effective length includes the encoding of the length itself.
-        self.len as usize + mem::size_of::<u64>()
+        // effective length includes the encoding of the length and checksum.
+        self.len as usize + framing::HEADER_SIZE
+    }
+
+    /// Returns the network length the sender counts for this datum (see
+    /// `net_len`). A decoded datum's length is the one on the wire, which is
+    /// shorter if its payload was compressed.
+    pub fn sent_len(&self) -> usize {
+        wire::header_len(self) + self.mem.len() + framing::HEADER_SIZE
     }
 
     /// Returns the datum type.
@@ -201,6 +773,73 @@ impl AsDatum {
         self.t
     }
 
+    /// Attaches the accuracy statistics the sender expects for this datum.
+    pub fn set_expected(&mut self, stat: Stat) {
+        self.expected = Some(stat);
+        self.update_len();
+    }
+
+    /// Returns the accuracy statistics the sender expects for this datum.
+    pub fn expected(&self) -> Option<Stat> {
+        self.expected
+    }
+
+    /// Records how long this datum waited in the sender's queue.
+    pub fn set_queue_delay(&mut self, delay: Duration) {
+        let us = delay.as_secs() * 1_000_000 + u64::from(delay.subsec_micros());
+        self.queue_delay = Some(us);
+        self.update_len();
+    }
+
+    /// Returns how long (in ms) this datum waited in the sender's queue.
+    pub fn queue_delay_in_ms(&self) -> Option<f64> {
+        self.queue_delay.map(|us| us as f64 / 1_000.0)
+    }
+
+    /// Sets how long after its creation this datum is still useful.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        let us = ttl.as_secs() * 1_000_000 + u64::from(ttl.subsec_micros());
+        self.ttl = Some(us);
+        self.update_len();
+    }
+
+    /// Returns when this datum stops being useful, if it has a TTL.
+    pub fn deadline(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.ttl.map(|us| self.ts + chrono::Duration::microseconds(us as i64))
+    }
+
+    /// Returns true if this datum has a TTL and is older than that at `now`.
+    /// Like the network latency, this relies on synchronized clocks.
+    pub fn is_stale(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        match (self.ttl, now.signed_duration_since(self.ts).num_microseconds()) {
+            (Some(ttl), Some(age)) => age > ttl as i64,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Returns the stream this datum belongs to.
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    /// Moves this datum to stream `stream_id`.
+    pub fn set_stream_id(&mut self, stream_id: u32) {
+        self.stream_id = stream_id;
+        self.update_len();
+    }
+
+    /// Returns the sequence number of this datum within its stream.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Numbers this datum in the sequence of live data of its stream.
+    pub fn set_seq(&mut self, seq: u64) {
+        self.seq = seq;
+        self.update_len();
+    }
+
     /// Return the serialized length of this data structure
     pub fn len(&self) -> usize {
         self.len as usize
@@ -212,6 +851,8 @@ impl ::std::fmt::Display for AsDatum {
         match self.t {
             AsDatumType::Live(level, frame_num) => {
                 f.debug_struct("AsDatum::Live")
+                    .field("stream_id", &self.stream_id)
+                    .field("seq", &self.seq)
                     .field("level", &level)
                     .field("frame_num", &frame_num)
                     .field("ts", &self.ts)
@@ -223,6 +864,19 @@ impl ::std::fmt::Display for AsDatum {
             AsDatumType::Dummy => write!(f, "probe data: {}", self.len),
             AsDatumType::LatencyProbe => write!(f, "probe latency"),
             AsDatumType::ReceiverCongest => write!(f, "receiver congest"),
+            AsDatumType::SenderDrops => write!(f, "sender drops"),
+            AsDatumType::ComputeCongest => write!(f, "compute congest"),
+            AsDatumType::AccuracyFeedback => write!(f, "accuracy feedback"),
+            AsDatumType::Coalesced => write!(f, "coalesced data: {}", self.len),
+            AsDatumType::Handshake => write!(f, "handshake"),
+            AsDatumType::DeliveryAck => write!(f, "delivery ack"),
+            AsDatumType::Detections => write!(f, "detections"),
+            AsDatumType::Thumbnail => write!(f, "thumbnail: {}", self.len),
+            AsDatumType::Historical(level, frame_num) => {
+                write!(f, "historical data: level {}, frame {}, {}", level, frame_num, self.len)
+            }
+            AsDatumType::Join => write!(f, "join"),
+            AsDatumType::Control => write!(f, "control: {}", self.len),
         }
     }
 }
@@ -244,29 +898,396 @@ pub enum AsDatumType {
 
     /// Signals that the receiver detects congestion.
     ReceiverCongest,
+
+    /// Reports data the sender has dropped.
+    SenderDrops,
+
+    /// Signals that the receiver's analytics can't keep up.
+    ComputeCongest,
+
+    /// Reports the accuracy the receiver achieves per level.
+    AccuracyFeedback,
+
+    /// Several small live frames in one datum (see `CoalescedFrame`).
+    Coalesced,
+
+    /// Announces how the sender compresses live payloads (see `AsCodec`).
+    Handshake,
+
+    /// Acknowledges the bytes the receiver has received so far.
+    DeliveryAck,
+
+    /// What the receiver's analytics detects in a frame (see `Detections`).
+    Detections,
+
+    /// A small JPEG of what the sender sees (see `ThumbnailSetting`).
+    Thumbnail,
+
+    /// A frame missed live and uploaded later, with (level, frame_num); its
+    /// timestamp is when it is uploaded.
+    Historical(usize, usize),
+
+    /// Joins a connection to a bond of connections (see `BondingSetting`).
+    Join,
+
+    /// A typed control message, which peers that don't know it skip (see
+    /// `ControlMessage`).
+    Control,
+}
+
+impl AsDatumType {
+    /// Returns the name of this type, regardless of level and frame.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AsDatumType::Live(_, _) => "live",
+            AsDatumType::Raw => "raw",
+            AsDatumType::Dummy => "dummy",
+            AsDatumType::LatencyProbe => "latency_probe",
+            AsDatumType::ReceiverCongest => "receiver_congest",
+            AsDatumType::SenderDrops => "sender_drops",
+            AsDatumType::ComputeCongest => "compute_congest",
+            AsDatumType::AccuracyFeedback => "accuracy_feedback",
+            AsDatumType::Coalesced => "coalesced",
+            AsDatumType::Handshake => "handshake",
+            AsDatumType::DeliveryAck => "delivery_ack",
+            AsDatumType::Detections => "detections",
+            AsDatumType::Thumbnail => "thumbnail",
+            AsDatumType::Historical(_, _) => "historical",
+            AsDatumType::Join => "join",
+            AsDatumType::Control => "control",
+        }
+    }
+}
+
+/// Why a datum is dropped instead of sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DropReason {
+    /// The datum has passed its deadline.
+    Deadline,
+
+    /// The queue is full.
+    QueueFull,
+
+    /// The datum is not a keyframe and can be shed first.
+    NonKeyframe,
+
+    /// The datum arrives after its TTL (dropped by the receiver).
+    Stale,
+
+    /// The datum is received but not analyzed, as the receiver is busy (see
+    /// `ShedConfig`).
+    Shed,
+
+    /// A frame filter of the sender vetoes the datum (see `FrameFilter`).
+    Filtered,
+
+    /// The configuration of the level leaves the frame out (see `skip` of
+    /// `VideoConfig`).
+    Skipped,
+
+    /// The datum is received but not analyzed, as the mailbox of the
+    /// analytics is full.
+    AnalyticsFull,
+}
+
+impl ::std::fmt::Display for DropReason {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            DropReason::Deadline => write!(f, "deadline"),
+            DropReason::QueueFull => write!(f, "queue_full"),
+            DropReason::NonKeyframe => write!(f, "non_keyframe"),
+            DropReason::Stale => write!(f, "stale"),
+            DropReason::Shed => write!(f, "shed"),
+            DropReason::Filtered => write!(f, "filtered"),
+            DropReason::Skipped => write!(f, "skipped"),
+            DropReason::AnalyticsFull => write!(f, "analytics_full"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+/// Number of dropped datums, keyed by reason and level.
+pub struct DropReport {
+    counts: BTreeMap<(DropReason, usize), usize>,
+}
+
+impl DropReport {
+    /// Adds `count` drops of data at `level` because of `reason`.
+    pub fn add(&mut self, reason: DropReason, level: usize, count: usize) {
+        *self.counts.entry((reason, level)).or_insert(0) += count;
+    }
+
+    /// Adds all drops from another report.
+    pub fn merge(&mut self, other: &DropReport) {
+        for (&(reason, level), &count) in &other.counts {
+            self.add(reason, level, count);
+        }
+    }
+
+    /// Returns the number of drops for a reason at a level.
+    pub fn get(&self, reason: DropReason, level: usize) -> usize {
+        self.counts.get(&(reason, level)).cloned().unwrap_or(0)
+    }
+
+    /// Returns the number of drops for a reason across all levels.
+    pub fn by_reason(&self, reason: DropReason) -> usize {
+        self.counts
+            .iter()
+            .filter(|&(&(r, _), _)| r == reason)
+            .map(|(_, &count)| count)
+            .sum()
+    }
+
+    /// Returns the total number of drops.
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    /// Returns true if nothing is dropped.
+    pub fn is_empty(&self) -> bool {
+        self.total() == 0
+    }
+
+    /// Decode from memory
+    pub fn from_mem(mem: &[u8]) -> Result<DropReport> {
+        let report = bincode::deserialize(mem)?;
+        Ok(report)
+    }
+
+    /// Encode into memory
+    pub fn to_mem(&self) -> Result<Vec<u8>> {
+        let mem = bincode::serialize(&self, bincode::Infinite)?;
+        Ok(mem)
+    }
+}
+
+impl ::std::fmt::Display for DropReport {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}", self.total())?;
+        for (&(reason, level), &count) in &self.counts {
+            write!(f, " {}@{}={}", reason, level, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+/// Where the latency of a datum goes (all in ms).
+pub struct LatencyBreakdown {
+    /// Time spent waiting in the sender's queue (monotonic clock).
+    pub queue: f64,
+
+    /// Time from leaving the sender's queue until the receiver gets it. This
+    /// includes transmission and relies on synchronized clocks.
+    pub network: f64,
+
+    /// Time the receiver spends processing the datum (monotonic clock).
+    pub processing: f64,
+}
+
+impl LatencyBreakdown {
+    /// Returns the sum of all components.
+    pub fn total(&self) -> f64 {
+        self.queue + self.network + self.processing
+    }
+}
+
+impl ::std::fmt::Display for LatencyBreakdown {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
+            "queue {:.3} ms, network {:.3} ms, processing {:.3} ms",
+            self.queue,
+            self.network,
+            self.processing
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Gaps in the sequence numbers (see `AsDatum::seq`) of the live data a
+/// receiver has seen since it connected. Data the sender drops from its queue
+/// leave gaps too (see `DropReport`).
+pub struct SequenceStats {
+    /// Data skipped by the sequence numbers that have not arrived (yet).
+    pub lost: u64,
+
+    /// Data that arrived after a later one of their stream.
+    pub reordered: u64,
+}
+
+impl ::std::fmt::Display for SequenceStats {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "lost {}, reordered {}", self.lost, self.reordered)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 /// Statistics report from the receiver side.
 pub struct ReceiverReport {
     latency: f64,
     goodput: f64,
     throughput: f64,
+    breakdown: LatencyBreakdown,
+    sequence: SequenceStats,
+
+    /// Not in bincode, so that reports to legacy peers keep their size.
+    #[serde(skip)]
+    processing: f64,
 }
 
 impl ReceiverReport {
     /// Creates
-    pub fn new(latency: f64, goodput: f64, throughput: f64) -> Self {
+    pub fn new(
+        latency: f64,
+        goodput: f64,
+        throughput: f64,
+        breakdown: LatencyBreakdown,
+        sequence: SequenceStats,
+        processing: f64,
+    ) -> Self {
         ReceiverReport {
             latency: latency,
             goodput: goodput,
             throughput: throughput,
+            breakdown,
+            sequence,
+            processing,
         }
     }
 
+    /// Returns the breakdown of the latency that triggered this report.
+    pub fn breakdown(&self) -> LatencyBreakdown {
+        self.breakdown
+    }
+
+    /// Returns the data lost and reordered so far.
+    pub fn sequence(&self) -> SequenceStats {
+        self.sequence
+    }
+
+    /// Returns the mean time (ms) the receiver spent decoding and analyzing
+    /// a datum over the last second. Unlike the latency, it doesn't grow with
+    /// the network, only when the receiver itself is slow; 0 from legacy
+    /// peers.
+    pub fn processing(&self) -> f64 {
+        self.processing
+    }
+
+    /// Decode from memory, in the stable wire schema or in bincode.
+    pub fn from_mem(mem: &[u8]) -> Result<ReceiverReport> {
+        wire::decode_report(mem)
+    }
+
+    /// Encode into memory, in the stable wire schema (see `wire`).
+    pub fn to_mem(&self) -> Result<Vec<u8>> {
+        Ok(wire::encode_report(self))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+/// State of the receiver's analytics, reported when it becomes the bottleneck.
+pub struct ComputeReport {
+    /// Number of data waiting to be processed.
+    pub queue_depth: usize,
+
+    /// Average time (ms) from receiving a datum until it's processed.
+    pub latency: f64,
+
+    /// Rate (kbps) of data the analytics processes.
+    pub rate: f64,
+
+    /// Data (frames) a second the analytics processes. Its cost is per frame,
+    /// so this, rather than `rate`, is what it keeps up with.
+    pub fps: f64,
+}
+
+impl ComputeReport {
+    /// Decode from memory
+    pub fn from_mem(mem: &[u8]) -> Result<ComputeReport> {
+        let report = bincode::deserialize(mem)?;
+        Ok(report)
+    }
+
+    /// Encode into memory
+    pub fn to_mem(&self) -> Result<Vec<u8>> {
+        let mem = bincode::serialize(&self, bincode::Infinite)?;
+        Ok(mem)
+    }
+}
+
+impl ::std::fmt::Display for ComputeReport {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
+            "queue depth {}, latency {:.3} ms, rate {:.3} kbps, {:.1} fps",
+            self.queue_depth,
+            self.latency,
+            self.rate,
+            self.fps
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+/// What the receiver's analytics detects in a frame, for applications that act
+/// on it (alerting, actuation).
+pub struct Detections {
+    /// Frame number of the frame.
+    pub frame_num: usize,
+
+    /// Level the frame was received at.
+    pub level: usize,
+
+    /// Number of objects detected.
+    pub objects: usize,
+
+    /// How the detections compare with the ground truth.
+    pub stat: Stat,
+}
+
+impl Detections {
+    /// Decode from memory
+    pub fn from_mem(mem: &[u8]) -> Result<Detections> {
+        let detections = bincode::deserialize(mem)?;
+        Ok(detections)
+    }
+
+    /// Encode into memory
+    pub fn to_mem(&self) -> Result<Vec<u8>> {
+        let mem = bincode::serialize(&self, bincode::Infinite)?;
+        Ok(mem)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+/// A frame within a coalesced datum.
+pub struct CoalescedFrame {
+    /// Level of the frame.
+    pub level: usize,
+
+    /// Frame number.
+    pub frame_num: usize,
+
+    /// Size (bytes) of the frame's data.
+    pub size: usize,
+
+    /// Accuracy statistics the sender expects for the frame.
+    pub expected: Option<Stat>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+/// Accuracy the receiver achieves per level over the last interval, so that
+/// the sender can recalibrate its profile.
+pub struct AccuracyReport {
+    /// (level, accuracy) of every level received in the interval.
+    pub levels: Vec<(usize, f64)>,
+}
+
+impl AccuracyReport {
     /// Decode from memory
-    pub fn from_mem(mem: &Vec<u8>) -> Result<ReceiverReport> {
-        let report = bincode::deserialize(&mem[..])?;
+    pub fn from_mem(mem: &[u8]) -> Result<AccuracyReport> {
+        let report = bincode::deserialize(mem)?;
         Ok(report)
     }
 
@@ -286,11 +1307,37 @@ pub struct AsDatum {
     /// The pointer to the actual memory. We only hold a reference to the memory
     /// to facilitate zero-copy network programming. Underlying the hood, it
     /// uses reference counting for safe free.
-    mem: Vec<u8>,
+    ///
+    /// The payload is not part of the serialized header: on the wire it
+    /// follows the header as is (see `AsCodec`).
+    #[serde(skip)]
+    mem: Bytes,
 
     /// Timestamp associated with the sender. We use unix time at UTC.
     ts: chrono::DateTime<chrono::Utc>,
 
+    /// Accuracy statistics the sender expects for this datum, looked up from
+    /// the per-frame stats. Only set for live data.
+    expected: Option<Stat>,
+
+    /// How long (in microseconds) this datum waited in the sender's queue,
+    /// measured with a monotonic clock. Set when it leaves the queue.
+    queue_delay: Option<u64>,
+
+    /// How long (in microseconds) after `ts` this datum is still useful to the
+    /// receiver. The receiver discards stale data without processing it.
+    ttl: Option<u64>,
+
+    /// The logical stream this datum belongs to, so that one connection can
+    /// carry several (e.g. video, audio and metadata). `PRIMARY_STREAM` unless
+    /// set otherwise.
+    stream_id: u32,
+
+    /// Number of this datum among the live data of its stream, counted from 0
+    /// as they enter the sender's queue (see `queue::SenderCtl`), so that the
+    /// receiver can tell gaps. 0 for other data.
+    seq: u64,
+
     /// The size of serialized version of this data structure (except this
     /// field). We use this field as a cache to avoid repeated call for
     /// serialization.
@@ -303,18 +1350,153 @@ impl Decoder for AsCodec {
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<AsDatum>> {
+        match self.split_frame(buf)? {
+            Some(frame) => self.decode_raw(frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A frame read off the wire, whose header and payload are yet to be decoded
+/// (see `AsCodec::split_frame`).
+#[derive(Debug)]
+pub(crate) struct RawFrame {
+    payload: BytesMut,
+    len: u64,
+    crc: u32,
+}
+
+impl AsCodec {
+    /// Creates a codec that counts into `stats`, e.g. to count both
+    /// directions of a connection together.
+    pub fn with_stats(stats: CodecStats) -> AsCodec {
+        AsCodec {
+            state: CodecState::Len,
+            compression: Compression::None,
+            format: WireFormat::default(),
+            stats,
+        }
+    }
+
+    /// Sets how this codec writes headers. It reads either format.
+    pub fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
+
+    /// Returns how this codec writes headers.
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// Returns a handle to the counters of what this codec has encoded and
+    /// decoded.
+    pub fn stats(&self) -> CodecStats {
+        self.stats.clone()
+    }
+
+    /// Decodes a datagram that holds one frame, or `None` if it is truncated.
+    /// Unlike on a stream, the rest of a truncated frame never arrives, so
+    /// every datagram is decoded from scratch.
+    pub fn decode_datagram(&mut self, datagram: &[u8]) -> Result<Option<AsDatum>> {
+        self.state = CodecState::Len;
+        let mut buf = BytesMut::from(datagram);
+        self.decode(&mut buf)
+    }
+
+    /// Writes the length and the header of `d`, and returns its payload as it
+    /// goes on the wire, which the caller writes next.
+    pub fn encode_header(&mut self, d: &AsDatum, buf: &mut BytesMut) -> Result<Bytes> {
+        let start = buf.len();
+        match self.encode_frame(d, buf) {
+            Ok(payload) => {
+                self.stats.encoded(d.t.name(), buf.len() - start + payload.len());
+                Ok(payload)
+            }
+            Err(e) => {
+                self.stats.encode_error(d.t.name());
+                Err(e)
+            }
+        }
+    }
+
+    fn encode_frame(&mut self, d: &AsDatum, buf: &mut BytesMut) -> Result<Bytes> {
+        let payload = if d.live_payload() {
+            self.compression.compress(&d.mem)
+        } else if d.t == AsDatumType::ReceiverCongest && self.format == WireFormat::Legacy {
+            wire::legacy_report(&d.mem)?
+        } else {
+            d.mem.clone()
+        };
+        let header_len = match self.format {
+            WireFormat::Stable => wire::header_len(d),
+            WireFormat::Legacy => bincode::serialized_size(d) as usize,
+        };
+        buf.reserve(framing::HEADER_SIZE + header_len);
+
+        // First write a placeholder for the frame header (the payload size and
+        // the checksum, which covers the size, the header and the payload),
+        // then the header of the datum.
+        let len_at = buf.len();
+        buf.put_slice(&[0; framing::HEADER_SIZE]);
+        let header_at = buf.len();
+        match self.format {
+            WireFormat::Stable => wire::encode_header(d, buf),
+            WireFormat::Legacy => {
+                bincode::serialize_into(&mut buf.writer(), d, bincode::Infinite)
+                    .map_err(|serialize_err| {
+                        io::Error::new(io::ErrorKind::Other, serialize_err)
+                    })?
+            }
+        }
+        let len = (buf.len() - header_at + payload.len()) as u64;
+        let mut crc = framing::Crc32::new();
+        crc.update(&len.to_be_bytes());
+        crc.update(&buf[header_at..]);
+        crc.update(&payload);
+        buf[len_at..header_at].copy_from_slice(&framing::encode_header(len, crc.finish()));
+
+        if d.t == AsDatumType::Handshake {
+            self.compression = d.compression()?;
+        }
+        Ok(payload)
+    }
+}
+
+/// Returns the `ErrorKind` of an error of the core framing.
+fn decode_error(e: framing::DecodeError) -> ErrorKind {
+    match e {
+        framing::DecodeError::Corrupt(c) => ErrorKind::DecodeError(c.expected, c.actual),
+        framing::DecodeError::TooLong(len) => ErrorKind::FrameTooLong(len),
+    }
+}
+
+impl AsCodec {
+    /// Splits the next frame off `buf`, without decoding it; framing alone is
+    /// cheap, so that decoding can happen elsewhere (see `decode_raw`).
+    pub(crate) fn split_frame(&mut self, buf: &mut BytesMut) -> Result<Option<RawFrame>> {
         trace!("Decode: {:?}", buf);
         loop {
             match self.state {
-                CodecState::Len if buf.len() < mem::size_of::<u64>() => {
-                    trace!("--> Buf len is {}; waiting for 8 to parse len.", buf.len());
+                CodecState::Len if buf.len() < framing::HEADER_SIZE => {
+                    trace!(
+                        "--> Buf len is {}; waiting for {} to parse len.",
+                        buf.len(),
+                        framing::HEADER_SIZE
+                    );
                     return Ok(None);
                 }
                 CodecState::Len => {
-                    let mut len_buf = buf.split_to(mem::size_of::<u64>());
-                    let len = Cursor::new(&mut len_buf).read_u64::<BigEndian>()?;
-                    trace!("--> Parsed len = {} from {:?}", len, len_buf);
-                    self.state = CodecState::Payload { len: len };
+                    let mut header = [0; framing::HEADER_SIZE];
+                    header.copy_from_slice(&buf.split_to(framing::HEADER_SIZE));
+                    let (len, crc) = match framing::decode_header(&header) {
+                        Ok(header) => header,
+                        Err(e) => {
+                            self.stats.decode_error(codec_stats::UNKNOWN);
+                            return Err(decode_error(e).into());
+                        }
+                    };
+                    trace!("--> Parsed len = {}, crc = {:08x}", len, crc);
+                    self.state = CodecState::Payload { len: len as u64, crc };
                 }
                 CodecState::Payload { len, .. } if buf.len() < len as usize => {
                     trace!(
@@ -324,20 +1506,68 @@ impl Decoder for AsCodec {
                     );
                     return Ok(None);
                 }
-                CodecState::Payload { len } => {
+                CodecState::Payload { len, crc } => {
+                    // The frame is consumed either way, so that decoding
+                    // resumes with the next one.
                     let payload = buf.split_to(len as usize);
                     self.state = CodecState::Len;
-                    let mut datum: AsDatum =
-                        bincode::deserialize_from(&mut Cursor::new(payload), bincode::Infinite)
-                            .map_err(|deserialize_err| {
-                                io::Error::new(io::ErrorKind::Other, deserialize_err)
-                            })?;
-                    datum.len = len;
-                    return Ok(Some(datum));
+                    return Ok(Some(RawFrame { payload, len, crc }));
                 }
             }
         }
     }
+
+    /// Decodes a frame split off the wire by `split_frame`, in the order
+    /// frames arrive (a handshake switches the compression of what follows).
+    pub(crate) fn decode_raw(&mut self, frame: RawFrame) -> Result<AsDatum> {
+        let RawFrame { mut payload, len, crc } = frame;
+        let actual = framing::frame_crc(len, &payload);
+        if actual != crc {
+            self.stats.decode_error(codec_stats::UNKNOWN);
+            bail!(ErrorKind::DecodeError(crc, actual));
+        }
+        let header = if payload.first() == Some(&wire::MAGIC) {
+            wire::decode_header(&payload)
+        } else {
+            let mut cursor = Cursor::new(&payload[..]);
+            bincode::deserialize_from(&mut cursor, bincode::Infinite)
+                .map(|datum: AsDatum| (datum, cursor.position() as usize))
+                .map_err(|deserialize_err| io::Error::other(deserialize_err).into())
+        };
+        let (mut datum, header_len) = match header {
+            Ok(header) => header,
+            Err(e) => {
+                self.stats.decode_error(codec_stats::UNKNOWN);
+                return Err(e);
+            }
+        };
+        payload.advance(header_len);
+        let name = datum.t.name();
+        match self.take_payload(&mut datum, payload) {
+            Ok(()) => {
+                datum.len = len;
+                self.stats.decoded(name, datum.net_len());
+                Ok(datum)
+            }
+            Err(e) => {
+                self.stats.decode_error(name);
+                Err(e)
+            }
+        }
+    }
+
+    fn take_payload(&mut self, datum: &mut AsDatum, payload: BytesMut) -> Result<()> {
+        datum.mem = if datum.live_payload() {
+            self.compression.decompress(payload)?
+        } else {
+            payload.freeze()
+        };
+        if datum.t == AsDatumType::Handshake {
+            self.compression = datum.compression()?;
+            info!("peer compresses with {:?}", self.compression);
+        }
+        Ok(())
+    }
 }
 
 impl Encoder for AsCodec {
@@ -345,16 +1575,9 @@ impl Encoder for AsCodec {
     type Error = Error;
 
     fn encode(&mut self, d: AsDatum, buf: &mut BytesMut) -> Result<()> {
-        let payload_size = d.len;
-        let message_size = mem::size_of::<u64>() + payload_size as usize;
-        buf.reserve(message_size);
-
-        // First write payload size
-        buf.put_u64::<BigEndian>(payload_size);
-        bincode::serialize_into(&mut buf.writer(), &d, bincode::Infinite)
-            .map_err(|serialize_err| {
-                io::Error::new(io::ErrorKind::Other, serialize_err)
-            })?;
+        let payload = self.encode_header(&d, buf)?;
+        buf.reserve(payload.len());
+        buf.put_slice(&payload);
 
         trace!("Encoded buffer: {:?}", buf);
         Ok(())
@@ -380,4 +1603,233 @@ mod tests {
         let decoded = codec.decode(&mut buf);
         assert_eq!(decoded.unwrap().unwrap(), expected);
     }
+
+    #[test]
+    fn handshake_enables_compression() {
+        let frame = AsDatum::new(1, 2, vec![7; 10_000]);
+        let mut buf = bytes::BytesMut::new();
+        let mut encoder = AsCodec::default();
+        encoder.encode(AsDatum::handshake(Compression::Lz4).unwrap(), &mut buf).unwrap();
+        let handshake_len = buf.len();
+        encoder.encode(frame.clone(), &mut buf).unwrap();
+        assert!(buf.len() - handshake_len < frame.net_len() / 10);
+
+        let mut decoder = AsCodec::default();
+        let handshake = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(handshake.compression().unwrap(), Compression::Lz4);
+        let decoded = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.datum_type(), frame.datum_type());
+        assert_eq!(decoded.mem, frame.mem);
+    }
+
+    #[test]
+    fn decompressed_size_is_capped() {
+        let payload = lz4_flex::compress_prepend_size(&[7; 10_000]);
+        let mut forged = BytesMut::from(&payload[..]);
+        LittleEndian::write_u32(&mut forged[..4], u32::max_value());
+        assert!(Compression::Lz4.decompress(forged).is_err());
+        assert!(Compression::Lz4.decompress(BytesMut::from(&[1, 0][..])).is_err());
+
+        let decompressed = Compression::Lz4.decompress(BytesMut::from(&payload[..])).unwrap();
+        assert_eq!(&decompressed[..], &[7; 10_000][..]);
+    }
+
+    #[test]
+    fn corrupt_frame_is_skipped() {
+        let mut buf = bytes::BytesMut::new();
+        let mut codec = AsCodec::default();
+        codec.encode(AsDatum::new(0, 1, vec![1; 100]), &mut buf).unwrap();
+        let second = AsDatum::new(0, 2, vec![2; 100]);
+        codec.encode(second.clone(), &mut buf).unwrap();
+        let last = buf.len() - second.net_len() - 1;
+        buf[last] ^= 0xFF;
+
+        match codec.decode(&mut buf) {
+            Err(Error(ErrorKind::DecodeError(expected, actual), _)) => assert!(expected != actual),
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), second);
+    }
+
+    #[test]
+    fn codec_counts_per_type() {
+        let mut buf = bytes::BytesMut::new();
+        let mut codec = AsCodec::default();
+        let live = AsDatum::new(0, 1, vec![1; 100]);
+        let probe = AsDatum::bw_probe(50);
+        let wire = live.net_len() + probe.net_len();
+        codec.encode(live, &mut buf).unwrap();
+        codec.encode(probe, &mut buf).unwrap();
+        let stats = codec.stats();
+        assert_eq!(stats.get("live").encoded, 1);
+        assert_eq!(stats.get("live").encoded_bytes + stats.get("dummy").encoded_bytes, wire as u64);
+        assert_eq!(buf.len(), wire);
+
+        buf[framing::HEADER_SIZE] ^= 0xFF;
+        assert!(codec.decode(&mut buf).is_err());
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert_eq!(stats.get(codec_stats::UNKNOWN).decode_errors, 1);
+        assert_eq!(stats.get("dummy").decoded, 1);
+        assert_eq!(stats.get("dummy").decoded_bytes, stats.get("dummy").encoded_bytes);
+        assert_eq!(stats.snapshot().len(), 3);
+    }
+
+    #[test]
+    fn lengths_past_the_limit_are_rejected() {
+        let mut buf = BytesMut::new();
+        AsCodec::default().encode(AsDatum::new(0, 0, vec![0; 10]), &mut buf).unwrap();
+        let len = framing::MAX_FRAME_LEN as u64 + 1;
+        buf[..framing::LEN_SIZE].copy_from_slice(&len.to_be_bytes());
+
+        let mut codec = AsCodec::default();
+        match codec.decode(&mut buf) {
+            Err(Error(ErrorKind::FrameTooLong(n), _)) => assert_eq!(n, len),
+            other => panic!("expected a frame too long, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn codec_matches_core_framing() {
+        let d = AsDatum::new(0, 0, String::from("Hello").into_bytes());
+        let mut buf = bytes::BytesMut::new();
+        AsCodec::default().encode(d.clone(), &mut buf).unwrap();
+
+        let mut decoder = awstream_core::framing::FrameDecoder::new();
+        decoder.push(&buf);
+        let payload = decoder.next_frame().unwrap().unwrap();
+        let mut expected = BytesMut::new();
+        wire::encode_header(&d, &mut expected);
+        expected.extend_from_slice(b"Hello");
+        assert_eq!(payload, &expected[..]);
+    }
+
+    #[test]
+    fn legacy_peers_interoperate() {
+        let mut d = AsDatum::new(2, 7, vec![3; 100]);
+        d.set_ttl(Duration::from_millis(500));
+        let report = ReceiverReport::new(
+            12.5,
+            800.0,
+            900.0,
+            LatencyBreakdown::default(),
+            SequenceStats::default(),
+            4.0,
+        );
+        let ack = AsDatum::ack(report.clone()).unwrap();
+
+        let mut legacy = AsCodec::default();
+        legacy.set_format(WireFormat::Legacy);
+        let mut buf = bytes::BytesMut::new();
+        legacy.encode(d.clone(), &mut buf).unwrap();
+        legacy.encode(ack, &mut buf).unwrap();
+        assert!(buf[framing::HEADER_SIZE] != wire::MAGIC);
+
+        // What goes on the wire is bincode, header and report alike.
+        let mut decoder = awstream_core::framing::FrameDecoder::new();
+        decoder.push(&buf);
+        let first = decoder.next_frame().unwrap().unwrap();
+        let header: AsDatum = bincode::deserialize(&first).unwrap();
+        assert_eq!(header.datum_type(), d.datum_type());
+
+        let mut codec = AsCodec::default();
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!((decoded.t, decoded.ts, decoded.ttl), (d.t, d.ts, d.ttl));
+        assert_eq!(decoded.mem, d.mem);
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        let decoded = ReceiverReport::from_mem(&decoded.mem).unwrap();
+        assert_eq!(decoded.goodput, report.goodput);
+        assert_eq!(decoded.sequence(), report.sequence());
+        assert_eq!(decoded.processing(), 0.0);
+
+        // The stable schema carries the processing time.
+        let decoded = ReceiverReport::from_mem(&report.to_mem().unwrap()).unwrap();
+        assert_eq!(decoded.processing(), 4.0);
+    }
+
+    #[test]
+    fn detections_round_trip() {
+        let detections = Detections {
+            frame_num: 42,
+            level: 3,
+            objects: 2,
+            stat: Stat {
+                true_positive: 1,
+                false_positive: 1,
+                false_negative: 0,
+            },
+        };
+        let d = AsDatum::detections(&detections).unwrap();
+        assert_eq!(d.datum_type(), AsDatumType::Detections);
+        assert_eq!(Detections::from_mem(&d.mem).unwrap(), detections);
+    }
+
+    #[test]
+    fn drop_report_works() {
+        let mut report = DropReport::default();
+        report.add(DropReason::Deadline, 2, 3);
+        report.add(DropReason::QueueFull, 2, 1);
+        report.add(DropReason::Deadline, 0, 1);
+        assert_eq!(report.total(), 5);
+        assert_eq!(report.by_reason(DropReason::Deadline), 4);
+        assert_eq!(report.get(DropReason::Deadline, 2), 3);
+
+        let decoded = DropReport::from_mem(&report.to_mem().unwrap()).unwrap();
+        assert_eq!(decoded, report);
+    }
+
+    #[test]
+    fn coalesce_round_trip() {
+        let mut first = AsDatum::new(2, 10, vec![1; 30]);
+        first.set_ttl(Duration::from_millis(500));
+        let second = AsDatum::new(3, 11, vec![2; 20]);
+        let separate = first.net_len() + second.net_len();
+
+        let d = AsDatum::coalesce(vec![first.clone(), second.clone()]).unwrap();
+        assert_eq!(d.datum_type(), AsDatumType::Coalesced);
+        assert!(d.net_len() < separate);
+        assert_eq!(d.live_frames(), vec![(2, 10), (3, 11)]);
+
+        let split = d.uncoalesce().unwrap();
+        assert_eq!(split.len(), 2);
+        assert_eq!(split[0], first);
+        assert_eq!(split[1].datum_type(), second.datum_type());
+        assert_eq!(split[1].mem, second.mem);
+        assert_eq!(split[1].ts, first.ts);
+
+        assert!(AsDatum::coalesce(vec![AsDatum::bw_probe(10)]).is_err());
+    }
+
+    #[test]
+    fn stream_id_survives_the_wire() {
+        let mut first = AsDatum::new(0, 1, vec![1; 10]);
+        first.set_stream_id(3);
+        let mut second = AsDatum::new(0, 2, vec![2; 10]);
+        second.set_stream_id(3);
+        let d = AsDatum::coalesce(vec![first.clone(), second.clone()]).unwrap();
+        assert_eq!(d.stream_id(), 3);
+
+        let mut buf = bytes::BytesMut::new();
+        let mut codec = AsCodec::default();
+        codec.encode(d, &mut buf).unwrap();
+        let split = codec.decode(&mut buf).unwrap().unwrap().uncoalesce().unwrap();
+        assert!(split.iter().all(|d| d.stream_id() == 3));
+
+        let primary = AsDatum::new(0, 3, vec![3; 10]);
+        assert_eq!(primary.stream_id(), PRIMARY_STREAM);
+        assert!(AsDatum::coalesce(vec![first, primary]).is_err());
+    }
+
+    #[test]
+    fn stale_after_ttl() {
+        let mut d = AsDatum::new(0, 0, vec![0; 10]);
+        let ts = d.ts;
+        let later = |ms| ts + chrono::Duration::milliseconds(ms);
+        assert!(!d.is_stale(later(1000)));
+
+        let len = d.len();
+        d.set_ttl(Duration::from_millis(500));
+        assert!(d.len() > len);
+        assert!(!d.is_stale(later(400)));
+        assert!(d.is_stale(later(600)));
+    }
 }
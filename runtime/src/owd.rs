@@ -0,0 +1,120 @@
+//! Congestion detection from the trend of one-way delay, in the style of
+//! GCC's trendline filter. The server measures the one-way delay of every
+//! latency probe; the smoothed delays of the last few probes are fitted with a
+//! line, and a slope above a threshold means queues build up somewhere along
+//! the path, even in routers before the client's own queue backs up. The
+//! server then reports congestion, which the client acts on as
+//! `Signal::RemoteCongest`.
+//!
+//! Only the slope matters, so that a constant offset between the clocks of
+//! client and server doesn't.
+
+use std::collections::VecDeque;
+
+/// Weight of the previous smoothed delay when a probe arrives.
+const SMOOTHING: f64 = 0.9;
+
+/// When the server reports congestion from the one-way delay of probes.
+///
+/// ```toml
+/// [owd_gradient]
+/// window = 10
+/// threshold = 5.0
+/// ```
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct OwdGradientConfig {
+    /// Number of probes the trend is fitted over.
+    #[serde(default = "default_window")]
+    pub window: usize,
+
+    /// Growth of the one-way delay (ms per second) above which the path is
+    /// congested.
+    #[serde(default = "default_threshold")]
+    pub threshold: f64,
+}
+
+fn default_window() -> usize {
+    10
+}
+
+fn default_threshold() -> f64 {
+    5.0
+}
+
+/// The trend of the one-way delay of the last probes.
+pub struct OwdTrend {
+    config: OwdGradientConfig,
+
+    /// (arrival in s, smoothed delay in ms) of the last `window` probes.
+    samples: VecDeque<(f64, f64)>,
+    smoothed: Option<f64>,
+}
+
+impl OwdTrend {
+    /// Creates a trend without probes.
+    pub fn new(config: OwdGradientConfig) -> OwdTrend {
+        OwdTrend {
+            config,
+            samples: VecDeque::with_capacity(config.window),
+            smoothed: None,
+        }
+    }
+
+    /// Adds a probe that arrives at `arrival` (s, on any clock) after
+    /// `delay` ms. Returns the slope (ms per second) once the window is full
+    /// if it exceeds the threshold.
+    pub fn add(&mut self, arrival: f64, delay: f64) -> Option<f64> {
+        let smoothed = match self.smoothed {
+            Some(s) => SMOOTHING * s + (1.0 - SMOOTHING) * delay,
+            None => delay,
+        };
+        self.smoothed = Some(smoothed);
+        if self.samples.len() == self.config.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((arrival, smoothed));
+        if self.samples.len() < self.config.window {
+            return None;
+        }
+        self.slope().filter(|&slope| slope > self.config.threshold)
+    }
+
+    /// Returns the least-squares slope of the smoothed delays over arrival,
+    /// if the arrivals spread at all.
+    fn slope(&self) -> Option<f64> {
+        let n = self.samples.len() as f64;
+        let mean_t = self.samples.iter().map(|s| s.0).sum::<f64>() / n;
+        let mean_d = self.samples.iter().map(|s| s.1).sum::<f64>() / n;
+        let (mut num, mut den) = (0.0, 0.0);
+        for &(t, d) in &self.samples {
+            num += (t - mean_t) * (d - mean_d);
+            den += (t - mean_t) * (t - mean_t);
+        }
+        if den > 0.0 { Some(num / den) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn growing_delay_is_congestion() {
+        let config = OwdGradientConfig {
+            window: 5,
+            threshold: 5.0,
+        };
+
+        // A constant delay, however large (e.g. from clock offset), is not.
+        let mut steady = OwdTrend::new(config);
+        assert!((0..20).all(|i| steady.add(i as f64, 500.0).is_none()));
+
+        // 50 ms more every second is, once the window fills.
+        let mut growing = OwdTrend::new(config);
+        let slopes = (0..8)
+            .map(|i| growing.add(i as f64, 20.0 + 50.0 * i as f64))
+            .collect::<Vec<_>>();
+        assert!(slopes[..4].iter().all(|s| s.is_none()));
+        assert!(slopes[4..].iter().all(|s| s.is_some()));
+    }
+}
@@ -0,0 +1,144 @@
+//! Canned bandwidth schedules for the emulation layer, selectable by name, so
+//! that experiments are comparable without sharing raw trace files. Every
+//! preset is a schedule of kbps, one per second, that is the same on every
+//! machine: the noisy ones come from a fixed seed rather than a recording.
+//!
+//! | name              | seconds | schedule                                   |
+//! |-------------------|---------|--------------------------------------------|
+//! | `step_drop`       | 60      | 5000, a drop to 1500 at 20 s, back at 40 s |
+//! | `slow_decay`      | 80      | 5000 down to 500 over 60 s, then 500       |
+//! | `cellular`        | 120     | fading around 2500 with noise and outages  |
+//! | `wifi_contention` | 80      | 6000 shared as stations join and leave     |
+
+use errors::*;
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/// A canned bandwidth schedule (see the `presets` module).
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    /// A sudden drop, and a sudden recovery.
+    StepDrop,
+
+    /// A linear decay, e.g. a client walking away from its access point.
+    SlowDecay,
+
+    /// Fading with noise and short outages, as a cellular link.
+    Cellular,
+
+    /// A WiFi link whose capacity is shared by other stations.
+    WifiContention,
+}
+
+/// All presets, in the order they are documented.
+pub const PRESETS: [Preset; 4] = [
+    Preset::StepDrop,
+    Preset::SlowDecay,
+    Preset::Cellular,
+    Preset::WifiContention,
+];
+
+impl Preset {
+    /// Returns the name a preset is selected by.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Preset::StepDrop => "step_drop",
+            Preset::SlowDecay => "slow_decay",
+            Preset::Cellular => "cellular",
+            Preset::WifiContention => "wifi_contention",
+        }
+    }
+
+    /// Returns the schedule, in kbps, one per second.
+    pub fn schedule(&self) -> Vec<f64> {
+        match *self {
+            Preset::StepDrop => (0..60)
+                .map(|s| if s >= 20 && s < 40 { 1500.0 } else { 5000.0 })
+                .collect(),
+            Preset::SlowDecay => (0..80u32)
+                .map(|s| 5000.0 - 4500.0 * f64::from(s.min(60)) / 60.0)
+                .collect(),
+            Preset::Cellular => {
+                let mut noise = XorShift(0x2545_f491);
+                (0..120u32)
+                    .map(|s| {
+                        let fading = 2500.0 + 1500.0 * (2.0 * PI * f64::from(s) / 40.0).sin();
+                        let kbps = fading + 800.0 * noise.next_signed();
+                        if (s >= 50 && s < 52) || (s >= 95 && s < 97) {
+                            200.0
+                        } else {
+                            kbps.max(100.0).round()
+                        }
+                    })
+                    .collect()
+            }
+            Preset::WifiContention => {
+                // Other stations contending, every 20 s.
+                let others = [0u8, 1, 3, 1];
+                let mut noise = XorShift(0x9e37_79b9);
+                (0..80)
+                    .map(|s| {
+                        let share = 6000.0 / f64::from(1 + others[s / 20]);
+                        (share * (1.0 + 0.05 * noise.next_signed())).round()
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Preset {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Preset> {
+        match PRESETS.iter().find(|p| p.name() == name) {
+            Some(&preset) => Ok(preset),
+            None => bail!("unknown preset {}", name),
+        }
+    }
+}
+
+/// A xorshift generator, so that the noise of a preset is the same on every
+/// machine.
+struct XorShift(u32);
+
+impl XorShift {
+    /// Returns the next number in [-1, 1].
+    fn next_signed(&mut self) -> f64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        f64::from(x) / f64::from(u32::max_value()) * 2.0 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_are_named_and_fixed() {
+        for preset in &PRESETS {
+            assert_eq!(preset.name().parse::<Preset>().unwrap(), *preset);
+            assert_eq!(preset.schedule(), preset.schedule());
+            assert!(preset.schedule().iter().all(|&kbps| kbps >= 100.0));
+        }
+        assert!("dsl".parse::<Preset>().is_err());
+
+        let step = Preset::StepDrop.schedule();
+        assert_eq!(step.len(), 60);
+        assert_eq!((step[19], step[20], step[40]), (5000.0, 1500.0, 5000.0));
+        let decay = Preset::SlowDecay.schedule();
+        assert_eq!((decay[0], decay[30], decay[79]), (5000.0, 2750.0, 500.0));
+    }
+}
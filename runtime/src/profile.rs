@@ -1,122 +1,100 @@
 /// A profile stores the list of <bandwidth, accuracy, configuration>. The
 /// simple implementation uses a list and performs binary search for items.
+///
+/// Profiles are files in one of three formats, picked by extension:
+/// headerless CSV (`bandwidth, <configuration>, accuracy`, any extension but
+/// the two below), or TOML (`.toml`) and JSON (`.json`), which name their
+/// fields and are easier to edit by hand:
+///
+/// ```toml
+/// # The lowest level.
+/// [[level]]
+/// bandwidth = 100.0
+/// width = 320
+/// skip = 5
+/// quant = 30
+/// accuracy = 0.5      # may be left out
+/// ```
+///
+/// JSON is the same, `{"level": [{"bandwidth": 100.0, "width": 320, ...}]}`.
+/// Accuracies are either given for every level or unknown.
+pub use awstream_core::profile::{Selection, SimpleProfile};
 use csv;
+use errors::*;
+use serde::Serialize;
 use serde::de::DeserializeOwned;
+use serde_json;
+use std::f64;
 use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::Read;
 use std::path::Path;
+use toml;
 
 /// Record is each individual rule in a profile.
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub struct Record<C> {
+    /// Bandwidth (kbps) the configuration needs.
     pub bandwidth: f64,
-    pub config: C,
-    _accuracy: f64,
-}
-
-const ADJUST_STICKY_MAX: usize = 3;
-
-/// A `SimpleProfile` isn't parameterized by the config.
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct SimpleProfile {
-    /// A list of bandwidths
-    levels: Vec<f64>,
 
-    /// The current config (serving as cache)
-    current: usize,
+    /// The configuration, e.g. a `VideoConfig`.
+    pub config: C,
 
-    /// How many times we can stick to current without degrading.
-    adjust_sticky_count: usize,
+    _accuracy: f64,
 }
 
-impl SimpleProfile {
-    /// Get current profile
-    #[inline]
-    pub fn current(&self) -> usize {
-        self.current
-    }
-
-    /// Finds the index of the configuration that matches (equal or smaller
-    /// than) the provided bandwidth.
-    fn get_level_index(&self, bw: f64) -> usize {
-        let pos = (&self.levels).binary_search_by(|v| {
-            v.partial_cmp(&bw).expect("failed to compare bandwidth")
-        });
-        match pos {
-            Ok(i) => i,
-            // If error, it could be the first (only 1 profile) or the last
-            // (fail to find).
-            Err(i) => if i == 0 { 0 } else { i - 1 },
+impl<C> Record<C> {
+    /// Creates a record. For testing purpose.
+    #[cfg(test)]
+    pub(crate) fn _new(bandwidth: f64, config: C, accuracy: f64) -> Record<C> {
+        Record {
+            bandwidth,
+            config,
+            _accuracy: accuracy,
         }
     }
 
-    /// Adjusts the profile with a configuration that satisfies the provided
-    /// bandwidth, i.e., equal or smaller. Returns a tuple of bandwidth and
-    /// configuration.
-    pub fn adjust_level(&mut self, bw: f64) -> Option<usize> {
-        let new_level = self.get_level_index(bw);
-        // Only if new level is more conservative
-        if self.current > new_level {
-            self.current = new_level;
-            self.adjust_sticky_count = ADJUST_STICKY_MAX;
-            Some(new_level)
-        } else if self.current == new_level {
-            if self.adjust_sticky_count == 0 {
-                // we've done enough sticky actions, decrease one level
-                self.adjust_sticky_count = ADJUST_STICKY_MAX;
-                self.decrease_level()
-            } else {
-                self.adjust_sticky_count -= 1;
-                None
-            }
-        } else {
-            None
-        }
+    /// Returns the accuracy of this record, NaN if the profile leaves it out.
+    pub fn accuracy(&self) -> f64 {
+        self._accuracy
     }
+}
 
-    /// Advances to next config. Returns the record if successful; otherwise,
-    /// return None (when we cannot advance any more).
-    pub fn advance_level(&mut self) -> Option<usize> {
-        if self.current < self.levels.len() - 1 {
-            self.current += 1;
-            Some(self.current)
-        } else {
-            None
-        }
-    }
+/// A record of a TOML or JSON profile: the fields of the configuration sit
+/// next to the bandwidth and the accuracy, which may be left out.
+#[derive(Serialize, Deserialize)]
+struct NamedRecord<C> {
+    bandwidth: f64,
 
-    /// Advances to next config. Returns the record if successful; otherwise,
-    /// return None (when we cannot advance any more).
-    pub fn decrease_level(&mut self) -> Option<usize> {
-        if self.current > 0 {
-            self.current -= 1;
-            Some(self.current)
-        } else {
-            None
-        }
-    }
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accuracy: Option<f64>,
 
-    /// Finds out the required rate for next configuration.
-    pub fn next_rate(&self) -> Option<f64> {
-        if self.current < self.levels.len() - 1 {
-            Some(self.levels[self.current + 1])
-        } else {
-            None
-        }
-    }
+    #[serde(flatten)]
+    config: C,
+}
 
-    /// Finds out the required delta rate for next configuration.
-    pub fn next_rate_delta(&self) -> Option<f64> {
-        if self.current < self.levels.len() - 1 {
-            trace!("calculating delta for level {}", self.current);
-            Some(self.levels[self.current + 1] - self.levels[self.current])
-        } else {
-            None
-        }
-    }
+/// A TOML or JSON profile, one `level` per record.
+#[derive(Serialize, Deserialize)]
+struct NamedProfile<C> {
+    level: Vec<NamedRecord<C>>,
+}
+
+/// The file format of a profile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Csv,
+    Toml,
+    Json,
+}
 
-    /// Am I current at maximum allowed configuration?
-    pub fn is_max(&self) -> bool {
-        self.current == self.levels.len() - 1
+impl Format {
+    /// The format of `path`, by its extension.
+    fn of(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Csv,
+        }
     }
 }
 
@@ -168,21 +146,41 @@ impl<C: Copy> Profile<C> {
 impl<C> Profile<C> {
     /// Creates a new profile using a vector containing all the records. For
     /// testing purpose.
-    pub fn _with_vec(vec: Vec<Record<C>>) -> Profile<C> {
-        let simple = vec.iter().map(|r| r.bandwidth).collect();
-        let simple_profile = SimpleProfile {
-            levels: simple,
-            current: 0,
-            adjust_sticky_count: ADJUST_STICKY_MAX,
-        };
+    #[cfg(test)]
+    pub(crate) fn _with_vec(vec: Vec<Record<C>>) -> Profile<C> {
         Profile {
+            simple_profile: levels_of(&vec),
             records: vec,
-            simple_profile: simple_profile,
         }
     }
+
+    /// Returns the levels alone, as the adaptation sees them.
     pub fn simplify(&self) -> SimpleProfile {
         self.simple_profile.clone()
     }
+
+    /// Sets how levels are picked for a bandwidth.
+    pub fn set_selection(&mut self, selection: Selection) {
+        self.simple_profile.set_selection(selection);
+    }
+
+    /// Returns all records, ordered by level.
+    pub fn records(&self) -> &[Record<C>] {
+        &self.records
+    }
+
+    /// Returns the level whose bandwidth is the nearest to `bandwidth`, the
+    /// lower one on a tie, e.g. to carry the level over to another profile.
+    pub fn nearest_level(&self, bandwidth: f64) -> usize {
+        let distance = |level: usize| (self.records[level].bandwidth - bandwidth).abs();
+        let mut nearest = 0;
+        for level in 1..self.records.len() {
+            if distance(level) < distance(nearest) {
+                nearest = level;
+            }
+        }
+        nearest
+    }
 }
 
 impl<C: Debug + Copy> Profile<C> {
@@ -203,6 +201,18 @@ impl<C: Debug + Copy> Profile<C> {
         }
     }
 
+    /// Jumps to `level` (clamped to the highest level). Returns the record of
+    /// the new level.
+    pub fn set_level(&mut self, level: usize) -> Record<C> {
+        let level = self.simple_profile.set_level(level);
+        info!(
+            "updating to level {}, configuration {:?}",
+            level,
+            self.records[level]
+        );
+        self.records[level]
+    }
+
     /// Advances to next config. Returns the record if successful; otherwise,
     /// return None (when we cannot advance any more).
     pub fn advance_config(&mut self) -> Option<Record<C>> {
@@ -222,35 +232,142 @@ impl<C: Debug + Copy> Profile<C> {
 
 impl<C: DeserializeOwned + Copy + Debug> Profile<C> {
     /// Creates a new `Profile` instance with a path pointing to the profile
-    /// file (CSV). The columns in the file needs to match the config type.
-    /// Because this is the loading phase, we bail early (use expect!).
+    /// file (CSV, TOML or JSON by extension). The columns (fields) in the file
+    /// need to match the config type. Because this is the loading phase, we
+    /// bail early (use expect!).
     pub fn new<P: AsRef<Path>>(path: P) -> Profile<C> {
-        let errmsg = format!("no profile file {:?}", path.as_ref());
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .from_path(path)
-            .expect(&errmsg);
-        let mut vec = Vec::new();
-        for record in rdr.deserialize() {
-            let record: Record<C> = record.expect("failed to parse the record");
-            vec.push(record);
+        let errmsg = format!("failed to load profile {:?}", path.as_ref());
+        Profile::open(path).expect(&errmsg)
+    }
+
+    /// Loads a profile as `new` does, but returns an error rather than
+    /// panicking, e.g. when the file is reloaded while running.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Profile<C>> {
+        let path = path.as_ref();
+        let vec = match Format::of(path) {
+            Format::Csv => {
+                let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_path(path)?;
+                let mut vec = Vec::new();
+                for record in rdr.deserialize() {
+                    let record: Record<C> = record?;
+                    vec.push(record);
+                }
+                vec
+            }
+            Format::Toml => {
+                let mut contents = String::new();
+                File::open(path)?.read_to_string(&mut contents)?;
+                let named: NamedProfile<C> = toml::from_str(&contents)
+                    .map_err(|e| format!("invalid TOML profile: {}", e))?;
+                from_named(named)
+            }
+            Format::Json => {
+                let named: NamedProfile<C> = serde_json::from_reader(File::open(path)?)?;
+                from_named(named)
+            }
+        };
+        if vec.is_empty() {
+            bail!("no configuration in profile");
+        }
+        // Accuracies are given for every level or for none, see `levels_of`.
+        let known = vec.iter().filter(|r| !r.accuracy().is_nan()).count();
+        if known != 0 && known != vec.len() {
+            bail!("profile gives the accuracy of {} of its {} levels", known, vec.len());
         }
 
-        let simple = vec.iter().map(|r| r.bandwidth).collect();
-        Profile {
+        Ok(Profile {
+            simple_profile: levels_of(&vec),
             records: vec,
-            simple_profile: SimpleProfile {
-                levels: simple,
-                current: 0,
-                adjust_sticky_count: ADJUST_STICKY_MAX,
-            },
+        })
+    }
+}
+
+impl<C: Serialize + Copy> Profile<C> {
+    /// Writes the profile as headerless CSV, the format `open` reads from a
+    /// `.csv` file.
+    pub fn to_csv(&self) -> Result<String> {
+        let mut wtr = csv::WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+        for record in &self.records {
+            wtr.serialize(record)?;
         }
+        let bytes = wtr.into_inner().map_err(|e| e.to_string())?;
+        let csv = String::from_utf8(bytes).map_err(|e| e.to_string())?;
+        Ok(csv)
+    }
+
+    /// Writes the profile as TOML, the format `open` reads from a `.toml`
+    /// file. Unknown accuracies are left out.
+    pub fn to_toml(&self) -> Result<String> {
+        let toml = toml::to_string(&self.to_named())
+            .map_err(|e| format!("failed to write TOML profile: {}", e))?;
+        Ok(toml)
+    }
+
+    /// Writes the profile as JSON, the format `open` reads from a `.json`
+    /// file. Unknown accuracies are left out.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.to_named())?)
+    }
+
+    /// Writes the profile to `path`, in the format of its extension, e.g. to
+    /// turn a CSV profile into TOML to edit it.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let contents = match Format::of(path) {
+            Format::Csv => self.to_csv()?,
+            Format::Toml => self.to_toml()?,
+            Format::Json => self.to_json()?,
+        };
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    fn to_named(&self) -> NamedProfile<C> {
+        let level = self.records
+            .iter()
+            .map(|r| {
+                NamedRecord {
+                    bandwidth: r.bandwidth,
+                    accuracy: if r._accuracy.is_nan() { None } else { Some(r._accuracy) },
+                    config: r.config,
+                }
+            })
+            .collect();
+        NamedProfile { level }
+    }
+}
+
+/// Returns the records of a TOML or JSON profile. A missing accuracy is NaN.
+fn from_named<C>(named: NamedProfile<C>) -> Vec<Record<C>> {
+    named
+        .level
+        .into_iter()
+        .map(|r| {
+            Record {
+                bandwidth: r.bandwidth,
+                config: r.config,
+                _accuracy: r.accuracy.unwrap_or(f64::NAN),
+            }
+        })
+        .collect()
+}
+
+/// Returns the levels of `records`, with their accuracy if every record has
+/// one (`open` rejects profiles where only some do).
+fn levels_of<C>(records: &[Record<C>]) -> SimpleProfile {
+    let mut simple = SimpleProfile::new(records.iter().map(|r| r.bandwidth).collect());
+    if records.iter().all(|r| !r.accuracy().is_nan()) {
+        let accuracies = records.iter().map(|r| r.accuracy()).collect();
+        simple.set_accuracies(accuracies).expect("one accuracy per record");
     }
+    simple
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use awstream_core::profile::ADJUST_STICKY_MAX;
+    use evaluation::testing::ScratchDir;
 
     #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
     struct DummyConfig {
@@ -314,4 +431,82 @@ mod tests {
 
         assert_eq!(profile.adjust_config(2.1).unwrap().config.v, 1);
     }
+
+    #[test]
+    fn test_profile_nearest_level() {
+        let profile = create_profile(4);
+        assert_eq!(profile.nearest_level(1.4), 1);
+        assert_eq!(profile.nearest_level(1.6), 2);
+        // The lower one on a tie, and the ends beyond the range.
+        assert_eq!(profile.nearest_level(2.5), 2);
+        assert_eq!(profile.nearest_level(-1.0), 0);
+        assert_eq!(profile.nearest_level(10.0), 3);
+    }
+
+    #[test]
+    fn test_profile_formats() {
+        use video::VideoConfig;
+
+        let dir = ScratchDir::new("profile-formats");
+        let toml_path = dir.join("profile.toml");
+        fs::write(
+            &toml_path,
+            r#"
+            # Hand-edited.
+            [[level]]
+            bandwidth = 100
+            width = 320
+            skip = 5
+            quant = 30
+            accuracy = 0.5
+
+            [[level]]
+            bandwidth = 400.5
+            width = 640
+            skip = 0
+            quant = 20
+            accuracy = 0.9
+            "#,
+        ).unwrap();
+        let profile: Profile<VideoConfig> = Profile::open(&toml_path).unwrap();
+        assert_eq!(profile.records().len(), 2);
+        assert_eq!(profile.records()[1].bandwidth, 400.5);
+        assert_eq!(profile.n_th(0), VideoConfig { width: 320, skip: 5, quant: 30 });
+        assert_eq!(profile.simplify().accuracy_of(1), Some(0.9));
+
+        // Every format reads back what it writes.
+        for name in &["profile.csv", "profile.json", "again.toml"] {
+            let path = dir.join(name);
+            profile.save(&path).unwrap();
+            let again: Profile<VideoConfig> = Profile::open(&path).unwrap();
+            assert_eq!(again.n_th(1), profile.n_th(1));
+            assert_eq!(again.records()[0].accuracy(), 0.5);
+        }
+
+        // Accuracies left out are unknown, and stay out when written.
+        let json_path = dir.join("partial.json");
+        fs::write(
+            &json_path,
+            r#"{"level": [{"bandwidth": 100, "width": 320, "skip": 0, "quant": 30}]}"#,
+        ).unwrap();
+        let profile: Profile<VideoConfig> = Profile::open(&json_path).unwrap();
+        assert!(profile.records()[0].accuracy().is_nan());
+        assert_eq!(profile.simplify().accuracy_of(0), None);
+        assert!(!profile.to_toml().unwrap().contains("accuracy"));
+
+        // Some accuracies but not all are an error rather than none.
+        fs::write(
+            &json_path,
+            r#"{"level": [{"bandwidth": 100, "width": 320, "skip": 0, "quant": 30},
+                          {"bandwidth": 200, "width": 640, "skip": 0, "quant": 30,
+                           "accuracy": 0.8}]}"#,
+        ).unwrap();
+        assert!(Profile::<VideoConfig>::open(&json_path).is_err());
+
+        // Neither an empty nor a misnamed profile loads.
+        fs::write(&json_path, r#"{"level": []}"#).unwrap();
+        assert!(Profile::<VideoConfig>::open(&json_path).is_err());
+        fs::write(&toml_path, "[[level]]\nbandwidth = 1.0\nwidht = 320\n").unwrap();
+        assert!(Profile::<VideoConfig>::open(&toml_path).is_err());
+    }
 }
@@ -1,59 +1,216 @@
 //! Channel that relays messages.
 
-use super::{AsDatum, AsDatumType};
+use super::{AsDatum, AsDatumType, DropReason};
+use catch_up::CatchUp;
+use chrono;
+use drops::DropCounter;
 use errors::*;
 use futures::{Async, Poll, Stream};
 use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicIsize, Ordering};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+/// `Occupancy` keeps track of what is buffered in the queue: the number of
+/// datums, the bytes they occupy on the wire and when each one entered. It is
+/// shared between both ends of the queue and the monitor.
+#[derive(Clone)]
+pub struct Occupancy {
+    inner: Arc<Mutex<OccupancyInner>>,
+}
+
+#[derive(Debug)]
+struct OccupancyInner {
+    /// Enqueue time and network length of every datum in the queue.
+    entries: VecDeque<(Instant, usize)>,
+
+    /// Sum of the network length of all queued datums.
+    bytes: usize,
+
+    /// Number of queued live datums.
+    live: usize,
+
+    /// Network length of data dropped instead of sent, since the last
+    /// `take_dropped`.
+    dropped: usize,
+
+    /// Bytes data gained as they left the queue (see `ReceiverCtl`), since the
+    /// last `take_grown`.
+    grown: usize,
+}
+
+impl Occupancy {
+    fn new() -> Occupancy {
+        let inner = OccupancyInner {
+            entries: VecDeque::new(),
+            bytes: 0,
+            live: 0,
+            dropped: 0,
+            grown: 0,
+        };
+        Occupancy { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    fn push(&self, datum: &AsDatum) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        let len = datum.net_len();
+        m.entries.push_back((Instant::now(), len));
+        m.bytes += len;
+        match datum.datum_type() {
+            AsDatumType::Live(_, _) | AsDatumType::Coalesced => m.live += 1,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Removes the oldest datum and returns how long it has been queued.
+    fn pop(&self, datum: &AsDatum) -> Result<Option<Duration>> {
+        let mut m = self.inner.lock()?;
+        let age = match m.entries.pop_front() {
+            Some((t, len)) => {
+                m.bytes -= len;
+                Some(t.elapsed())
+            }
+            None => None,
+        };
+        match datum.datum_type() {
+            AsDatumType::Live(_, _) | AsDatumType::Coalesced => m.live -= 1,
+            _ => {}
+        }
+        Ok(age)
+    }
+
+    /// Counts a datum of `len` bytes (on the wire) dropped after it leaves the
+    /// queue.
+    fn discard(&self, len: usize) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        m.dropped += len;
+        Ok(())
+    }
+
+    /// Returns the bytes dropped instead of sent since the last call, which
+    /// were produced but never reach the socket.
+    pub fn take_dropped(&self) -> Result<usize> {
+        let mut m = self.inner.lock()?;
+        Ok(::std::mem::take(&mut m.dropped))
+    }
+
+    /// Counts `len` bytes a datum gained after it was counted as produced.
+    fn grow(&self, len: usize) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        m.grown += len;
+        Ok(())
+    }
+
+    /// Returns the bytes data gained as they left the queue since the last
+    /// call, which the socket sends on top of what was produced.
+    pub fn take_grown(&self) -> Result<usize> {
+        let mut m = self.inner.lock()?;
+        Ok(::std::mem::take(&mut m.grown))
+    }
+
+    /// Returns the number of bytes currently buffered in the queue.
+    pub fn bytes(&self) -> Result<usize> {
+        let m = self.inner.lock()?;
+        Ok(m.bytes)
+    }
+
+    /// Returns the number of live datums currently buffered in the queue.
+    pub fn live(&self) -> Result<usize> {
+        let m = self.inner.lock()?;
+        Ok(m.live)
+    }
+
+    /// Returns how long the oldest datum has been waiting in the queue, or
+    /// `None` if the queue is empty.
+    pub fn oldest_age(&self) -> Result<Option<Duration>> {
+        let m = self.inner.lock()?;
+        Ok(m.entries.front().map(|&(t, _)| t.elapsed()))
+    }
+}
+
+/// The sending end of the queue.
 pub struct SenderCtl {
     inner: UnboundedSender<AsDatum>,
-    counter: Arc<AtomicIsize>,
+    occupancy: Occupancy,
+    drops: DropCounter,
 }
 
 impl SenderCtl {
-    pub fn new(tx: UnboundedSender<AsDatum>, counter: Arc<AtomicIsize>) -> Self {
+    pub fn new(
+        tx: UnboundedSender<AsDatum>,
+        occupancy: Occupancy,
+        drops: DropCounter,
+    ) -> Self {
         SenderCtl {
             inner: tx,
-            counter: counter,
+            occupancy,
+            drops,
         }
     }
+
+    /// Returns a handle to the drop counter of this queue.
+    pub fn drops(&self) -> DropCounter {
+        self.drops.clone()
+    }
 }
 
+/// The receiving end of the queue. Live data whose deadline (see
+/// `AsDatum::deadline`) passes while they are queued are dropped here instead
+/// of sent: they are of no use to the receiver and only add to congestion.
+///
+/// Live data are numbered (see `AsDatum::seq`) as they leave the queue to be
+/// sent, so that data dropped on purpose leave no gap the receiver would take
+/// for loss in the network. Data are also stamped with how long they waited,
+/// which makes them longer than when they were counted as produced; the
+/// difference is counted in the occupancy (see `Occupancy::take_grown`).
 pub struct ReceiverCtl {
     inner: UnboundedReceiver<AsDatum>,
-    counter: Arc<AtomicIsize>,
+    occupancy: Occupancy,
+    drops: DropCounter,
+    catch_up: Option<CatchUp>,
+    next_seq: u64,
 }
 
 impl ReceiverCtl {
-    pub fn new(rx: UnboundedReceiver<AsDatum>, counter: Arc<AtomicIsize>) -> Self {
+    pub fn new(rx: UnboundedReceiver<AsDatum>, occupancy: Occupancy, drops: DropCounter) -> Self {
         ReceiverCtl {
             inner: rx,
-            counter: counter,
+            occupancy,
+            drops,
+            catch_up: None,
+            next_seq: 0,
         }
     }
+
+    /// Remembers the frames dropped past their deadline in `catch_up`.
+    pub fn set_catch_up(&mut self, catch_up: CatchUp) {
+        self.catch_up = Some(catch_up);
+    }
+
+    /// Returns a handle to the occupancy of this queue.
+    pub fn occupancy(&self) -> Occupancy {
+        self.occupancy.clone()
+    }
 }
 
 pub fn queue() -> (SenderCtl, ReceiverCtl) {
     let (tx, rx) = unbounded();
-    let c = Arc::new(AtomicIsize::new(0));
+    let o = Occupancy::new();
+    let drops = DropCounter::new();
     (
-        SenderCtl::new(tx, c.clone()),
-        ReceiverCtl::new(rx, c.clone()),
+        SenderCtl::new(tx, o.clone(), drops.clone()),
+        ReceiverCtl::new(rx, o.clone(), drops),
     )
 }
 
 impl SenderCtl {
     pub fn send(&self, datum: AsDatum) -> Result<()> {
-        let q_len = self.counter.load(Ordering::SeqCst);
-        if q_len > 0 {
-            info!("queue built up");
+        if self.occupancy.live()? > 0 {
+            info!("queue built up: {} bytes", self.occupancy.bytes()?);
         }
 
-        if let AsDatumType::Live(_, _) = datum.datum_type() {
-            self.counter.fetch_add(1, Ordering::SeqCst);
-        }
+        self.occupancy.push(&datum)?;
 
         self.inner.unbounded_send(datum).map_err(|_| {
             Error::from_kind(ErrorKind::DataPlane)
@@ -66,14 +223,94 @@ impl Stream for ReceiverCtl {
     type Error = ();
 
     fn poll(&mut self) -> Poll<Option<AsDatum>, ()> {
-        let item = try_ready!(self.inner.poll());
+        loop {
+            let mut item = try_ready!(self.inner.poll());
 
-        if let Some(ref datum) = item {
-            if let AsDatumType::Live(_, _) = datum.datum_type() {
-                self.counter.fetch_sub(1, Ordering::SeqCst);
+            if let Some(ref mut datum) = item {
+                let age = self.occupancy.pop(datum).map_err(|_| ())?;
+                if datum.live_payload() && datum.is_stale(chrono::Utc::now()) {
+                    trace!("drop {} past its deadline", datum);
+                    self.occupancy.discard(datum.net_len()).map_err(|_| ())?;
+                    for (level, frame_num) in datum.live_frames() {
+                        self.drops.add(DropReason::Deadline, level, 1).map_err(|_| ())?;
+                        if let Some(ref catch_up) = self.catch_up {
+                            catch_up.miss(frame_num).map_err(|_| ())?;
+                        }
+                    }
+                    continue;
+                }
+                let produced = datum.net_len();
+                if let Some(age) = age {
+                    datum.set_queue_delay(age);
+                }
+                if datum.live_payload() {
+                    datum.set_seq(self.next_seq);
+                    self.next_seq += 1;
+                }
+                let grown = datum.net_len().saturating_sub(produced);
+                self.occupancy.grow(grown).map_err(|_| ())?;
             }
+
+            return Ok(Async::Ready(item));
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+
+    #[test]
+    fn occupancy_tracks_bytes() {
+        let (tx, rx) = queue();
+        let occupancy = rx.occupancy();
+        let live = AsDatum::new(0, 1, vec![0; 100]);
+        let probe = AsDatum::bw_probe(50);
+        let expected = live.net_len() + probe.net_len();
+
+        tx.send(live.clone()).unwrap();
+        tx.send(probe).unwrap();
+        assert_eq!(occupancy.bytes().unwrap(), expected);
+        assert_eq!(occupancy.live().unwrap(), 1);
+        assert!(occupancy.oldest_age().unwrap().is_some());
+
+        let mut rx = rx.wait();
+        let received = rx.next().unwrap().unwrap();
+        assert_eq!(received.datum_type(), live.datum_type());
+        assert_eq!(received.seq(), 0);
+        assert!(received.queue_delay_in_ms().is_some());
+        assert_eq!(occupancy.bytes().unwrap(), expected - live.net_len());
+        // The queue delay makes the datum longer than it was produced.
+        assert_eq!(live.net_len() + occupancy.take_grown().unwrap(), received.net_len());
+        assert_eq!(occupancy.live().unwrap(), 0);
+
+        rx.next().unwrap().unwrap();
+        assert_eq!(occupancy.bytes().unwrap(), 0);
+        assert!(occupancy.oldest_age().unwrap().is_none());
+    }
+
+    #[test]
+    fn late_data_are_dropped() {
+        let (tx, rx) = queue();
+        let drops = tx.drops();
+        let mut late = AsDatum::new(2, 1, vec![0; 100]);
+        late.set_ttl(Duration::from_millis(1));
+        let late_len = late.net_len();
+        let mut fresh = AsDatum::new(2, 2, vec![0; 200]);
+        fresh.set_ttl(Duration::from_secs(60));
+        tx.send(late).unwrap();
+        tx.send(fresh.clone()).unwrap();
+        ::std::thread::sleep(Duration::from_millis(5));
 
-        Ok(Async::Ready(item))
+        let occupancy = rx.occupancy();
+        let mut rx = rx.wait();
+        let received = rx.next().unwrap().unwrap();
+        assert_eq!(received.datum_type(), fresh.datum_type());
+        // The late datum leaves no gap.
+        assert_eq!(received.seq(), 0);
+        assert_eq!(occupancy.bytes().unwrap(), 0);
+        assert_eq!(drops.take().unwrap().get(DropReason::Deadline, 2), 1);
+        assert_eq!(occupancy.take_dropped().unwrap(), late_len);
     }
 }
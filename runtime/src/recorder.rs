@@ -0,0 +1,265 @@
+//! The client's recorder: the last few minutes of data at full quality, kept
+//! on disk whatever is sent live, so that a time range can be extracted later
+//! (see the `extract` binary). Data are stored in segments, one file per
+//! `segment` seconds named after when it starts; segments older than the
+//! retention are removed as new ones begin.
+
+use bincode;
+use chrono::{DateTime, Utc};
+use errors::*;
+use futures::Stream;
+use futures::sync::mpsc::{UnboundedSender, unbounded};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Where and how long the client records.
+///
+/// ```toml
+/// [recorder]
+/// dir = "recording"
+/// minutes = 10
+/// segment = 60
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct RecorderConfig {
+    /// Directory of the segments.
+    pub dir: String,
+
+    /// How long (minutes) data are kept. 10 if not set.
+    #[serde(default = "default_minutes")]
+    pub minutes: u64,
+
+    /// How long (s) each segment lasts. 60 if not set.
+    #[serde(default = "default_segment")]
+    pub segment: u64,
+}
+
+fn default_minutes() -> u64 {
+    10
+}
+
+fn default_segment() -> u64 {
+    60
+}
+
+/// A frame as recorded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Recording {
+    /// When the frame is captured.
+    pub ts: DateTime<Utc>,
+
+    /// Frame number.
+    pub frame_num: usize,
+
+    /// Level of the data, the highest one the source knows the frame at.
+    pub level: usize,
+
+    /// Encoded data.
+    pub data: Vec<u8>,
+}
+
+/// Writes recordings into the segment they fall in.
+struct Segments {
+    config: RecorderConfig,
+    current: Option<(i64, BufWriter<File>)>,
+}
+
+impl Segments {
+    fn new(config: RecorderConfig) -> Result<Segments> {
+        fs::create_dir_all(&config.dir)?;
+        Ok(Segments {
+            config,
+            current: None,
+        })
+    }
+
+    fn write(&mut self, recording: &Recording) -> Result<()> {
+        let segment = self.config.segment.max(1) as i64;
+        let start = recording.ts.timestamp() / segment * segment;
+        if self.current.as_ref().map(|&(s, _)| s) != Some(start) {
+            if let Some((_, mut old)) = self.current.take() {
+                old.flush()?;
+            }
+            let path = Path::new(&self.config.dir).join(format!("{}.rec", start));
+            self.current = Some((start, BufWriter::new(File::create(path)?)));
+            self.prune(start)?;
+        }
+        // Every recording is flushed as it's written, so that the segment is
+        // whole up to it even if the client exits before the recorder thread
+        // gets to finish.
+        if let Some((_, ref mut writer)) = self.current {
+            bincode::serialize_into(&mut *writer, recording, bincode::Infinite)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some((_, ref mut writer)) = self.current {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the segments that end before the retention, counted back from
+    /// `now` (s).
+    fn prune(&self, now: i64) -> Result<()> {
+        let oldest = now - (self.config.minutes * 60) as i64;
+        for (start, path) in segments(&self.config.dir)? {
+            if start + (self.config.segment as i64) <= oldest {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns (start, path) of every segment in `dir`.
+fn segments(dir: &str) -> Result<Vec<(i64, PathBuf)>> {
+    let mut all = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "rec") {
+            let start = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok());
+            if let Some(start) = start {
+                all.push((start, path));
+            }
+        }
+    }
+    all.sort();
+    Ok(all)
+}
+
+/// Records everything it receives as `config` says, from a dedicated thread.
+pub fn spawn(config: &RecorderConfig) -> Result<UnboundedSender<Recording>> {
+    let mut segments = Segments::new(config.clone())?;
+    let (tx, rx) = unbounded::<Recording>();
+    thread::spawn(move || {
+        for recording in rx.wait() {
+            let recording = recording.expect("recorder stream never fails");
+            if let Err(e) = segments.write(&recording) {
+                error!("failed to record frame {}: {}", recording.frame_num, e);
+                break;
+            }
+        }
+        if let Err(e) = segments.flush() {
+            error!("failed to flush recording: {}", e);
+        }
+    });
+    Ok(tx)
+}
+
+/// Reads recordings one after another from `path`. A segment that ends in a
+/// truncated recording (e.g. the client was killed while writing it) is read
+/// up to it.
+fn read_recordings(path: &Path) -> Result<Vec<Recording>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    let mut cursor = Cursor::new(&contents);
+    let mut recordings = Vec::new();
+    while (cursor.position() as usize) < contents.len() {
+        let at = cursor.position();
+        match bincode::deserialize_from(&mut cursor, bincode::Infinite) {
+            Ok(recording) => recordings.push(recording),
+            Err(e) => {
+                if let bincode::ErrorKind::IoError(ref io_err) = *e {
+                    if io_err.kind() == io::ErrorKind::UnexpectedEof {
+                        warn!("{:?} ends in a truncated recording at byte {}", path, at);
+                        break;
+                    }
+                }
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(recordings)
+}
+
+/// Returns what is recorded in `dir` from `from` until `to` (inclusive), in
+/// order.
+pub fn extract_recordings(dir: &str,
+                          from: DateTime<Utc>,
+                          to: DateTime<Utc>)
+                          -> Result<Vec<Recording>> {
+    let mut recordings = Vec::new();
+    for (start, path) in segments(dir)? {
+        if start > to.timestamp() {
+            break;
+        }
+        let within = read_recordings(&path)?.into_iter().filter(|r| r.ts >= from && r.ts <= to);
+        recordings.extend(within);
+    }
+    recordings.sort_by_key(|r| r.ts);
+    Ok(recordings)
+}
+
+/// Writes `recordings` into `path`, in the format of a segment.
+pub fn write_recordings(path: &str, recordings: &[Recording]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for recording in recordings {
+        bincode::serialize_into(&mut writer, recording, bincode::Infinite)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use evaluation::testing::ScratchDir;
+
+    #[test]
+    fn old_segments_are_removed() {
+        let dir = ScratchDir::new("recording");
+        let config = RecorderConfig {
+            dir: dir.path().to_str().unwrap().to_string(),
+            minutes: 2,
+            segment: 60,
+        };
+        let mut recorder = Segments::new(config.clone()).unwrap();
+        let at = |s: i64, frame_num: usize| Recording {
+            ts: Utc.timestamp_opt(s, 0).unwrap(),
+            frame_num,
+            level: 4,
+            data: vec![frame_num as u8; 10],
+        };
+        for (i, &s) in [0, 30, 60, 90, 120, 150, 180].iter().enumerate() {
+            recorder.write(&at(s, i)).unwrap();
+        }
+        recorder.flush().unwrap();
+
+        // The segment starting at 0 has ended 2 minutes before 180.
+        let starts = segments(&config.dir).unwrap().into_iter().map(|(s, _)| s).collect::<Vec<_>>();
+        assert_eq!(starts, [60, 120, 180]);
+
+        let from = Utc.timestamp_opt(100, 0).unwrap();
+        let to = Utc.timestamp_opt(150, 0).unwrap();
+        let range = extract_recordings(&config.dir, from, to).unwrap();
+        assert_eq!(range, [at(120, 4), at(150, 5)]);
+
+        let out = dir.join("range.out");
+        write_recordings(out.to_str().unwrap(), &range).unwrap();
+        assert_eq!(read_recordings(&out).unwrap(), range);
+    }
+
+    #[test]
+    fn truncated_tail_is_skipped() {
+        let dir = ScratchDir::new("recording-tail");
+        let recording = Recording {
+            ts: Utc.timestamp_opt(0, 0).unwrap(),
+            frame_num: 1,
+            level: 0,
+            data: vec![1; 100],
+        };
+        let path = dir.join("0.rec");
+        write_recordings(path.to_str().unwrap(), &[recording.clone()]).unwrap();
+
+        let mut tail = bincode::serialize(&recording, bincode::Infinite).unwrap();
+        tail.truncate(50);
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&tail).unwrap();
+        assert_eq!(read_recordings(&path).unwrap(), [recording]);
+    }
+}
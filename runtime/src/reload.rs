@@ -0,0 +1,129 @@
+//! Reloading the profile while the client runs, so that a profile refreshed
+//! offline takes effect without restarting the client and dropping the
+//! session. The client checks the modification time of its profile file every
+//! `Setting::profile_reload` ms, and reloads whenever asked through
+//! `Subscribers::reloads`. A profile that fails to load is skipped, and the
+//! old one stays; the file should be renamed over the old one rather than
+//! written in place, so that it is never read half written.
+
+use errors::*;
+use futures::Stream;
+use futures::stream;
+use futures::sync::mpsc::UnboundedReceiver;
+use profile::Profile;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio_timer;
+use video::VideoConfig;
+
+/// Reloaded profiles.
+pub type Reloads = Box<dyn Stream<Item = Profile<VideoConfig>, Error = Error> + Send>;
+
+/// Watches a profile file for changes.
+pub struct ProfileWatch {
+    path: PathBuf,
+
+    /// When the file was last modified, as of the last check.
+    modified: Option<SystemTime>,
+}
+
+impl ProfileWatch {
+    /// Watches `path`, as it is now.
+    pub fn new<P: AsRef<Path>>(path: P) -> ProfileWatch {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path);
+        ProfileWatch { path, modified }
+    }
+
+    /// Whether the file has been modified since the last check. A file that
+    /// is missing (e.g. while being replaced) is not, but it is once back.
+    pub fn changed(&mut self) -> bool {
+        match modified(&self.path) {
+            None => {
+                self.modified = None;
+                false
+            }
+            Some(time) if Some(time) != self.modified => {
+                self.modified = Some(time);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Loads the profile, or `None` if it fails to load.
+    pub fn load(&self) -> Option<Profile<VideoConfig>> {
+        match Profile::open(&self.path) {
+            Ok(profile) => Some(profile),
+            Err(e) => {
+                warn!("failed to reload profile {:?}, keep the old one: {}", self.path, e);
+                None
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Returns the profiles reloaded from `path`: every `interval` (ms) if it has
+/// changed, if set, and on every trigger.
+pub fn watch<P>(path: P, interval: Option<u64>, triggers: UnboundedReceiver<()>) -> Reloads
+where
+    P: AsRef<Path>,
+{
+    let mut watch = ProfileWatch::new(path);
+    let polls: Box<dyn Stream<Item = bool, Error = Error> + Send> = match interval {
+        Some(ms) => {
+            let timer = tokio_timer::wheel()
+                .tick_duration(Duration::from_millis(50))
+                .build()
+                .interval(Duration::from_millis(ms));
+            Box::new(timer.map(|_| false).map_err(Error::from))
+        }
+        None => Box::new(stream::empty()),
+    };
+    let triggers = triggers
+        .map(|_| true)
+        .map_err(|_| Error::from_kind(ErrorKind::ControlPlane));
+    let reloads = polls.select(triggers).filter_map(move |forced| {
+        let changed = watch.changed();
+        if changed || forced {
+            watch.load()
+        } else {
+            None
+        }
+    });
+    Box::new(reloads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evaluation::testing::ScratchDir;
+
+    #[test]
+    fn watch_follows_the_file() {
+        let dir = ScratchDir::new("profile-watch");
+        let path = dir.join("profile.csv");
+        fs::write(&path, "100,640,0,30,0.9\n").unwrap();
+
+        let mut watch = ProfileWatch::new(&path);
+        assert!(!watch.changed());
+        assert_eq!(watch.load().unwrap().records().len(), 1);
+
+        // Missing while being replaced, then back.
+        fs::remove_file(&path).unwrap();
+        assert!(!watch.changed());
+        fs::write(&path, "100,320,0,30,0.9\n200,640,0,30,0.95\n").unwrap();
+        assert!(watch.changed());
+        assert!(!watch.changed());
+        assert_eq!(watch.load().unwrap().records().len(), 2);
+
+        // A broken profile doesn't load.
+        fs::write(&path, "not a profile\n").unwrap();
+        assert!(watch.load().is_none());
+    }
+}
@@ -0,0 +1,406 @@
+//! The server's heuristic for when to report congestion to the client, and
+//! its offline replay. The server compares the latency of every live datum
+//! with what a simple model expects (`min net latency + size / goodput`,
+//! scaled up to tolerate noise) and reports when it is exceeded. The server
+//! can log every decision with its inputs (`Setting::decision_dir`), and
+//! `replay` evaluates another threshold against such a log.
+//!
+//! Whether a late datum leads to a report depends on the `ReportTrigger`:
+//! requiring several late data among the recent ones ignores isolated late
+//! frames, which would otherwise show up as spurious congestion.
+//!
+//! Loss among the recent data lowers the latency that counts as late, so that
+//! a link that drops data rather than queue them is reported too: at
+//! `REPORT_LOSS`, every datum is late. Only data lost in the network count,
+//! so only over UDP: data the sender drops on purpose leave no gap in the
+//! sequence numbers, and a TCP connection loses nothing else.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::path::Path;
+use csv;
+use errors::*;
+
+/// Minimum interval (ms) between two congestion reports.
+pub const REPORT_INTERVAL: f64 = 500.0;
+
+/// Share of the recent data lost at which every datum counts as late.
+pub const REPORT_LOSS: f64 = 0.05;
+
+/// Data over which the share lost is counted.
+pub const LOSS_WINDOW: usize = 50;
+
+/// How much latency above the ideal (ms) is tolerated before reporting, as a
+/// multiplier of the ideal latency that depends on the ideal latency itself.
+///
+/// ```toml
+/// [report_threshold]
+/// bands = [
+///     { upper = 100, multiplier = 10.0 },
+///     { upper = 200, multiplier = 7.0 },
+///     { upper = 300, multiplier = 4.0 },
+/// ]
+/// otherwise = 5.0
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReportThreshold {
+    /// The first band whose upper bound is not exceeded applies.
+    pub bands: Vec<Band>,
+
+    /// Multiplier beyond the last band.
+    pub otherwise: f64,
+}
+
+/// Multiplier for ideal latencies up to a bound.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    /// Upper bound (ms, inclusive, on the ideal latency truncated to whole
+    /// ms).
+    pub upper: u64,
+
+    /// Multiplier of the ideal latency.
+    pub multiplier: f64,
+}
+
+impl Band {
+    fn new(upper: u64, multiplier: f64) -> Band {
+        Band { upper, multiplier }
+    }
+}
+
+impl Default for ReportThreshold {
+    fn default() -> ReportThreshold {
+        ReportThreshold {
+            bands: vec![Band::new(100, 10.0), Band::new(200, 7.0), Band::new(300, 4.0)],
+            otherwise: 5.0,
+        }
+    }
+}
+
+impl ReportThreshold {
+    /// Returns the latency (ms) above which a datum with `ideal` latency is
+    /// late.
+    pub fn expected(&self, ideal: f64) -> f64 {
+        let whole = ideal as u64;
+        let multiplier = self.bands
+            .iter()
+            .find(|band| whole <= band.upper)
+            .map(|band| band.multiplier)
+            .unwrap_or(self.otherwise);
+        multiplier * ideal
+    }
+
+    /// Decides on a datum whose latency is `observed` given the latency model
+    /// (`net_delay + tx_delay`, ms) and the share of the recent data `loss`.
+    pub fn decide(
+        &self,
+        net_delay: f64,
+        tx_delay: f64,
+        observed: f64,
+        loss: f64,
+    ) -> ReportDecision {
+        let ideal = net_delay + tx_delay;
+        let expected = self.expected(ideal);
+        ReportDecision {
+            ts: 0.0,
+            level: 0,
+            frame_num: 0,
+            net_delay,
+            tx_delay,
+            ideal,
+            expected,
+            observed,
+            loss,
+            high: is_late(observed, expected, loss),
+            reported: false,
+        }
+    }
+}
+
+/// Whether a datum is late: its latency and the loss together score above
+/// 1, each relative to what alone is late.
+fn is_late(observed: f64, expected: f64, loss: f64) -> bool {
+    observed > expected * (1.0 - loss / REPORT_LOSS)
+}
+
+/// How many of the last `LOSS_WINDOW` data were lost on the way.
+#[derive(Debug, Clone, Default)]
+pub struct LossWindow {
+    /// Data lost in total when each of the recent data arrived.
+    lost: VecDeque<u64>,
+}
+
+impl LossWindow {
+    /// Adds a datum that arrives when `lost` data are lost in total, and
+    /// returns the share lost of those since the oldest datum in the window.
+    pub fn push(&mut self, lost: u64) -> f64 {
+        if self.lost.len() > LOSS_WINDOW {
+            self.lost.pop_front();
+        }
+        self.lost.push_back(lost);
+        let received = (self.lost.len() - 1) as u64;
+        let lost = lost.saturating_sub(self.lost[0]);
+        if received + lost == 0 {
+            0.0
+        } else {
+            lost as f64 / (received + lost) as f64
+        }
+    }
+}
+
+/// When late data lead to a report.
+///
+/// ```toml
+/// report_trigger = "single"
+/// report_trigger = { window = { k = 3, n = 5 } }
+/// ```
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportTrigger {
+    /// Every late datum.
+    #[default]
+    Single,
+
+    /// At least `k` of the last `n` data are late.
+    Window {
+        /// Late data required.
+        k: usize,
+
+        /// Data considered.
+        n: usize,
+    },
+}
+
+impl ReportTrigger {
+    /// Returns an empty window for this trigger.
+    pub fn window(&self) -> LateWindow {
+        let (k, n) = match *self {
+            ReportTrigger::Single => (1, 1),
+            ReportTrigger::Window { k, n } => (k.max(1), n.max(k).max(1)),
+        };
+        LateWindow {
+            k,
+            n,
+            recent: VecDeque::with_capacity(n),
+            late: 0,
+        }
+    }
+}
+
+/// Which of the last `n` data are late.
+#[derive(Debug, Clone)]
+pub struct LateWindow {
+    k: usize,
+    n: usize,
+    recent: VecDeque<bool>,
+    late: usize,
+}
+
+impl LateWindow {
+    /// Adds whether the latest datum is late, and returns true if at least
+    /// `k` of the last `n` data are.
+    pub fn push(&mut self, high: bool) -> bool {
+        if self.recent.len() == self.n && self.recent.pop_front() == Some(true) {
+            self.late -= 1;
+        }
+        self.recent.push_back(high);
+        self.late += high as usize;
+        self.late >= self.k
+    }
+}
+
+/// One decision of the report heuristic with its inputs, as logged by the
+/// server (one CSV record per live datum).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReportDecision {
+    /// When the datum was received (seconds since the Unix epoch).
+    pub ts: f64,
+
+    /// Level of the datum.
+    pub level: usize,
+
+    /// Frame number of the datum.
+    pub frame_num: usize,
+
+    /// Minimum network latency seen recently (ms).
+    pub net_delay: f64,
+
+    /// Time to transmit the datum at the current goodput (ms).
+    pub tx_delay: f64,
+
+    /// `net_delay + tx_delay` (ms).
+    pub ideal: f64,
+
+    /// Latency above which the datum counts as late (ms).
+    pub expected: f64,
+
+    /// Latency of the datum (ms).
+    pub observed: f64,
+
+    /// Share of the recent data lost (0 in logs from before it was).
+    #[serde(default)]
+    pub loss: f64,
+
+    /// Whether the datum is late.
+    pub high: bool,
+
+    /// Whether a report was sent, which needs the trigger to fire and is
+    /// rate limited by `REPORT_INTERVAL`.
+    pub reported: bool,
+}
+
+/// Reads decisions logged by the server.
+pub fn read_decisions<P: AsRef<Path>>(path: P) -> Result<Vec<ReportDecision>> {
+    let file = File::open(path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut decisions = Vec::new();
+    for record in reader.deserialize() {
+        decisions.push(record?);
+    }
+    Ok(decisions)
+}
+
+/// How often a threshold finds data late and reports.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplaySummary {
+    /// Decisions taken.
+    pub decisions: usize,
+
+    /// Data found late.
+    pub high: usize,
+
+    /// Reports sent.
+    pub reports: usize,
+}
+
+/// Replays `decisions` with `threshold` and `trigger`, including the rate
+/// limit on reports. Returns the summary of the decisions as recorded, as
+/// replayed, and how many data are late under one but not the other.
+pub fn replay(
+    decisions: &[ReportDecision],
+    threshold: &ReportThreshold,
+    trigger: ReportTrigger,
+) -> (ReplaySummary, ReplaySummary, usize) {
+    let mut recorded = ReplaySummary::default();
+    let mut replayed = ReplaySummary::default();
+    let mut flipped = 0;
+    let mut last_report = None;
+    let mut window = trigger.window();
+    for d in decisions {
+        recorded.decisions += 1;
+        recorded.high += d.high as usize;
+        recorded.reports += d.reported as usize;
+
+        let high = is_late(d.observed, threshold.expected(d.ideal), d.loss);
+        replayed.decisions += 1;
+        replayed.high += high as usize;
+        if window.push(high) {
+            let due = match last_report {
+                Some(last) => (d.ts - last) * 1000.0 > REPORT_INTERVAL,
+                None => true,
+            };
+            if due {
+                last_report = Some(d.ts);
+                replayed.reports += 1;
+            }
+        }
+        if high != d.high {
+            flipped += 1;
+        }
+    }
+    (recorded, replayed, flipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    #[test]
+    fn default_threshold_bands() {
+        let t = ReportThreshold::default();
+        assert_eq!(t.expected(50.0), 500.0);
+        assert_eq!(t.expected(100.5), 1005.0);
+        assert_eq!(t.expected(150.0), 1050.0);
+        assert_eq!(t.expected(250.0), 1000.0);
+        assert_eq!(t.expected(400.0), 2000.0);
+    }
+
+    #[test]
+    fn threshold_from_toml() {
+        let t: ReportThreshold = toml::from_str(
+            r#"
+            bands = [
+                { upper = 100, multiplier = 10.0 },
+                { upper = 200, multiplier = 7.0 },
+                { upper = 300, multiplier = 4.0 },
+            ]
+            otherwise = 5.0
+            "#,
+        ).unwrap();
+        assert_eq!(t, ReportThreshold::default());
+    }
+
+    #[test]
+    fn replay_reproduces_recorded_decisions() {
+        let t = ReportThreshold::default();
+        let mut decisions = Vec::new();
+        let mut last_report = f64::NEG_INFINITY;
+        for i in 0..20 {
+            let mut d = t.decide(20.0, 10.0, if i % 3 == 0 { 400.0 } else { 50.0 }, 0.0);
+            d.ts = 1000.0 + i as f64 * 0.1;
+            if d.high && (d.ts - last_report) * 1000.0 > REPORT_INTERVAL {
+                last_report = d.ts;
+                d.reported = true;
+            }
+            decisions.push(d);
+        }
+
+        let (recorded, replayed, flipped) = replay(&decisions, &t, ReportTrigger::Single);
+        assert_eq!(recorded, replayed);
+        assert_eq!(recorded.high, 7);
+        assert_eq!(recorded.reports, 4);
+        assert_eq!(flipped, 0);
+
+        let strict = ReportThreshold {
+            bands: Vec::new(),
+            otherwise: 1.5,
+        };
+        let (_, replayed, flipped) = replay(&decisions, &strict, ReportTrigger::Single);
+        assert_eq!(replayed.high, 20);
+        assert_eq!(flipped, 13);
+
+        // Isolated late frames (one in three) never make 2 of 3.
+        let window = ReportTrigger::Window { k: 2, n: 3 };
+        let (_, replayed, _) = replay(&decisions, &t, window);
+        assert_eq!(replayed.high, 7);
+        assert_eq!(replayed.reports, 0);
+    }
+
+    #[test]
+    fn loss_makes_data_late() {
+        let t = ReportThreshold::default();
+        assert!(!t.decide(20.0, 10.0, 200.0, 0.0).high);
+        assert!(t.decide(20.0, 10.0, 200.0, 0.04).high);
+        assert!(t.decide(20.0, 10.0, 10.0, REPORT_LOSS).high);
+
+        // One in ten lost, then none.
+        let mut w = LossWindow::default();
+        assert_eq!(w.push(0), 0.0);
+        let shares = (1..=LOSS_WINDOW as u64 * 2)
+            .map(|i| w.push(::std::cmp::min(i / 9, 5)))
+            .collect::<Vec<_>>();
+        assert!((shares[44] - 0.1).abs() < 1e-9);
+        assert_eq!(shares[LOSS_WINDOW * 2 - 1], 0.0);
+    }
+
+    #[test]
+    fn window_counts_recent_late_data() {
+        let mut w = ReportTrigger::Window { k: 2, n: 3 }.window();
+        let fired = [true, false, true, false, false, true, true]
+            .iter()
+            .map(|&high| w.push(high))
+            .collect::<Vec<_>>();
+        assert_eq!(fired, [false, false, true, false, false, false, true]);
+    }
+}
@@ -0,0 +1,62 @@
+//! The reverse channel: data the server sends back to the client, e.g.
+//! detections or annotated thumbnails. It reuses the machinery of the client's
+//! data: a `TimerSource` over its own profile, a `Monitor` on the server's
+//! socket that learns the delivery rate from the client's acks, and the same
+//! adaptation.
+
+use super::adaptation::Adaptation;
+use super::client::core_adapt;
+use super::controller::{Delivery, Monitor};
+use super::detector;
+use super::errors::*;
+use super::queue::ReceiverCtl;
+use super::setting::{DetectorKind, ReverseSetting};
+use super::source::{SourceStat, TimerSource};
+use super::video::VideoSource;
+use super::Adapt;
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use tokio_core::reactor::Handle;
+
+/// Spawns the reverse channel of a connection on `handle`. `sent` counts the
+/// bytes the server's socket writes and `delivery` the client's acks. Returns
+/// the data to send, and the count of bytes produced, where the server adds
+/// whatever else it sends on the socket. The channel adapts until `stop`
+/// fires.
+pub fn spawn(
+    setting: &ReverseSetting,
+    detector: DetectorKind,
+    sent: Arc<AtomicUsize>,
+    delivery: Delivery,
+    stop: oneshot::Receiver<()>,
+    handle: &Handle,
+) -> (ReceiverCtl, SourceStat) {
+    let mut source = VideoSource::new(setting.source_path.clone(), setting.profile_path.clone());
+    source.load_stats(&setting.stat_path);
+    let mut profile = source.simple_profile();
+    let ((src_tx, src_rx), data, stat) =
+        TimerSource::spawn(source, None, None, None, None, None, handle.clone());
+
+    let monitor = Monitor::new(
+        stat.clone(),
+        sent,
+        delivery,
+        data.occupancy(),
+        detector::build(detector),
+    ).skip(1);
+    let probing = src_rx.map_err(|_| Error::from_kind(ErrorKind::ControlPlane));
+    let mut adaptation = Adaptation::default();
+    let work = monitor
+        .select(probing)
+        .for_each(move |signal| {
+            core_adapt(signal, &mut adaptation, &mut profile, 0.0, src_tx.clone());
+            Ok(())
+        })
+        .map_err(|e| error!("reverse channel failed: {}", e));
+    let stop = stop.then(|_| Ok(()));
+    handle.spawn(work.select(stop).then(|_| Ok(())));
+
+    (data, stat)
+}
@@ -1,23 +1,97 @@
 //! The main entrance for server functionality.
 
-use super::{AsCodec, AsDatum, AsDatumType, ReceiverReport};
+use super::{AsCodec, AsDatum, AsDatumType, CodecStats, ControlMessage, Detections, DropReason,
+            DropReport, LatencyBreakdown, PRIMARY_STREAM, ReceiverReport};
 use super::analytics::VideoAnalytics;
-use super::bw_monitor::{BwMonitor, LatencyMonitor};
-use super::setting::Setting;
-use super::utils::StreamingStat;
+use super::bond::{Bonded, Bonds, Reassemble};
+use super::bw_monitor::{BreakdownMonitor, BwMonitor, ComputeMonitor, DatumLatency, SequenceMonitor,
+                        StreamMonitor};
+use super::conn_log::{ConnEvent, ConnLog};
+use super::controller::{Acknowledger, Delivery};
+use super::decode_worker;
+use super::drops::DropCounter;
+use super::jitter::{JitterBuffer, JitterBufferConfig};
+use super::owd::OwdTrend;
+use super::report::{LateWindow, LossWindow, REPORT_INTERVAL, ReportDecision, ReportThreshold};
+use super::reverse;
+use super::setting::{Setting, Transport};
+use super::shed::Shedder;
+use super::tls::{self, Conn, WriteHalf};
+use super::socket::{BufferStats, Socket, merge_until_done, skip_corrupt};
+use super::udp::Datagrams;
+use super::utils::{StreamingStat, spawn_csv_log};
+use super::websocket;
 use chrono;
 use chrono::{DateTime, TimeZone, Utc};
 use errors::*;
-use futures::{Future, Sink, Stream};
+use evaluation::{FRAME_FILE, RUN_FILE, RunFrame, RunSecond, Stat};
+use futures::{Future, Sink, Stream, future, stream};
+use futures::sync::mpsc::{UnboundedSender, unbounded};
+use futures::sync::oneshot;
 use interval;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
 use std::io;
 use std::net::SocketAddr;
-use std::time::Duration;
-use tokio_core::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError, sync_channel};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpListener;
 use tokio_core::reactor::{Core, Handle};
-use tokio_io::AsyncRead;
 use tokio_timer;
 
+/// Warns about profile drift if the achieved accuracy differs this much from
+/// what the client expects.
+const ACCURACY_DRIFT_THRESHOLD: f64 = 0.1;
+
+/// Reports compute congestion if this many data wait for the analytics.
+const COMPUTE_QUEUE_THRESHOLD: usize = 15;
+
+/// Minimum interval (ms) between two compute congestion reports.
+const COMPUTE_REPORT_INTERVAL: f64 = 1000.0;
+
+/// Interval (ms) between two reports of the accuracy achieved per level.
+const ACCURACY_REPORT_INTERVAL: f64 = 5000.0;
+
+/// Interval (in seconds) between two latency snapshots in the event log.
+const LATENCY_SNAPSHOT_INTERVAL: usize = 10;
+
+/// Panic message if the statistics of a connection can't be updated.
+const ERRMSG: &str = "fail to update statistics";
+
+/// Data a connection's analytics holds before later frames are shed, so that
+/// analytics falling behind never stalls the connection.
+const ANALYTICS_MAILBOX: usize = 64;
+
+/// A datum handed to the analytics: level, frame number, the accuracy the
+/// client expects, size, when it was received, its latency (ms), when it was
+/// captured and how long (ms) decoding it took.
+type AnalyticsWork = (usize, usize, Option<Stat>, usize, Instant, f64, DateTime<Utc>, f64);
+
+/// Writes run results to `file` in `dir`, if any. Failing to create the log
+/// only loses the results, not the connection.
+fn result_log<T: Serialize + Send + 'static>(
+    dir: Option<&String>,
+    file: &str,
+) -> Option<UnboundedSender<T>> {
+    dir.and_then(|dir| {
+        let path = format!("{}/{}", dir, file);
+        match fs::create_dir_all(dir).map_err(Error::from).and_then(|_| spawn_csv_log(&path)) {
+            Ok(tx) => Some(tx),
+            Err(e) => {
+                error!("failed to create result log {}: {}", path, e);
+                None
+            }
+        }
+    })
+}
+
+fn duration_in_ms(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0
+}
+
 fn time_diff_in_ms<Tz: TimeZone>(a: DateTime<Tz>, b: DateTime<Tz>) -> f64 {
     (a.timestamp() as f64 - b.timestamp() as f64) * 1000.0 +
         (a.timestamp_subsec_millis() as f64 - b.timestamp_subsec_millis() as f64)
@@ -26,111 +100,886 @@ fn time_diff_in_ms<Tz: TimeZone>(a: DateTime<Tz>, b: DateTime<Tz>) -> f64 {
 /// Run the server. The server listens for new connections, parses input, and
 /// prints performance statistics (latency, accuracy, etc).
 ///
+/// Every experiment in the setting (see `Setting::experiments`) listens on its
+/// own port and uses its own profile, stats and analytics, so that several
+/// experiments can share one server process.
+///
 /// The function will block until the server is shutdown.
 pub fn server(setting: Setting) {
+    server_with_subscribers(setting, Vec::new())
+}
+
+/// Run the server as `server` does, and publish what the analytics detects in
+/// every frame, with the client it comes from, to `subscribers`.
+pub fn server_with_subscribers(
+    setting: Setting,
+    subscribers: Vec<UnboundedSender<(SocketAddr, Detections)>>,
+) {
+    server_with_hooks(setting, subscribers, Vec::new())
+}
+
+/// A live frame of a client's primary stream, as the server receives it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveFrame {
+    /// Frame number of the frame.
+    pub frame_num: usize,
+
+    /// Level the frame was sent at.
+    pub level: usize,
+
+    /// The frame itself, e.g. encoded video.
+    pub data: Vec<u8>,
+}
+
+/// Run the server as `server_with_subscribers` does; in addition, hand every
+/// live frame, with the client it comes from, to `frames`, for applications
+/// that process the actual data (e.g. decode it and run a detector) rather
+/// than the analytics the experiment emulates.
+pub fn server_with_hooks(
+    setting: Setting,
+    subscribers: Vec<UnboundedSender<(SocketAddr, Detections)>>,
+    frames: Vec<UnboundedSender<(SocketAddr, LiveFrame)>>,
+) {
     let mut core = Core::new().unwrap();
     let handle = core.handle();
-    let addr = ([0, 0, 0, 0], setting.port).into();
-    let listener = TcpListener::bind(&addr, &handle).unwrap();
 
-    // Accept all incoming sockets
-    let server = listener.incoming().for_each(move |(socket, addr)| {
-        let analytics = VideoAnalytics::new(&setting.profile_path, &setting.stat_path);
-        handle_conn(socket, addr, analytics, &handle)
-    });
+    let experiments = setting
+        .experiments()
+        .into_iter()
+        .map(|experiment| {
+            let addr = ([0, 0, 0, 0], experiment.port).into();
+            let listener = TcpListener::bind(&addr, &handle).unwrap();
+            info!("experiment {} listens on {}", experiment.name, addr);
+            let datagrams = match experiment.transport {
+                Transport::Tcp => None,
+                Transport::Udp => {
+                    info!("experiment {} receives live data over udp", experiment.name);
+                    Some(Datagrams::bind(&addr, &handle).unwrap())
+                }
+            };
+            let acceptor = match experiment.tls {
+                Some(ref tls_setting) => Some(tls::acceptor(tls_setting).unwrap()),
+                None => {
+                    warn!("experiment {} accepts plaintext connections", experiment.name);
+                    None
+                }
+            };
+
+            if experiment.websocket {
+                info!("experiment {} takes senders over websocket", experiment.name);
+            }
+            let over_websocket = experiment.websocket;
+
+            // Accept all incoming sockets
+            let handle = handle.clone();
+            let shared = Shared {
+                experiment,
+                subscribers: subscribers.clone(),
+                frames: frames.clone(),
+                datagrams,
+                bonds: Bonds::new(),
+                handle: handle.clone(),
+            };
+            listener.incoming().for_each(move |(socket, addr)| {
+                let conn: Box<dyn Future<Item = Conn, Error = io::Error>> = match acceptor {
+                    Some(ref acceptor) => tls::accept(acceptor, socket),
+                    None => Box::new(future::ok(Conn::Plain(socket))),
+                };
+                let conn: Box<dyn Future<Item = Conn, Error = io::Error>> = if over_websocket {
+                    Box::new(conn.and_then(websocket::accept))
+                } else {
+                    conn
+                };
+
+                // The handshake and the first datum are waited for on their
+                // own, not to hold up the listener.
+                let shared = shared.clone();
+                let work = conn.map_err(Error::from)
+                    .and_then(move |conn| identify(conn, addr, shared));
+                let work = work.map_err(move |e| warn!("connection from {} failed: {}", addr, e));
+                handle.spawn(work);
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Open listeners
+    core.run(future::join_all(experiments)).unwrap();
+}
+
+/// What the connections of an experiment share.
+#[derive(Clone)]
+struct Shared {
+    experiment: Setting,
+    subscribers: Vec<UnboundedSender<(SocketAddr, Detections)>>,
+    frames: Vec<UnboundedSender<(SocketAddr, LiveFrame)>>,
+    datagrams: Option<Datagrams>,
+    bonds: Bonds,
+    handle: Handle,
+}
+
+/// What a connection receives.
+type ConnData = Box<dyn Stream<Item = AsDatum, Error = Error>>;
+
+/// A connection whose first datum has been read (see `identify`).
+struct Incoming {
+    data: ConnData,
+    write: WriteHalf,
+
+    /// Wire stats of both directions.
+    wire: CodecStats,
 
-    // Open listener
-    core.run(server).unwrap();
+    /// What the other connections of its bond receive, if it is the primary
+    /// connection of one.
+    bonded: Option<Bonded>,
 }
 
-/// The main server logic that handles a particular socket.
+/// Reads the first datum of a connection, which tells the connections of a
+/// bond (see `bond`) apart. A bond's other connections only hand what they
+/// receive to its primary connection, handled as any other.
+fn identify(conn: Conn, addr: SocketAddr, shared: Shared) -> impl Future<Item = (), Error = Error> {
+    let (tcp_read, tcp_write) = conn.split();
+    let wire = CodecStats::default();
+    let codec = AsCodec::with_stats(wire.clone());
+    let data = skip_corrupt(decode_worker::spawn(tcp_read, codec, &shared.handle));
+    data.into_future().map_err(|(e, _)| e).and_then(move |(first, rest)| {
+        let join = match first {
+            Some(ref datum) if datum.datum_type() == AsDatumType::Join => Some(datum.bond()?),
+            _ => None,
+        };
+        let (data, bonded) = match join {
+            Some((bond, false)) => {
+                info!("connection from {} joins bond {}", addr, bond);
+                let primary = shared.bonds
+                    .join(bond)?
+                    .sink_map_err(|_| Error::from_kind(ErrorKind::DataPlane));
+                shared.handle.spawn(rest.forward(primary).then(move |result| {
+                    if let Err(e) = result {
+                        warn!("connection from {} leaves bond {}: {}", addr, bond, e);
+                    }
+                    Ok(())
+                }));
+                return Ok(());
+            }
+            Some((bond, true)) => {
+                info!(
+                    "connection from {} leads bond {} ({} bonds lost so far)",
+                    addr,
+                    bond,
+                    shared.bonds.lost()
+                );
+                (Box::new(rest) as ConnData, Some(shared.bonds.primary(bond)?))
+            }
+            None => (Box::new(stream::iter_ok(first).chain(rest)) as ConnData, None),
+        };
+        let experiment = &shared.experiment;
+        let analytics = VideoAnalytics::new(
+            &experiment.profile_path,
+            &experiment.stat_path,
+            experiment.accuracy_model.as_ref(),
+            experiment.manifest_path.as_ref(),
+            experiment.wraparound,
+        );
+        let incoming = Incoming {
+            data,
+            write: tcp_write,
+            wire,
+            bonded,
+        };
+        handle_conn(
+            incoming,
+            addr,
+            analytics,
+            experiment,
+            &shared.subscribers,
+            &shared.frames,
+            shared.datagrams.as_ref(),
+            &shared.handle,
+        )?;
+        Ok(())
+    })
+}
+
+/// The main server logic that handles a particular connection.
 fn handle_conn(
-    socket: TcpStream,
+    incoming: Incoming,
     addr: SocketAddr,
     analytics: VideoAnalytics,
+    experiment: &Setting,
+    subscribers: &[UnboundedSender<(SocketAddr, Detections)>],
+    frames: &[UnboundedSender<(SocketAddr, LiveFrame)>],
+    datagrams: Option<&Datagrams>,
     handle: &Handle,
 ) -> io::Result<()> {
-    info!("new connection from {}", addr);
+    info!("new connection from {} to experiment {}", addr, experiment.name);
+    let summary_dir = experiment.summary_dir.clone();
+    let name = experiment.name.clone();
 
-    let transport = socket.framed(AsCodec::default());
-    let (transport_write, transport_read) = transport.split();
+    // Identifies the connection in logs.
+    let client = format!("{} ({})", addr, experiment.name);
+    let client_clone = client.clone();
+
+    let log = ConnLog::new(
+        experiment.event_dir.as_deref(),
+        &experiment.name,
+        addr,
+    );
+    log.log(ConnEvent::Connect {
+        experiment: experiment.name.clone(),
+        client: addr.to_string(),
+    });
+
+    // Both directions count into the same wire stats. What the server sends
+    // (reports, acks and the reverse channel, if any) goes through a channel
+    // to the write half.
+    let Incoming {
+        data: transport_read,
+        write: tcp_write,
+        wire,
+        bonded,
+    } = incoming;
+    // Over UDP, live data arrive apart from the connection, which still decides
+    // when the client is gone.
+    let datagrams = datagrams.cloned();
+    let transport_read: ConnData = match datagrams {
+        Some(ref datagrams) => {
+            let live = datagrams.register(addr).map_err(|_| Error::from_kind(ErrorKind::DataPlane));
+            Box::new(merge_until_done(transport_read, live))
+        }
+        None => transport_read,
+    };
+    // So do a bond's other connections' shares of the data, which go back in
+    // sequence with the connection's own.
+    let transport_read: ConnData = match bonded {
+        Some(bonded) => {
+            let shares = bonded.map_err(|_| Error::from_kind(ErrorKind::DataPlane));
+            Box::new(Reassemble::new(merge_until_done(transport_read, shares)))
+        }
+        None => transport_read,
+    };
+    let mut encoder = AsCodec::with_stats(wire.clone());
+    encoder.set_format(experiment.wire_format);
+    let (mut transport, sent) = Socket::with_codec(tcp_write, encoder);
+    let (capacity, backpressure) = experiment.buffer.unwrap_or_default().sizes();
+    transport.set_buffers(capacity, backpressure);
+    let buffered = transport.buffer_stats();
+    let (control_tx, control_rx) = unbounded::<AsDatum>();
+    let transport_write = control_tx
+        .clone()
+        .sink_map_err(|_| Error::from_kind(ErrorKind::DataPlane));
+    if let Some(level) = experiment.pin_level {
+        info!("client {}\tpinned at level {}", client, level);
+        let pin = ControlMessage::PinLevel(Some(level));
+        let sent = AsDatum::control(&pin)
+            .map_err(|e| e.to_string())
+            .and_then(|d| control_tx.unbounded_send(d).map_err(|e| e.to_string()));
+        if let Err(e) = sent {
+            warn!("failed to pin {}: {}", client, e);
+        }
+    }
+
+    let delivery = Delivery::new();
+    let (reverse_stopper, reverse_stop) = oneshot::channel();
+    let outgoing: Box<dyn Stream<Item = AsDatum, Error = ()>> = match experiment.reverse {
+        Some(ref reverse) => {
+            info!("reverse channel to {} from {}", client, reverse.source_path);
+            let (data, produced) = reverse::spawn(
+                reverse,
+                experiment.detector,
+                sent,
+                delivery.clone(),
+                reverse_stop,
+                handle,
+            );
+            // The monitor of the reverse channel sees all that goes into the
+            // socket.
+            let control = control_rx.inspect(move |datum| {
+                produced.data.fetch_add(datum.net_len(), Ordering::SeqCst);
+            });
+            Box::new(merge_until_done(control, data))
+        }
+        None => Box::new(control_rx),
+    };
+    let outgoing = outgoing.map_err(|_| Error::from_kind(ErrorKind::DataPlane));
+    handle.spawn(transport.send_all(outgoing).then(|result| {
+        if let Err(e) = result {
+            warn!("failed to send to client: {}", e);
+        }
+        Ok(())
+    }));
 
     let mut goodput = BwMonitor::new();
     let mut throughput = BwMonitor::new();
-    let mut latency_mon = LatencyMonitor::new();
-    let mut reporter = Reporter::new(
+    let mut latency = DatumLatency::new();
+    let mut breakdown = BreakdownMonitor::new();
+    let mut compute = ComputeMonitor::new();
+    let mut streams = StreamMonitor::new();
+    let streams_clone = streams.clone();
+    let mut historical = BwMonitor::new();
+    let historical_clone = historical.clone();
+    let sequence = SequenceMonitor::new();
+    let detections = DetectionSink {
+        addr,
+        client: if experiment.send_detections {
+            Some(control_tx)
+        } else {
+            None
+        },
+        subscribers: subscribers.to_vec(),
+    };
+    let result_dir = experiment
+        .result_dir
+        .as_ref()
+        .map(|dir| format!("{}/{}-{}-{}", dir, experiment.name, addr.ip(), addr.port()));
+    let results = result_log(result_dir.as_ref(), RUN_FILE);
+    let frame_results = result_log(result_dir.as_ref(), FRAME_FILE);
+    if let Some(ref jitter) = experiment.jitter_buffer {
+        info!("client {}\tjitter buffer of {} ms", client, jitter.delay);
+    }
+    let (analytics_tx, analytics_thread) = spawn_analytics(
+        analytics.clone(),
+        compute.clone(),
+        experiment.analytics_cost,
+        experiment.jitter_buffer,
+        detections,
+        frame_results,
+    );
+    let drops = DropCounter::new();
+    let drops_clone = drops.clone();
+    let shed = experiment.shed.map(|config| {
+        info!(
+            "client {}\tshed above {} data behind, analyze 1 in {} frames",
+            client,
+            config.threshold,
+            config.every
+        );
+        Shedder::new(config)
+    });
+    let decisions = experiment.decision_dir.as_ref().and_then(|dir| {
+        let path = format!(
+            "{}/decisions-{}-{}-{}.csv",
+            dir,
+            experiment.name,
+            addr.ip(),
+            addr.port()
+        );
+        match spawn_csv_log(&path) {
+            Ok(tx) => Some(tx),
+            Err(e) => {
+                error!("failed to create decision log {}: {}", path, e);
+                None
+            }
+        }
+    });
+    let reporter = Reporter::new(
         transport_write,
         goodput.clone(),
         throughput.clone(),
-        latency_mon.clone(),
+        latency.clone(),
+        breakdown.clone(),
+        compute.clone(),
+        sequence.clone(),
+        analytics_tx,
         analytics.clone(),
+        log.clone(),
+        experiment.report_threshold.clone(),
+        experiment.report_trigger.window(),
+        decisions,
+        experiment.owd_gradient.map(OwdTrend::new),
+        shed.clone(),
+        datagrams.as_ref().map(|_| LossWindow::default()),
     );
+    let summary = analytics.clone();
+    let thumbnail_path = experiment.thumbnail_dir.as_ref().map(|dir| {
+        format!("{}/thumbnail-{}-{}-{}.jpg", dir, experiment.name, addr.ip(), addr.port())
+    });
+    let disconnect_log = log.clone();
+
+    // Frames received per level, to log when their distribution over the
+    // last second changes.
+    let mut last_counts = BTreeMap::new();
+    let mut last_frames = BTreeMap::new();
+    let mut seconds = 0;
 
     let timer = tokio_timer::Timer::default();
     let (ticks, tick_stopper) = interval::new(timer, Duration::from_millis(1000));
 
-    let errmsg = "fail to update statistics";
-
     let estimate_throughput = ticks.for_each(move |_| {
         // in each tick, measure bandwidth
-        goodput.update(1000).expect(&errmsg);
-        throughput.update(1000).expect(&errmsg);;
-        latency_mon.update().expect(&errmsg);;
+        goodput.update(1000).expect(ERRMSG);
+        throughput.update(1000).expect(ERRMSG);
+        latency.update().expect(ERRMSG);
+        breakdown.update().expect(ERRMSG);
+        compute.update(1000).expect(ERRMSG);
+        streams.update(1000).expect(ERRMSG);
+        historical.update(1000).expect(ERRMSG);
+        let counts = analytics.level_counts().unwrap();
+        let frames = counts
+            .iter()
+            .map(|(&level, &n)| (level, n - last_counts.get(&level).cloned().unwrap_or(0)))
+            .filter(|&(_, n)| n > 0)
+            .collect::<BTreeMap<usize, usize>>();
+        if frames != last_frames {
+            log.log(ConnEvent::Levels { frames: frames.clone() });
+            last_frames = frames;
+        }
+        last_counts = counts;
+
+        seconds += 1;
+        if seconds % LATENCY_SNAPSHOT_INTERVAL == 0 {
+            for (datum_type, snapshot) in latency.snapshots().expect(ERRMSG) {
+                log.log(ConnEvent::Latency {
+                    datum_type: datum_type.to_string(),
+                    latency: snapshot,
+                });
+            }
+        }
+
+        let per_stream = streams.report().expect(ERRMSG);
+        if per_stream.keys().any(|&stream| stream != PRIMARY_STREAM) {
+            for (stream, s) in &per_stream {
+                info!(
+                    "client {}\tstream {}\tlevel {}\tframes {}\tgoodput {} kbps\tlatency {:.3} ms",
+                    client,
+                    stream,
+                    s.level,
+                    s.frames,
+                    s.goodput,
+                    s.latency
+                );
+            }
+            log.log(ConnEvent::Streams { streams: per_stream });
+        }
+
+        let accuracy = analytics.accuracy().unwrap();
+        let expected = analytics.expected_accuracy().unwrap();
+
+        // Accuracy only covers the frames analyzed.
+        let shed_ratio = match shed {
+            Some(ref shed) => shed.take_ratio().expect(ERRMSG),
+            None => 0.0,
+        };
+        if shed_ratio > 0.0 {
+            info!(
+                "client {}\tshed {:.1}% of frames, accuracy of the rest",
+                client,
+                shed_ratio * 100.0
+            );
+        }
         info!(
-            "client {}\tgoodput {} kbps\tthroughput {} kbps\tlatency {:.3} ms\taccuracy {:.4}",
-            addr,
+            "client {}\tgoodput {} kbps\tthroughput {} kbps\t\
+             latency {:.3} ms (p99 {:.3}, max {:.3}, {})\tanalytics {}\tprocessing {:.3} ms\t\
+             accuracy {:.4}\texpected {:.4}\tdrops {}\t{}\thistorical {} kbps",
+            client,
             goodput.rate().unwrap(),
             throughput.rate().unwrap(),
-            latency_mon.rate().unwrap(),
-            analytics.accuracy().unwrap()
+            latency.live.rate().unwrap(),
+            latency.live.percentile(99.0).unwrap(),
+            latency.live.max().unwrap(),
+            breakdown.rate().unwrap(),
+            compute.report().unwrap(),
+            compute.processing().unwrap(),
+            accuracy,
+            expected,
+            drops.take().unwrap(),
+            sequence.total().unwrap(),
+            historical.rate().unwrap()
         );
+        if let Some(ref results) = results {
+            let second = RunSecond {
+                second: seconds - 1,
+                accuracy,
+                latency: latency.live.rate().unwrap(),
+                bytes: (throughput.rate().unwrap() * 1000.0 / 8.0) as usize,
+                shed: shed_ratio,
+                processing: compute.processing().unwrap(),
+            };
+            if results.unbounded_send(second).is_err() {
+                error!("result log has stopped");
+            }
+        }
+        if (accuracy - expected).abs() > ACCURACY_DRIFT_THRESHOLD {
+            warn!(
+                "client {}\tprofile drift: expected accuracy {:.4}, achieved {:.4}",
+                client,
+                expected,
+                accuracy
+            );
+        }
         Ok(())
     });
 
     // Spawn a new task dedicated to measure bandwidth
     handle.spawn(estimate_throughput.map_err(|_| ()));
 
-    let process_connection = transport_read
-        .for_each(move |as_datum| {
-            let size = as_datum.len() as usize;
-            reporter.throughput.add(size).expect(&errmsg);;
-            match as_datum.datum_type() {
-                AsDatumType::Live(level, frame_num) => {
-                    let size = as_datum.len() as usize;
-                    reporter.goodput.add(size).expect(&errmsg);
-                    reporter.report(level, frame_num, as_datum)?
-                }
-                AsDatumType::Dummy => {}
-                AsDatumType::LatencyProbe => {
-                    let now = chrono::Utc::now();
-                    let latency = time_diff_in_ms(now, as_datum.ts);
-                    reporter.update_net_latency(latency);
-                }
-                _ => {}
-            }
-            Ok(())
-        })
-        .map_err(|_| ());
+    let mut dispatcher = Dispatcher {
+        addr,
+        reporter,
+        streams: streams_clone,
+        historical: historical_clone,
+        drops: drops_clone,
+        delivery,
+        thumbnail_path,
+        frames: frames.to_vec(),
+        first_datum: true,
+    };
+    let process_connection = transport_read.for_each(move |as_datum| dispatcher.dispatch(as_datum));
 
     // Spawn a new task dedicated to processing the connection
-    handle.spawn(process_connection.and_then(|_| {
+    handle.spawn(process_connection.then(move |result| {
+        let reason = match result {
+            Ok(()) => "closed by client".to_string(),
+            Err(e) => e.to_string(),
+        };
+        info!("client {}\tdisconnected: {}", client_clone, reason);
+        log_wire_stats(&client_clone, &wire, &buffered, &disconnect_log);
+        disconnect_log.log(ConnEvent::Disconnect { reason });
         tick_stopper.send(()).expect("failed to send");
+        let _ = reverse_stopper.send(());
+        if let Some(datagrams) = datagrams {
+            datagrams.unregister(&addr);
+        }
+        // The connection's end drops the sender of the analytics, which
+        // then drains its queue; the summary waits for it, off the event loop.
+        thread::spawn(move || {
+            if analytics_thread.join().is_err() {
+                error!("client {}\tanalytics panicked", client_clone);
+            }
+            if let Some(dir) = summary_dir {
+                write_level_summary(&summary, &dir, &name, addr, &client_clone);
+            }
+        });
         Ok(())
     }));
     Ok(())
 }
 
+/// Accounts for every datum a connection receives, and hands live frames to
+/// the analytics (through its `Reporter`) and to the server's subscribers.
+struct Dispatcher<T: Sink<SinkItem = AsDatum, SinkError = Error>> {
+    addr: SocketAddr,
+    reporter: Reporter<T>,
+    streams: StreamMonitor,
+    historical: BwMonitor,
+    drops: DropCounter,
+    delivery: Delivery,
+
+    /// Where the latest thumbnail is written, if anywhere.
+    thumbnail_path: Option<String>,
+
+    /// Receivers of every live frame of the primary stream.
+    frames: Vec<UnboundedSender<(SocketAddr, LiveFrame)>>,
+
+    /// No live datum has arrived yet.
+    first_datum: bool,
+}
+
+impl<T: Sink<SinkItem = AsDatum, SinkError = Error>> Dispatcher<T> {
+    /// Accounts for `as_datum` as it arrives.
+    fn dispatch(&mut self, as_datum: AsDatum) -> Result<()> {
+        let received = Instant::now();
+        let size = as_datum.len() as usize;
+        self.reporter.throughput.add(size).expect(ERRMSG);
+        self.reporter.acknowledge(as_datum.sent_len())?;
+        match as_datum.datum_type() {
+            AsDatumType::Live(_, _) |
+            AsDatumType::Coalesced => {
+                self.reporter.sequence.add(as_datum.stream_id(), as_datum.seq())?;
+                // Coalesced frames are reported one by one, as if they had
+                // arrived separately. Only the primary stream goes to the
+                // analytics and drives congestion reports; the others are
+                // only accounted for.
+                let data = match as_datum.datum_type() {
+                    AsDatumType::Coalesced => as_datum.uncoalesce()?,
+                    _ => vec![as_datum],
+                };
+                for datum in data {
+                    if let AsDatumType::Live(level, frame_num) = datum.datum_type() {
+                        let now = chrono::Utc::now();
+                        let latency = time_diff_in_ms(now, datum.ts);
+                        // Stale data are of no use, so they are not goodput.
+                        if !datum.is_stale(now) {
+                            self.reporter.goodput.add(datum.len()).expect(ERRMSG);
+                        }
+                        self.streams.add(datum.stream_id(), level, datum.len(), latency)?;
+                        if datum.stream_id() != PRIMARY_STREAM {
+                            continue;
+                        }
+                        if self.first_datum {
+                            self.first_datum = false;
+                            self.reporter.log.log(ConnEvent::FirstDatum { level, frame_num });
+                        }
+                        if !self.frames.is_empty() {
+                            let frame = LiveFrame {
+                                frame_num,
+                                level,
+                                data: datum.mem.to_vec(),
+                            };
+                            let addr = self.addr;
+                            self.frames.retain(|f| f.unbounded_send((addr, frame.clone())).is_ok());
+                        }
+                        let dropped = self.reporter.report(level, frame_num, datum, received)?;
+                        if let Some(reason) = dropped {
+                            self.drops.add(reason, level, 1)?;
+                        }
+                    }
+                }
+            }
+            AsDatumType::Dummy => {
+                let latency = time_diff_in_ms(chrono::Utc::now(), as_datum.ts);
+                self.reporter.latency.dummy.add(latency)?;
+            }
+            AsDatumType::LatencyProbe => {
+                let now = chrono::Utc::now();
+                let latency = time_diff_in_ms(now, as_datum.ts);
+                self.reporter.latency.probe.add(latency)?;
+                self.reporter.probe(now, latency)?;
+            }
+            AsDatumType::SenderDrops => {
+                let report = DropReport::from_mem(&as_datum.mem)?;
+                self.drops.merge(&report)?;
+            }
+            AsDatumType::DeliveryAck => self.delivery.ack(as_datum.delivered()?),
+            // Frames the client missed live are only accounted for; they
+            // are late by design and say nothing about congestion.
+            AsDatumType::Historical(level, frame_num) => {
+                trace!("historical datum, level: {}, frame: {}", level, frame_num);
+                self.historical.add(size)?;
+            }
+            AsDatumType::Thumbnail => {
+                trace!("thumbnail of {} bytes", as_datum.mem.len());
+                if let Some(ref path) = self.thumbnail_path {
+                    if let Err(e) = fs::write(path, &as_datum.mem) {
+                        warn!("failed to write thumbnail {}: {}", path, e);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Logs what a client's connection has sent and received, and how full its
+/// send buffer has been.
+fn log_wire_stats(client: &str, wire: &CodecStats, buffered: &BufferStats, log: &ConnLog) {
+    let stats = wire.snapshot();
+    for (name, s) in &stats {
+        info!(
+            "client {}\twire {}: received {} ({} bytes, {} errors), sent {} ({} bytes)",
+            client,
+            name,
+            s.decoded,
+            s.decoded_bytes,
+            s.decode_errors,
+            s.encoded,
+            s.encoded_bytes
+        );
+    }
+    log.log(ConnEvent::Wire { stats });
+    let stats = buffered.snapshot();
+    info!(
+        "client {}\tsend buffer: high watermark {} bytes (backpressure at {}), \
+         {} sends rejected",
+        client,
+        stats.high_watermark,
+        stats.backpressure,
+        stats.rejected
+    );
+    log.log(ConnEvent::Buffer { stats });
+}
+
+/// Writes the level summary of a client's connection to `dir`, named after
+/// the experiment and the client like its event log and thumbnails.
+fn write_level_summary(
+    summary: &VideoAnalytics,
+    dir: &str,
+    experiment: &str,
+    addr: SocketAddr,
+    client: &str,
+) {
+    let path = format!("{}/levels-{}-{}-{}.csv", dir, experiment, addr.ip(), addr.port());
+    match summary.write_summary(&path) {
+        Ok(_) => info!("client {}\tlevel summary written to {}", client, path),
+        Err(e) => error!("client {}\tfailed to write level summary: {}", client, e),
+    }
+}
+
+/// Runs the analytics in its own thread, so that slow analytics builds up a
+/// queue (tracked by `compute`, at most `ANALYTICS_MAILBOX` deep) instead of
+/// stalling the connection. If `cost`
+/// is set, each datum additionally takes that long (ms) to process, which
+/// emulates a receiver whose compute is the bottleneck. If `jitter` is set,
+/// data pass a jitter buffer first (see the `jitter` module). What the
+/// analytics detects goes to `detections`, and what each frame achieves to
+/// `frames`. The thread quits once the sender is gone and every datum queued
+/// until then is processed.
+fn spawn_analytics(
+    mut analytics: VideoAnalytics,
+    mut compute: ComputeMonitor,
+    cost: Option<f64>,
+    jitter: Option<JitterBufferConfig>,
+    mut detections: DetectionSink,
+    mut frames: Option<UnboundedSender<RunFrame>>,
+) -> (SyncSender<AnalyticsWork>, JoinHandle<()>) {
+    let (tx, rx) = sync_channel::<AnalyticsWork>(ANALYTICS_MAILBOX);
+    let thread = thread::spawn(move || {
+        let mut jitter = jitter.map(|config| JitterBuffer::new(&config));
+        while let Some((work, held)) = next_work(&rx, &mut jitter, &mut compute) {
+            let (level, frame_num, expected, size, received, latency, _, decode) = work;
+            let started = Instant::now();
+            if let Some(cost) = cost {
+                thread::sleep(Duration::from_micros((cost * 1000.0) as u64));
+            }
+            let result = analytics
+                .add(frame_num, level, size)
+                .and_then(|result| {
+                    // Frames past the end of the dataset may be left out.
+                    let result = match result {
+                        Some(result) => result,
+                        None => return Ok(()),
+                    };
+                    if let Some(tx) = frames.take() {
+                        let frame = RunFrame {
+                            frame_num,
+                            level,
+                            latency,
+                            accuracy: result.accuracy,
+                        };
+                        if tx.unbounded_send(frame).is_ok() {
+                            frames = Some(tx);
+                        }
+                    }
+                    // Only detection workloads have detections to publish.
+                    match result.stat {
+                        Some(stat) => detections.publish(Detections {
+                            frame_num,
+                            level,
+                            objects: stat.true_positive + stat.false_positive,
+                            stat,
+                        }),
+                        None => Ok(()),
+                    }
+                })
+                .and_then(|_| match expected {
+                    Some(stat) => analytics.add_expected(stat),
+                    None => Ok(()),
+                })
+                .and_then(|_| {
+                    // Time held in the jitter buffer is not compute; time
+                    // waiting for the analytics is, but not processing.
+                    let waited = duration_in_ms(received.elapsed()) - duration_in_ms(held);
+                    compute.done(size, waited, decode + duration_in_ms(started.elapsed()))
+                });
+            if let Err(e) = result {
+                error!("analytics failed: {}", e);
+                break;
+            }
+        }
+        if let Some(late) = jitter.map(|j| j.late()).filter(|&late| late > 0) {
+            info!("{} data arrived too late for the jitter buffer", late);
+        }
+    });
+    (tx, thread)
+}
+
+/// Returns the next datum for the analytics, and how long the jitter buffer
+/// held it, or `None` once the connection is gone and nothing is held.
+fn next_work(
+    rx: &Receiver<AnalyticsWork>,
+    jitter: &mut Option<JitterBuffer<AnalyticsWork>>,
+    compute: &mut ComputeMonitor,
+) -> Option<(AnalyticsWork, Duration)> {
+    let buffer = match *jitter {
+        Some(ref mut buffer) => buffer,
+        None => return rx.recv().ok().map(|work| (work, Duration::from_secs(0))),
+    };
+    loop {
+        let now = Instant::now();
+        if let Some(released) = buffer.pop(now) {
+            compute.enqueue().expect("failed to count released datum");
+            return Some(released);
+        }
+        let work = match buffer.next_release() {
+            Some(release) => match rx.recv_timeout(release - now) {
+                Ok(work) => work,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    let released = buffer.flush(now);
+                    if released.is_some() {
+                        compute.enqueue().expect("failed to count released datum");
+                    }
+                    return released;
+                }
+            },
+            None => rx.recv().ok()?,
+        };
+        compute.hold().expect("failed to count held datum");
+        let captured = work.6;
+        buffer.push(work, captured, Instant::now());
+    }
+}
+
+/// Where what the analytics detects on a connection goes: back to the client
+/// (see `Setting::send_detections`) and to the server's subscribers.
+struct DetectionSink {
+    addr: SocketAddr,
+    client: Option<UnboundedSender<AsDatum>>,
+    subscribers: Vec<UnboundedSender<(SocketAddr, Detections)>>,
+}
+
+impl DetectionSink {
+    fn publish(&mut self, detections: Detections) -> Result<()> {
+        if let Some(client) = self.client.take() {
+            if client.unbounded_send(AsDatum::detections(&detections)?).is_ok() {
+                self.client = Some(client);
+            }
+        }
+        let addr = self.addr;
+        self.subscribers.retain(|s| s.unbounded_send((addr, detections)).is_ok());
+        Ok(())
+    }
+}
+
 struct Reporter<T: Sink<SinkItem = AsDatum, SinkError = Error>> {
     last_report_time: DateTime<Utc>,
+    last_compute_report: DateTime<Utc>,
+    last_accuracy_report: DateTime<Utc>,
+    acks: Acknowledger,
     net_latency: StreamingStat,
     app_latency: StreamingStat,
     reporter: T,
 
     goodput: BwMonitor,
     throughput: BwMonitor,
-    latency: LatencyMonitor,
+    latency: DatumLatency,
+    breakdown: BreakdownMonitor,
+    compute: ComputeMonitor,
+    sequence: SequenceMonitor,
 
-    analytics: VideoAnalytics,
+    analytics: SyncSender<AnalyticsWork>,
+    accuracy: VideoAnalytics,
+    log: ConnLog,
+
+    /// When latency is high enough to report.
+    threshold: ReportThreshold,
+
+    /// Which of the recent data were late, to decide when to report.
+    window: LateWindow,
+
+    /// How many of the recent data were lost in the network, which makes
+    /// data late sooner; `None` if the transport loses nothing (TCP).
+    loss: Option<LossWindow>,
+
+    /// Where every report decision is logged, if anywhere.
+    decisions: Option<UnboundedSender<ReportDecision>>,
+
+    /// The trend of the one-way delay of latency probes, if it decides when
+    /// to report too.
+    owd: Option<OwdTrend>,
+
+    /// Which frames the analytics skips while it is behind, if it may.
+    shed: Option<Shedder>,
 }
 
 impl<T: Sink<SinkItem = AsDatum, SinkError = Error>> Reporter<T> {
@@ -138,18 +987,43 @@ impl<T: Sink<SinkItem = AsDatum, SinkError = Error>> Reporter<T> {
         reporter: T,
         goodput: BwMonitor,
         throughput: BwMonitor,
-        latency: LatencyMonitor,
-        analytics: VideoAnalytics,
+        latency: DatumLatency,
+        breakdown: BreakdownMonitor,
+        compute: ComputeMonitor,
+        sequence: SequenceMonitor,
+        analytics: SyncSender<AnalyticsWork>,
+        accuracy: VideoAnalytics,
+        log: ConnLog,
+        threshold: ReportThreshold,
+        window: LateWindow,
+        decisions: Option<UnboundedSender<ReportDecision>>,
+        owd: Option<OwdTrend>,
+        shed: Option<Shedder>,
+        loss: Option<LossWindow>,
     ) -> Self {
         Reporter {
             last_report_time: chrono::Utc::now(),
+            last_compute_report: chrono::Utc::now(),
+            last_accuracy_report: chrono::Utc::now(),
+            acks: Acknowledger::new(),
             net_latency: StreamingStat::new(::std::f64::INFINITY, 10),
             app_latency: StreamingStat::new(::std::f64::INFINITY, 10),
             reporter: reporter,
             goodput: goodput,
             throughput: throughput,
             latency: latency,
+            breakdown,
+            compute,
+            sequence,
             analytics: analytics,
+            accuracy,
+            log,
+            threshold,
+            window,
+            loss,
+            decisions,
+            owd,
+            shed,
         }
     }
 
@@ -161,59 +1035,185 @@ impl<T: Sink<SinkItem = AsDatum, SinkError = Error>> Reporter<T> {
         self.net_latency.add(latency);
     }
 
+    /// probe is called whenever a latency probe arrives at `now` after
+    /// `latency` ms. Reports congestion if the one-way delay of probes grows
+    /// too fast (see `owd`), even if no datum is late yet.
+    pub fn probe(&mut self, now: DateTime<Utc>, latency: f64) -> Result<()> {
+        self.update_net_latency(latency);
+        let arrival = now.timestamp() as f64 + f64::from(now.timestamp_subsec_micros()) / 1e6;
+        let slope = match self.owd {
+            Some(ref mut trend) => trend.add(arrival, latency),
+            None => None,
+        };
+        if let Some(slope) = slope {
+            if time_diff_in_ms(now, self.last_report_time) > REPORT_INTERVAL {
+                self.last_report_time = now;
+                debug!("one-way delay grows {:.1} ms/s", slope);
+                let breakdown = LatencyBreakdown {
+                    network: latency,
+                    ..LatencyBreakdown::default()
+                };
+                let report = ReceiverReport::new(
+                    latency,
+                    self.goodput.rate().unwrap(),
+                    self.throughput.rate().unwrap(),
+                    breakdown,
+                    self.sequence.total()?,
+                    self.compute.processing()?,
+                );
+                let datum = AsDatum::ack(report.clone())?;
+                self.log.log(ConnEvent::Report { report });
+                self.reporter.start_send(datum)?;
+                self.reporter.poll_complete()?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn update_latency(&mut self, latency: f64) {
-        self.latency.add(latency).expect(
+        self.latency.live.add(latency).expect(
             &"failed to update latency",
         );
     }
 
-    /// report is called whenever we receive a new datum
-    pub fn report(&mut self, level: usize, frame_num: usize, datum: AsDatum) -> Result<()> {
+    /// Counts `size` bytes as received and acknowledges them to the client
+    /// when an ack is due (see `Acknowledger`).
+    pub fn acknowledge(&mut self, size: usize) -> Result<()> {
+        if let Some(datum) = self.acks.add(size)? {
+            self.reporter.start_send(datum)?;
+            self.reporter.poll_complete()?;
+        }
+        Ok(())
+    }
+
+    /// report is called whenever we receive a new datum; `received` marks
+    /// when it was read off the connection. Returns why the datum is
+    /// discarded instead of handed to the analytics, if it is: it is stale,
+    /// shed while the analytics is busy, or the analytics' mailbox is full.
+    pub fn report(
+        &mut self,
+        level: usize,
+        frame_num: usize,
+        datum: AsDatum,
+        received: Instant,
+    ) -> Result<Option<DropReason>> {
         let ts = datum.ts;
         let now = chrono::Utc::now();
+        let decode = duration_in_ms(received.elapsed());
         let latency = time_diff_in_ms(now, ts);
         self.update_latency(latency);
         self.update_app_latency(latency);
-        self.analytics.add(frame_num, level)?;
+        let dropped = if datum.is_stale(now) {
+            trace!("level: {}, frame: {} is stale after {:.1} ms", level, frame_num, latency);
+            Some(DropReason::Stale)
+        } else if !self.admit()? {
+            trace!("level: {}, frame: {} is shed, analytics is busy", level, frame_num);
+            Some(DropReason::Shed)
+        } else {
+            let expected = datum.expected();
+            let work = (level, frame_num, expected, datum.len(), received, latency, ts, decode);
+            self.compute.enqueue()?;
+            match self.analytics.try_send(work) {
+                Ok(()) => None,
+                Err(TrySendError::Full(_)) => {
+                    self.compute.cancel()?;
+                    trace!("level: {}, frame: {} is shed, analytics is behind", level, frame_num);
+                    Some(DropReason::AnalyticsFull)
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    self.compute.cancel()?;
+                    return Err(Error::from_kind(ErrorKind::DataPlane));
+                }
+            }
+        };
+
+        let queue = datum.queue_delay_in_ms().unwrap_or(0.0);
+        let breakdown = LatencyBreakdown {
+            queue,
+            network: (latency - queue).max(0.0),
+            processing: decode,
+        };
+        self.breakdown.add(breakdown)?;
         trace!(
-            "level: {}, latency: {:.1}, size: {}",
+            "level: {}, latency: {:.1} ({}), size: {}",
             level,
             latency,
+            breakdown,
             datum.len()
         );
 
-        if self.latency_is_high(latency, &datum) {
+        let loss = match self.loss {
+            Some(ref mut loss) => loss.push(self.sequence.total()?.lost),
+            None => 0.0,
+        };
+        let mut decision = self.decide(latency, loss, &datum);
+        if self.window.push(decision.high) {
             let time_since_last_report = time_diff_in_ms(now, self.last_report_time);
-            if time_since_last_report > 500.0 {
+            if time_since_last_report > REPORT_INTERVAL {
                 self.last_report_time = now;
+                decision.reported = true;
                 let report = ReceiverReport::new(
                     latency,
                     self.goodput.rate().unwrap(),
                     self.throughput.rate().unwrap(),
+                    breakdown,
+                    self.sequence.total()?,
+                    self.compute.processing()?,
                 );
                 trace!("report {:?}", report);
-                let datum = AsDatum::ack(report)?;
+                let datum = AsDatum::ack(report.clone())?;
+                self.log.log(ConnEvent::Report { report });
                 self.reporter.start_send(datum)?;
                 self.reporter.poll_complete()?;
             }
         }
-        Ok(())
+        if let Some(ref log) = self.decisions {
+            decision.ts = now.timestamp() as f64 + f64::from(now.timestamp_subsec_micros()) / 1e6;
+            decision.level = level;
+            decision.frame_num = frame_num;
+            if log.unbounded_send(decision).is_err() {
+                error!("decision log has stopped");
+            }
+        }
+
+        if self.compute.depth()? > COMPUTE_QUEUE_THRESHOLD &&
+            time_diff_in_ms(now, self.last_compute_report) > COMPUTE_REPORT_INTERVAL
+        {
+            self.last_compute_report = now;
+            let report = self.compute.report()?;
+            debug!("compute congest {}", report);
+            self.log.log(ConnEvent::ComputeReport { report });
+            let datum = AsDatum::compute_report(&report)?;
+            self.reporter.start_send(datum)?;
+            self.reporter.poll_complete()?;
+        }
+
+        if time_diff_in_ms(now, self.last_accuracy_report) > ACCURACY_REPORT_INTERVAL {
+            self.last_accuracy_report = now;
+            let report = self.accuracy.level_accuracy()?;
+            if !report.levels.is_empty() {
+                debug!("accuracy feedback {:?}", report.levels);
+                let datum = AsDatum::accuracy_report(&report)?;
+                self.reporter.start_send(datum)?;
+                self.reporter.poll_complete()?;
+            }
+        }
+        Ok(dropped)
+    }
+
+    /// Whether the analytics takes the next frame, given its backlog.
+    fn admit(&mut self) -> Result<bool> {
+        match self.shed {
+            Some(ref mut shed) => shed.admit(self.compute.depth()?),
+            None => Ok(true),
+        }
     }
 
     #[inline]
-    fn latency_is_high(&self, current_latency: f64, datum: &AsDatum) -> bool {
+    fn decide(&self, current_latency: f64, loss: f64, datum: &AsDatum) -> ReportDecision {
         // Build a latency model: expected = min_net + size / rate + noise
         let net_delay = self.net_latency.min();
         let tx_delay = datum.len() as f64 / self.goodput.rate().unwrap();
-        let ideal = net_delay + tx_delay;
-
-        let expected = match ideal as u64 {
-            0...100 => 10.0 * ideal,
-            100...200 => 7.0 * ideal,
-            200...300 => 4.0 * ideal,
-            _ => 5.0 * ideal,
-        };
-
-        current_latency > expected
+        self.threshold.decide(net_delay, tx_delay, current_latency, loss)
     }
 }
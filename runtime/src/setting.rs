@@ -1,13 +1,36 @@
 //! A flexible client/server runtime setting in TOML.
 
+use accuracy_model::AccuracyModelConfig;
+use awstream_core::aimd::{AIMD_DECREASE, AIMD_INCREASE};
+use awstream_core::pid::PidGains;
+use bbr::BbrConfig;
+use budget::BudgetWindow;
+use {Compression, PRIMARY_STREAM, WireFormat};
+use catch_up::CatchUpConfig;
+use coalesce::CoalesceConfig;
+use contention::LoadConfig;
+use evaluation::{MotionConfig, Wraparound};
+use jitter::JitterBufferConfig;
+use recorder::RecorderConfig;
+use owd::OwdGradientConfig;
+use profile::Selection;
+use report::{ReportThreshold, ReportTrigger};
+use shed::ShedConfig;
+use socket::BufferConfig;
 use std::fs::File;
-use std::io::Read;
-use std::io::Result;
+use std::io::{Read, Write};
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
 use toml;
 
 /// The runtime setting.
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Setting {
+    /// Name of the experiment, used to tell experiments apart in the server's
+    /// logs. `default` if not set.
+    #[serde(default = "default_name")]
+    pub name: String,
+
     /// Server's IP address.
     pub server: String,
 
@@ -17,11 +40,664 @@ pub struct Setting {
     /// Path to the profile.
     pub profile_path: String,
 
+    /// If set, the client checks every this many ms whether the profile file
+    /// has changed, and reloads it without dropping the session.
+    #[serde(default)]
+    pub profile_reload: Option<u64>,
+
+    /// Path to source (video). It is either a CSV file, a packed source (see
+    /// the `pack` binary), which is mapped instead of loaded, or a directory
+    /// of per-configuration shards.
+    pub source_path: String,
+
+    /// Path to stat (per frame stat), `stat.csv` or its index (see the `index`
+    /// binary of the evaluation), which loads faster.
+    pub stat_path: String,
+
+    /// Path to the manifest of the dataset (see the `manifest` binary of the
+    /// video crate). The server takes the dataset's length from it, or from
+    /// the stats if not set.
+    #[serde(default)]
+    pub manifest_path: Option<String>,
+
+    /// What becomes of frames past the end of the dataset: they `wrap` around
+    /// to its first frame (the default), or `truncate` leaves them out of the
+    /// accuracy.
+    #[serde(default)]
+    pub wraparound: Wraparound,
+
+    /// How many times the client goes through the source before it shuts
+    /// down. Loops forever if not set.
+    #[serde(default)]
+    pub repeat: Option<usize>,
+
+    /// Frame rate of the source (before skipping). 30 if not set.
+    #[serde(default)]
+    pub fps: Option<f64>,
+
+    /// If set, data are only useful this long (ms) after they're created; the
+    /// client drops data that pass this deadline before they are sent, and the
+    /// server discards data that arrive later.
+    #[serde(default)]
+    pub ttl: Option<u64>,
+
+    /// If set, the server writes a per-level summary into this directory when
+    /// a connection closes.
+    #[serde(default)]
+    pub summary_dir: Option<String>,
+
+    /// If set, the server writes what every connection achieves per second
+    /// and per frame into a directory of its own in this directory, which the
+    /// `compare` and `cdf` binaries of the evaluation read.
+    #[serde(default)]
+    pub result_dir: Option<String>,
+
+    /// If set, the server's analytics takes this long (ms) per datum. The
+    /// analytics itself is a lookup; use this to emulate a server whose compute
+    /// is the bottleneck.
+    #[serde(default)]
+    pub analytics_cost: Option<f64>,
+
+    /// If set, the server holds live data in a jitter buffer and hands them to
+    /// the analytics at the cadence they were captured at (see
+    /// `JitterBufferConfig`).
+    #[serde(default)]
+    pub jitter_buffer: Option<JitterBufferConfig>,
+
+    /// If set, the server's analytics only takes every few frames while it
+    /// is behind (see `ShedConfig`).
+    #[serde(default)]
+    pub shed: Option<ShedConfig>,
+
+    /// If set, the server's analytics scores every frame with this accuracy
+    /// model instead of looking up its per-frame stats, which the server then
+    /// doesn't load (see `AccuracyModelConfig`).
+    #[serde(default)]
+    pub accuracy_model: Option<AccuracyModelConfig>,
+
+    /// Which level the client starts at. The lowest level if not set.
+    #[serde(default)]
+    pub startup: StartupPolicy,
+
+    /// How the client adapts. AWStream's profile-based adaptation if not set.
+    #[serde(default)]
+    pub policy: AdaptationPolicy,
+
+    /// How the client probes for bandwidth. Dummy bytes if not set.
+    #[serde(default)]
+    pub probing: Probing,
+
+    /// If set, the rate (kbps) the network guarantees: the client never adapts
+    /// to a level below what fits within it, however congested it appears.
+    #[serde(default)]
+    pub floor_rate: Option<f64>,
+
+    /// If set, the budget (kbps) the client never sends above, whatever
+    /// probing suggests: it drops the levels above it and paces what goes
+    /// into the socket at this rate.
+    #[serde(default)]
+    pub max_rate: Option<f64>,
+
+    /// Windows of the day (local time) in which the client uses fewer
+    /// levels, e.g. to conserve bandwidth during business hours.
+    #[serde(default)]
+    pub budget_schedule: Vec<BudgetWindow>,
+
+    /// If set, the client stays at this level and does not adapt; a server
+    /// pins every client that connects to it. For controlled experiments.
+    #[serde(default)]
+    pub pin_level: Option<usize>,
+
+    /// If set, the client holds every level at least this long (ms) before
+    /// it advances or probes again; it still degrades at once.
+    #[serde(default)]
+    pub min_dwell: Option<u64>,
+
+    /// If set, the client recalibrates the accuracy of its profile with the
+    /// accuracy the server reports per level, weighting each report by this
+    /// (between 0 and 1), and picks levels by accuracy.
+    #[serde(default)]
+    pub recalibration: Option<f64>,
+
+    /// How the client picks the level for a bandwidth: the highest that fits
+    /// ("index", the default) or the most accurate that fits ("accuracy"),
+    /// which differ if the profile isn't Pareto-sorted.
+    #[serde(default)]
+    pub level_selection: Selection,
+
+    /// How the client detects congestion. The queue-latency heuristic if not
+    /// set.
+    #[serde(default)]
+    pub detector: DetectorKind,
+
+    /// If set, the client sends small frames together (see
+    /// `CoalesceConfig`).
+    #[serde(default)]
+    pub coalesce: Option<CoalesceConfig>,
+
+    /// If set, the client uploads frames it missed live once the link has
+    /// capacity to spare (see `CatchUpConfig`).
+    #[serde(default)]
+    pub catch_up: Option<CatchUpConfig>,
+
+    /// If set, the client records the last few minutes of data at full
+    /// quality on disk, whatever it sends (see `RecorderConfig`).
+    #[serde(default)]
+    pub recorder: Option<RecorderConfig>,
+
+    /// How the client compresses live payloads. The client announces it to
+    /// the server when it connects. Not compressed if not set.
+    #[serde(default)]
+    pub compression: Compression,
+
+    /// How headers go on the wire. The stable wire schema if not set; peers
+    /// read either.
+    #[serde(default)]
+    pub wire_format: WireFormat,
+
+    /// How data go over the network. Everything over TCP if not set.
+    #[serde(default)]
+    pub transport: Transport,
+
+    /// If set, the connection is encrypted with TLS (`[tls]` section).
+    /// Plaintext if not set, which is only meant for local testing.
+    #[serde(default)]
+    pub tls: Option<TlsSetting>,
+
+    /// If set, the server takes senders over WebSocket (inside TLS, if set)
+    /// instead, e.g. browsers running `awstream_core`. Not with udp.
+    #[serde(default)]
+    pub websocket: bool,
+
+    /// If set, the client sends over several connections at once and stripes
+    /// its data across them (`[bonding]` section).
+    #[serde(default)]
+    pub bonding: Option<BondingSetting>,
+
+    /// Sizes of the send buffers. 32 KiB each if not set (see
+    /// `BufferConfig`).
+    #[serde(default)]
+    pub buffer: Option<BufferConfig>,
+
+    /// If set, `client load` runs several clients at once, sharing an
+    /// emulated bottleneck (see `LoadConfig`).
+    #[serde(default)]
+    pub load: Option<LoadConfig>,
+
+    /// If set, the client skips frames of static scenes (see
+    /// `MotionSetting`).
+    #[serde(default)]
+    pub motion: Option<MotionSetting>,
+
+    /// If set, the server writes an event log (as JSON lines) for every
+    /// connection into this directory.
+    #[serde(default)]
+    pub event_dir: Option<String>,
+
+    /// If set, the client writes every adaptation decision (as JSON lines)
+    /// into this file.
+    #[serde(default)]
+    pub event_log: Option<String>,
+
+    /// If set, the client writes every adaptation decision with the level it
+    /// leads to into this CSV file (`timestamp, state, signal, action, level,
+    /// rate`).
+    #[serde(default)]
+    pub decision_log: Option<String>,
+
+    /// If set, the client writes the level every frame is sent at into this
+    /// CSV file (`timestamp, frame_num, level`), which the `runtime` binary of
+    /// the evaluation reads.
+    #[serde(default)]
+    pub level_log: Option<String>,
+
+    /// If set, the client writes the bandwidth spent on probes every second
+    /// into this CSV file (`timestamp, kbps, share`).
+    #[serde(default)]
+    pub probe_log: Option<String>,
+
+    /// If set, the server logs every decision whether to report congestion,
+    /// with its inputs, into this directory (see the `replay` binary).
+    #[serde(default)]
+    pub decision_dir: Option<String>,
+
+    /// When the server finds latency high enough to report congestion.
+    #[serde(default)]
+    pub report_threshold: ReportThreshold,
+
+    /// How many late data it takes to report congestion; a single one if not
+    /// set.
+    #[serde(default)]
+    pub report_trigger: ReportTrigger,
+
+    /// If set, the server also reports congestion when the one-way delay of
+    /// latency probes grows too fast (see `OwdGradientConfig`).
+    #[serde(default)]
+    pub owd_gradient: Option<OwdGradientConfig>,
+
+    /// Additional experiments the server hosts at the same time, each with
+    /// its own port (`[[experiment]]` sections).
+    #[serde(default, rename = "experiment")]
+    pub experiments: Vec<ExperimentSetting>,
+
+    /// Secondary streams the client sends on the same connection as the
+    /// adapted one (`[[stream]]` sections).
+    #[serde(default, rename = "stream")]
+    pub streams: Vec<StreamSetting>,
+
+    /// If set, the server sends what its analytics detects in every frame
+    /// back to the client (see `Subscribers::detections`).
+    #[serde(default)]
+    pub send_detections: bool,
+
+    /// If set, the server sends data back to the client on every connection,
+    /// adapted to the network in that direction (`[reverse]` section).
+    #[serde(default)]
+    pub reverse: Option<ReverseSetting>,
+
+    /// If set, the client sends thumbnails next to its data at a small fixed
+    /// budget (`[thumbnail]` section).
+    #[serde(default)]
+    pub thumbnail: Option<ThumbnailSetting>,
+
+    /// If set, the server writes the latest thumbnail of every connection into
+    /// this directory.
+    #[serde(default)]
+    pub thumbnail_dir: Option<String>,
+}
+
+fn default_name() -> String {
+    "default".to_string()
+}
+
+/// How the client picks its first level. Starting closer to where it will
+/// settle shortens convergence on stable links.
+///
+/// ```toml
+/// startup = "middle"
+/// startup = { level = 3 }
+/// startup = { last_good = "last-level.txt" }
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StartupPolicy {
+    /// Starts at the lowest level.
+    #[default]
+    Lowest,
+
+    /// Starts at the middle level.
+    Middle,
+
+    /// Starts at a level chosen by the operator.
+    Level(usize),
+
+    /// Starts at the last level the client was steady at, persisted in this
+    /// file; the lowest level if there is none yet.
+    LastGood(String),
+}
+
+impl StartupPolicy {
+    /// Returns the level to start at, given the number of levels.
+    pub fn level(&self, levels: usize) -> usize {
+        let highest = levels.saturating_sub(1);
+        match *self {
+            StartupPolicy::Lowest => 0,
+            StartupPolicy::Middle => highest / 2,
+            StartupPolicy::Level(level) => ::std::cmp::min(level, highest),
+            StartupPolicy::LastGood(ref path) => {
+                match read_level(path) {
+                    Ok(level) => ::std::cmp::min(level, highest),
+                    Err(e) => {
+                        warn!("no last good level in {}: {}, start at 0", path, e);
+                        0
+                    }
+                }
+            }
+        }
+    }
+
+    /// Persists `level` as the last good level, if the policy keeps one.
+    pub fn save(&self, level: usize) -> Result<()> {
+        match *self {
+            StartupPolicy::LastGood(ref path) => {
+                let mut file = File::create(path)?;
+                writeln!(file, "{}", level)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Motion-triggered frame skipping on the client, replaying per-frame motion
+/// measured by the video crate.
+///
+/// ```toml
+/// [motion]
+/// path = "motion.csv"
+/// threshold = 2.0
+/// max_skip = 10
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct MotionSetting {
+    /// Path to the per-frame motion (`frame_num, motion`).
+    pub path: String,
+
+    /// When frames are skipped.
+    #[serde(flatten)]
+    pub config: MotionConfig,
+}
+
+/// The adaptation policy of the client. AIMD is the classic baseline AWStream
+/// is compared against: it only follows a target rate and picks the highest
+/// level within it, without probing. PID follows a target rate too, moved
+/// every monitor interval by how far the queue latency is from a setpoint.
+///
+/// ```toml
+/// policy = "awstream"
+/// policy = { aimd = {} }
+/// policy = { aimd = { increase = 500.0, decrease = 0.7 } }
+/// policy = { pid = { setpoint = 50.0, kp = 2.0, ki = 10.0, kd = 0.0 } }
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AdaptationPolicy {
+    /// Profile-based adaptation with probing (the state machine).
+    #[default]
+    Awstream,
+
+    /// Additive increase (kbps per second of empty queue) and multiplicative
+    /// decrease on congestion of a target rate.
+    Aimd {
+        /// Additive increase (kbps).
+        #[serde(default = "default_aimd_increase")]
+        increase: f64,
+
+        /// Multiplicative decrease, between 0 and 1.
+        #[serde(default = "default_aimd_decrease")]
+        decrease: f64,
+    },
+
+    /// A PID controller that keeps the queue latency at a setpoint, adapting
+    /// every monitor interval without probing.
+    Pid(PidGains),
+}
+
+/// How the client probes for bandwidth before advancing a level: with dummy
+/// bytes on top of its data, or with data at other levels, paced on estimates
+/// of bandwidth and RTT (see the `bbr` module).
+///
+/// ```toml
+/// probing = "dummy"
+/// probing = { bbr = {} }
+/// probing = { bbr = { bw_window = 2000, rtt_window = 10000 } }
+/// ```
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Probing {
+    /// Dummy bytes, added up to the rate probed for.
+    #[default]
+    Dummy,
+
+    /// Data, with the pacing gains of BBR.
+    Bbr(BbrConfig),
+}
+
+/// The congestion detector of the client (see the `detector` module).
+///
+/// ```toml
+/// detector = "delay_gradient"
+/// ```
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectorKind {
+    /// Congestion whenever data is queued.
+    #[default]
+    QueueLatency,
+
+    /// Congestion while the queue latency grows.
+    DelayGradient,
+
+    /// Congestion while the queue latency grows or once it is too large.
+    Hybrid,
+
+    /// Congestion once the queue latency and the share of bytes lost score
+    /// high enough together.
+    Fused,
+}
+
+/// How data go over the network (see the `udp` module).
+///
+/// ```toml
+/// transport = "udp"
+/// ```
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// Everything over one TCP connection.
+    #[default]
+    Tcp,
+
+    /// Live data and probes in UDP datagrams, the rest over TCP, so that
+    /// TCP's congestion control does not interfere with the adaptation.
+    Udp,
+}
+
+fn default_aimd_increase() -> f64 {
+    AIMD_INCREASE
+}
+
+fn default_aimd_decrease() -> f64 {
+    AIMD_DECREASE
+}
+
+fn read_level(path: &str) -> Result<usize> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    contents
+        .trim()
+        .parse::<usize>()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// An additional experiment hosted on its own port. Fields that are not set
+/// are inherited from the top-level setting.
+#[derive(Deserialize, Clone)]
+pub struct ExperimentSetting {
+    /// Name of the experiment (must be unique).
+    pub name: String,
+
+    /// Data connection port (must be unique).
+    pub port: u16,
+
+    /// Path to the profile.
+    #[serde(default)]
+    pub profile_path: Option<String>,
+
     /// Path to source (video).
+    #[serde(default)]
+    pub source_path: Option<String>,
+
+    /// Path to stat (per frame stat), `stat.csv` or its index (see the `index`
+    /// binary of the evaluation), which loads faster.
+    #[serde(default)]
+    pub stat_path: Option<String>,
+
+    /// Directory for per-level summaries.
+    #[serde(default)]
+    pub summary_dir: Option<String>,
+
+    /// Emulated analytics cost (ms per datum).
+    #[serde(default)]
+    pub analytics_cost: Option<f64>,
+
+    /// Jitter buffer in front of the analytics.
+    #[serde(default)]
+    pub jitter_buffer: Option<JitterBufferConfig>,
+
+    /// When the analytics sheds frames.
+    #[serde(default)]
+    pub shed: Option<ShedConfig>,
+
+    /// Level every client of the experiment is pinned at.
+    #[serde(default)]
+    pub pin_level: Option<usize>,
+}
+
+/// A secondary stream of the client, e.g. audio or metadata next to the
+/// video. It is sent at a fixed level and does not adapt.
+///
+/// ```toml
+/// [[stream]]
+/// id = 1
+/// source_path = "audio.csv"
+/// profile_path = "audio-profile.csv"
+/// stat_path = "audio-stat.csv"
+/// level = 0
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct StreamSetting {
+    /// Stream ID on the wire (must be unique and not `PRIMARY_STREAM`).
+    pub id: u32,
+
+    /// Path to the profile.
+    pub profile_path: String,
+
+    /// Path to source.
     pub source_path: String,
 
-    /// Path to stat (per frame stat).
+    /// Path to stat (per frame stat), `stat.csv` or its index (see the `index`
+    /// binary of the evaluation), which loads faster.
     pub stat_path: String,
+
+    /// Level the stream is sent at. The lowest level if not set.
+    #[serde(default)]
+    pub level: usize,
+}
+
+/// Data the server sends back to the client, e.g. detections or annotated
+/// thumbnails. They have their own profile and adapt like the client's data.
+///
+/// ```toml
+/// [reverse]
+/// source_path = "results.csv"
+/// profile_path = "results-profile.csv"
+/// stat_path = "results-stat.csv"
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ReverseSetting {
+    /// Path to the profile.
+    pub profile_path: String,
+
+    /// Path to source.
+    pub source_path: String,
+
+    /// Path to stat (per frame stat), `stat.csv` or its index (see the `index`
+    /// binary of the evaluation), which loads faster.
+    pub stat_path: String,
+}
+
+/// Bonding of several connections, e.g. over the cellular and the WiFi
+/// interface, that the client stripes its data across (see `bond`).
+///
+/// ```toml
+/// [bonding]
+/// local_addrs = ["10.0.0.2", "192.168.1.5"]
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct BondingSetting {
+    /// Number of connections, the primary one included. 2 if not set.
+    #[serde(default = "default_bonding_connections")]
+    pub connections: usize,
+
+    /// Local addresses to connect from, one per connection (the primary one
+    /// first). If set, they decide the number of connections instead.
+    #[serde(default)]
+    pub local_addrs: Vec<String>,
+}
+
+fn default_bonding_connections() -> usize {
+    2
+}
+
+impl BondingSetting {
+    /// Returns the number of connections to open.
+    pub fn connections(&self) -> usize {
+        if self.local_addrs.is_empty() {
+            self.connections
+        } else {
+            self.local_addrs.len()
+        }
+    }
+}
+
+/// TLS for the connection. The server presents `cert` and `key`; the client,
+/// which has no `key`, verifies it for `domain` against `ca`.
+///
+/// ```toml
+/// [tls]
+/// cert = "server.crt"
+/// key = "server.key"
+/// domain = "awstream.example.com"
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct TlsSetting {
+    /// Path to the server's certificate chain (PEM).
+    pub cert: String,
+
+    /// Path to the server's private key (PEM, PKCS8 or RSA). Only the server
+    /// needs it.
+    #[serde(default)]
+    pub key: Option<String>,
+
+    /// Name the client verifies the server's certificate for.
+    pub domain: String,
+
+    /// Path to the certificates (PEM) the client trusts. `cert` if not set,
+    /// i.e. the server's certificate is self-signed.
+    #[serde(default)]
+    pub ca: Option<String>,
+}
+
+/// Thumbnails (small JPEGs) of the frame the client is sending, every few
+/// seconds whatever its level, so that operators can see what the camera sees
+/// even when the data are heavily degraded. They do not adapt: thumbnails
+/// larger than the budget allows are never sent.
+///
+/// ```toml
+/// [thumbnail]
+/// path = "thumbnails"
+/// interval = 5
+/// budget = 8.0
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ThumbnailSetting {
+    /// Directory of the thumbnails of the frames of the source (e.g. downscaled
+    /// copies of them), named after their frame number as the frames of a
+    /// dataset are (`000001.jpg` and on).
+    pub path: String,
+
+    /// Interval (s) between two thumbnails. 5 if not set.
+    #[serde(default = "default_thumbnail_interval")]
+    pub interval: u64,
+
+    /// Budget (kbps) of the thumbnails. 8 if not set.
+    #[serde(default = "default_thumbnail_budget")]
+    pub budget: f64,
+}
+
+impl ThumbnailSetting {
+    /// Returns the most bytes (on the wire) a thumbnail may take.
+    pub fn max_bytes(&self) -> usize {
+        (self.budget * 1000.0 / 8.0 * self.interval as f64) as usize
+    }
+}
+
+fn default_thumbnail_interval() -> u64 {
+    5
+}
+
+fn default_thumbnail_budget() -> f64 {
+    8.0
 }
 
 impl Setting {
@@ -31,6 +707,264 @@ impl Setting {
         let mut file = File::open(file)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
-        Ok(toml::from_str(&contents).unwrap())
+        let setting: Setting = toml::from_str(&contents).unwrap();
+        setting.check()?;
+        Ok(setting)
+    }
+
+    /// Checks that experiments have unique names and ports, that streams have
+    /// unique IDs other than the primary stream's, that neither compression
+    /// (whose handshake datagrams would miss), TLS nor bonding is used over UDP,
+    /// and that bonding has valid local addresses.
+    fn check(&self) -> Result<()> {
+        if self.transport == Transport::Udp && self.compression != Compression::None {
+            let msg = "compression is not supported over udp";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        if self.transport == Transport::Udp && self.tls.is_some() {
+            let msg = "tls does not cover udp datagrams";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        if self.transport == Transport::Udp && self.websocket {
+            let msg = "websocket senders can't send udp datagrams";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        if let Some(ref bonding) = self.bonding {
+            if self.transport == Transport::Udp {
+                let msg = "bonding is not supported over udp";
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            if bonding.connections() == 0 {
+                let msg = "bonding needs at least one connection";
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            if let Some(addr) = bonding.local_addrs.iter().find(|a| a.parse::<IpAddr>().is_err()) {
+                let msg = format!("invalid local address {}", addr);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        }
+        if self.owd_gradient.map_or(false, |owd| owd.window < 2) {
+            let msg = "owd_gradient needs a window of at least 2 probes";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        if let Some(max_rate) = self.max_rate {
+            if max_rate <= 0.0 || self.floor_rate.map_or(false, |floor| floor > max_rate) {
+                let msg =
+                    format!("max_rate {} must be positive and not below floor_rate", max_rate);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        }
+        if self.profile_reload == Some(0) {
+            let msg = "profile_reload needs an interval of at least 1 ms";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        for window in &self.budget_schedule {
+            let unlimited = window.max_rate.is_none() && window.max_level.is_none();
+            if window.from == window.to || unlimited {
+                let msg = format!("budget window from {} needs an end and a limit", window.from);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        }
+        if self.shed.map_or(false, |s| s.every == 0) {
+            let msg = "shed must analyze 1 in at least 1 frame";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        if self.buffer.and_then(|b| b.backpressure) == Some(0) {
+            let msg = "buffer backpressure must be positive";
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        if let Some(ref load) = self.load {
+            if load.clients == 0 {
+                let msg = "load needs at least one client";
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            if load.bottleneck.map_or(false, |kbps| kbps <= 0.0) {
+                let msg = "load bottleneck must be positive";
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        }
+        for (i, a) in self.streams.iter().enumerate() {
+            if a.id == PRIMARY_STREAM || self.streams[i + 1..].iter().any(|b| b.id == a.id) {
+                let msg = format!("stream {} conflicts with another stream", a.id);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        }
+        let experiments = self.experiments();
+        for (i, a) in experiments.iter().enumerate() {
+            for b in &experiments[i + 1..] {
+                if a.name == b.name || a.port == b.port {
+                    let msg = format!(
+                        "experiments {} (port {}) and {} (port {}) conflict",
+                        a.name,
+                        a.port,
+                        b.name,
+                        b.port
+                    );
+                    return Err(Error::new(ErrorKind::InvalidData, msg));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns all experiments: the top-level one, followed by one for each
+    /// `[[experiment]]` section.
+    pub fn experiments(&self) -> Vec<Setting> {
+        let base = Setting {
+            experiments: Vec::new(),
+            ..self.clone()
+        };
+        let mut all = vec![base.clone()];
+        for e in &self.experiments {
+            let or = |v: &Option<String>, default: &str| {
+                v.clone().unwrap_or_else(|| default.to_string())
+            };
+            all.push(Setting {
+                name: e.name.clone(),
+                port: e.port,
+                profile_path: or(&e.profile_path, &base.profile_path),
+                source_path: or(&e.source_path, &base.source_path),
+                stat_path: or(&e.stat_path, &base.stat_path),
+                summary_dir: e.summary_dir.clone().or_else(|| base.summary_dir.clone()),
+                analytics_cost: e.analytics_cost.or(base.analytics_cost),
+                jitter_buffer: e.jitter_buffer.or(base.jitter_buffer),
+                shed: e.shed.or(base.shed),
+                pin_level: e.pin_level.or(base.pin_level),
+                ..base.clone()
+            });
+        }
+        all
+    }
+
+    /// Returns the experiment called `name`.
+    pub fn experiment(&self, name: &str) -> Option<Setting> {
+        self.experiments().into_iter().find(|e| e.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evaluation::testing::ScratchDir;
+
+    #[test]
+    fn experiments_inherit_top_level() {
+        let setting: Setting = toml::from_str(
+            r#"
+            server = "127.0.0.1"
+            port = 8889
+            profile_path = "profile.csv"
+            source_path = "source.csv"
+            stat_path = "stat.csv"
+
+            [[experiment]]
+            name = "slow"
+            port = 8890
+            stat_path = "slow.csv"
+            analytics_cost = 50.0
+            "#,
+        ).unwrap();
+        assert!(setting.check().is_ok());
+
+        let experiments = setting.experiments();
+        assert_eq!(experiments.len(), 2);
+        assert_eq!(experiments[0].name, "default");
+        assert_eq!(experiments[0].stat_path, "stat.csv");
+
+        let slow = setting.experiment("slow").unwrap();
+        assert_eq!(slow.port, 8890);
+        assert_eq!(slow.profile_path, "profile.csv");
+        assert_eq!(slow.stat_path, "slow.csv");
+        assert_eq!(slow.analytics_cost, Some(50.0));
+        assert!(slow.experiments.is_empty());
+
+        assert_eq!(setting.startup, StartupPolicy::Lowest);
+
+        let mut conflict = setting.clone();
+        conflict.experiments[0].port = 8889;
+        assert!(conflict.check().is_err());
+    }
+
+    #[test]
+    fn streams_need_distinct_ids() {
+        let mut setting: Setting = toml::from_str(
+            r#"
+            server = "127.0.0.1"
+            port = 8889
+            profile_path = "profile.csv"
+            source_path = "source.csv"
+            stat_path = "stat.csv"
+
+            [[stream]]
+            id = 1
+            profile_path = "audio-profile.csv"
+            source_path = "audio.csv"
+            stat_path = "audio-stat.csv"
+            "#,
+        ).unwrap();
+        assert_eq!(setting.streams[0].level, 0);
+        assert!(setting.check().is_ok());
+        assert_eq!(setting.experiments()[0].streams, setting.streams);
+
+        let mut duplicate = setting.streams[0].clone();
+        setting.streams.push(duplicate.clone());
+        assert!(setting.check().is_err());
+        duplicate.id = PRIMARY_STREAM;
+        setting.streams = vec![duplicate];
+        assert!(setting.check().is_err());
+    }
+
+    #[test]
+    fn startup_policies() {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            startup: StartupPolicy,
+        }
+        let parse = |s: &str| toml::from_str::<Wrapper>(s).unwrap().startup;
+        assert_eq!(parse("startup = \"middle\""), StartupPolicy::Middle);
+        assert_eq!(parse("startup = { level = 9 }"), StartupPolicy::Level(9));
+
+        assert_eq!(StartupPolicy::Lowest.level(5), 0);
+        assert_eq!(StartupPolicy::Middle.level(5), 2);
+        assert_eq!(StartupPolicy::Level(9).level(5), 4);
+
+        let dir = ScratchDir::new("last-good");
+        let path = dir.join("level");
+        let last_good = StartupPolicy::LastGood(path.to_str().unwrap().to_string());
+        assert_eq!(last_good.level(5), 0);
+        last_good.save(3).unwrap();
+        assert_eq!(last_good.level(5), 3);
+    }
+
+    #[test]
+    fn adaptation_policies() {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            #[serde(default)]
+            policy: AdaptationPolicy,
+        }
+        let parse = |s: &str| toml::from_str::<Wrapper>(s).unwrap().policy;
+        assert_eq!(parse(""), AdaptationPolicy::Awstream);
+        assert_eq!(
+            parse("policy = { aimd = {} }"),
+            AdaptationPolicy::Aimd {
+                increase: AIMD_INCREASE,
+                decrease: AIMD_DECREASE,
+            }
+        );
+        assert_eq!(
+            parse("policy = { aimd = { increase = 500.0 } }"),
+            AdaptationPolicy::Aimd {
+                increase: 500.0,
+                decrease: AIMD_DECREASE,
+            }
+        );
+        assert_eq!(
+            parse("policy = { pid = { setpoint = 50.0 } }"),
+            AdaptationPolicy::Pid(PidGains {
+                setpoint: 50.0,
+                ..PidGains::default()
+            })
+        );
     }
 }
@@ -0,0 +1,121 @@
+//! Shedding on a busy server. Once more data wait for a connection's analytics
+//! than it is configured for, only every k-th frame is analyzed; the others
+//! are received and accounted for, but skipped. Shedding stops once the
+//! backlog halves. The share of frames shed is reported every second next to
+//! the accuracy, which then only covers the frames analyzed.
+
+use errors::*;
+use std::sync::{Arc, Mutex};
+
+/// When the server sheds frames before its analytics.
+///
+/// ```toml
+/// [shed]
+/// threshold = 30
+/// every = 3
+/// ```
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ShedConfig {
+    /// Data waiting for the analytics above which it sheds.
+    #[serde(default = "default_threshold")]
+    pub threshold: usize,
+
+    /// While shedding, one frame in this many is analyzed.
+    #[serde(default = "default_every")]
+    pub every: usize,
+}
+
+fn default_threshold() -> usize {
+    30
+}
+
+fn default_every() -> usize {
+    3
+}
+
+#[derive(Default)]
+struct Counts {
+    received: usize,
+    shed: usize,
+}
+
+/// Decides which frames the analytics skips. Clones share the counts, so that
+/// the connection's timer can take them.
+#[derive(Clone)]
+pub struct Shedder {
+    config: ShedConfig,
+    shedding: bool,
+    skipped: usize,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Shedder {
+    pub fn new(config: ShedConfig) -> Shedder {
+        Shedder {
+            config,
+            shedding: false,
+            skipped: 0,
+            counts: Arc::new(Mutex::new(Counts::default())),
+        }
+    }
+
+    /// Whether a frame that arrives while `depth` data wait for the analytics
+    /// is analyzed.
+    pub fn admit(&mut self, depth: usize) -> Result<bool> {
+        if !self.shedding && depth > self.config.threshold {
+            info!("analytics {} data behind, analyze 1 in {} frames", depth, self.config.every);
+            self.shedding = true;
+            self.skipped = 0;
+        } else if self.shedding && depth <= self.config.threshold / 2 {
+            info!("analytics {} data behind, stop shedding", depth);
+            self.shedding = false;
+        }
+        let admit = if self.shedding && self.skipped + 1 < self.config.every {
+            self.skipped += 1;
+            false
+        } else {
+            self.skipped = 0;
+            true
+        };
+        let mut counts = self.counts.lock()?;
+        counts.received += 1;
+        if !admit {
+            counts.shed += 1;
+        }
+        Ok(admit)
+    }
+
+    /// Returns the share of frames shed since the last call, 0 if none were
+    /// received.
+    pub fn take_ratio(&self) -> Result<f64> {
+        let mut counts = self.counts.lock()?;
+        let ratio = if counts.received > 0 {
+            counts.shed as f64 / counts.received as f64
+        } else {
+            0.0
+        };
+        *counts = Counts::default();
+        Ok(ratio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheds_while_behind() {
+        let mut shedder = Shedder::new(ShedConfig {
+            threshold: 10,
+            every: 3,
+        });
+        let admitted = [5, 11, 11, 11, 11, 11, 6, 5, 5]
+            .iter()
+            .map(|&depth| shedder.admit(depth).unwrap())
+            .collect::<Vec<_>>();
+        // Shedding keeps on until the backlog halves.
+        assert_eq!(admitted, vec![true, false, false, true, false, false, true, true, true]);
+        assert!((shedder.take_ratio().unwrap() - 4.0 / 9.0).abs() < 1e-9);
+        assert_eq!(shedder.take_ratio().unwrap(), 0.0);
+    }
+}
@@ -0,0 +1,255 @@
+//! A discrete-time simulation of the client's controller (the monitor, the
+//! congestion detector, the adaptation and the prober) over a bandwidth trace,
+//! fast enough to sweep its parameters (see the `sweep` binary).
+//!
+//! Every monitor interval, the source produces the current level's rate (and
+//! probes, if probing) into a queue that the link drains at the trace's
+//! bandwidth. Data are assumed to be as late as the queue is long. The
+//! adaptation and the prober are the client's own (see `client::core_action`
+//! and `source::ProbeTracker`), ticking once per monitor interval.
+
+use AdaptAction;
+use adaptation::{Adaptation, Signal};
+use awstream_core::pid::{Pid, PidGains};
+use client::{PROBE_EXTRA, core_action};
+use controller::MONITOR_INTERVAL;
+use csv;
+use detector::{self, Measurement};
+use errors::*;
+use presets::Preset;
+use profile::{Profile, SimpleProfile};
+use setting::DetectorKind;
+use source::ProbeTracker;
+use video::VideoConfig;
+
+/// Parameters of the controller to simulate. The client's values by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ControllerParams {
+    /// Congestion signals tolerated during startup.
+    pub startup_congest: usize,
+
+    /// `QueueEmpty` signals required before probing.
+    pub steady: usize,
+
+    /// Times to stick to a level when asked to adjust to it.
+    pub adjust_sticky: usize,
+
+    /// Probes target this much more than the next level needs.
+    pub probe_extra: f64,
+
+    /// Monitor interval (ms).
+    pub monitor_interval: u64,
+}
+
+impl Default for ControllerParams {
+    fn default() -> ControllerParams {
+        ControllerParams {
+            startup_congest: Adaptation::STARTUP_CONGEST_ENOUGH,
+            steady: Adaptation::STEADY_ENOUGH,
+            adjust_sticky: ::awstream_core::profile::ADJUST_STICKY_MAX,
+            probe_extra: PROBE_EXTRA,
+            monitor_interval: MONITOR_INTERVAL,
+        }
+    }
+}
+
+/// What a simulation achieves.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct SimulationResult {
+    /// Mean accuracy of the levels sent at.
+    pub accuracy: f64,
+
+    /// Mean latency (ms) of the queue.
+    pub latency: f64,
+
+    /// 95th percentile latency (ms) of the queue.
+    pub p95_latency: f64,
+
+    /// Number of level changes.
+    pub level_changes: usize,
+}
+
+/// Simulates the controller with `params` over `trace` (kbps, one per second)
+/// for a profile of `rates` (kbps) and `accuracies` per level, starting at
+/// the lowest level.
+pub fn simulate(
+    rates: &[f64],
+    accuracies: &[f64],
+    trace: &[f64],
+    params: &ControllerParams,
+) -> SimulationResult {
+    let mut profile = SimpleProfile::new(rates.to_vec());
+    profile.set_adjust_sticky(params.adjust_sticky);
+    let mut adaptation = Adaptation::with_params(params.startup_congest, params.steady);
+    let mut detector = detector::build(DetectorKind::QueueLatency);
+    let mut prober = ProbeTracker::new(params.monitor_interval.max(1));
+
+    let interval = params.monitor_interval.max(1) as f64;
+    let ticks = (trace.len() as f64 * 1000.0 / interval) as usize;
+    let mut queued = 0.0; // kbits
+    let mut latencies = Vec::with_capacity(ticks);
+    let mut accuracy = 0.0;
+    let mut level_changes = 0;
+
+    for tick in 0..ticks {
+        let bandwidth = trace[(tick as f64 * interval / 1000.0) as usize];
+        let level = profile.current();
+        let m = send(&mut queued, rates[level] + prober.pace_in_kbps(), bandwidth, interval);
+        latencies.push(m.latency);
+        accuracy += accuracies.get(level).cloned().unwrap_or(0.0);
+
+        // The source reacts to the adaptation as `TimerSource` does.
+        let mut signal = detector.detect(m);
+        while let Some(s) = signal.take() {
+            let action = core_action(s, &mut adaptation, &mut profile, 0.0, params.probe_extra);
+            match action {
+                Some(AdaptAction::StartProbe(target)) => prober.start_probe(target),
+                Some(AdaptAction::IncreaseProbePace) => {
+                    if !prober.inc_pace() {
+                        signal = Some(Signal::ProbeDone);
+                    }
+                }
+                Some(AdaptAction::ToLevel(_)) |
+                Some(AdaptAction::DecreaseDegradation) |
+                Some(AdaptAction::StopProbe) => prober.stop_probe(),
+                _ => {}
+            }
+        }
+        if profile.current() != level {
+            level_changes += 1;
+        }
+    }
+
+    summarize(latencies, accuracy, level_changes)
+}
+
+/// Simulates a PID controller with `gains` (see `AdaptationPolicy::Pid`) as
+/// `simulate` does the state machine, so that both can be compared on the
+/// same traces. It starts at the lowest level too.
+pub fn simulate_pid(
+    rates: &[f64],
+    accuracies: &[f64],
+    trace: &[f64],
+    gains: PidGains,
+    monitor_interval: u64,
+) -> SimulationResult {
+    let mut profile = SimpleProfile::new(rates.to_vec());
+    let lowest = rates.first().cloned().unwrap_or(0.0);
+    let highest = rates.last().cloned().unwrap_or(0.0);
+    let mut pid = Pid::new(lowest, lowest, highest, gains);
+
+    let interval = monitor_interval.max(1) as f64;
+    let ticks = (trace.len() as f64 * 1000.0 / interval) as usize;
+    let mut queued = 0.0; // kbits
+    let mut latencies = Vec::with_capacity(ticks);
+    let mut accuracy = 0.0;
+    let mut level_changes = 0;
+
+    for tick in 0..ticks {
+        let bandwidth = trace[(tick as f64 * interval / 1000.0) as usize];
+        let level = profile.current();
+        let m = send(&mut queued, rates[level], bandwidth, interval);
+        latencies.push(m.latency);
+        accuracy += accuracies.get(level).cloned().unwrap_or(0.0);
+
+        if m.latency.is_finite() {
+            let rate = pid.update(m.latency, interval / 1000.0);
+            profile.set_level(profile.level_for_rate(rate));
+        }
+        if profile.current() != level {
+            level_changes += 1;
+        }
+    }
+
+    summarize(latencies, accuracy, level_changes)
+}
+
+/// Sends `produced` kbps into the queue (`queued` kbits) for `interval` ms,
+/// drained at `bandwidth` kbps. Returns what the monitor measures.
+fn send(queued: &mut f64, produced: f64, bandwidth: f64, interval: f64) -> Measurement {
+    *queued += produced * interval / 1000.0;
+    let sent = queued.min(bandwidth * interval / 1000.0);
+    *queued -= sent;
+
+    let rate = sent * 1000.0 / interval;
+    let latency = if bandwidth > 0.0 {
+        *queued * 1000.0 / bandwidth
+    } else {
+        ::std::f64::INFINITY
+    };
+    Measurement {
+        rate,
+        latency,
+        loss: 0.0,
+    }
+}
+
+/// Summarizes the latency of every tick, and the sum of their accuracies.
+fn summarize(mut latencies: Vec<f64>, accuracy: f64, level_changes: usize) -> SimulationResult {
+    let ticks = latencies.len();
+    if ticks == 0 {
+        return SimulationResult::default();
+    }
+    let mean_latency = latencies.iter().sum::<f64>() / ticks as f64;
+    latencies.sort_by(|a, b| a.partial_cmp(b).expect("latency is not a number"));
+    let p95 = latencies[((ticks - 1) as f64 * 0.95) as usize];
+    SimulationResult {
+        accuracy: accuracy / ticks as f64,
+        latency: mean_latency,
+        p95_latency: p95,
+        level_changes,
+    }
+}
+
+/// Reads the bandwidth (kbps) and accuracy of every level of a profile.
+pub fn read_profile(path: &str) -> (Vec<f64>, Vec<f64>) {
+    let profile = Profile::<VideoConfig>::new(path);
+    let records = profile.records();
+    (
+        records.iter().map(|r| r.bandwidth).collect(),
+        records.iter().map(|r| r.accuracy()).collect(),
+    )
+}
+
+/// Reads a bandwidth trace (`second, kbps` without header), or returns the
+/// schedule of a preset if `path` names one (see `presets`).
+pub fn read_trace(path: &str) -> Result<Vec<f64>> {
+    if let Ok(preset) = path.parse::<Preset>() {
+        return Ok(preset.schedule());
+    }
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).from_path(path)?;
+    let mut trace = Vec::new();
+    for record in reader.deserialize() {
+        let (_second, kbps): (usize, f64) = record?;
+        trace.push(kbps);
+    }
+    Ok(trace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settles_below_the_bandwidth() {
+        let rates = [100.0, 200.0, 400.0, 800.0];
+        let accuracies = [0.5, 0.6, 0.7, 0.8];
+        let trace = vec![500.0; 120];
+        let result = simulate(&rates, &accuracies, &trace, &ControllerParams::default());
+        assert!(result.accuracy > 0.6);
+        assert!(result.accuracy < 0.8);
+        assert!(result.level_changes > 0);
+
+        let unlimited = simulate(&rates,
+                                 &accuracies,
+                                 &vec![10_000.0; 120],
+                                 &ControllerParams::default());
+        assert!(unlimited.accuracy > result.accuracy);
+        assert_eq!(unlimited.p95_latency, 0.0);
+
+        let pid = simulate_pid(&rates, &accuracies, &trace, PidGains::default(), MONITOR_INTERVAL);
+        assert!(pid.accuracy > 0.6);
+        assert!(pid.accuracy < 0.8);
+        assert!(pid.level_changes > 0);
+    }
+}
@@ -2,25 +2,134 @@
 //! for bandwidth estimation.
 
 use errors::*;
-use super::{AsCodec, AsDatum};
-use bytes::BytesMut;
-use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
-use std::{fmt, io};
+use super::{AsCodec, AsDatum, CodecStats, WireFormat};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream, stream};
+use iovec::IoVec;
+use std::{cmp, fmt, io};
+use std::collections::VecDeque;
 use std::io::Write;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use tokio_core::net::TcpStream;
-use tokio_io::AsyncRead;
+use tls::WriteHalf;
+use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{Decoder, Encoder};
-use tokio_io::io::WriteHalf;
+
+/// Data ready to be written, in order. They go out together, with one
+/// vectored write (`writev`) per flush instead of one write per chunk.
+#[derive(Debug, Default)]
+struct Chunks(VecDeque<Bytes>);
+
+impl Buf for Chunks {
+    fn remaining(&self) -> usize {
+        self.0.iter().map(|c| c.len()).sum()
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.0.front().map_or(&[], |c| &c[..])
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        while cnt > 0 {
+            let n = {
+                let front = self.0.front_mut().expect("advance past the chunks");
+                let n = cmp::min(cnt, front.len());
+                front.advance(n);
+                if !front.is_empty() {
+                    break;
+                }
+                n
+            };
+            self.0.pop_front();
+            cnt -= n;
+        }
+    }
+
+    fn bytes_vec<'a>(&'a self, dst: &mut [&'a IoVec]) -> usize {
+        let mut n = 0;
+        for (chunk, iovec) in self.0.iter().zip(dst.iter_mut()) {
+            *iovec = chunk[..].into();
+            n += 1;
+        }
+        n
+    }
+}
+
+/// Sizes of a socket's buffer, in bytes, 32 KiB each if not set. They stay
+/// fixed whatever the frames: a buffer that grows with them would hold back
+/// the data that tell the adaptation the link is congested.
+///
+/// ```toml
+/// [buffer]
+/// capacity = 65536
+/// backpressure = 262144
+/// ```
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct BufferConfig {
+    /// Initial capacity of the buffer.
+    #[serde(default)]
+    pub capacity: Option<usize>,
+
+    /// Bytes waiting to be written beyond which sends are rejected until
+    /// they drain.
+    #[serde(default)]
+    pub backpressure: Option<usize>,
+}
+
+impl BufferConfig {
+    /// Returns the capacity and backpressure boundary.
+    pub fn sizes(&self) -> (usize, usize) {
+        let capacity = self.capacity.unwrap_or(Socket::INITIAL_CAPACITY);
+        let backpressure = self.backpressure.unwrap_or(Socket::BACKPRESSURE_BOUNDARY);
+        (capacity, backpressure)
+    }
+}
+
+/// How full a socket's buffer has been.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct BufferSnapshot {
+    /// Initial capacity of the buffer.
+    pub capacity: usize,
+
+    /// Backpressure boundary.
+    pub backpressure: usize,
+
+    /// Most bytes that have waited to be written at once.
+    pub high_watermark: usize,
+
+    /// Sends rejected because of backpressure.
+    pub rejected: u64,
+}
+
+/// A handle to how full a socket's buffer has been. Clones share it.
+#[derive(Debug, Clone, Default)]
+pub struct BufferStats {
+    inner: Arc<Mutex<BufferSnapshot>>,
+}
+
+impl BufferStats {
+    fn update<F: FnOnce(&mut BufferSnapshot)>(&self, f: F) {
+        f(&mut self.inner.lock().expect("failed to update buffer stats"));
+    }
+
+    /// Returns how full the buffer has been so far.
+    pub fn snapshot(&self) -> BufferSnapshot {
+        *self.inner.lock().expect("failed to read buffer stats")
+    }
+}
 
 /// `Socket` manages sending data over the network with encoder `AsCodec`. When
 /// sending, it updates a counter of `AtomicUsize` so that other monitors can
-/// learn the throughput.
+/// learn the throughput. Data count by their network length (see
+/// `AsDatum::net_len`) as they are written, the way their producers count
+/// them, whatever the encoder makes of them on the wire (e.g. compressed).
+///
+/// Only headers and small payloads are copied into the socket buffer; large
+/// payloads are queued and written from where they already are.
 #[derive(Debug)]
 pub struct Socket {
-    /// The write half of a `TcpStream`, which implements `Sink` interface.
-    net: WriteHalf<TcpStream>,
+    /// The write half of a connection, which implements `Sink` interface.
+    net: WriteHalf,
 
     /// Encoder that teach us how to encode.
     encoder: AsCodec,
@@ -28,28 +137,108 @@ pub struct Socket {
     /// Counter keeps track of bytes sent.
     bytes: Arc<AtomicUsize>,
 
-    /// Internal socket buffer.
+    /// For every datum not written in full, in order: its bytes left on the
+    /// wire and its network length not counted yet.
+    unwritten: VecDeque<(usize, usize)>,
+
+    /// Internal socket buffer for headers and small payloads.
     buffer: BytesMut,
+
+    /// Data ready to be written, in order, before what is in `buffer`.
+    chunks: Chunks,
+
+    /// Sends are rejected while this many bytes wait to be written.
+    backpressure: usize,
+
+    /// How full the buffer has been.
+    buffer_stats: BufferStats,
 }
 
 impl Socket {
-    /// Send buffer size.
-    const INITIAL_CAPACITY: usize = 16 * 1_024;
+    /// Send buffer size unless set otherwise (see `BufferConfig`).
+    const INITIAL_CAPACITY: usize = 32 * 1_024;
 
-    /// Triggers `poll_complete` if buffered item exceeds the boundary.
+    /// Triggers `poll_complete` if buffered item exceeds the boundary, unless
+    /// set otherwise.
     const BACKPRESSURE_BOUNDARY: usize = Socket::INITIAL_CAPACITY;
 
-    /// Creates a new Socket by taking owner ship of the write half of
-    /// TcpStream. Also we return a copy of the counter.
-    pub fn new(tcp: WriteHalf<TcpStream>) -> (Socket, Arc<AtomicUsize>) {
+    /// Payloads smaller than this are copied into the buffer, which saves a
+    /// write per datum.
+    const COPY_BOUNDARY: usize = 4 * 1_024;
+
+    /// Creates a new Socket by taking owner ship of the write half of a
+    /// connection, which encodes with `encoder`. Also we return a copy of the
+    /// counter.
+    pub fn with_codec(tcp: WriteHalf, encoder: AsCodec) -> (Socket, Arc<AtomicUsize>) {
         let counter = Arc::new(AtomicUsize::new(0));
-        let socket = Socket {
+        let socket = Socket::sharing(tcp, encoder, counter.clone());
+        (socket, counter)
+    }
+
+    /// Creates a new Socket that counts into `counter`, e.g. one of several
+    /// connections that send together.
+    pub fn sharing(tcp: WriteHalf, encoder: AsCodec, counter: Arc<AtomicUsize>) -> Socket {
+        let mut socket = Socket {
             net: tcp,
-            encoder: AsCodec::default(),
-            bytes: counter.clone(),
-            buffer: BytesMut::with_capacity(Socket::INITIAL_CAPACITY),
+            encoder,
+            bytes: counter,
+            unwritten: VecDeque::new(),
+            buffer: BytesMut::new(),
+            chunks: Chunks::default(),
+            backpressure: 0,
+            buffer_stats: BufferStats::default(),
         };
-        (socket, counter)
+        socket.set_buffers(Socket::INITIAL_CAPACITY, Socket::BACKPRESSURE_BOUNDARY);
+        socket
+    }
+
+    /// Sets the capacity of the buffer and the backpressure boundary (see
+    /// `BufferConfig::sizes`).
+    pub fn set_buffers(&mut self, capacity: usize, backpressure: usize) {
+        self.buffer.reserve(capacity);
+        self.backpressure = backpressure;
+        self.buffer_stats.update(|s| {
+            s.capacity = capacity;
+            s.backpressure = backpressure;
+        });
+    }
+
+    /// Returns a handle to the counters of what has been encoded.
+    pub fn stats(&self) -> CodecStats {
+        self.encoder.stats()
+    }
+
+    /// Returns how headers go on the wire (see `AsCodec::set_format`).
+    pub fn wire_format(&self) -> WireFormat {
+        self.encoder.format()
+    }
+
+    /// Returns a handle to how full the buffer has been.
+    pub fn buffer_stats(&self) -> BufferStats {
+        self.buffer_stats.clone()
+    }
+
+    /// Bytes waiting to be written.
+    fn buffered(&self) -> usize {
+        self.buffer.len() + self.chunks.remaining()
+    }
+
+    /// Returns the network length of what `n` more bytes written complete,
+    /// with a datum written in part counted in proportion.
+    fn written(&mut self, mut n: usize) -> usize {
+        let mut counted = 0;
+        while let Some(&mut (ref mut wire, ref mut net)) = self.unwritten.front_mut() {
+            if n < *wire {
+                let part = *net * n / *wire;
+                *wire -= n;
+                *net -= part;
+                return counted + part;
+            }
+            n -= *wire;
+            counted += *net;
+            self.unwritten.pop_front();
+        }
+        counted
     }
 }
 
@@ -58,30 +247,50 @@ impl Sink for Socket {
     type SinkError = Error;
 
     fn start_send(&mut self, item: AsDatum) -> StartSend<AsDatum, Error> {
-        // If the buffer is already over 8KiB, then attempt to flush it. If
-        // after flushing it's *still* over 8KiB, then apply backpressure
-        // (reject the send).
-        if self.buffer.len() >= Socket::BACKPRESSURE_BOUNDARY {
+        // If the buffer is already over the boundary, then attempt to flush
+        // it. If after flushing it's *still* over the boundary, then apply
+        // backpressure (reject the send).
+        if self.buffered() >= self.backpressure {
             try!(self.poll_complete());
 
-            if self.buffer.len() >= Socket::BACKPRESSURE_BOUNDARY {
+            if self.buffered() >= self.backpressure {
+                self.buffer_stats.update(|s| s.rejected += 1);
                 return Ok(AsyncSink::NotReady(item));
             }
         }
 
-        try!(self.encoder.encode(item, &mut self.buffer));
+        let before = self.buffered();
+        let payload = self.encoder.encode_header(&item, &mut self.buffer)?;
+        if payload.len() < Socket::COPY_BOUNDARY {
+            self.buffer.extend_from_slice(&payload);
+        } else {
+            let headers = self.buffer.take().freeze();
+            self.chunks.0.push_back(headers);
+            self.chunks.0.push_back(payload);
+        }
+        let buffered = self.buffered();
+        self.unwritten.push_back((buffered - before, item.net_len()));
+        self.buffer_stats.update(|s| s.high_watermark = cmp::max(s.high_watermark, buffered));
 
         Ok(AsyncSink::Ready)
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
         trace!("flushing socket");
-        while !self.buffer.is_empty() {
-            trace!("writing; remaining={}", self.buffer.len());
+        loop {
+            if !self.buffer.is_empty() {
+                let buffered = self.buffer.take().freeze();
+                self.chunks.0.push_back(buffered);
+            }
+            if self.chunks.0.is_empty() {
+                break;
+            }
+            trace!("writing; remaining={}, chunks={}", self.buffered(), self.chunks.0.len());
 
-            let n = try_nb!(self.net.write(&self.buffer));
+            let n = try_ready!(self.net.write_buf(&mut self.chunks));
 
-            self.bytes.fetch_add(n, Ordering::SeqCst);
+            let written = self.written(n);
+            self.bytes.fetch_add(written, Ordering::SeqCst);
             info!("complete sending item with size {}", n);
 
             if n == 0 {
@@ -92,8 +301,6 @@ impl Sink for Socket {
                     ).into(),
                 );
             }
-
-            let _ = self.buffer.split_to(n);
         }
 
         // Try flushing the underlying IO
@@ -104,6 +311,38 @@ impl Sink for Socket {
     }
 }
 
+/// Skips data that fail their checksum, which `AsCodec` reports as
+/// `ErrorKind::DecodeError` and resynchronizes after, instead of ending the
+/// stream.
+pub fn skip_corrupt<S>(data: S) -> impl Stream<Item = AsDatum, Error = Error>
+where
+    S: Stream<Item = AsDatum, Error = Error>,
+{
+    data.then(|result| match result {
+        Ok(datum) => Ok(Some(datum)),
+        Err(Error(ErrorKind::DecodeError(expected, actual), _)) => {
+            warn!("skip corrupt datum: expected crc {:08x}, got {:08x}", expected, actual);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }).filter_map(|datum| datum)
+}
+
+/// Merges `other` into `main`. The merged stream ends when `main` does,
+/// whatever is left of `other`, e.g. data that wind down the connection with
+/// control data that would go on forever.
+pub fn merge_until_done<S, T>(main: S, other: T) -> impl Stream<Item = S::Item, Error = S::Error>
+where
+    S: Stream,
+    T: Stream<Item = S::Item, Error = S::Error>,
+{
+    main.map(Some)
+        .chain(stream::once(Ok(None)))
+        .select(other.map(Some))
+        .take_while(|item| Ok(item.is_some()))
+        .filter_map(|item| item)
+}
+
 /// A `Stream` of messages decoded from an `AsyncRead`.
 pub struct FramedRead<T, D> {
     inner: T,
@@ -193,3 +432,48 @@ where
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, stream};
+
+    #[test]
+    fn chunks_write_together() {
+        let mut chunks = Chunks::default();
+        for chunk in &[&b"head"[..], &b"payload"[..], &b"tail"[..]] {
+            chunks.0.push_back(Bytes::from(*chunk));
+        }
+        let dummy: &[u8] = &[0];
+        let mut iovecs: [&IoVec; 4] = [dummy.into(); 4];
+        assert_eq!(chunks.bytes_vec(&mut iovecs), 3);
+        assert_eq!(&iovecs[1][..], b"payload");
+
+        // A partial write leaves the rest of a chunk in place.
+        chunks.advance(6);
+        assert_eq!(chunks.bytes(), b"yload");
+        assert_eq!(chunks.remaining(), 9);
+        chunks.advance(9);
+        assert!(chunks.0.is_empty());
+    }
+
+    #[test]
+    fn buffers_are_fixed() {
+        assert_eq!(BufferConfig::default().sizes(), (32 * 1_024, 32 * 1_024));
+
+        let fixed = BufferConfig {
+            capacity: None,
+            backpressure: Some(50_000),
+        };
+        assert_eq!(fixed.sizes(), (32 * 1_024, 50_000));
+    }
+
+    #[test]
+    fn merged_stream_ends_with_main() {
+        let main = stream::iter_ok::<_, ()>(vec![1, 2, 3]);
+        let other = stream::repeat(0);
+        let merged = merge_until_done(main, other).collect().wait().unwrap();
+        assert_eq!(merged.iter().filter(|&&i| i > 0).collect::<Vec<_>>(), [&1, &2, &3]);
+        assert!(merged.contains(&0));
+    }
+}
@@ -1,18 +1,32 @@
-use super::{Adapt, AdaptAction, AsDatum, Experiment};
+use super::{Adapt, AdaptAction, AsDatum, DropReason, Experiment};
+use chrono::Utc;
 use super::adaptation::Signal;
+use super::bbr::{BbrEstimate, BbrProber};
+use super::catch_up::CatchUp;
+use super::coalesce::Coalescer;
+use super::filter::{self, FrameFilter};
 use super::queue::ReceiverCtl;
 use super::queue::queue;
-use futures::Stream;
+use super::recorder::Recording;
+use futures::{Async, Future, Poll, Stream};
 use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio_core::reactor::Handle;
 use tokio_timer;
 
 type SourceCtrl = (UnboundedSender<AdaptAction>, UnboundedReceiver<Signal>);
 type SourceData = ReceiverCtl;
-type SourceStat = Arc<AtomicUsize>;
+
+/// Bytes the source has produced since the monitor last took them, with
+/// bandwidth probes counted apart from data (live data, latency probes and
+/// reports).
+#[derive(Clone)]
+pub struct SourceStat {
+    pub data: Arc<AtomicUsize>,
+    pub probe: Arc<AtomicUsize>,
+}
 
 pub type Source = (SourceCtrl, SourceData, SourceStat);
 
@@ -25,7 +39,7 @@ pub struct TimerSource;
 /// Probing is evenly spaced in each tick within a second. So complication of
 /// this data type is due to the calculation of a proper rate. See `start_probe`
 /// for details.
-struct ProbeTracker {
+pub(crate) struct ProbeTracker {
     /// We need to know the tick_period to calculate how large each probe packet
     /// is for a even distribution.
     pub tick_period: u64,
@@ -47,7 +61,7 @@ struct ProbeTracker {
 const NUM_PROBE_REQUIRED: usize = 3;
 
 impl ProbeTracker {
-    fn new(tick_period: u64) -> ProbeTracker {
+    pub fn new(tick_period: u64) -> ProbeTracker {
         ProbeTracker {
             tick_period: tick_period,
             target_in_kbps: 0.0,
@@ -69,6 +83,19 @@ impl ProbeTracker {
         self.pace = self.delta;
     }
 
+    /// Changes the tick period. The pace of an ongoing probe is recomputed
+    /// for the new period from the probe rate, keeping how far it has got.
+    pub fn set_tick_period(&mut self, tick_period: u64) {
+        self.tick_period = tick_period;
+        if self.target_pace == 0 {
+            return;
+        }
+        let steps = self.pace / ::std::cmp::max(self.delta, 1);
+        let target_in_kbps = self.target_in_kbps;
+        self.start_probe(target_in_kbps);
+        self.pace = self.delta * steps;
+    }
+
     /// Probing is the additive increase phase (as AIMD in TCP).
     pub fn inc_pace(&mut self) -> bool {
         if self.pace < self.target_pace {
@@ -86,6 +113,11 @@ impl ProbeTracker {
         self.delta = 0;
     }
 
+    /// Returns the rate (kbps) the probe is currently sent at.
+    pub fn pace_in_kbps(&self) -> f64 {
+        self.pace as f64 * 8.0 / self.tick_period as f64
+    }
+
     fn next(&self) -> Option<AsDatum> {
         if self.target_pace > 0 {
             Some(AsDatum::bw_probe(self.pace))
@@ -95,82 +127,419 @@ impl ProbeTracker {
     }
 }
 
+/// How the source probes: with dummy bytes on top of its data, or, given the
+/// estimates of the monitor, with data at other levels (see the `bbr` module)
+/// whose rates (kbps) it keeps.
+enum Prober {
+    Dummy(ProbeTracker),
+    Bbr(BbrProber, Vec<f64>),
+}
+
+impl Prober {
+    fn new<A: Adapt>(source: &A, bbr: Option<BbrEstimate>) -> Prober {
+        match bbr {
+            Some(estimate) => {
+                let profile = source.simple_profile();
+                let rates = (0..profile.len()).filter_map(|l| profile.rate_of(l)).collect();
+                Prober::Bbr(BbrProber::new(estimate), rates)
+            }
+            None => Prober::Dummy(ProbeTracker::new(source.period_in_ms())),
+        }
+    }
+
+    /// Probes for `additional_kbps` above the rate of the `current` level.
+    /// Returns false, leaving the probe as it is, if one is going already: a
+    /// duplicate would restart its ramp and send its first steps twice.
+    fn start_probe(&mut self, current: usize, additional_kbps: f64) -> bool {
+        if self.is_probing() {
+            return false;
+        }
+        match *self {
+            Prober::Dummy(ref mut p) => p.start_probe(additional_kbps),
+            Prober::Bbr(ref mut p, ref rates) => {
+                let rate = rates.get(current).cloned().unwrap_or(0.0);
+                p.start_probe(rate, additional_kbps, Instant::now())
+            }
+        }
+        true
+    }
+
+    /// Follows the rates of a reloaded profile, stopping any probe.
+    fn reload<A: Adapt>(&mut self, source: &A) {
+        self.stop_probe();
+        if let Prober::Bbr(_, ref mut rates) = *self {
+            let profile = source.simple_profile();
+            *rates = (0..profile.len()).filter_map(|l| profile.rate_of(l)).collect();
+        }
+    }
+
+    fn set_tick_period(&mut self, tick_period: u64) {
+        if let Prober::Dummy(ref mut p) = *self {
+            p.set_tick_period(tick_period);
+        }
+    }
+
+    /// Returns false once the probe is done.
+    fn inc_pace(&mut self) -> bool {
+        match *self {
+            Prober::Dummy(ref mut p) => p.inc_pace(),
+            Prober::Bbr(ref p, _) => p.inc_pace(),
+        }
+    }
+
+    fn stop_probe(&mut self) {
+        match *self {
+            Prober::Dummy(ref mut p) => p.stop_probe(),
+            Prober::Bbr(ref mut p, _) => p.stop_probe(),
+        }
+    }
+
+    fn is_probing(&self) -> bool {
+        match *self {
+            Prober::Dummy(ref p) => p.next().is_some(),
+            Prober::Bbr(ref p, _) => p.is_probing(),
+        }
+    }
+
+    /// Returns the dummy probe to send at this tick, if any.
+    fn next(&self) -> Option<AsDatum> {
+        match *self {
+            Prober::Dummy(ref p) => p.next(),
+            Prober::Bbr(..) => None,
+        }
+    }
+
+    /// Returns the level to send the datum of this tick at, which differs
+    /// from `current` only while probing with data.
+    fn level(&mut self, current: usize, period: u64) -> usize {
+        match *self {
+            Prober::Dummy(_) => current,
+            Prober::Bbr(ref mut p, ref rates) => {
+                let gain = p.gain(Instant::now(), period);
+                p.level(rates, current, gain)
+            }
+        }
+    }
+}
+
+/// Fires every `period` (ms), which may change from one tick to the next.
+struct Ticker {
+    timer: tokio_timer::Timer,
+    period: Arc<AtomicUsize>,
+    next: Instant,
+    sleep: tokio_timer::Sleep,
+}
+
+impl Ticker {
+    fn new(period: Arc<AtomicUsize>) -> Ticker {
+        let timer = tokio_timer::wheel()
+            .tick_duration(Duration::from_millis(1))
+            .build();
+        let first = Duration::from_millis(period.load(Ordering::SeqCst) as u64);
+        Ticker {
+            sleep: timer.sleep(first),
+            next: Instant::now() + first,
+            timer,
+            period,
+        }
+    }
+}
+
+impl Stream for Ticker {
+    type Item = ();
+    type Error = tokio_timer::TimerError;
+
+    fn poll(&mut self) -> Poll<Option<()>, Self::Error> {
+        try_ready!(self.sleep.poll());
+
+        // Ticks are scheduled from the previous one, so that the period holds
+        // on average, unless we fall behind.
+        let now = Instant::now();
+        self.next += Duration::from_millis(self.period.load(Ordering::SeqCst) as u64);
+        if self.next < now {
+            self.next = now;
+        }
+        self.sleep = self.timer.sleep(self.next - now);
+        Ok(Async::Ready(Some(())))
+    }
+}
+
+/// Makes the ticker and the prober follow the period of `source`, which
+/// changes with its level.
+fn follow_period<A: Adapt>(source: &A, period: &AtomicUsize, prober: &mut Prober) {
+    let new = source.period_in_ms();
+    if period.swap(new as usize, Ordering::SeqCst) != new as usize {
+        info!("source period: {} ms", new);
+        prober.set_tick_period(new);
+    }
+}
+
 enum Incoming {
     Timer,
     Adapt(AdaptAction),
 }
 
 impl TimerSource {
-    pub fn spawn<As>(mut source: As, handle: Handle) -> Source
+    /// Spawns the source on `handle`. If `filter` is set, every datum passes
+    /// it before being sent. If `coalescer` is set, small live data are sent
+    /// together. If `catch_up` is set, frames filtered, degraded below its
+    /// level or dropped from the queue are uploaded again whenever the queue
+    /// is empty at a tick, one per tick after the live datum. If `recorder` is
+    /// set, every frame goes to it at the highest level, whether it is sent
+    /// or not. If `bbr` is set, the source probes with data paced on its
+    /// estimates instead of with dummy bytes.
+    pub fn spawn<As>(
+        mut source: As,
+        mut filter: Option<Box<dyn FrameFilter>>,
+        mut coalescer: Option<Coalescer>,
+        catch_up: Option<CatchUp>,
+        recorder: Option<UnboundedSender<Recording>>,
+        bbr: Option<BbrEstimate>,
+        handle: Handle,
+    ) -> Source
     where
         As: Adapt + Experiment + 'static,
     {
         let timer_tick = source.period_in_ms();
-        let timer = tokio_timer::wheel()
-            .tick_duration(Duration::from_millis(1))
-            .build()
-            .interval(Duration::from_millis(timer_tick))
+        let period = Arc::new(AtomicUsize::new(timer_tick as usize));
+        let timer = Ticker::new(period.clone())
             .map_err(|_e| ())
             .map(|_e| Incoming::Timer);
+        let mut highest = source.simple_profile().len().saturating_sub(1);
 
         let (adapt_tx, adapt_rx) = unbounded();
         let adapter = adapt_rx.map(|level| Incoming::Adapt(level));
 
-        let (data_tx, data_rx) = queue();
-        let counter = Arc::new(AtomicUsize::new(0));
-        let counter_clone = counter.clone();
+        let (data_tx, mut data_rx) = queue();
+        if let Some(ref catch_up) = catch_up {
+            data_rx.set_catch_up(catch_up.clone());
+        }
+        let drops = data_tx.drops();
+        let occupancy = data_rx.occupancy();
+        let stat = SourceStat {
+            data: Arc::new(AtomicUsize::new(0)),
+            probe: Arc::new(AtomicUsize::new(0)),
+        };
+        let counter_clone = stat.data.clone();
+        let probe_counter = stat.probe.clone();
 
-        let mut prober = ProbeTracker::new(timer_tick);
+        let mut prober = Prober::new(&source, bbr);
         let (probe_tx, probe_rx) = unbounded();
 
-        let mut ticks = 0;
-        let one_second_ticks = 1000 / timer_tick;
+        // Latency probes and drop reports go out once per second, whatever the
+        // period.
+        let mut last_second = Instant::now();
+
+        // Whether the level is pinned (see `AdaptAction::PinLevel`).
+        let mut pinned = false;
 
         let work = timer.select(adapter).for_each(
             move |incoming| match incoming {
                 Incoming::Timer => {
-                    ticks += 1;
+                    let idle = occupancy.bytes().expect("failed to read queue occupancy") == 0;
 
                     // when one sec, send probe_rtt
-                    if ticks == one_second_ticks {
+                    if last_second.elapsed() >= Duration::from_secs(1) {
+                        last_second = Instant::now();
                         let p = AsDatum::latency_probe();
                         counter_clone.fetch_add(p.net_len(), Ordering::SeqCst);
                         data_tx.send(p).map(|_| ()).map_err(|_| ()).expect(
                             "failed to send probing latency packet",
                         );
-                        ticks = 0;
+
+                        // and report what has been dropped in the last second
+                        let dropped = drops.take().expect("failed to read drops");
+                        if !dropped.is_empty() {
+                            info!("drops: {}", dropped);
+                            let d = AsDatum::drop_report(&dropped).expect(
+                                "failed to encode drop report",
+                            );
+                            counter_clone.fetch_add(d.net_len(), Ordering::SeqCst);
+                            data_tx.send(d).map(|_| ()).map_err(|_| ()).expect(
+                                "failed to send drop report",
+                            );
+                        }
                     }
 
-                    let (size, frame_num) = source.next_datum();
+                    // Small data held back are sent once they have waited
+                    // long enough, even if no more data come.
+                    if let Some(d) = coalescer.as_mut().and_then(|c| c.due(Instant::now())) {
+                        counter_clone.fetch_add(d.net_len(), Ordering::SeqCst);
+                        data_tx.send(d).map_err(|_| ())?;
+                    }
+
+                    let (size, frame_num) = match source.next_datum() {
+                        Some(datum) => datum,
+                        None => {
+                            if let Some(d) = coalescer.as_mut().and_then(|c| c.flush()) {
+                                counter_clone.fetch_add(d.net_len(), Ordering::SeqCst);
+                                let _ = data_tx.send(d);
+                            }
+                            // Returning an error terminates the `for_each`,
+                            // which drops `data_tx` and ends the data stream.
+                            info!("source reaches the end of data");
+                            return Err(());
+                        }
+                    };
+                    let skipped = source.take_skipped();
+                    if skipped > 0 {
+                        drops
+                            .add(DropReason::Skipped, source.current_level(), skipped)
+                            .expect("failed to count skipped data");
+                    }
                     if size == 0 {
                         return Ok(());
                     }
 
+                    if let Some(ref r) = recorder {
+                        let (level, size) = match source.datum_size_at(highest, frame_num) {
+                            Some(full) => (highest, full),
+                            None => (source.current_level(), size),
+                        };
+                        let recording = Recording {
+                            ts: Utc::now(),
+                            frame_num,
+                            level,
+                            data: vec![0; size],
+                        };
+                        if r.unbounded_send(recording).is_err() {
+                            error!("recorder has stopped");
+                        }
+                    }
+
                     if let Some(p) = prober.next() {
-                        counter_clone.fetch_add(p.net_len(), Ordering::SeqCst);
+                        probe_counter.fetch_add(p.net_len(), Ordering::SeqCst);
                         data_tx.send(p).map(|_| ()).map_err(|_| ()).expect(
                             "failed to send probing packet",
                         );
                     }
 
-                    let level = source.current_level();
-                    let data_to_send = AsDatum::new(level, frame_num, vec![0; size]);
+                    // Probing with data sends this datum at another level.
+                    let own = source.current_level();
+                    let (current, size) = match prober.level(own, source.period_in_ms()) {
+                        level if level != own => match source.datum_size_at(level, frame_num) {
+                            Some(probed) => (level, probed),
+                            None => (own, size),
+                        },
+                        _ => (own, size),
+                    };
+                    let (level, size) = match filter {
+                        Some(ref mut f) => {
+                            match filter::apply(&mut **f, &mut source, current, frame_num, size) {
+                                Some(datum) => datum,
+                                None => {
+                                    debug!("filter drops datum {}", frame_num);
+                                    drops
+                                        .add(DropReason::Filtered, current, 1)
+                                        .expect("failed to count filtered datum");
+                                    if let Some(ref c) = catch_up {
+                                        c.miss(frame_num).expect("failed to remember missed frame");
+                                    }
+                                    return Ok(());
+                                }
+                            }
+                        }
+                        None => (current, size),
+                    };
+                    if let Some(ref c) = catch_up {
+                        if level < c.level() {
+                            c.miss(frame_num).expect("failed to remember missed frame");
+                        }
+                    }
+                    let expected = if level == own {
+                        source.expected_stat(frame_num)
+                    } else {
+                        source.expected_stat_at(level, frame_num)
+                    };
+                    // Only the current level has actual data to send.
+                    let payload = if level == own {
+                        source.payload(frame_num, size)
+                    } else {
+                        vec![0; size]
+                    };
+                    let mut data_to_send = AsDatum::new(level, frame_num, payload);
+                    if let Some(stat) = expected {
+                        data_to_send.set_expected(stat);
+                    }
+                    if let Some(ttl) = source.ttl() {
+                        data_to_send.set_ttl(ttl);
+                    }
                     info!("add new, level: {}, size: {}", level, size);
-                    counter_clone.fetch_add(data_to_send.net_len(), Ordering::SeqCst);
-                    data_tx.send(data_to_send).map(|_| ()).map_err(|_| ())
+                    let data_to_send = match coalescer {
+                        Some(ref mut c) => c.push(data_to_send),
+                        None => Some(data_to_send),
+                    };
+                    if let Some(d) = data_to_send {
+                        counter_clone.fetch_add(d.net_len(), Ordering::SeqCst);
+                        data_tx.send(d).map_err(|_| ())?;
+                    }
+
+                    // Missed frames only take what the link has to spare,
+                    // never while probing.
+                    if let Some(ref c) = catch_up {
+                        if idle && !prober.is_probing() {
+                            let historical = c.next_datum(&mut source)
+                                .expect("failed to read missed frames");
+                            if let Some(d) = historical {
+                                let left = c.len().expect("failed to read missed frames");
+                                info!("catch up, {} ({} frames left)", d, left);
+                                counter_clone.fetch_add(d.net_len(), Ordering::SeqCst);
+                                data_tx.send(d).map_err(|_| ())?;
+                            }
+                        }
+                    }
+                    Ok(())
                 }
-                Incoming::Adapt(AdaptAction::ToRate(rate)) => {
+                Incoming::Adapt(AdaptAction::PinLevel(level)) => {
                     prober.stop_probe();
-                    source.adapt(rate);
+                    source.set_level(level);
+                    follow_period(&source, &period, &mut prober);
+                    pinned = true;
+                    info!("pinned at level {}", source.current_level());
+                    Ok(())
+                }
+                Incoming::Adapt(AdaptAction::Unpin) => {
+                    pinned = false;
+                    info!("unpinned at level {}", source.current_level());
+                    Ok(())
+                }
+                Incoming::Adapt(AdaptAction::Reload(profile, level)) => {
+                    source.reload(profile, level);
+                    highest = source.simple_profile().len().saturating_sub(1);
+                    prober.reload(&source);
+                    follow_period(&source, &period, &mut prober);
+                    info!("profile reloaded at level {}", source.current_level());
+                    Ok(())
+                }
+                // Pinned, the level stays whatever the adaptation asks.
+                Incoming::Adapt(_) if pinned => Ok(()),
+                Incoming::Adapt(AdaptAction::ToRate(rate)) => {
+                    // The rate may come every monitor interval (see
+                    // `AdaptationPolicy::Pid`); only a new level matters.
+                    let level = source.simple_profile().level_for_rate(rate);
+                    if level != source.current_level() {
+                        prober.stop_probe();
+                        source.set_level(level);
+                        follow_period(&source, &period, &mut prober);
+                    }
                     Ok(())
                 }
                 Incoming::Adapt(AdaptAction::DecreaseDegradation) => {
                     prober.stop_probe();
                     source.dec_degradation();
+                    follow_period(&source, &period, &mut prober);
+                    Ok(())
+                }
+                Incoming::Adapt(AdaptAction::ToLevel(level)) => {
+                    prober.stop_probe();
+                    source.set_level(level);
+                    follow_period(&source, &period, &mut prober);
                     Ok(())
                 }
                 Incoming::Adapt(AdaptAction::StartProbe(target_in_kbps)) => {
-                    prober.start_probe(target_in_kbps);
+                    if !prober.start_probe(source.current_level(), target_in_kbps) {
+                        debug!("already probing, ignore probe for {:.1} kbps", target_in_kbps);
+                    }
                     Ok(())
                 }
                 Incoming::Adapt(AdaptAction::IncreaseProbePace) => {
@@ -187,6 +556,111 @@ impl TimerSource {
         );
         handle.spawn(work);
 
-        ((adapt_tx, probe_rx), data_rx, counter.clone())
+        ((adapt_tx, probe_rx), data_rx, stat)
+    }
+
+    /// Spawns a secondary stream on `handle`: every datum of `source` at its
+    /// current level, tagged with `stream_id`. Its bytes count in `stat`,
+    /// which is shared with the primary source so that the monitor sees all
+    /// that goes into the socket. Secondary streams neither adapt nor probe.
+    pub fn spawn_secondary<As>(
+        mut source: As,
+        stream_id: u32,
+        stat: SourceStat,
+        handle: Handle,
+    ) -> SourceData
+    where
+        As: Adapt + Experiment + 'static,
+    {
+        let period = Arc::new(AtomicUsize::new(source.period_in_ms() as usize));
+        let (data_tx, data_rx) = queue();
+        let work = Ticker::new(period).map_err(|_e| ()).for_each(move |_| {
+            let (size, frame_num) = match source.next_datum() {
+                Some(datum) => datum,
+                None => {
+                    info!("stream {} reaches the end of data", stream_id);
+                    return Err(());
+                }
+            };
+            if size == 0 {
+                return Ok(());
+            }
+            let payload = source.payload(frame_num, size);
+            let mut data_to_send = AsDatum::new(source.current_level(), frame_num, payload);
+            data_to_send.set_stream_id(stream_id);
+            if let Some(ttl) = source.ttl() {
+                data_to_send.set_ttl(ttl);
+            }
+            stat.data.fetch_add(data_to_send.net_len(), Ordering::SeqCst);
+            data_tx.send(data_to_send).map_err(|_| ())
+        });
+        handle.spawn(work);
+
+        data_rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AsDatumType;
+    use evaluation::testing::ScratchDir;
+    use std::fs;
+    use tokio_core::reactor::Core;
+    use video::VideoSource;
+
+    #[test]
+    fn data_end_with_the_source() {
+        let dir = ScratchDir::new("source-end");
+        let profile = dir.join("profile.csv");
+        let source = dir.join("source.csv");
+        fs::write(&profile, "100,320,0,30,0.5\n").unwrap();
+        let frames = (1..5).map(|frame| format!("320,0,30,{},10\n", frame));
+        fs::write(&source, frames.collect::<String>()).unwrap();
+        let mut video = VideoSource::new(&source, &profile);
+        video.set_fps(1000.0);
+        video.set_repeat(Some(2));
+
+        let mut core = Core::new().unwrap();
+        let (_ctrl, data, _stat) =
+            TimerSource::spawn(video, None, None, None, None, None, core.handle());
+        let data = core.run(data.collect()).unwrap();
+        let frames = data.iter()
+            .filter_map(|d| match d.datum_type() {
+                AsDatumType::Live(_, frame_num) => Some(frame_num),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(frames, vec![1, 2, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn probe_rate_survives_period_change() {
+        // 240 kbps is 3000 bytes per 100 ms tick.
+        let mut prober = ProbeTracker::new(100);
+        prober.start_probe(240.0);
+        assert_eq!(prober.target_pace, 3000);
+        assert_eq!(prober.pace, 1000);
+
+        prober.set_tick_period(200);
+        assert_eq!(prober.target_pace, 6000);
+        assert_eq!(prober.pace, 2000);
+        assert!(prober.inc_pace());
+        assert_eq!(prober.next().map(|p| p.datum_type()), Some(::AsDatumType::Dummy));
+    }
+
+    #[test]
+    fn duplicate_probes_are_suppressed() {
+        let mut prober = Prober::Dummy(ProbeTracker::new(100));
+        assert!(prober.start_probe(0, 240.0));
+        assert!(prober.inc_pace());
+
+        // The probe keeps its pace rather than ramping up again.
+        assert!(!prober.start_probe(0, 720.0));
+        assert_eq!(prober.next().map(|p| p.mem.len()), Some(2000));
+
+        prober.stop_probe();
+        assert!(prober.start_probe(0, 720.0));
+        assert_eq!(prober.next().map(|p| p.mem.len()), Some(3000));
     }
 }
@@ -0,0 +1,108 @@
+//! The thumbnail side channel: every few seconds, a small JPEG of the frame
+//! the client is sending, whatever its level, at a small fixed budget (see
+//! `ThumbnailSetting`).
+
+use super::AsDatum;
+use super::errors::*;
+use super::setting::ThumbnailSetting;
+use super::source::SourceStat;
+use futures::{Stream, stream};
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio_timer;
+
+/// The thumbnails of the frames of the source, one per frame.
+pub struct Thumbnails {
+    dir: String,
+    max_bytes: usize,
+}
+
+impl Thumbnails {
+    /// Finds the thumbnails in the directory of `setting`.
+    pub fn new(setting: &ThumbnailSetting) -> Result<Thumbnails> {
+        if !fs::metadata(&setting.path)?.is_dir() {
+            bail!("thumbnails {} is not a directory", setting.path);
+        }
+        Ok(Thumbnails {
+            dir: setting.path.clone(),
+            max_bytes: setting.max_bytes(),
+        })
+    }
+
+    /// Returns the thumbnail of frame `frame_num`, or `None` if it has none or
+    /// one larger than the budget allows.
+    pub fn of(&self, frame_num: usize) -> Option<AsDatum> {
+        let path = format!("{}/{:06}.jpg", self.dir, frame_num);
+        let datum = match fs::read(&path) {
+            Ok(jpeg) => AsDatum::thumbnail(jpeg),
+            Err(e) => {
+                warn!("no thumbnail {}: {}", path, e);
+                return None;
+            }
+        };
+        if datum.net_len() > self.max_bytes {
+            warn!("skip thumbnail {}: {} bytes over {}", path, datum.net_len(), self.max_bytes);
+            return None;
+        }
+        Some(datum)
+    }
+}
+
+/// Returns the thumbnail of the frame last sent (`sent`, 0 before the first),
+/// one right away and then one every interval, forever. What they take is
+/// counted in `stat`, as produced along with the data they share the socket
+/// with.
+pub fn stream(
+    setting: &ThumbnailSetting,
+    sent: Arc<AtomicUsize>,
+    stat: SourceStat,
+) -> Result<impl Stream<Item = AsDatum, Error = Error>> {
+    let thumbnails = Thumbnails::new(setting)?;
+    info!("thumbnails from {}, one every {} s", setting.path, setting.interval);
+    let ticks = tokio_timer::Timer::default().interval(Duration::from_secs(setting.interval));
+    let thumbnails = stream::once(Ok(())).chain(ticks).from_err().filter_map(move |_| {
+        let frame_num = sent.load(Ordering::SeqCst);
+        if frame_num == 0 {
+            return None;
+        }
+        let datum = thumbnails.of(frame_num)?;
+        stat.data.fetch_add(datum.net_len(), Ordering::SeqCst);
+        Some(datum)
+    });
+    Ok(thumbnails)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evaluation::testing::ScratchDir;
+
+    #[test]
+    fn thumbnails_follow_the_frames() {
+        let dir = ScratchDir::new("thumbnails");
+        fs::write(dir.join("000001.jpg"), vec![1; 100]).unwrap();
+        fs::write(dir.join("000002.jpg"), vec![2; 10_000]).unwrap();
+        fs::write(dir.join("000003.jpg"), vec![3; 200]).unwrap();
+
+        // 1 kbps for 5 s is 625 bytes.
+        let setting = ThumbnailSetting {
+            path: dir.path().to_str().unwrap().to_string(),
+            interval: 5,
+            budget: 1.0,
+        };
+        assert_eq!(setting.max_bytes(), 625);
+        let thumbnails = Thumbnails::new(&setting).unwrap();
+        assert_eq!(thumbnails.of(1).unwrap().mem.len(), 100);
+        assert!(thumbnails.of(2).is_none());
+        assert_eq!(thumbnails.of(3).unwrap().mem.len(), 200);
+        assert!(thumbnails.of(4).is_none());
+
+        let missing = ThumbnailSetting {
+            path: dir.join("missing").to_str().unwrap().to_string(),
+            ..setting
+        };
+        assert!(Thumbnails::new(&missing).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+//! TLS (rustls) for the connection between the client and the server. Without
+//! a `[tls]` section, the connection is plaintext, which is only meant for
+//! local testing.
+
+use bytes::Buf;
+use futures::{Async, Future, Poll};
+use futures::sync::BiLock;
+use rustls::{Certificate, ClientConfig, NoClientAuth, PrivateKey, ServerConfig};
+use rustls::internal::pemfile;
+use setting::TlsSetting;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::sync::Arc;
+use tokio_core::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+use webpki::DNSNameRef;
+use websocket::WebSocket;
+
+/// A connection, encrypted or not.
+pub enum Conn {
+    /// Plaintext.
+    Plain(TcpStream),
+
+    /// TLS, on the client side.
+    Client(client::TlsStream<TcpStream>),
+
+    /// TLS, on the server side.
+    Server(server::TlsStream<TcpStream>),
+
+    /// A WebSocket, on the server side, over either of the others.
+    WebSocket(Box<WebSocket>),
+}
+
+impl Conn {
+    /// Splits the connection into halves that can be used apart. Unlike the
+    /// halves of `AsyncRead::split`, the write half keeps vectored writes (see
+    /// `AsyncWrite::write_buf`).
+    pub fn split(self) -> (ReadHalf, WriteHalf) {
+        let (a, b) = BiLock::new(self);
+        (ReadHalf { conn: a }, WriteHalf { conn: b })
+    }
+
+    /// Returns the underlying TCP stream.
+    pub fn tcp(&self) -> &TcpStream {
+        match *self {
+            Conn::Plain(ref tcp) => tcp,
+            Conn::Client(ref tls) => tls.get_ref().0,
+            Conn::Server(ref tls) => tls.get_ref().0,
+            Conn::WebSocket(ref ws) => ws.get_ref().tcp(),
+        }
+    }
+}
+
+impl ::std::fmt::Debug for Conn {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            Conn::Plain(ref tcp) => write!(f, "plaintext {:?}", tcp),
+            Conn::Client(_) | Conn::Server(_) => write!(f, "tls {:?}", self.tcp()),
+            Conn::WebSocket(ref ws) => write!(f, "websocket over {:?}", ws.get_ref()),
+        }
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Conn::Plain(ref mut s) => s.read(buf),
+            Conn::Client(ref mut s) => s.read(buf),
+            Conn::Server(ref mut s) => s.read(buf),
+            Conn::WebSocket(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Conn::Plain(ref mut s) => s.write(buf),
+            Conn::Client(ref mut s) => s.write(buf),
+            Conn::Server(ref mut s) => s.write(buf),
+            Conn::WebSocket(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Conn::Plain(ref mut s) => s.flush(),
+            Conn::Client(ref mut s) => s.flush(),
+            Conn::Server(ref mut s) => s.flush(),
+            Conn::WebSocket(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Conn {}
+
+impl AsyncWrite for Conn {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            Conn::Plain(ref mut s) => AsyncWrite::shutdown(s),
+            Conn::Client(ref mut s) => s.shutdown(),
+            Conn::Server(ref mut s) => s.shutdown(),
+            Conn::WebSocket(ref mut s) => s.shutdown(),
+        }
+    }
+
+    /// Plaintext writes all chunks of `buf` with one `writev`; TLS encrypts
+    /// into its own buffer anyway, and a WebSocket sends a chunk per message.
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        match *self {
+            Conn::Plain(ref mut s) => s.write_buf(buf),
+            Conn::Client(ref mut s) => s.write_buf(buf),
+            Conn::Server(ref mut s) => s.write_buf(buf),
+            Conn::WebSocket(ref mut s) => s.write_buf(buf),
+        }
+    }
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "connection is in use")
+}
+
+/// The read half of a connection (see `Conn::split`).
+pub struct ReadHalf {
+    conn: BiLock<Conn>,
+}
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.conn.poll_lock() {
+            Async::Ready(mut conn) => conn.read(buf),
+            Async::NotReady => Err(would_block()),
+        }
+    }
+}
+
+impl AsyncRead for ReadHalf {}
+
+/// The write half of a connection (see `Conn::split`).
+pub struct WriteHalf {
+    conn: BiLock<Conn>,
+}
+
+impl ::std::fmt::Debug for WriteHalf {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "WriteHalf")
+    }
+}
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.conn.poll_lock() {
+            Async::Ready(mut conn) => conn.write(buf),
+            Async::NotReady => Err(would_block()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.conn.poll_lock() {
+            Async::Ready(mut conn) => conn.flush(),
+            Async::NotReady => Err(would_block()),
+        }
+    }
+}
+
+impl AsyncWrite for WriteHalf {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.conn.poll_lock() {
+            Async::Ready(mut conn) => conn.shutdown(),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        match self.conn.poll_lock() {
+            Async::Ready(mut conn) => conn.write_buf(buf),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+fn invalid(path: &str, what: &str) -> io::Error {
+    let msg = format!("no valid {} in {}", what, path);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(ref certs) if certs.is_empty() => Err(invalid(path, "certificate")),
+        Ok(certs) => Ok(certs),
+        Err(()) => Err(invalid(path, "certificate")),
+    }
+}
+
+/// Reads a PKCS8 or an RSA private key.
+fn read_key(path: &str) -> io::Result<PrivateKey> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut &contents[..]).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut &contents[..]).unwrap_or_default();
+    pkcs8.into_iter().chain(rsa).next().ok_or_else(|| invalid(path, "private key"))
+}
+
+/// Connects over TLS on `tcp`, verifying the server's certificate for
+/// `setting.domain` against `setting.ca` (or the server's own certificate if
+/// it is self-signed).
+pub fn connect(
+    tcp: TcpStream,
+    setting: &TlsSetting,
+) -> io::Result<Box<dyn Future<Item = Conn, Error = io::Error>>> {
+    let mut config = ClientConfig::new();
+    let ca = setting.ca.as_ref().unwrap_or(&setting.cert);
+    for cert in read_certs(ca)? {
+        config.root_store.add(&cert).map_err(|_| invalid(ca, "certificate"))?;
+    }
+    let domain = DNSNameRef::try_from_ascii_str(&setting.domain).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("invalid domain {}", setting.domain))
+    })?;
+    let work = TlsConnector::from(Arc::new(config)).connect(domain, tcp).map(Conn::Client);
+    Ok(Box::new(work))
+}
+
+/// Returns what the server accepts connections with.
+pub fn acceptor(setting: &TlsSetting) -> io::Result<TlsAcceptor> {
+    let key = setting.key.as_ref().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "the server needs the key of its certificate")
+    })?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(read_certs(&setting.cert)?, read_key(key)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts a TLS connection on `tcp`.
+pub fn accept(
+    acceptor: &TlsAcceptor,
+    tcp: TcpStream,
+) -> Box<dyn Future<Item = Conn, Error = io::Error>> {
+    Box::new(acceptor.accept(tcp).map(Conn::Server))
+}
@@ -0,0 +1,109 @@
+//! Two-tier level changes for sources that encode live (e.g. a camera). The
+//! skip is cheap to change: frames are dropped after capture and the encoder
+//! never knows. The width and the quantizer are not: the encoder has to be
+//! reconfigured, which costs a keyframe. So a small move between levels only
+//! changes the skip, on top of the width and quantizer the encoder already
+//! runs, as long as some skip stays within the rate of the new level; larger
+//! moves, or moves no skip can make, reconfigure the encoder.
+//!
+//! The skip that stands in for a level is the smallest of the profile's skips
+//! whose rate falls between the bandwidth of the level below and that of the
+//! level, with the rate scaled by the frames kept: a configuration of rate `r`
+//! at skip `s` sends about `r * (s + 1) / (s' + 1)` at skip `s'`.
+
+use profile::Profile;
+use video::VideoConfig;
+
+/// Default number of levels a move may span and still only change the skip.
+pub const SKIP_TIER_MOVE: usize = 1;
+
+/// Plans level changes in two tiers.
+#[derive(Debug, Clone)]
+pub struct TwoTier {
+    /// The level whose width and quantizer the encoder runs.
+    encoder: usize,
+
+    /// Most levels a move may span and still only change the skip.
+    max_move: usize,
+}
+
+impl TwoTier {
+    /// Starts with the encoder at `level`. Moves of up to `max_move` levels
+    /// only change the skip.
+    pub fn new(level: usize, max_move: usize) -> TwoTier {
+        TwoTier {
+            encoder: level,
+            max_move,
+        }
+    }
+
+    /// Returns the configuration that makes `level` of `profile`: the level's
+    /// own, or the encoder's width and quantizer at another skip.
+    pub fn plan(&mut self, profile: &Profile<VideoConfig>, level: usize) -> VideoConfig {
+        let records = profile.records();
+        let (encoder, target) = (records[self.encoder], records[level]);
+        let same_encoder = (encoder.config.width, encoder.config.quant) ==
+            (target.config.width, target.config.quant);
+        let distance = if level > self.encoder {
+            level - self.encoder
+        } else {
+            self.encoder - level
+        };
+        if !same_encoder && distance <= self.max_move {
+            let frames = (encoder.config.skip + 1) as f64;
+            let rate_at = |skip: usize| encoder.bandwidth * frames / (skip + 1) as f64;
+            let floor = if level > 0 { records[level - 1].bandwidth } else { 0.0 };
+            let mut skips = records.iter().map(|r| r.config.skip).collect::<Vec<_>>();
+            skips.sort();
+            skips.dedup();
+            let fits = |skip: &&usize| {
+                let rate = rate_at(**skip);
+                rate <= target.bandwidth && rate > floor
+            };
+            if let Some(&skip) = skips.iter().find(fits) {
+                info!("level {} by skip {} on the encoder of level {}", level, skip, self.encoder);
+                return VideoConfig {
+                    skip,
+                    ..encoder.config
+                };
+            }
+        }
+        if !same_encoder {
+            info!("level {} reconfigures the encoder", level);
+        }
+        self.encoder = level;
+        target.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use profile::Record;
+
+    #[test]
+    fn small_moves_only_change_skip() {
+        let config = |width, skip, quant| VideoConfig { width, skip, quant };
+        let profile = Profile::_with_vec(vec![
+            Record::_new(100.0, config(320, 5, 30), 0.5),
+            Record::_new(150.0, config(320, 2, 30), 0.6),
+            Record::_new(300.0, config(640, 5, 30), 0.7),
+            Record::_new(1000.0, config(960, 0, 20), 0.9),
+        ]);
+
+        let mut tiers = TwoTier::new(3, 1);
+        // Down one: 960x20 at skip 5 sends 167, within (150, 300].
+        assert_eq!(tiers.plan(&profile, 2), config(960, 5, 20));
+        // Down two from the encoder's level: reconfigure.
+        assert_eq!(tiers.plan(&profile, 1), config(320, 2, 30));
+        // Same width and quantizer: the level's own, whatever the move.
+        assert_eq!(tiers.plan(&profile, 0), config(320, 5, 30));
+
+        let mut tiers = TwoTier::new(2, 1);
+        // Up one: 640x30 at skip 2 sends 600, within (300, 1000].
+        assert_eq!(tiers.plan(&profile, 3), config(640, 2, 30));
+        // Down one from the encoder's level, but no skip of 640x30 sends
+        // within (100, 150]: reconfigure.
+        assert_eq!(tiers.plan(&profile, 1), config(320, 2, 30));
+    }
+}
@@ -0,0 +1,227 @@
+//! UDP transport for data that need not be reliable. Live frames and probes go
+//! in datagrams, one `AsCodec` frame each, so that only AWStream reacts to
+//! congestion; everything else (handshakes, reports, acks, historical data)
+//! stays on the TCP connection. Datagrams are not fragmented: live data too
+//! large for one go over TCP too, and are counted as `oversized` in the
+//! codec stats.
+//!
+//! The client sends its datagrams from the local address of its TCP
+//! connection (TCP and UDP ports are apart), so that the server tells which
+//! connection a datagram belongs to by its source address.
+
+use super::{AsCodec, AsDatum, AsDatumType};
+use super::codec_stats::CodecStats;
+use super::socket::Socket;
+use bytes::BytesMut;
+use errors::*;
+use futures::{Async, Future, Poll, Sink, StartSend, Stream};
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio_core::net::{UdpCodec, UdpFramed, UdpSocket};
+use tokio_core::reactor::Handle;
+use tokio_io::codec::Encoder;
+
+/// The largest payload of a UDP datagram. Larger data go over TCP.
+pub const MAX_DATAGRAM: usize = 65_507;
+
+/// Returns true if `d` is of a type that goes in datagrams: live data and
+/// probes.
+fn datagram_type(d: &AsDatum) -> bool {
+    match d.datum_type() {
+        AsDatumType::Live(_, _) |
+        AsDatumType::Coalesced |
+        AsDatumType::Dummy |
+        AsDatumType::LatencyProbe => true,
+        _ => false,
+    }
+}
+
+/// Returns true if `d` goes in a datagram: live data and probes that fit in
+/// one.
+pub fn unreliable(d: &AsDatum) -> bool {
+    datagram_type(d) && d.net_len() <= MAX_DATAGRAM
+}
+
+/// Encodes data into datagrams to `peer` and decodes datagrams with the source
+/// address, or `None` for datagrams that are truncated or corrupt.
+struct DatagramCodec {
+    codec: AsCodec,
+    peer: SocketAddr,
+    bytes: Arc<AtomicUsize>,
+}
+
+impl UdpCodec for DatagramCodec {
+    type In = (SocketAddr, Option<AsDatum>);
+    type Out = AsDatum;
+
+    fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
+        match self.codec.decode_datagram(buf) {
+            Ok(datum) => Ok((*src, datum)),
+            Err(e) => {
+                warn!("skip datagram from {}: {}", src, e);
+                Ok((*src, None))
+            }
+        }
+    }
+
+    fn encode(&mut self, d: AsDatum, buf: &mut Vec<u8>) -> SocketAddr {
+        let len = d.net_len();
+        let mut frame = BytesMut::with_capacity(len);
+        match self.codec.encode(d, &mut frame) {
+            Ok(()) => {
+                self.bytes.fetch_add(len, Ordering::SeqCst);
+                buf.extend_from_slice(&frame);
+            }
+            Err(e) => error!("failed to encode datagram: {}", e),
+        }
+        self.peer
+    }
+}
+
+/// A `Socket` that sends live data and probes in datagrams instead (see
+/// `unreliable`). Both count into the same counter of bytes sent, by network
+/// length (see `Socket`). Those too
+/// large for a datagram fall back to the `Socket`, and are counted into its
+/// codec stats.
+pub struct SplitSocket {
+    reliable: Socket,
+    datagrams: UdpFramed<DatagramCodec>,
+    stats: CodecStats,
+}
+
+impl SplitSocket {
+    /// Binds a UDP socket at `local`, the local address of the TCP connection
+    /// of `reliable`, to send datagrams to `peer`. `bytes` is the counter of
+    /// `reliable`.
+    pub fn bind(
+        reliable: Socket,
+        local: SocketAddr,
+        peer: SocketAddr,
+        bytes: Arc<AtomicUsize>,
+        handle: &Handle,
+    ) -> Result<SplitSocket> {
+        let udp = UdpSocket::bind(&local, handle)?;
+        let stats = reliable.stats();
+        let mut encoder = AsCodec::with_stats(stats.clone());
+        encoder.set_format(reliable.wire_format());
+        let codec = DatagramCodec {
+            codec: encoder,
+            peer,
+            bytes,
+        };
+        Ok(SplitSocket {
+            reliable,
+            datagrams: udp.framed(codec),
+            stats,
+        })
+    }
+}
+
+impl Sink for SplitSocket {
+    type SinkItem = AsDatum;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: AsDatum) -> StartSend<AsDatum, Error> {
+        if unreliable(&item) {
+            return Ok(self.datagrams.start_send(item)?);
+        }
+        if datagram_type(&item) {
+            debug!("{} is too large for a datagram, send it over TCP", item);
+            self.stats.oversized(item.datum_type().name());
+        }
+        self.reliable.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        let datagrams = self.datagrams.poll_complete()?;
+        let reliable = self.reliable.poll_complete()?;
+        match (datagrams, reliable) {
+            (Async::Ready(()), Async::Ready(())) => Ok(Async::Ready(())),
+            _ => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Datagrams received on an experiment's port, handed to the connection whose
+/// client sends them.
+#[derive(Clone)]
+pub struct Datagrams {
+    peers: Arc<Mutex<HashMap<SocketAddr, UnboundedSender<AsDatum>>>>,
+}
+
+impl Datagrams {
+    /// Binds a UDP socket at `addr` and spawns a task on `handle` that hands
+    /// what it receives to registered connections.
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> Result<Datagrams> {
+        let udp = UdpSocket::bind(addr, handle)?;
+        let codec = DatagramCodec {
+            codec: AsCodec::default(),
+            peer: *addr,
+            bytes: Arc::new(AtomicUsize::new(0)),
+        };
+        let datagrams = Datagrams { peers: Arc::new(Mutex::new(HashMap::new())) };
+        let peers = datagrams.peers.clone();
+        let demux = udp.framed(codec).for_each(move |(src, datum)| {
+            if let Some(datum) = datum {
+                let mut peers = peers.lock().unwrap();
+                let delivered = match peers.get(&src) {
+                    Some(tx) => tx.unbounded_send(datum).is_ok(),
+                    None => {
+                        trace!("datagram from unknown peer {}", src);
+                        true
+                    }
+                };
+                if !delivered {
+                    peers.remove(&src);
+                }
+            }
+            Ok(())
+        });
+        handle.spawn(demux.map_err(|e| error!("datagram socket failed: {}", e)));
+        Ok(datagrams)
+    }
+
+    /// Returns the data `peer` sends in datagrams from now on.
+    pub fn register(&self, peer: SocketAddr) -> UnboundedReceiver<AsDatum> {
+        let (tx, rx) = unbounded();
+        self.peers.lock().unwrap().insert(peer, tx);
+        rx
+    }
+
+    /// Stops handing datagrams from `peer` on.
+    pub fn unregister(&self, peer: &SocketAddr) {
+        self.peers.lock().unwrap().remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagrams_hold_one_frame() {
+        let peer: SocketAddr = ([127, 0, 0, 1], 8889).into();
+        let mut codec = DatagramCodec {
+            codec: AsCodec::default(),
+            peer,
+            bytes: Arc::new(AtomicUsize::new(0)),
+        };
+        let d = AsDatum::new(1, 2, vec![3; 100]);
+        assert!(unreliable(&d));
+        let mut buf = Vec::new();
+        assert_eq!(codec.encode(d.clone(), &mut buf), peer);
+        assert_eq!(codec.bytes.load(Ordering::SeqCst), d.net_len());
+
+        let (src, decoded) = codec.decode(&peer, &buf[..buf.len() - 1]).unwrap();
+        assert_eq!(src, peer);
+        assert!(decoded.is_none());
+        assert_eq!(codec.decode(&peer, &buf).unwrap().1, Some(d));
+
+        assert!(!unreliable(&AsDatum::thumbnail(vec![0; 10])));
+        assert!(!unreliable(&AsDatum::new(0, 0, vec![0; MAX_DATAGRAM])));
+    }
+}
@@ -1,5 +1,47 @@
 //! Utility structures and functions.
 
+use csv;
+use errors::*;
+use futures::Stream;
+use futures::sync::mpsc::{UnboundedSender, unbounded};
+use serde::Serialize;
+use serde_json;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::thread;
+
+/// Writes every item it receives as one line of JSON to `path`, from a
+/// dedicated thread.
+pub fn spawn_json_log<T: Serialize + Send + 'static>(path: &str) -> Result<UnboundedSender<T>> {
+    let mut file = LineWriter::new(File::create(path)?);
+    let (tx, rx) = unbounded();
+    thread::spawn(move || for item in rx.wait() {
+        let item = item.expect("log stream never fails");
+        let line = serde_json::to_string(&item).expect("failed to serialize log item");
+        if let Err(e) = writeln!(file, "{}", line) {
+            error!("failed to write log: {}", e);
+            break;
+        }
+    });
+    Ok(tx)
+}
+
+/// Writes every item it receives as one CSV record (with a header) to `path`,
+/// from a dedicated thread.
+pub fn spawn_csv_log<T: Serialize + Send + 'static>(path: &str) -> Result<UnboundedSender<T>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    let (tx, rx) = unbounded();
+    thread::spawn(move || for item in rx.wait() {
+        let item = item.expect("log stream never fails");
+        let written = writer.serialize(item).map_err(Error::from);
+        if let Err(e) = written.and_then(|_| writer.flush().map_err(Error::from)) {
+            error!("failed to write log: {}", e);
+            break;
+        }
+    });
+    Ok(tx)
+}
+
 pub struct ExponentialSmooth {
     val: f64,
     alpha: f64,
@@ -0,0 +1,137 @@
+//! Dry-run validation of an experiment: loads the setting, profile, source and
+//! stat files and cross-checks them without connecting to the server, so that
+//! a misconfigured experiment fails before it starts.
+
+use super::setting::Setting;
+use super::video::VideoSource;
+use std::fmt;
+use std::path::Path;
+
+/// Problems found in one `(configuration, frame)` check are capped at this
+/// many examples in the summary.
+const MAX_EXAMPLES: usize = 5;
+
+/// What `validate` found.
+#[derive(Default, Debug)]
+pub struct Summary {
+    /// Number of levels in the profile.
+    pub levels: usize,
+
+    /// Bandwidth of the lowest and the highest level.
+    pub bandwidth: (f64, f64),
+
+    /// Number of frames in the source.
+    pub frames: usize,
+
+    /// Everything that is wrong; empty if the experiment is good to go.
+    pub problems: Vec<String>,
+}
+
+impl Summary {
+    /// Returns true if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "levels: {}, bandwidth: {:.2} - {:.2}, frames: {}",
+            self.levels,
+            self.bandwidth.0,
+            self.bandwidth.1,
+            self.frames
+        )?;
+        if self.is_ok() {
+            write!(f, "OK")
+        } else {
+            for problem in &self.problems {
+                writeln!(f, "problem: {}", problem)?;
+            }
+            write!(f, "{} problem(s)", self.problems.len())
+        }
+    }
+}
+
+/// Loads everything `setting` refers to and cross-checks it: levels are
+/// sorted by bandwidth, profile accuracy is within [0, 1], and every
+/// (configuration, frame) has both a size and a stat.
+pub fn validate(setting: &Setting) -> Summary {
+    let mut summary = Summary::default();
+
+    let files = [
+        ("profile", &setting.profile_path),
+        ("source", &setting.source_path),
+        ("stat", &setting.stat_path),
+    ];
+    for &(name, path) in &files {
+        if !Path::new(path).exists() {
+            summary.problems.push(format!("{} {} does not exist", name, path));
+        }
+    }
+    if !summary.is_ok() {
+        return summary;
+    }
+
+    let mut source = VideoSource::new(&setting.source_path, &setting.profile_path);
+    source.load_stats(&setting.stat_path);
+
+    {
+        let records = source.profile().records();
+        summary.levels = records.len();
+        if let (Some(first), Some(last)) = (records.first(), records.last()) {
+            summary.bandwidth = (first.bandwidth, last.bandwidth);
+        } else {
+            summary.problems.push("profile is empty".to_string());
+        }
+        for (level, pair) in records.windows(2).enumerate() {
+            if pair[0].bandwidth > pair[1].bandwidth {
+                summary.problems.push(format!(
+                    "level {} ({}) needs more bandwidth than level {} ({})",
+                    level,
+                    pair[0].bandwidth,
+                    level + 1,
+                    pair[1].bandwidth
+                ));
+            }
+        }
+        for (level, record) in records.iter().enumerate() {
+            let accuracy = record.accuracy();
+            // NaN is an accuracy the profile leaves out.
+            if !accuracy.is_nan() && !(0.0..=1.0).contains(&accuracy) {
+                summary.problems.push(format!(
+                    "level {} ({}) has accuracy {} outside [0, 1]",
+                    level,
+                    record.config,
+                    accuracy
+                ));
+            }
+        }
+    }
+
+    let coverage = source.coverage();
+    summary.frames = coverage.frames;
+    let missing = [
+        ("frame size", &coverage.missing_sizes),
+        ("stat", &coverage.missing_stats),
+    ];
+    for &(what, list) in &missing {
+        if list.is_empty() {
+            continue;
+        }
+        let examples = list.iter()
+            .take(MAX_EXAMPLES)
+            .map(|&(config, frame)| format!("{}@{}", config, frame))
+            .collect::<Vec<_>>();
+        summary.problems.push(format!(
+            "{} missing {}(s), e.g. {}",
+            list.len(),
+            what,
+            examples.join(", ")
+        ));
+    }
+
+    summary
+}
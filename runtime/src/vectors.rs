@@ -0,0 +1,349 @@
+//! Golden test vectors of the codec, so that other implementations (e.g. a
+//! receiver in Python or a sender in C) can check themselves against this
+//! one. Every datum type is encoded by every suite, a wire format (see
+//! `WireFormat`) with or without compression, into a file of its own; files of
+//! suites with compression start with the handshake that enables it.
+//! `MANIFEST` describes what every file decodes to.
+//!
+//! `check_vectors` holds files (e.g. from another implementation, or from an
+//! earlier build) against this implementation: they must decode to what the
+//! manifest says and be what it encodes, byte for byte.
+//!
+//! `runtime/vectors` holds a subset, built by hand from the schema rather than
+//! by this codec, so that a change to the wire format shows up as a failing
+//! test instead of new vectors.
+
+use super::{AccuracyReport, AsCodec, AsDatum, AsDatumType, Compression, ComputeReport,
+            ControlMessage, Detections, DropReason, DropReport, LatencyBreakdown, ReceiverReport,
+            SequenceStats, WireFormat};
+use bytes::BytesMut;
+use chrono::{DateTime, TimeZone, Utc};
+use errors::*;
+use evaluation::Stat;
+use serde_json;
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::Duration;
+use tokio_io::codec::{Decoder, Encoder};
+use wire;
+
+/// The file, next to the suites, that describes every vector (JSON).
+pub const MANIFEST: &str = "vectors.json";
+
+/// What a vector decodes to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Vector {
+    /// Suite: the wire format, and `-lz4` if compressed.
+    pub suite: String,
+
+    /// Name of the vector, within its suite.
+    pub name: String,
+
+    /// Path of the file, relative to the manifest.
+    pub file: String,
+
+    /// Code of the datum type (see `wire::type_code`).
+    pub type_code: u8,
+
+    /// Level of live and historical data.
+    pub level: Option<usize>,
+
+    /// Frame number of live and historical data.
+    pub frame_num: Option<usize>,
+
+    /// Stream of the datum.
+    pub stream_id: u32,
+
+    /// Sequence number of the datum.
+    pub seq: u64,
+
+    /// Timestamp, in seconds since the epoch.
+    pub ts_secs: i64,
+
+    /// Nanoseconds of the timestamp.
+    pub ts_nanos: u32,
+
+    /// Accuracy statistics the sender expects.
+    pub expected: Option<Stat>,
+
+    /// Queue delay (us).
+    pub queue_delay: Option<u64>,
+
+    /// TTL (us).
+    pub ttl: Option<u64>,
+
+    /// Payload, decompressed, in hex.
+    pub payload: String,
+}
+
+/// The suites every datum is encoded by.
+const SUITES: [(WireFormat, Compression); 4] = [
+    (WireFormat::Stable, Compression::None),
+    (WireFormat::Stable, Compression::Lz4),
+    (WireFormat::Legacy, Compression::None),
+    (WireFormat::Legacy, Compression::Lz4),
+];
+
+fn suite_name(format: WireFormat, compression: Compression) -> String {
+    let format = match format {
+        WireFormat::Stable => "stable",
+        WireFormat::Legacy => "legacy",
+    };
+    match compression {
+        Compression::None => format.to_string(),
+        Compression::Lz4 => format!("{}-lz4", format),
+    }
+}
+
+fn timestamp() -> DateTime<Utc> {
+    Utc.timestamp_opt(1_500_000_000, 123_456_789).unwrap()
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 256) as u8).collect()
+}
+
+fn stamped(mut d: AsDatum) -> AsDatum {
+    d.ts = timestamp();
+    d
+}
+
+/// Returns the data the vectors hold, one of every datum type and a few
+/// variants, all at the same timestamp.
+pub fn reference_data() -> Result<Vec<(&'static str, AsDatum)>> {
+    let stat = Stat {
+        true_positive: 5,
+        false_positive: 1,
+        false_negative: 2,
+    };
+    let mut live = AsDatum::new(3, 42, pattern(64));
+    live.set_expected(stat);
+    live.set_ttl(Duration::from_secs(2));
+    live.set_queue_delay(Duration::from_micros(1_500));
+    live.set_seq(7);
+    let mut secondary = AsDatum::new(0, 43, pattern(16));
+    secondary.set_stream_id(2);
+    let mut raw = AsDatum::new(0, 0, pattern(8));
+    raw.t = AsDatumType::Raw;
+    let report = ReceiverReport::new(
+        250.5,
+        1_200.0,
+        1_500.0,
+        LatencyBreakdown {
+            queue: 10.0,
+            network: 230.5,
+            processing: 10.0,
+        },
+        SequenceStats {
+            lost: 3,
+            reordered: 1,
+        },
+        6.5,
+    );
+    let mut drops = DropReport::default();
+    drops.add(DropReason::Deadline, 2, 3);
+    let compute = ComputeReport {
+        queue_depth: 20,
+        latency: 120.0,
+        rate: 800.0,
+        fps: 12.5,
+    };
+    let accuracy = AccuracyReport { levels: vec![(0, 0.5), (3, 0.9)] };
+    let detections = Detections {
+        frame_num: 42,
+        level: 3,
+        objects: 6,
+        stat,
+    };
+    let coalesced = AsDatum::coalesce(vec![
+        stamped(AsDatum::new(0, 44, pattern(10))),
+        stamped(AsDatum::new(0, 45, pattern(12))),
+    ])?;
+
+    let data = vec![
+        ("live", live),
+        ("live_secondary", secondary),
+        ("raw", raw),
+        ("dummy", AsDatum::bw_probe(32)),
+        ("latency_probe", AsDatum::latency_probe()),
+        ("receiver_congest", AsDatum::ack(report)?),
+        ("sender_drops", AsDatum::drop_report(&drops)?),
+        ("compute_congest", AsDatum::compute_report(&compute)?),
+        ("accuracy_feedback", AsDatum::accuracy_report(&accuracy)?),
+        ("coalesced", coalesced),
+        ("handshake", AsDatum::handshake(Compression::Lz4)?),
+        ("delivery_ack", AsDatum::delivery_ack(123_456)?),
+        ("detections", AsDatum::detections(&detections)?),
+        ("thumbnail", AsDatum::thumbnail(pattern(48))),
+        ("historical", AsDatum::historical(1, 40, pattern(24))),
+        ("join", AsDatum::join(7, true)?),
+        ("control", AsDatum::control(&ControlMessage::PinLevel(None))?),
+    ];
+    Ok(data.into_iter().map(|(name, d)| (name, stamped(d))).collect())
+}
+
+/// Returns the bytes of `d` as a suite encodes it.
+fn encode(d: &AsDatum, format: WireFormat, compression: Compression) -> Result<BytesMut> {
+    let mut codec = AsCodec::default();
+    codec.set_format(format);
+    let mut buf = BytesMut::new();
+    if compression != Compression::None {
+        codec.encode(stamped(AsDatum::handshake(compression)?), &mut buf)?;
+    }
+    codec.encode(d.clone(), &mut buf)?;
+    Ok(buf)
+}
+
+/// Returns the last datum in `bytes`.
+fn decode_last(bytes: &[u8]) -> Result<Option<AsDatum>> {
+    let mut buf = BytesMut::from(bytes);
+    let mut codec = AsCodec::default();
+    let mut last = None;
+    while let Some(d) = codec.decode(&mut buf)? {
+        last = Some(d);
+    }
+    Ok(last)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn describe(suite: &str, name: &str, d: &AsDatum) -> Vector {
+    let (level, frame_num) = match d.t {
+        AsDatumType::Live(level, frame_num) |
+        AsDatumType::Historical(level, frame_num) => (Some(level), Some(frame_num)),
+        _ => (None, None),
+    };
+    Vector {
+        suite: suite.to_string(),
+        name: name.to_string(),
+        file: format!("{}/{}.bin", suite, name),
+        type_code: wire::type_code(d.t),
+        level,
+        frame_num,
+        stream_id: d.stream_id,
+        seq: d.seq,
+        ts_secs: d.ts.timestamp(),
+        ts_nanos: d.ts.timestamp_subsec_nanos(),
+        expected: d.expected,
+        queue_delay: d.queue_delay,
+        ttl: d.ttl,
+        payload: hex(&d.mem),
+    }
+}
+
+/// Writes the vectors of every suite and `MANIFEST` into `dir`. Returns the
+/// vectors.
+pub fn write_vectors<P: AsRef<Path>>(dir: P) -> Result<Vec<Vector>> {
+    let dir = dir.as_ref();
+    let data = reference_data()?;
+    let mut vectors = Vec::new();
+    for &(format, compression) in &SUITES {
+        let suite = suite_name(format, compression);
+        fs::create_dir_all(dir.join(&suite))?;
+        for &(name, ref d) in &data {
+            // What the vector decodes to, e.g. a receiver report in bincode
+            // in the legacy suites.
+            let bytes = encode(d, format, compression)?;
+            let decoded = decode_last(&bytes)?.expect("a vector holds a datum");
+            let vector = describe(&suite, name, &decoded);
+            fs::write(dir.join(&vector.file), bytes)?;
+            vectors.push(vector);
+        }
+    }
+    serde_json::to_writer_pretty(File::create(dir.join(MANIFEST))?, &vectors)?;
+    Ok(vectors)
+}
+
+/// Checks the vectors in `dir` (see `MANIFEST`) against this implementation.
+/// Returns what doesn't match, one line per vector, empty if all do.
+pub fn check_vectors<P: AsRef<Path>>(dir: P) -> Result<Vec<String>> {
+    let dir = dir.as_ref();
+    let vectors: Vec<Vector> = serde_json::from_reader(File::open(dir.join(MANIFEST))?)?;
+    let data = reference_data()?;
+    let mut mismatches = Vec::new();
+    for vector in &vectors {
+        let id = format!("{}/{}", vector.suite, vector.name);
+        let bytes = fs::read(dir.join(&vector.file))?;
+
+        // Decodes to what the manifest says.
+        match decode_last(&bytes) {
+            Ok(Some(ref d)) if describe(&vector.suite, &vector.name, d) != *vector => {
+                let actual = describe(&vector.suite, &vector.name, d);
+                mismatches.push(format!("{}: decodes to {:?}", id, actual));
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => mismatches.push(format!("{}: holds no datum", id)),
+            Err(e) => mismatches.push(format!("{}: fails to decode: {}", id, e)),
+        }
+
+        // Is what this implementation encodes.
+        let suite = SUITES
+            .iter()
+            .find(|&&(format, compression)| suite_name(format, compression) == vector.suite);
+        let reference = data.iter().find(|&&(name, _)| name == vector.name);
+        match (suite, reference) {
+            (Some(&(format, compression)), Some(&(_, ref d))) => {
+                let expected = encode(d, format, compression)?;
+                let differs = (0..expected.len()).find(|&i| bytes.get(i) != Some(&expected[i]));
+                if let Some(at) = differs {
+                    mismatches.push(format!("{}: differs from the reference at byte {}", id, at));
+                } else if bytes.len() != expected.len() {
+                    let past = bytes.len() - expected.len();
+                    mismatches.push(format!("{}: {} bytes past the reference", id, past));
+                }
+            }
+            _ => mismatches.push(format!("{}: no such suite or datum in this implementation", id)),
+        }
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evaluation::testing::ScratchDir;
+
+    #[test]
+    fn vectors_check_out() {
+        let dir = ScratchDir::new("vectors");
+        let vectors = write_vectors(dir.path()).unwrap();
+        assert_eq!(vectors.len(), SUITES.len() * reference_data().unwrap().len());
+        assert!(check_vectors(dir.path()).unwrap().is_empty());
+
+        // One byte off in the payload of a live datum.
+        let path = dir.join("stable/live.bin");
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(&path, bytes).unwrap();
+        let mismatches = check_vectors(dir.path()).unwrap();
+        assert_eq!(mismatches.len(), 2);
+        assert!(mismatches.iter().all(|m| m.starts_with("stable/live:")));
+    }
+
+    #[test]
+    fn committed_vectors_check_out() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("vectors");
+        let mismatches = check_vectors(&dir).unwrap();
+        assert!(mismatches.is_empty(), "{:?}", mismatches);
+
+        // Every datum decodes to the reference, not only to the manifest.
+        let data = reference_data().unwrap();
+        let vectors: Vec<Vector> =
+            serde_json::from_reader(File::open(dir.join(MANIFEST)).unwrap()).unwrap();
+        assert_eq!(vectors.len(), 16);
+        for vector in &vectors {
+            let &(_, ref d) = data.iter().find(|&&(name, _)| name == vector.name).unwrap();
+            let decoded = decode_last(&fs::read(dir.join(&vector.file)).unwrap()).unwrap().unwrap();
+            assert_eq!(decoded.t, d.t, "{}", vector.file);
+            assert_eq!((decoded.ts, decoded.seq, decoded.stream_id), (d.ts, d.seq, d.stream_id));
+            assert_eq!((decoded.expected, decoded.queue_delay, decoded.ttl),
+                       (d.expected, d.queue_delay, d.ttl));
+            if d.t != AsDatumType::ReceiverCongest {
+                assert_eq!(decoded.mem, d.mem, "{}", vector.file);
+            }
+        }
+    }
+}
@@ -1,81 +1,575 @@
 use super::Adapt;
 use super::Experiment;
-use super::profile::{Profile, SimpleProfile};
+use super::evaluation::{self, Stat, StatIndex};
+use super::errors::*;
+use super::profile::{Profile, Selection, SimpleProfile};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use csv;
-use std::collections::BTreeMap;
-use std::path::Path;
+use memmap::Mmap;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
 
+/// A video configuration (one level of the profile).
 #[derive(Serialize, Deserialize)]
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 pub struct VideoConfig {
+    /// Frame width in pixels.
     pub width: usize,
+
+    /// Frames skipped between two frames sent.
     pub skip: usize,
+
+    /// Quantization parameter of the encoder.
     pub quant: usize,
 }
 
+impl From<evaluation::VideoConfig> for VideoConfig {
+    fn from(c: evaluation::VideoConfig) -> VideoConfig {
+        VideoConfig {
+            width: c.width,
+            skip: c.skip,
+            quant: c.quant,
+        }
+    }
+}
+
+impl From<VideoConfig> for evaluation::VideoConfig {
+    fn from(c: VideoConfig) -> evaluation::VideoConfig {
+        evaluation::VideoConfig::new(c.width, c.skip, c.quant)
+    }
+}
+
 impl ::std::fmt::Display for VideoConfig {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         write!(f, "{}x{}x{}", self.width, self.skip, self.quant)
     }
 }
 
+/// Returns the highest level of `configs` whose frame rate, of a source of
+/// `fps`, is at most `processed` frames a second; the lowest if none is.
+pub fn sustainable_level(configs: &[VideoConfig], fps: f64, processed: f64) -> usize {
+    configs
+        .iter()
+        .rposition(|c| fps / (c.skip + 1) as f64 <= processed)
+        .unwrap_or(0)
+}
+
+/// Frame sizes of a single configuration, indexed by frame number. Sizes are
+/// stored contiguously as `u32` so that a lookup is a plain array access,
+/// either in memory or in a mapped packed source (see `pack_source`).
+enum Shard {
+    Owned(Vec<u32>),
+    Mapped {
+        map: Arc<Mmap>,
+        offset: usize,
+        len: usize,
+    },
+}
+
+impl Default for Shard {
+    fn default() -> Shard {
+        Shard::Owned(Vec::new())
+    }
+}
+
+impl Shard {
+    /// Marks a frame that is absent from the source.
+    const MISSING: u32 = u32::MAX;
+
+    /// Sets the size of `frame`. A size that doesn't fit in a `u32` (or is
+    /// `MISSING`) is an error, rather than a size silently cut short.
+    fn insert(&mut self, frame: usize, size: usize) -> Result<()> {
+        let stored = match u32::try_from(size) {
+            Ok(stored) if stored != Shard::MISSING => stored,
+            _ => bail!("frame {} of {} bytes is too large for a source", frame, size),
+        };
+        match *self {
+            Shard::Owned(ref mut sizes) => {
+                if frame >= sizes.len() {
+                    sizes.resize(frame + 1, Shard::MISSING);
+                }
+                sizes[frame] = stored;
+            }
+            Shard::Mapped { .. } => panic!("a mapped shard is read-only"),
+        }
+        Ok(())
+    }
+
+    /// Returns the size of `frame` as stored, `MISSING` included.
+    fn raw(&self, frame: usize) -> Option<u32> {
+        match *self {
+            Shard::Owned(ref sizes) => sizes.get(frame).cloned(),
+            Shard::Mapped { ref map, offset, len } if frame < len => {
+                Some(LittleEndian::read_u32(&map[offset + frame * 4..]))
+            }
+            Shard::Mapped { .. } => None,
+        }
+    }
+
+    fn get(&self, frame: usize) -> Option<usize> {
+        match self.raw(frame) {
+            Some(size) if size != Shard::MISSING => Some(size as usize),
+            _ => None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Shard::Owned(ref sizes) => sizes.len(),
+            Shard::Mapped { len, .. } => len,
+        }
+    }
+
+    /// Returns the largest frame number in this shard.
+    fn max_frame(&self) -> usize {
+        self.len().saturating_sub(1)
+    }
+}
+
+/// The first bytes of a packed source file.
+pub const PACKED_SOURCE_MAGIC: &[u8; 4] = b"AWSP";
+
+const PACKED_SOURCE_VERSION: u32 = 1;
+const PACKED_HEADER_LEN: usize = 12;
+const PACKED_CONFIG_LEN: usize = 20;
+
+/// Frame rate of a source unless set otherwise.
+pub(crate) const DEFAULT_FPS: f64 = 30.0;
+
+/// Shards kept in memory at most when they are loaded lazily. The ones used
+/// least recently are dropped first, never the one playing.
+const MAX_SHARDS: usize = 8;
+
 pub struct VideoSource {
-    map: BTreeMap<(VideoConfig, usize), usize>,
+    shards: BTreeMap<VideoConfig, Shard>,
+
+    /// If set, shards are loaded lazily from this directory.
+    shard_dir: Option<PathBuf>,
+
+    /// Configurations of the shards loaded lazily, used least recently first.
+    recent: VecDeque<VideoConfig>,
+
+    /// Shards being loaded ahead, in the background, for the levels next to
+    /// the one playing.
+    loading: BTreeMap<VideoConfig, Receiver<Shard>>,
+
     frame: usize,
     num: usize,
+
+    /// How many times to go through the source; `None` loops forever.
+    repeat: Option<usize>,
+
+    /// How many times we have gone through the source.
+    loops: usize,
+
+    /// How long a frame stays useful after it's captured.
+    ttl: Option<Duration>,
+
+    /// Frame rate of the source (before skipping).
+    fps: f64,
+
+    /// Frames skipped since the count was last taken (see `take_skipped`).
+    skipped: usize,
+
     config: VideoConfig,
     profile: Profile<VideoConfig>,
+
+    /// Per-frame accuracy statistics, used to tell what accuracy to expect.
+    stats: StatIndex,
 }
 
 impl VideoSource {
+    /// Creates a new `VideoSource`. `source` is either a single CSV file with
+    /// `(width, skip, quant, frame_num, size)` entries, or a directory of
+    /// per-configuration shards (see `shard_path`) that are only loaded when
+    /// the configuration is first used.
     pub fn new<P>(source: P, profile: P) -> VideoSource
     where
         P: AsRef<Path>,
     {
-        let errmsg = format!("no source file {:?}", source.as_ref());
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .from_path(source)
-            .expect(&errmsg);
-        let mut map = BTreeMap::new();
-        let mut num = 0;
-        for record in rdr.deserialize() {
-            let errmsg = "failed to parse the source";
-            let record: (VideoConfig, usize, usize) = record.expect(errmsg);
-            map.insert((record.0, record.1), record.2);
-            num = ::std::cmp::max(num, record.1);
-        }
-
         let p = Profile::new(profile);
         let init = p.init_config();
-        VideoSource {
-            map: map,
-            frame: 1,
-            num: num,
-            config: init,
-            profile: p,
+
+        let mut source = if source.as_ref().is_dir() {
+            VideoSource {
+                shards: BTreeMap::new(),
+                shard_dir: Some(source.as_ref().to_path_buf()),
+                recent: VecDeque::new(),
+                loading: BTreeMap::new(),
+                frame: 1,
+                num: 0,
+                repeat: None,
+                loops: 0,
+                ttl: None,
+                fps: DEFAULT_FPS,
+                skipped: 0,
+                config: init,
+                profile: p,
+                stats: StatIndex::default(),
+            }
+        } else {
+            let shards = load_source(source);
+            let num = shards.values().map(Shard::max_frame).max().unwrap_or(0);
+            VideoSource {
+                shards,
+                shard_dir: None,
+                recent: VecDeque::new(),
+                loading: BTreeMap::new(),
+                frame: 1,
+                num,
+                repeat: None,
+                loops: 0,
+                ttl: None,
+                fps: DEFAULT_FPS,
+                skipped: 0,
+                config: init,
+                profile: p,
+                stats: StatIndex::default(),
+            }
+        };
+        source.ensure_loaded(init);
+        source
+    }
+
+    /// Makes sure frame sizes for `config` are available, loading its shard if
+    /// necessary (waiting for it if it is being loaded ahead).
+    fn ensure_loaded(&mut self, config: VideoConfig) {
+        let dir = match self.shard_dir {
+            Some(ref dir) => dir.clone(),
+            None => return,
+        };
+        if !self.shards.contains_key(&config) {
+            // A load that failed in the background fails here again, with
+            // its error.
+            let shard = self.loading
+                .remove(&config)
+                .and_then(|loading| loading.recv().ok())
+                .unwrap_or_else(|| load_shard(shard_path(&dir, config)));
+            self.num = ::std::cmp::max(self.num, shard.max_frame());
+            self.shards.insert(config, shard);
+        }
+        self.recent.retain(|c| *c != config);
+        self.recent.push_back(config);
+        while self.recent.len() > MAX_SHARDS {
+            let playing = self.config;
+            match self.recent.iter().position(|c| *c != playing) {
+                Some(i) => {
+                    let evicted = self.recent.remove(i).expect("position within recent");
+                    self.shards.remove(&evicted);
+                }
+                None => break,
+            }
         }
     }
 
-    pub fn next_frame(&mut self) -> (usize, usize) {
-        let frame_size = self.map.get(&(self.config, self.frame)).expect(&format!(
-            "Source file corrupted. Failed to find frame size for {}@{}",
-            self.config,
-            self.frame
-        ));
-        let frame_num = self.frame;
-        self.frame += 1;
+    /// Starts loading the shards of the levels next to `level` in the
+    /// background, so that the adaptation rarely waits for a shard, and
+    /// stops loading any others.
+    fn load_ahead(&mut self, level: usize) {
+        let dir = match self.shard_dir {
+            Some(ref dir) => dir.clone(),
+            None => return,
+        };
+        let ahead = {
+            let records = self.profile.records();
+            [level.checked_sub(1), Some(level + 1)]
+                .iter()
+                .filter_map(|l| l.and_then(|l| records.get(l)))
+                .map(|r| r.config)
+                .collect::<Vec<_>>()
+        };
+        self.loading.retain(|c, _| ahead.contains(c));
+        for config in ahead {
+            if self.shards.contains_key(&config) || self.loading.contains_key(&config) {
+                continue;
+            }
+            let (tx, rx) = mpsc::channel();
+            let path = shard_path(&dir, config);
+            thread::spawn(move || {
+                let _ = tx.send(load_shard(path));
+            });
+            self.loading.insert(config, rx);
+        }
+    }
+
+    /// Switches to a new configuration. Playback continues at the next frame
+    /// the new configuration keeps: with skip `s`, frames 1, 1 + (s + 1), ...
+    fn set_config(&mut self, config: VideoConfig) {
+        self.ensure_loaded(config);
+        self.config = config;
+        let level = self.profile.current_level();
+        self.load_ahead(level);
+
+        let step = config.skip + 1;
+        let offset = (self.frame - 1) % step;
+        if offset != 0 {
+            self.frame += step - offset;
+            self.skipped += step - offset;
+            self.wrap();
+        }
+    }
+
+    /// Goes back to the first frame once past the last one.
+    fn wrap(&mut self) {
         if self.frame >= self.num {
             self.frame = 1;
+            self.loops += 1;
+        }
+    }
+
+    /// Loads per-frame accuracy statistics (`stat.csv` or its index) so that
+    /// outgoing data can carry the accuracy expected at the chosen level.
+    pub fn load_stats<P: AsRef<Path>>(&mut self, path: P) {
+        self.stats = StatIndex::open(&path).unwrap_or_else(|e| {
+            panic!("failed to load stats {:?}: {}", path.as_ref(), e)
+        });
+    }
+
+    /// Sets how many times to go through the source before signaling the end
+    /// of data. `None` loops forever.
+    pub fn set_repeat(&mut self, repeat: Option<usize>) {
+        self.repeat = repeat;
+    }
+
+    /// Sets how long a frame stays useful after it's captured. `None` means
+    /// frames never expire.
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    /// Sets the frame rate of the source (before skipping).
+    pub fn set_fps(&mut self, fps: f64) {
+        self.fps = fps;
+    }
+
+    /// Sets how the profile picks levels for a bandwidth.
+    pub fn set_selection(&mut self, selection: Selection) {
+        self.profile.set_selection(selection);
+    }
+
+    /// Returns the size and number of the next frame, or `None` after the
+    /// source has been repeated enough times.
+    pub fn next_frame(&mut self) -> Option<(usize, usize)> {
+        if let Some(repeat) = self.repeat {
+            if self.loops >= repeat {
+                return None;
+            }
+        }
+
+        let frame_size = self.shards
+            .get(&self.config)
+            .and_then(|shard| shard.get(self.frame))
+            .unwrap_or_else(|| {
+                panic!(
+                    "Source file corrupted. Failed to find frame size for {}@{}",
+                    self.config,
+                    self.frame
+                )
+            });
+        let frame_num = self.frame;
+        self.frame += self.config.skip + 1;
+        self.skipped += self.config.skip;
+        self.wrap();
+        Some((frame_size, frame_num))
+    }
+}
+
+/// Frames of the source that lack a size or a stat for some configuration.
+pub struct Coverage {
+    /// Number of frames in the source.
+    pub frames: usize,
+
+    /// (configuration, frame) pairs without a frame size.
+    pub missing_sizes: Vec<(VideoConfig, usize)>,
+
+    /// (configuration, frame) pairs without a per-frame stat.
+    pub missing_stats: Vec<(VideoConfig, usize)>,
+}
+
+impl VideoSource {
+    /// Returns the profile of this source.
+    pub fn profile(&self) -> &Profile<VideoConfig> {
+        &self.profile
+    }
+
+    /// Cross-checks that every configuration in the profile has a size and a
+    /// stat for every frame the source will play. Loads all shards.
+    pub fn coverage(&mut self) -> Coverage {
+        let configs = self.profile
+            .records()
+            .iter()
+            .map(|r| r.config)
+            .collect::<Vec<_>>();
+
+        // Every shard is checked once loaded, as they may not all be kept; the
+        // frames past its end are only known once all are loaded.
+        let mut shards = Vec::new();
+        for &config in &configs {
+            self.ensure_loaded(config);
+            let (len, missing) = match self.shards.get(&config) {
+                Some(shard) => {
+                    let missing = (1..shard.len()).filter(|&f| shard.get(f).is_none());
+                    (shard.len(), missing.collect())
+                }
+                None => (0, Vec::new()),
+            };
+            shards.push((config, len, missing));
         }
-        (*frame_size, frame_num)
+
+        let mut coverage = Coverage {
+            frames: self.num.saturating_sub(1),
+            missing_sizes: Vec::new(),
+            missing_stats: Vec::new(),
+        };
+        for (config, len, missing) in shards {
+            let past_end = ::std::cmp::max(len, 1)..self.num;
+            coverage.missing_sizes.extend(missing.into_iter().chain(past_end).map(|f| (config, f)));
+            for frame in 1..self.num {
+                if self.stats.get(frame, config.into()).is_none() {
+                    coverage.missing_stats.push((config, frame));
+                }
+            }
+        }
+        coverage
     }
 }
 
+/// Returns the path of the shard for `config` within `dir`.
+pub fn shard_path<P: AsRef<Path>>(dir: P, config: VideoConfig) -> PathBuf {
+    dir.as_ref().join(format!("source-{}.csv", config))
+}
+
+/// Loads a monolithic source file into per-configuration shards. A packed
+/// source is mapped instead, so that processes on one machine share it.
+fn load_source<P: AsRef<Path>>(path: P) -> BTreeMap<VideoConfig, Shard> {
+    if is_packed(&path) {
+        return map_source(&path).unwrap_or_else(|e| {
+            panic!("failed to map source {:?}: {}", path.as_ref(), e)
+        });
+    }
+    let errmsg = format!("no source file {:?}", path.as_ref());
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(path)
+        .expect(&errmsg);
+    let mut shards = BTreeMap::new();
+    for record in rdr.deserialize() {
+        let errmsg = "failed to parse the source";
+        let record: (VideoConfig, usize, usize) = record.expect(errmsg);
+        shards
+            .entry(record.0)
+            .or_insert_with(Shard::default)
+            .insert(record.1, record.2)
+            .expect(errmsg);
+    }
+    shards
+}
+
+fn is_packed<P: AsRef<Path>>(path: P) -> bool {
+    let mut magic = [0; 4];
+    match File::open(path).and_then(|mut f| f.read_exact(&mut magic)) {
+        Ok(()) => &magic == PACKED_SOURCE_MAGIC,
+        Err(_) => false,
+    }
+}
+
+/// Maps a packed source: a header (magic, version, number of configurations),
+/// a table of (width, skip, quant, first size, number of sizes) per
+/// configuration, and the sizes, indexed by frame. All little-endian `u32`.
+fn map_source<P: AsRef<Path>>(path: P) -> io::Result<BTreeMap<VideoConfig, Shard>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let file = File::open(path)?;
+    // Packed sources are written once; they must not change while mapped.
+    let map = Arc::new(unsafe { Mmap::map(&file)? });
+    if map.len() < PACKED_HEADER_LEN || &map[..4] != PACKED_SOURCE_MAGIC {
+        return Err(invalid("not a packed source"));
+    }
+    if LittleEndian::read_u32(&map[4..]) != PACKED_SOURCE_VERSION {
+        return Err(invalid("unsupported packed source version"));
+    }
+    let configs = LittleEndian::read_u32(&map[8..]) as usize;
+    let sizes_start = PACKED_HEADER_LEN + configs * PACKED_CONFIG_LEN;
+    if map.len() < sizes_start {
+        return Err(invalid("truncated packed source"));
+    }
+    let sizes = (map.len() - sizes_start) / 4;
+
+    let mut shards = BTreeMap::new();
+    for i in 0..configs {
+        let entry = &map[PACKED_HEADER_LEN + i * PACKED_CONFIG_LEN..];
+        let field = |n: usize| LittleEndian::read_u32(&entry[n * 4..]) as usize;
+        let config = VideoConfig {
+            width: field(0),
+            skip: field(1),
+            quant: field(2),
+        };
+        let (first, len) = (field(3), field(4));
+        if first + len > sizes {
+            return Err(invalid("packed source points past its sizes"));
+        }
+        let shard = Shard::Mapped {
+            map: map.clone(),
+            offset: sizes_start + first * 4,
+            len,
+        };
+        shards.insert(config, shard);
+    }
+    Ok(shards)
+}
+
+/// Packs a source file (see `VideoSource::new`) into `out`, which loads
+/// without parsing and is shared among processes on one machine.
+pub fn pack_source<P: AsRef<Path>, Q: AsRef<Path>>(source: P, out: Q) -> Result<()> {
+    let shards = load_source(source);
+    let mut writer = BufWriter::new(File::create(out)?);
+    writer.write_all(PACKED_SOURCE_MAGIC)?;
+    writer.write_u32::<LittleEndian>(PACKED_SOURCE_VERSION)?;
+    writer.write_u32::<LittleEndian>(shards.len() as u32)?;
+    let mut first = 0;
+    for (config, shard) in &shards {
+        for &field in &[config.width, config.skip, config.quant, first, shard.len()] {
+            let field = u32::try_from(field)
+                .map_err(|_| format!("{} is too large for a packed source", field))?;
+            writer.write_u32::<LittleEndian>(field)?;
+        }
+        first += shard.len();
+    }
+    for shard in shards.values() {
+        for frame in 0..shard.len() {
+            let size = shard.raw(frame).unwrap_or(Shard::MISSING);
+            writer.write_u32::<LittleEndian>(size)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Loads a shard file with `(frame_num, size)` entries.
+fn load_shard<P: AsRef<Path>>(path: P) -> Shard {
+    let errmsg = format!("no source shard {:?}", path.as_ref());
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(path)
+        .expect(&errmsg);
+    let mut shard = Shard::default();
+    for record in rdr.deserialize() {
+        let record: (usize, usize) = record.expect("failed to parse the source shard");
+        shard.insert(record.0, record.1).expect("failed to parse the source shard");
+    }
+    shard
+}
+
 impl Adapt for VideoSource {
     fn adapt(&mut self, bw: f64) {
         match self.profile.adjust_config(bw) {
-            Some(c) => self.config = c.config,
+            Some(c) => self.set_config(c.config),
             None => {}
         }
     }
@@ -86,22 +580,251 @@ impl Adapt for VideoSource {
 
     fn dec_degradation(&mut self) {
         match self.profile.advance_config() {
-            Some(c) => self.config = c.config,
+            Some(c) => self.set_config(c.config),
             None => {}
         }
     }
 
+    fn set_level(&mut self, level: usize) {
+        let record = self.profile.set_level(level);
+        self.set_config(record.config);
+    }
+
     fn simple_profile(&self) -> SimpleProfile {
         self.profile.simplify()
     }
 
+    fn reload(&mut self, mut profile: Profile<VideoConfig>, level: usize) {
+        // A configuration this source can't play would panic once chosen, so
+        // such a profile is refused and the current one kept.
+        if let Some(ref dir) = self.shard_dir {
+            let missing = profile
+                .records()
+                .iter()
+                .map(|r| r.config)
+                .find(|c| !self.shards.contains_key(c) && !shard_path(dir, *c).exists());
+            if let Some(config) = missing {
+                error!("profile not reloaded: no source shard for {}", config);
+                return;
+            }
+        }
+        profile.set_selection(self.profile.simplify().selection());
+        let old = ::std::mem::replace(&mut self.profile, profile);
+        let coverage = self.coverage();
+        if let Some(&(config, frame)) = coverage.missing_sizes.first() {
+            error!(
+                "profile not reloaded: no frame size for {}@{} ({} missing)",
+                config,
+                frame,
+                coverage.missing_sizes.len()
+            );
+            self.profile = old;
+            return;
+        }
+        self.set_level(level);
+    }
+
+    fn configs(&self) -> Vec<VideoConfig> {
+        self.profile.records().iter().map(|r| r.config).collect()
+    }
+
     fn period_in_ms(&self) -> u64 {
-        33
+        let period = 1000.0 * (self.config.skip + 1) as f64 / self.fps;
+        ::std::cmp::max(period.round() as u64, 1)
     }
 }
 
 impl Experiment for VideoSource {
-    fn next_datum(&mut self) -> (usize, usize) {
+    fn next_datum(&mut self) -> Option<(usize, usize)> {
         self.next_frame()
     }
+
+    fn expected_stat(&self, frame_num: usize) -> Option<Stat> {
+        self.stats.get(frame_num, self.config.into())
+    }
+
+    fn datum_size_at(&mut self, level: usize, frame_num: usize) -> Option<usize> {
+        let config = self.profile.records().get(level)?.config;
+        self.ensure_loaded(config);
+        self.shards.get(&config).and_then(|shard| shard.get(frame_num))
+    }
+
+    fn expected_stat_at(&self, level: usize, frame_num: usize) -> Option<Stat> {
+        let config = self.profile.records().get(level)?.config;
+        self.stats.get(frame_num, config.into())
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    fn take_skipped(&mut self) -> usize {
+        ::std::mem::replace(&mut self.skipped, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evaluation::testing::ScratchDir;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn sustainable_level_follows_frame_rate() {
+        let config = |width, skip| VideoConfig {
+            width,
+            skip,
+            quant: 20,
+        };
+        let configs = [config(320, 5), config(320, 2), config(640, 2), config(640, 0)];
+        assert_eq!(sustainable_level(&configs, 30.0, 30.0), 3);
+        assert_eq!(sustainable_level(&configs, 30.0, 12.0), 2);
+        assert_eq!(sustainable_level(&configs, 30.0, 6.0), 0);
+        assert_eq!(sustainable_level(&configs, 30.0, 1.0), 0);
+    }
+
+    #[test]
+    fn repeat_ends_the_source() {
+        let dir = ScratchDir::new("video-repeat");
+        let profile = dir.join("profile.csv");
+        let source = dir.join("source.csv");
+        fs::write(&profile, "100,320,0,30,0.5\n").unwrap();
+        let frames = (1..5).map(|frame| format!("320,0,30,{},10\n", frame));
+        fs::write(&source, frames.collect::<String>()).unwrap();
+
+        // Without a repeat count, the source loops forever.
+        let mut video = VideoSource::new(&source, &profile);
+        let looped = (0..100).filter_map(|_| video.next_frame()).count();
+        assert_eq!(looped, 100);
+
+        let mut video = VideoSource::new(&source, &profile);
+        video.set_repeat(Some(2));
+        let frames = (0..100).map_while(|_| video.next_frame()).map(|(_, f)| f);
+        assert_eq!(frames.collect::<Vec<_>>(), vec![1, 2, 3, 1, 2, 3]);
+        assert_eq!(video.next_frame(), None);
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut shard = Shard::default();
+        shard.insert(1, 1000).unwrap();
+        assert!(shard.insert(2, u32::MAX as usize).is_err());
+        assert!(shard.insert(3, u32::MAX as usize + 1).is_err());
+        assert_eq!((shard.get(1), shard.get(2), shard.len()), (Some(1000), None, 2));
+    }
+
+    #[test]
+    fn frames_follow_skip() {
+        let dir = ScratchDir::new("video-source");
+        let profile = dir.join("profile.csv");
+        let source = dir.join("source.csv");
+        fs::write(&profile, "100,320,2,30,0.5\n200,640,0,30,0.8\n").unwrap();
+        {
+            let mut f = fs::File::create(&source).unwrap();
+            for frame in 1..14 {
+                let size = if (frame - 1) % 3 == 0 { 10 } else { 0 };
+                writeln!(f, "320,2,30,{},{}", frame, size).unwrap();
+                writeln!(f, "640,0,30,{},20", frame).unwrap();
+            }
+        }
+
+        let mut video = VideoSource::new(&source, &profile);
+        assert_eq!(video.period_in_ms(), 100);
+        assert_eq!(video.next_frame(), Some((10, 1)));
+        assert_eq!(video.next_frame(), Some((10, 4)));
+        assert_eq!(video.take_skipped(), 4);
+
+        video.set_level(1);
+        assert_eq!(video.period_in_ms(), 33);
+        assert_eq!(video.next_frame(), Some((20, 7)));
+        assert_eq!(video.next_frame(), Some((20, 8)));
+
+        // Frame 9 is skipped at level 0.
+        video.set_level(0);
+        assert_eq!(video.next_frame(), Some((10, 10)));
+        assert_eq!(video.take_skipped(), 3);
+
+        video.set_fps(15.0);
+        assert_eq!(video.period_in_ms(), 200);
+
+        // A packed source plays the same.
+        let packed = dir.join("source.bin");
+        pack_source(&source, &packed).unwrap();
+        let mut video = VideoSource::new(&packed, &profile);
+        assert_eq!(video.next_frame(), Some((10, 1)));
+        video.set_level(1);
+        assert_eq!(video.next_frame(), Some((20, 4)));
+        assert_eq!(video.datum_size_at(0, 7), Some(10));
+        assert_eq!(video.datum_size_at(0, 14), None);
+    }
+
+    #[test]
+    fn lazy_shards_are_loaded_ahead_and_bounded() {
+        let dir = ScratchDir::new("video-shards");
+        let profile = dir.join("profile.csv");
+        let levels = MAX_SHARDS + 4;
+        let mut rows = String::new();
+        for level in 0..levels {
+            let config = VideoConfig {
+                width: 320,
+                skip: 0,
+                quant: 40 - level,
+            };
+            rows.push_str(&format!("{},320,0,{},0.5\n", 100 * (level + 1), config.quant));
+            let sizes = (1..6).map(|frame| format!("{},{}\n", frame, 10 * level + frame));
+            fs::write(shard_path(dir.path(), config), sizes.collect::<String>()).unwrap();
+        }
+        fs::write(&profile, rows).unwrap();
+        let mut video = VideoSource::new(dir.path(), &profile);
+
+        // The next level up is on its way before it's chosen.
+        video.set_level(0);
+        let next = video.configs()[1];
+        assert!(video.loading.contains_key(&next));
+        video.set_level(1);
+        assert!(video.shards.contains_key(&next) && !video.loading.contains_key(&next));
+
+        // Going through every level keeps only so many shards, and those
+        // dropped are loaded again when needed.
+        for level in 0..levels {
+            video.set_level(level);
+            assert!(video.shards.len() <= MAX_SHARDS);
+        }
+        assert_eq!(video.next_frame(), Some((10 * (levels - 1) + 1, 1)));
+        for level in 0..levels {
+            assert_eq!(video.datum_size_at(level, 2), Some(10 * level + 2));
+            assert!(video.shards.len() <= MAX_SHARDS);
+            assert!(video.shards.contains_key(&video.config));
+        }
+        assert!(video.coverage().missing_sizes.is_empty());
+    }
+
+    #[test]
+    fn reload_keeps_a_profile_it_cant_play() {
+        let dir = ScratchDir::new("video-reload");
+        let profile = dir.join("profile.csv");
+        let source = dir.join("source.csv");
+        fs::write(&profile, "100,320,0,30,0.5\n").unwrap();
+        {
+            let mut f = fs::File::create(&source).unwrap();
+            for frame in 1..6 {
+                writeln!(f, "320,0,30,{},10", frame).unwrap();
+                writeln!(f, "640,0,30,{},20", frame).unwrap();
+            }
+        }
+        let mut video = VideoSource::new(&source, &profile);
+        let before = video.configs();
+
+        let unknown = dir.join("unknown.csv");
+        fs::write(&unknown, "100,320,0,30,0.5\n300,1280,0,30,0.9\n").unwrap();
+        video.reload(Profile::new(&unknown), 1);
+        assert_eq!(video.configs(), before);
+
+        let known = dir.join("known.csv");
+        fs::write(&known, "100,320,0,30,0.5\n200,640,0,30,0.8\n").unwrap();
+        video.reload(Profile::new(&known), 1);
+        assert_eq!(video.configs().len(), 2);
+        assert_eq!(video.next_frame(), Some((20, 1)));
+    }
 }
@@ -0,0 +1,297 @@
+//! WebSocket transport, for senders that can't open a plain connection, e.g.
+//! a browser running `awstream_core`. After the opening handshake (an HTTP
+//! Upgrade), the payloads of the binary messages a sender sends are the bytes
+//! of its frames (see `awstream_core::websocket`), and what the server writes
+//! goes out as binary messages.
+
+use awstream_core::websocket::{self, Message, MessageDecoder};
+use base64;
+use futures::{Async, Future, Poll};
+use sha1::Sha1;
+use std::{cmp, mem};
+use std::io::{self, Read, Write};
+use tls::Conn;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::write_all;
+
+/// Appended to the key of a handshake before it is hashed (RFC 6455).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest handshake request taken.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// Returns the `Sec-WebSocket-Accept` answering `key`.
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    base64::encode(&sha1.digest().bytes())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Returns the `Sec-WebSocket-Key` of an Upgrade request.
+fn request_key(request: &[u8]) -> io::Result<String> {
+    let request = ::std::str::from_utf8(request).map_err(|_| invalid("request is not utf-8"))?;
+    let mut lines = request.split("\r\n");
+    match lines.next() {
+        Some(line) if line.starts_with("GET ") => {}
+        _ => return Err(invalid("not a websocket upgrade")),
+    }
+    lines
+        .filter_map(|line| {
+            let mut header = line.splitn(2, ':');
+            match (header.next(), header.next()) {
+                (Some(name), Some(value)) => Some((name.trim(), value.trim())),
+                _ => None,
+            }
+        })
+        .find(|&(name, _)| name.eq_ignore_ascii_case("sec-websocket-key"))
+        .map(|(_, key)| key.to_string())
+        .ok_or_else(|| invalid("no websocket key"))
+}
+
+/// Reads a request up to the blank line that ends its headers, and returns it
+/// with the bytes read past it.
+struct ReadRequest {
+    conn: Option<Conn>,
+    buf: Vec<u8>,
+}
+
+impl Future for ReadRequest {
+    type Item = (Conn, Vec<u8>, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            if let Some(end) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let rest = self.buf.split_off(end + 4);
+                let request = mem::replace(&mut self.buf, Vec::new());
+                let conn = self.conn.take().expect("poll a request after it is read");
+                return Ok(Async::Ready((conn, request, rest)));
+            }
+            if self.buf.len() > MAX_REQUEST {
+                return Err(invalid("websocket request is too long"));
+            }
+            let mut chunk = [0; 1024];
+            let conn = self.conn.as_mut().expect("poll a request after it is read");
+            let n = try_nb!(conn.read(&mut chunk));
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no websocket request"));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+/// Completes the opening handshake a sender starts on `conn`, and returns the
+/// connection that carries its messages.
+pub fn accept(conn: Conn) -> Box<dyn Future<Item = Conn, Error = io::Error>> {
+    let request = ReadRequest {
+        conn: Some(conn),
+        buf: Vec::new(),
+    };
+    let upgraded = request.and_then(|(conn, request, rest)| {
+        let key = request_key(&request)?;
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key)
+        );
+        Ok(write_all(conn, response.into_bytes()).map(move |(conn, _)| {
+            let mut messages = MessageDecoder::new();
+            messages.push(&rest);
+            Conn::WebSocket(Box::new(WebSocket::new(conn, messages)))
+        }))
+    });
+    Box::new(upgraded.flatten())
+}
+
+/// A connection past its opening handshake, which reads the payloads of the
+/// binary messages it receives and writes binary messages.
+pub struct WebSocket {
+    conn: Conn,
+
+    /// Messages received but not read yet.
+    messages: MessageDecoder,
+
+    /// Payload of the message being read.
+    unread: Vec<u8>,
+
+    /// Messages not written in full yet (pongs included).
+    unwritten: Vec<u8>,
+
+    /// The sender has closed the connection.
+    closed: bool,
+}
+
+impl WebSocket {
+    fn new(conn: Conn, messages: MessageDecoder) -> WebSocket {
+        WebSocket {
+            conn,
+            messages,
+            unread: Vec::new(),
+            unwritten: Vec::new(),
+            closed: false,
+        }
+    }
+
+    /// Returns the connection the messages go over.
+    pub fn get_ref(&self) -> &Conn {
+        &self.conn
+    }
+
+    fn write_out(&mut self) -> io::Result<()> {
+        while !self.unwritten.is_empty() {
+            let n = self.conn.write(&self.unwritten)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write message"));
+            }
+            self.unwritten.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl Read for WebSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.unread.is_empty() {
+                let n = cmp::min(buf.len(), self.unread.len());
+                buf[..n].copy_from_slice(&self.unread[..n]);
+                self.unread.drain(..n);
+                return Ok(n);
+            }
+            if self.closed {
+                return Ok(0);
+            }
+            match self.messages.next_message() {
+                Some(Ok(Message::Binary(payload))) => self.unread = payload,
+                // Answered along with what is written next.
+                Some(Ok(Message::Ping(payload))) => {
+                    websocket::encode_pong(&payload, None, &mut self.unwritten)
+                }
+                Some(Ok(Message::Pong(_))) => {}
+                Some(Ok(Message::Close)) => self.closed = true,
+                Some(Err(e)) => return Err(invalid(&format!("bad websocket message: {:?}", e))),
+                None => {
+                    let mut chunk = [0; 8 * 1024];
+                    let n = self.conn.read(&mut chunk)?;
+                    if n == 0 {
+                        self.closed = true;
+                    }
+                    self.messages.push(&chunk[..n]);
+                }
+            }
+        }
+    }
+}
+
+impl Write for WebSocket {
+    /// Takes all of `buf` (up to the largest message) once what was taken
+    /// before has been written.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_out()?;
+        let buf = &buf[..cmp::min(buf.len(), websocket::MAX_MESSAGE_LEN)];
+        websocket::encode(buf, None, &mut self.unwritten);
+        match self.write_out() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            result => result?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_out()?;
+        self.conn.flush()
+    }
+}
+
+impl AsyncRead for WebSocket {}
+
+impl AsyncWrite for WebSocket {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.write_out());
+        self.conn.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AsCodec, AsDatum};
+    use awstream_core::framing::FrameDecoder;
+    use bytes::BytesMut;
+    use futures::Stream;
+    use socket::FramedRead;
+    use std::net;
+    use std::thread;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_io::codec::Encoder;
+
+    #[test]
+    fn handshake_answers_the_key() {
+        // The example of RFC 6455.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let request = b"GET /chat HTTP/1.1\r\nHost: server\r\nUpgrade: websocket\r\n\
+                        sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        assert_eq!(request_key(request).unwrap(), "dGhlIHNhbXBsZSBub25jZQ==");
+        assert!(request_key(b"POST / HTTP/1.1\r\n\r\n").is_err());
+        assert!(request_key(b"GET / HTTP/1.1\r\nHost: server\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn data_go_both_ways_in_messages() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A sender, as a browser would be, sends a datum in two messages.
+        let sender = thread::spawn(move || {
+            let mut tcp = net::TcpStream::connect(addr).unwrap();
+            tcp.write_all(
+                b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            ).unwrap();
+            let mut frame = BytesMut::new();
+            AsCodec::default().encode(AsDatum::new(1, 2, vec![3; 1000]), &mut frame).unwrap();
+            let mut wire = Vec::new();
+            websocket::encode(&frame[..10], Some([1, 2, 3, 4]), &mut wire);
+            websocket::encode(&frame[10..], Some([5, 6, 7, 8]), &mut wire);
+            tcp.write_all(&wire).unwrap();
+
+            // What comes back is the response, then a message of one frame.
+            let mut received = Vec::new();
+            tcp.read_to_end(&mut received).unwrap();
+            let end = received.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            assert!(received.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+            let mut messages = MessageDecoder::new();
+            messages.push(&received[end + 4..]);
+            let mut frames = FrameDecoder::new();
+            match messages.next_message() {
+                Some(Ok(Message::Binary(payload))) => frames.push(&payload),
+                other => panic!("unexpected {:?}", other),
+            }
+            assert!(frames.next_frame().unwrap().is_ok());
+        });
+
+        let accepted = listener.incoming().into_future().map_err(|(e, _)| e);
+        let (tcp, _) = core.run(accepted).unwrap().0.unwrap();
+        let conn = core.run(accept(Conn::Plain(tcp))).unwrap();
+        let (read, mut write) = conn.split();
+        let data = FramedRead::new(read, AsCodec::default());
+        let (datum, _) = core.run(data.into_future()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(datum.unwrap().mem, vec![3; 1000]);
+
+        let mut frame = BytesMut::new();
+        AsCodec::default().encode(AsDatum::new(0, 1, vec![1; 10]), &mut frame).unwrap();
+        write.write_all(&frame).unwrap();
+        drop(write);
+        sender.join().unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+//! A stable wire schema for the header of `AsDatum` and for `ReceiverReport`.
+//! Bincode ties what goes on the wire to the layout of Rust structs and the
+//! order of enum variants: reordering a field breaks every peer built before,
+//! and a peer in another language has to mimic bincode. This schema spells out
+//! every field instead, big endian like the framing, with datum types by fixed
+//! codes (see `type_code`).
+//!
+//! The header of a datum is
+//!
+//! | field        | type  |                                             |
+//! |--------------|-------|---------------------------------------------|
+//! | magic        | u8    | `MAGIC`                                     |
+//! | version      | u8    | `VERSION`                                   |
+//! | header_len   | u16   | bytes of the header, these fields included  |
+//! | type         | u8    | see `type_code`                             |
+//! | flags        | u8    | which of the optional fields follow         |
+//! | stream_id    | u32   |                                             |
+//! | seq          | u64   |                                             |
+//! | ts_secs      | i64   | seconds since the epoch (UTC)               |
+//! | ts_nanos     | u32   |                                             |
+//! | level        | u32   | live and historical data only               |
+//! | frame_num    | u64   | live and historical data only               |
+//! | expected     | 3 u32 | TP, FP, FN, if `FLAG_EXPECTED`              |
+//! | queue_delay  | u64   | microseconds, if `FLAG_QUEUE_DELAY`         |
+//! | ttl          | u64   | microseconds, if `FLAG_TTL`                 |
+//!
+//! Later versions only append fields and grow `header_len`, so that a decoder
+//! skips what it doesn't know. Headers in bincode (`WireFormat::Legacy`) never
+//! start with `MAGIC`: they start with the index of the datum type's variant, a
+//! little endian u32.
+
+use super::{AsDatum, AsDatumType, LatencyBreakdown, ReceiverReport, SequenceStats};
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{TimeZone, Utc};
+use errors::*;
+use evaluation::Stat;
+use std::io::Cursor;
+
+/// The first byte of a header or report in this schema.
+pub const MAGIC: u8 = 0xA5;
+
+/// The version this build writes; bincode counts as version 1.
+pub const VERSION: u8 = 2;
+
+const FLAG_EXPECTED: u8 = 1;
+const FLAG_QUEUE_DELAY: u8 = 1 << 1;
+const FLAG_TTL: u8 = 1 << 2;
+
+/// Bytes of a header without optional fields.
+const BASE_LEN: usize = 30;
+
+/// Bytes of a report in bincode, which never changes size. Reports in this
+/// schema are longer.
+const LEGACY_REPORT_LEN: usize = 64;
+
+/// Bytes of a report in this schema.
+const REPORT_LEN: usize = 2 + LEGACY_REPORT_LEN + 8;
+
+/// How a codec writes headers (see `AsCodec`). It reads both.
+///
+/// ```toml
+/// wire_format = "legacy"
+/// ```
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WireFormat {
+    /// This schema.
+    #[default]
+    Stable,
+
+    /// Bincode, for peers built before this schema. Receiver reports are
+    /// written in bincode too.
+    Legacy,
+}
+
+/// Returns the code of a datum type on the wire. Codes are never reused: 16 was
+/// a level pin, now a `Control` message.
+pub fn type_code(t: AsDatumType) -> u8 {
+    match t {
+        AsDatumType::Live(_, _) => 1,
+        AsDatumType::Raw => 2,
+        AsDatumType::Dummy => 3,
+        AsDatumType::LatencyProbe => 4,
+        AsDatumType::ReceiverCongest => 5,
+        AsDatumType::SenderDrops => 6,
+        AsDatumType::ComputeCongest => 7,
+        AsDatumType::AccuracyFeedback => 8,
+        AsDatumType::Coalesced => 9,
+        AsDatumType::Handshake => 10,
+        AsDatumType::DeliveryAck => 11,
+        AsDatumType::Detections => 12,
+        AsDatumType::Thumbnail => 13,
+        AsDatumType::Historical(_, _) => 14,
+        AsDatumType::Join => 15,
+        AsDatumType::Control => 17,
+    }
+}
+
+fn datum_type(code: u8, level: usize, frame_num: usize) -> Result<AsDatumType> {
+    let t = match code {
+        1 => AsDatumType::Live(level, frame_num),
+        2 => AsDatumType::Raw,
+        3 => AsDatumType::Dummy,
+        4 => AsDatumType::LatencyProbe,
+        5 => AsDatumType::ReceiverCongest,
+        6 => AsDatumType::SenderDrops,
+        7 => AsDatumType::ComputeCongest,
+        8 => AsDatumType::AccuracyFeedback,
+        9 => AsDatumType::Coalesced,
+        10 => AsDatumType::Handshake,
+        11 => AsDatumType::DeliveryAck,
+        12 => AsDatumType::Detections,
+        13 => AsDatumType::Thumbnail,
+        14 => AsDatumType::Historical(level, frame_num),
+        15 => AsDatumType::Join,
+        17 => AsDatumType::Control,
+        _ => bail!("unknown datum type {} on the wire", code),
+    };
+    Ok(t)
+}
+
+fn frame_of(t: AsDatumType) -> Option<(usize, usize)> {
+    match t {
+        AsDatumType::Live(level, frame_num) |
+        AsDatumType::Historical(level, frame_num) => Some((level, frame_num)),
+        _ => None,
+    }
+}
+
+/// Returns the bytes of the header of `d` in this schema.
+pub fn header_len(d: &AsDatum) -> usize {
+    let mut len = BASE_LEN;
+    if frame_of(d.t).is_some() {
+        len += 12;
+    }
+    if d.expected.is_some() {
+        len += 12;
+    }
+    if d.queue_delay.is_some() {
+        len += 8;
+    }
+    if d.ttl.is_some() {
+        len += 8;
+    }
+    len
+}
+
+/// Writes the header of `d` into `buf`.
+pub fn encode_header(d: &AsDatum, buf: &mut BytesMut) {
+    let len = header_len(d);
+    buf.reserve(len);
+    let flags = if d.expected.is_some() { FLAG_EXPECTED } else { 0 } |
+        if d.queue_delay.is_some() { FLAG_QUEUE_DELAY } else { 0 } |
+        if d.ttl.is_some() { FLAG_TTL } else { 0 };
+    buf.put_u8(MAGIC);
+    buf.put_u8(VERSION);
+    buf.put_u16_be(len as u16);
+    buf.put_u8(type_code(d.t));
+    buf.put_u8(flags);
+    buf.put_u32_be(d.stream_id);
+    buf.put_u64_be(d.seq);
+    buf.put_i64_be(d.ts.timestamp());
+    buf.put_u32_be(d.ts.timestamp_subsec_nanos());
+    if let Some((level, frame_num)) = frame_of(d.t) {
+        buf.put_u32_be(level as u32);
+        buf.put_u64_be(frame_num as u64);
+    }
+    if let Some(stat) = d.expected {
+        buf.put_u32_be(stat.true_positive as u32);
+        buf.put_u32_be(stat.false_positive as u32);
+        buf.put_u32_be(stat.false_negative as u32);
+    }
+    if let Some(us) = d.queue_delay {
+        buf.put_u64_be(us);
+    }
+    if let Some(us) = d.ttl {
+        buf.put_u64_be(us);
+    }
+}
+
+/// Reads a header off the front of `buf`. Returns the datum, without its
+/// payload, and the bytes the header takes.
+pub fn decode_header(buf: &[u8]) -> Result<(AsDatum, usize)> {
+    let mut cursor = Cursor::new(buf);
+    if cursor.read_u8()? != MAGIC {
+        bail!("not a header of the stable wire schema");
+    }
+    let version = cursor.read_u8()?;
+    if version < VERSION {
+        bail!("unknown wire schema version {}", version);
+    }
+    let len = cursor.read_u16::<BigEndian>()? as usize;
+    let code = cursor.read_u8()?;
+    let flags = cursor.read_u8()?;
+    let stream_id = cursor.read_u32::<BigEndian>()?;
+    let seq = cursor.read_u64::<BigEndian>()?;
+    let secs = cursor.read_i64::<BigEndian>()?;
+    let nanos = cursor.read_u32::<BigEndian>()?;
+    let ts = match Utc.timestamp_opt(secs, nanos).single() {
+        Some(ts) => ts,
+        None => bail!("invalid timestamp {}.{:09} on the wire", secs, nanos),
+    };
+    let (level, frame_num) = match code {
+        1 | 14 => {
+            let level = cursor.read_u32::<BigEndian>()? as usize;
+            (level, cursor.read_u64::<BigEndian>()? as usize)
+        }
+        _ => (0, 0),
+    };
+    let t = datum_type(code, level, frame_num)?;
+    let expected = if flags & FLAG_EXPECTED != 0 {
+        Some(Stat {
+            true_positive: cursor.read_u32::<BigEndian>()? as usize,
+            false_positive: cursor.read_u32::<BigEndian>()? as usize,
+            false_negative: cursor.read_u32::<BigEndian>()? as usize,
+        })
+    } else {
+        None
+    };
+    let queue_delay = if flags & FLAG_QUEUE_DELAY != 0 {
+        Some(cursor.read_u64::<BigEndian>()?)
+    } else {
+        None
+    };
+    let ttl = if flags & FLAG_TTL != 0 {
+        Some(cursor.read_u64::<BigEndian>()?)
+    } else {
+        None
+    };
+    if (cursor.position() as usize) > len || len > buf.len() {
+        bail!("header of {} bytes does not fit its fields", len);
+    }
+    let datum = AsDatum {
+        t,
+        mem: Bytes::new(),
+        ts,
+        expected,
+        queue_delay,
+        ttl,
+        stream_id,
+        seq,
+        len: 0,
+    };
+    Ok((datum, len))
+}
+
+/// Returns `report` in this schema: `MAGIC`, `VERSION`, then latency,
+/// goodput, throughput and the latency breakdown (queue, network,
+/// processing) as f64, the data lost and reordered as u64, and the mean
+/// processing time of the receiver as f64. Fields are only ever appended.
+pub fn encode_report(report: &ReceiverReport) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(REPORT_LEN);
+    buf.put_u8(MAGIC);
+    buf.put_u8(VERSION);
+    for &v in &[
+        report.latency,
+        report.goodput,
+        report.throughput,
+        report.breakdown.queue,
+        report.breakdown.network,
+        report.breakdown.processing,
+    ]
+    {
+        buf.put_f64_be(v);
+    }
+    buf.put_u64_be(report.sequence.lost);
+    buf.put_u64_be(report.sequence.reordered);
+    buf.put_f64_be(report.processing);
+    buf
+}
+
+/// Reads a report in either schema: bincode if it has its size, this schema
+/// otherwise.
+pub fn decode_report(mem: &[u8]) -> Result<ReceiverReport> {
+    if mem.len() == LEGACY_REPORT_LEN {
+        return Ok(::bincode::deserialize(mem)?);
+    }
+    let mut cursor = Cursor::new(mem);
+    if cursor.read_u8()? != MAGIC || cursor.read_u8()? < VERSION {
+        bail!("not a report of the stable wire schema");
+    }
+    let mut f = || cursor.read_f64::<BigEndian>();
+    let (latency, goodput, throughput) = (f()?, f()?, f()?);
+    let breakdown = LatencyBreakdown {
+        queue: f()?,
+        network: f()?,
+        processing: f()?,
+    };
+    let sequence = SequenceStats {
+        lost: cursor.read_u64::<BigEndian>()?,
+        reordered: cursor.read_u64::<BigEndian>()?,
+    };
+    // Reports from builds before the processing time end here.
+    let processing = if cursor.position() < mem.len() as u64 {
+        cursor.read_f64::<BigEndian>()?
+    } else {
+        0.0
+    };
+    Ok(ReceiverReport::new(latency, goodput, throughput, breakdown, sequence, processing))
+}
+
+/// Rewrites a report in this schema into bincode, for `WireFormat::Legacy`.
+pub fn legacy_report(mem: &[u8]) -> Result<Bytes> {
+    let report = decode_report(mem)?;
+    Ok(::bincode::serialize(&report, ::bincode::Infinite)?.into())
+}